@@ -0,0 +1,21 @@
+fn main() {
+    #[cfg(feature = "ffi")]
+    generate_ffi_header();
+}
+
+/// Write the C header for `src/ffi.rs` to `include/mostro_push.h`.
+#[cfg(feature = "ffi")]
+fn generate_ffi_header() {
+    let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    println!("cargo:rerun-if-changed=src/ffi.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+
+    let config = cbindgen::Config::from_file(format!("{}/cbindgen.toml", crate_dir))
+        .expect("Failed to read cbindgen.toml");
+    cbindgen::Builder::new()
+        .with_src(format!("{}/src/ffi.rs", crate_dir))
+        .with_config(config)
+        .generate()
+        .expect("Failed to generate FFI header")
+        .write_to_file(format!("{}/include/mostro_push.h", crate_dir));
+}
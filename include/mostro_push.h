@@ -0,0 +1,65 @@
+/* Generated by cbindgen from src/ffi.rs; do not edit. */
+
+#ifndef MOSTRO_PUSH_H
+#define MOSTRO_PUSH_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define MOSTRO_PUSH_OK 0
+
+// A required pointer was null
+#define MOSTRO_PUSH_ERR_NULL_POINTER 1
+
+// The server public key is not a valid 33-byte compressed secp256k1 key
+#define MOSTRO_PUSH_ERR_INVALID_PUBKEY 2
+
+// Platform is not one of the `MOSTRO_PUSH_PLATFORM_*` constants
+#define MOSTRO_PUSH_ERR_INVALID_PLATFORM 3
+
+// The device token is empty, too long or not UTF-8
+#define MOSTRO_PUSH_ERR_INVALID_TOKEN 4
+
+// The output buffer is too small for the envelope; `mostro_push_envelope_size()` always fits
+#define MOSTRO_PUSH_ERR_BUFFER_TOO_SMALL 5
+
+#define MOSTRO_PUSH_ERR_INTERNAL 6
+
+#define MOSTRO_PUSH_PLATFORM_IOS 1
+
+#define MOSTRO_PUSH_PLATFORM_ANDROID 2
+
+// Web Push; the device token is the browser's subscription as JSON
+#define MOSTRO_PUSH_PLATFORM_WEB 3
+
+// Largest encrypted token in bytes. Tokens too long for the standard
+// envelope get a larger one, up to this size.
+size_t mostro_push_envelope_size(void);
+
+// Encrypt `device_token` to the server's compressed public key.
+//
+// `trade_pubkey` may be null for a v1 envelope; otherwise it must point to
+// the 32-byte trade pubkey, producing a v2 envelope bound to it. On success
+// the envelope is written to `out`, at most `mostro_push_envelope_size()`
+// bytes, and its length is stored in `written`. On error nothing is written
+// to `out`.
+//
+// # Safety
+//
+// Every non-null pointer must be valid for the length given with it (32
+// bytes for `trade_pubkey`) for the duration of the call, and `written`
+// must be valid for a write.
+int32_t mostro_push_encrypt_token(const uint8_t *server_pubkey,
+                                  size_t server_pubkey_len,
+                                  uint8_t platform,
+                                  const uint8_t *device_token,
+                                  size_t device_token_len,
+                                  const uint8_t *trade_pubkey,
+                                  uint8_t *out,
+                                  size_t out_len,
+                                  size_t *written);
+
+#endif /* MOSTRO_PUSH_H */
@@ -0,0 +1,105 @@
+use log::{info, warn};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::crypto::Platform;
+use crate::utils::signing::SigningClient;
+
+/// Operator alert fired the first time a trade pubkey registers, e.g. to follow
+/// onboarding during a closed beta. Re-registrations of a stored pubkey are ignored.
+pub struct RegistrationAlerts {
+    enabled: bool,
+    webhook_url: Option<String>,
+    client: SigningClient,
+    fired: AtomicU64,
+}
+
+impl RegistrationAlerts {
+    pub fn new(enabled: bool, webhook_url: Option<String>) -> Self {
+        Self {
+            enabled,
+            webhook_url,
+            client: SigningClient::default(),
+            fired: AtomicU64::new(0),
+        }
+    }
+
+    /// Send webhooks through `client`, e.g. to sign them.
+    pub fn with_client(mut self, client: SigningClient) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Alerts fired since start.
+    pub fn fired(&self) -> u64 {
+        self.fired.load(Ordering::Relaxed)
+    }
+
+    /// Called after every successful registration. Returns true if the alert fired.
+    pub fn on_registered(&self, trade_pubkey: &str, platform: &Platform, is_new: bool) -> bool {
+        if !self.enabled || !is_new {
+            return false;
+        }
+
+        self.fired.fetch_add(1, Ordering::Relaxed);
+        let pubkey_prefix = &trade_pubkey[..16.min(trade_pubkey.len())];
+        info!("First registration for trade_pubkey {}... ({})", pubkey_prefix, platform);
+
+        if let Some(url) = &self.webhook_url {
+            let client = self.client.clone();
+            let request = client
+                .post(url)
+                .json(&serde_json::json!({
+                    "event": "first_registration",
+                    "trade_pubkey_prefix": pubkey_prefix,
+                    "platform": platform,
+                    "registered_at": chrono::Utc::now().to_rfc3339(),
+                }));
+            tokio::spawn(async move {
+                match client.send(request).await {
+                    Ok(response) if !response.status().is_success() => {
+                        warn!("First-registration webhook returned {}", response.status());
+                    }
+                    Err(e) => warn!("First-registration webhook failed: {}", e),
+                    Ok(_) => {}
+                }
+            });
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{MemoryTokenStore, TokenStore};
+
+    const PUBKEY_A: &str = "a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2";
+    const PUBKEY_B: &str = "b1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2";
+
+    #[tokio::test]
+    async fn test_alert_fires_only_for_new_pubkeys() {
+        let store = MemoryTokenStore::new(48);
+        let alerts = RegistrationAlerts::new(true, None);
+
+        let is_new = store.register(PUBKEY_A.to_string(), "token-1".to_string(), Platform::Android).await;
+        assert!(alerts.on_registered(PUBKEY_A, &Platform::Android, is_new));
+
+        // Same pubkey refreshing its token is not a first registration
+        let is_new = store.register(PUBKEY_A.to_string(), "token-2".to_string(), Platform::Android).await;
+        assert!(!alerts.on_registered(PUBKEY_A, &Platform::Android, is_new));
+
+        let is_new = store.register(PUBKEY_B.to_string(), "token-3".to_string(), Platform::Ios).await;
+        assert!(alerts.on_registered(PUBKEY_B, &Platform::Ios, is_new));
+    }
+
+    #[tokio::test]
+    async fn test_disabled_alert_never_fires() {
+        let store = MemoryTokenStore::new(48);
+        let alerts = RegistrationAlerts::new(false, None);
+
+        let is_new = store.register(PUBKEY_A.to_string(), "token-1".to_string(), Platform::Android).await;
+        assert!(is_new);
+        assert!(!alerts.on_registered(PUBKEY_A, &Platform::Android, is_new));
+    }
+}
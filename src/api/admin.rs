@@ -0,0 +1,1071 @@
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use base64::Engine;
+use log::{info, warn};
+use nostr_sdk::{Event, EventBuilder, Keys, Kind, Tag};
+use sha2::{Digest, Sha256};
+use chrono::Utc;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use super::dashboard;
+use super::routes::{catch_up_payload, json_config, AppState};
+use crate::models::{
+    AnnotationsResponse, ErrorCode, ErrorResponse, ExportResponse, ExportedRegistration, FlagEvaluation,
+    FlagOverrideRequest, FlagsResponse, GcResponse,
+    LeadershipRequest, LeadershipResponse, MigrateRequest, MigrationReport, PreviewMode, PreviewRequest,
+    PreviewResponse, ReconnectResponse,
+    SetAnnotationRequest, TasksResponse, TestSendRequest, TestSendResponse, UnregisterResponse, WatchListResponse, WatchRecordsResponse,
+    WatchRequest, WatchResponse,
+};
+use crate::flags::{FlagError, FLAGS};
+use crate::nostr::listener::{grouping_id, recipient, wake_payload};
+use crate::nostr::InboundEvent;
+use crate::push::PushPayload;
+use crate::store::{migrate, store_key, AnnotationError, PushEnvironment, RegisteredToken};
+
+/// Default and longest `/admin/watch` durations.
+const DEFAULT_WATCH_SECS: u64 = 3600;
+const MAX_WATCH_SECS: u64 = 86_400;
+
+/// How long `/admin/reconnect` waits for the listener to reconnect.
+const RECONNECT_WAIT: Duration = Duration::from_secs(5);
+
+/// Operator endpoints, authenticated with `Authorization: Bearer <ADMIN_TOKEN>`.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/admin")
+            .app_data(json_config())
+            .route(
+                "/registrations/{trade_pubkey}/annotations",
+                web::get().to(get_annotations),
+            )
+            .route(
+                "/registrations/{trade_pubkey}/annotations/{key}",
+                web::put().to(set_annotation),
+            )
+            .route(
+                "/registrations/{trade_pubkey}/annotations/{key}",
+                web::delete().to(remove_annotation),
+            )
+            .route("/registrations/{trade_pubkey}", web::delete().to(evict_registration))
+            .route("/config", web::get().to(effective_config))
+            .route("/export", web::get().to(export_registrations))
+            .route("/migrate", web::post().to(migrate_registrations))
+            .route("/leadership", web::post().to(set_leadership))
+            .route("/reconnect", web::post().to(reconnect_relays))
+            .route("/test-send", web::post().to(test_send))
+            .route("/preview", web::post().to(preview))
+            .route("/stats/sli", web::get().to(delivery_sli))
+            .route("/gc", web::post().to(run_gc))
+            .route("/tasks", web::get().to(list_tasks))
+            .route("/tasks/{name}/run", web::post().to(run_task))
+            .route("/watch", web::get().to(list_watches))
+            .route("/watch/{trade_pubkey}", web::get().to(watch_records))
+            .route("/watch/{trade_pubkey}", web::put().to(watch_pubkey))
+            .route("/watch/{trade_pubkey}", web::delete().to(unwatch_pubkey))
+            .route("/dashboard", web::get().to(dashboard::dashboard))
+            .route("/dashboard/data", web::get().to(dashboard::dashboard_data))
+            .route("/flags", web::get().to(list_flags))
+            .route("/flags/{flag}/{trade_pubkey}", web::get().to(evaluate_flag))
+            .route("/flags/{flag}/{trade_pubkey}", web::put().to(override_flag))
+            .route("/flags/{flag}/{trade_pubkey}", web::delete().to(clear_flag_override))
+    );
+}
+
+/// The admin token a request presents: as a bearer token, or as the
+/// password of HTTP basic auth so a browser can open the dashboard.
+fn provided_token(http_req: &HttpRequest) -> Option<String> {
+    let header = http_req.headers().get("Authorization")?.to_str().ok()?;
+    if let Some(token) = header.strip_prefix("Bearer ") {
+        return Some(token.to_string());
+    }
+    let credentials = base64::engine::general_purpose::STANDARD
+        .decode(header.strip_prefix("Basic ")?)
+        .ok()?;
+    let credentials = String::from_utf8(credentials).ok()?;
+    credentials.split_once(':').map(|(_, password)| password.to_string())
+}
+
+/// Returns a 401 response unless the request carries the configured admin token.
+pub(super) fn authorize(http_req: &HttpRequest, state: &AppState) -> Result<(), HttpResponse> {
+    let provided = provided_token(http_req);
+
+    match (&state.admin_token, provided) {
+        (Some(expected), Some(provided)) if constant_time_eq(expected.as_bytes(), provided.as_bytes()) => {
+            Ok(())
+        }
+        _ => {
+            warn!("Rejected unauthorized admin request to {}", http_req.path());
+            Err(HttpResponse::Unauthorized().json(ErrorResponse::new(
+                ErrorCode::Unauthorized,
+                "Missing or invalid admin token",
+            )))
+        }
+    }
+}
+
+/// Audit actor for an authorized request. There is a single admin token, so
+/// this identifies which token was used (e.g. across a rotation), not a person.
+fn actor(http_req: &HttpRequest) -> String {
+    let token = provided_token(http_req).unwrap_or_default();
+    format!("admin-token:{}", &hex::encode(Sha256::digest(token.as_bytes()))[..8])
+}
+
+pub(super) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn annotation_error(e: AnnotationError) -> HttpResponse {
+    match e {
+        AnnotationError::NotRegistered => HttpResponse::NotFound()
+            .json(AnnotationsResponse::error(ErrorCode::NotRegistered, e.to_string())),
+        _ => HttpResponse::BadRequest()
+            .json(AnnotationsResponse::error(ErrorCode::InvalidAnnotation, e.to_string())),
+    }
+}
+
+/// Every device of `trade_pubkey` in every environment. Annotations belong to
+/// the pubkey, so they are read from and written to all of them.
+async fn registrations_of(state: &AppState, trade_pubkey: &str) -> Vec<(String, RegisteredToken)> {
+    let mut registrations = Vec::new();
+    for environment in PushEnvironment::ALL {
+        let key = store_key(trade_pubkey, environment);
+        registrations.extend(state.token_store.devices(&key, state.max_devices_per_pubkey).await);
+    }
+    registrations
+}
+
+/// The pubkey's annotations, as its first registration holds them.
+async fn annotations_of(state: &AppState, trade_pubkey: &str) -> Option<BTreeMap<String, String>> {
+    registrations_of(state, trade_pubkey).await.into_iter().next().map(|(_, token)| token.annotations)
+}
+
+async fn get_annotations(
+    http_req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if let Err(resp) = authorize(&http_req, &state) {
+        return resp;
+    }
+
+    match annotations_of(&state, &path).await {
+        Some(annotations) => HttpResponse::Ok().json(AnnotationsResponse::ok("OK", annotations)),
+        None => annotation_error(AnnotationError::NotRegistered),
+    }
+}
+
+async fn set_annotation(
+    http_req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    req: web::Json<SetAnnotationRequest>,
+) -> impl Responder {
+    if let Err(resp) = authorize(&http_req, &state) {
+        return resp;
+    }
+
+    let (trade_pubkey, key) = path.into_inner();
+    let mut result = Err(AnnotationError::NotRegistered);
+    for (device, _) in registrations_of(&state, &trade_pubkey).await {
+        result = state.token_store.set_annotation(&device, &key, &req.value).await;
+        if result.is_err() {
+            break;
+        }
+    }
+    state.audit.record(
+        &actor(&http_req),
+        "set_annotation",
+        &format!("{}/{}", trade_pubkey, key),
+        result.as_ref().map(|_| ()).map_err(ToString::to_string),
+    );
+    if let Err(e) = result {
+        return annotation_error(e);
+    }
+    info!(
+        "Annotated {}... with {}",
+        &trade_pubkey[..16.min(trade_pubkey.len())],
+        key
+    );
+
+    let annotations = annotations_of(&state, &trade_pubkey).await.unwrap_or_default();
+    HttpResponse::Ok().json(AnnotationsResponse::ok("Annotation set", annotations))
+}
+
+async fn remove_annotation(
+    http_req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    if let Err(resp) = authorize(&http_req, &state) {
+        return resp;
+    }
+
+    let (trade_pubkey, key) = path.into_inner();
+    let mut result = Err(AnnotationError::NotRegistered);
+    for (device, _) in registrations_of(&state, &trade_pubkey).await {
+        let removed = state.token_store.remove_annotation(&device, &key).await;
+        result = match (result, removed) {
+            (Ok(earlier), Ok(removed)) => Ok(earlier || removed),
+            (_, removed) => removed,
+        };
+        if result.is_err() {
+            break;
+        }
+    }
+    state.audit.record(
+        &actor(&http_req),
+        "remove_annotation",
+        &format!("{}/{}", trade_pubkey, key),
+        result.as_ref().map(|_| ()).map_err(ToString::to_string),
+    );
+    let removed = match result {
+        Ok(removed) => removed,
+        Err(e) => return annotation_error(e),
+    };
+
+    let annotations = annotations_of(&state, &trade_pubkey).await.unwrap_or_default();
+    let message = if removed { "Annotation removed" } else { "Annotation not found" };
+    HttpResponse::Ok().json(AnnotationsResponse::ok(message, annotations))
+}
+
+/// Remove a registration, e.g. one reported as abusive.
+async fn evict_registration(
+    http_req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if let Err(resp) = authorize(&http_req, &state) {
+        return resp;
+    }
+
+    let trade_pubkey = path.into_inner();
+    let mut removed = false;
+    for (slot_key, _) in state.token_store.devices(&trade_pubkey, state.max_devices_per_pubkey).await {
+        removed |= state.token_store.unregister(&slot_key).await.is_some();
+    }
+    let result = if removed { Ok(()) } else { Err("not registered".to_string()) };
+    state.audit.record(&actor(&http_req), "evict", &trade_pubkey, result);
+
+    if removed {
+        HttpResponse::Ok().json(UnregisterResponse::ok("Registration evicted"))
+    } else {
+        HttpResponse::NotFound().json(UnregisterResponse::error(
+            ErrorCode::NotRegistered,
+            "No registration for this trade_pubkey",
+        ))
+    }
+}
+
+async fn effective_config(http_req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    if let Err(resp) = authorize(&http_req, &state) {
+        return resp;
+    }
+    HttpResponse::Ok().json(state.config_report.as_ref())
+}
+
+/// All registrations, including device tokens, for `/admin/migrate` on a new instance.
+async fn export_registrations(
+    http_req: HttpRequest,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    if let Err(resp) = authorize(&http_req, &state) {
+        return resp;
+    }
+
+    let registrations: Vec<ExportedRegistration> = state.token_store.export().await
+        .into_iter()
+        .map(|(trade_pubkey, token)| ExportedRegistration { trade_pubkey, token })
+        .collect();
+    info!("Exporting {} registrations", registrations.len());
+    HttpResponse::Ok().json(ExportResponse { registrations })
+}
+
+/// Pull registrations from a running instance's `/admin/export` into this one.
+async fn migrate_registrations(
+    http_req: HttpRequest,
+    state: web::Data<AppState>,
+    req: web::Json<MigrateRequest>,
+) -> impl Responder {
+    if let Err(resp) = authorize(&http_req, &state) {
+        return resp;
+    }
+
+    info!(
+        "Migrating registrations from {} (policy: {:?}, dry run: {})",
+        req.source_url, req.conflict_policy, req.dry_run
+    );
+    let action = if req.dry_run { "migrate_dry_run" } else { "migrate" };
+    let client = reqwest::Client::new();
+    let registrations = match migrate::fetch_export(&client, &req.source_url, &req.source_admin_token).await {
+        Ok(registrations) => registrations,
+        Err(e) => {
+            warn!("Migration export failed: {}", e);
+            state.audit.record(&actor(&http_req), action, &req.source_url, Err(e.to_string()));
+            return HttpResponse::BadGateway().json(MigrationReport {
+                message: format!("Failed to export from source: {}", e),
+                dry_run: req.dry_run,
+                error_code: Some(ErrorCode::MigrationFailed),
+                ..Default::default()
+            });
+        }
+    };
+
+    let report = migrate::apply(state.token_store.as_ref(), registrations, req.conflict_policy, req.dry_run).await;
+    state.audit.record(
+        &actor(&http_req),
+        action,
+        &req.source_url,
+        if report.success { Ok(()) } else { Err(report.message.clone()) },
+    );
+    if report.success {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::InternalServerError().json(report)
+    }
+}
+
+/// Promote this instance to leader or demote it to standby.
+async fn set_leadership(
+    http_req: HttpRequest,
+    state: web::Data<AppState>,
+    req: web::Json<LeadershipRequest>,
+) -> impl Responder {
+    if let Err(resp) = authorize(&http_req, &state) {
+        return resp;
+    }
+
+    state.leadership.set_leader(req.leader);
+    state.audit.record(
+        &actor(&http_req),
+        if req.leader { "promote" } else { "demote" },
+        "leadership",
+        Ok(()),
+    );
+    HttpResponse::Ok().json(LeadershipResponse { leader: state.leadership.is_leader() })
+}
+
+/// Drop relay connections and reconnect now, skipping any backoff.
+async fn reconnect_relays(http_req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    if let Err(resp) = authorize(&http_req, &state) {
+        return resp;
+    }
+
+    let mut connections = state.reconnect.subscribe();
+    connections.borrow_and_update();
+    state.reconnect.request();
+    let reconnected = matches!(tokio::time::timeout(RECONNECT_WAIT, connections.changed()).await, Ok(Ok(())));
+    if !reconnected {
+        warn!("Listener did not reconnect within {:?}", RECONNECT_WAIT);
+    }
+    state.audit.record(
+        &actor(&http_req),
+        "reconnect",
+        "relays",
+        if reconnected { Ok(()) } else { Err("reconnect still pending".to_string()) },
+    );
+    HttpResponse::Ok().json(ReconnectResponse {
+        reconnected,
+        relays: state.relay_health.snapshot(),
+    })
+}
+
+/// Delivery SLI per configured window.
+async fn delivery_sli(http_req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    if let Err(resp) = authorize(&http_req, &state) {
+        return resp;
+    }
+    HttpResponse::Ok().json(state.metrics.delivery_sli.report(Utc::now()))
+}
+
+/// Send a test push to a raw device token, to check provider credentials
+/// without registering the device.
+async fn test_send(
+    http_req: HttpRequest,
+    state: web::Data<AppState>,
+    req: web::Json<TestSendRequest>,
+) -> impl Responder {
+    if let Err(resp) = authorize(&http_req, &state) {
+        return resp;
+    }
+
+    let TestSendRequest { platform, device_token, app_id } = req.into_inner();
+    let Some((provider, result)) = state
+        .dispatcher
+        .test_send(&platform, &device_token, app_id.as_deref(), &PushPayload::test_notification())
+        .await
+    else {
+        return HttpResponse::BadRequest().json(ErrorResponse::new(
+            ErrorCode::UnsupportedPlatform,
+            "No push service is configured for this platform",
+        ));
+    };
+    state.audit.record(&actor(&http_req), "test_send", &format!("{}/{}", platform, provider), result.clone());
+
+    HttpResponse::Ok().json(TestSendResponse {
+        success: result.is_ok(),
+        provider: provider.to_string(),
+        error: result.err(),
+    })
+}
+
+/// Build the push a device would get, for a synthetic event or a catch-up
+/// or test push, through the same payload, decorator, sealing and provider
+/// code as real dispatch. Nothing is sent and no credentials are minted.
+async fn preview(
+    http_req: HttpRequest,
+    state: web::Data<AppState>,
+    req: web::Json<PreviewRequest>,
+) -> impl Responder {
+    if let Err(resp) = authorize(&http_req, &state) {
+        return resp;
+    }
+
+    let req = req.into_inner();
+    let failed = |message: String| HttpResponse::BadRequest().json(ErrorResponse::new(ErrorCode::PreviewFailed, message));
+    let mut token = RegisteredToken::new(req.device_token, req.platform)
+        .with_app_id(req.app_id)
+        .with_environment(req.environment);
+    if let Some(push_key) = req.push_key {
+        token.push_key = Some(push_key.to_ascii_lowercase());
+        if token.push_key_bytes().is_none() {
+            return failed("push_key must be 32 hex-encoded bytes".to_string());
+        }
+    }
+
+    let (category, payload) = match req.mode {
+        PreviewMode::Test => (None, PushPayload::test_notification()),
+        PreviewMode::CatchUp => match &req.trade_pubkey {
+            Some(trade_pubkey) => (None, catch_up_payload(&state, trade_pubkey)),
+            None => return failed("catch_up previews need a trade_pubkey".to_string()),
+        },
+        PreviewMode::Event => {
+            let event = match synthetic_event(req.trade_pubkey.as_deref(), &req.tags) {
+                Ok(event) => event,
+                Err(e) => return failed(format!("invalid synthetic event: {}", e)),
+            };
+            let trade_pubkey = recipient(&event);
+            let inbound = InboundEvent {
+                event_id: event.id.to_hex(),
+                grouping_id: grouping_id(state.grouping.as_ref(), &event, trade_pubkey.as_deref()),
+                trade_pubkey,
+                no_push: false,
+                category: state.classifier.classify(&event),
+                relay: None,
+            };
+            (inbound.category, wake_payload(&state.flags, &inbound))
+        }
+    };
+
+    match state.dispatcher.preview(&token, &payload).await {
+        Ok(requests) if requests.is_empty() => HttpResponse::BadRequest().json(ErrorResponse::new(
+            ErrorCode::UnsupportedPlatform,
+            "No push service is configured for this platform",
+        )),
+        Ok(requests) => HttpResponse::Ok().json(PreviewResponse {
+            category,
+            sealed: token.push_key.is_some(),
+            requests,
+        }),
+        Err(e) => failed(e),
+    }
+}
+
+/// A kind 1059 event as Mostro would send it to `trade_pubkey`, carrying `tags`.
+fn synthetic_event(trade_pubkey: Option<&str>, tags: &[Vec<String>]) -> Result<Event, String> {
+    let mut parsed = Vec::new();
+    if let Some(trade_pubkey) = trade_pubkey {
+        parsed.push(Tag::parse(vec!["p", trade_pubkey]).map_err(|e| e.to_string())?);
+    }
+    for tag in tags {
+        parsed.push(Tag::parse(tag.clone()).map_err(|e| e.to_string())?);
+    }
+    EventBuilder::new(Kind::Custom(1059), "", parsed)
+        .to_event(&Keys::generate())
+        .map_err(|e| e.to_string())
+}
+
+/// Run a GC pass now rather than at its next scheduled time.
+async fn run_gc(http_req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    if let Err(resp) = authorize(&http_req, &state) {
+        return resp;
+    }
+    let datasets = state.gc.run().await;
+    let failed: Vec<_> = datasets.iter().filter_map(|d| d.error.as_ref().map(|e| format!("{}: {}", d.dataset, e))).collect();
+    let result = if failed.is_empty() { Ok(()) } else { Err(failed.join(", ")) };
+    state.audit.record(&actor(&http_req), "gc", "all", result);
+    HttpResponse::Ok().json(GcResponse { datasets })
+}
+
+async fn list_tasks(http_req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    if let Err(resp) = authorize(&http_req, &state) {
+        return resp;
+    }
+    HttpResponse::Ok().json(TasksResponse { tasks: state.scheduler.snapshot() })
+}
+
+/// Run a background task now instead of at its next scheduled time.
+async fn run_task(
+    http_req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if let Err(resp) = authorize(&http_req, &state) {
+        return resp;
+    }
+
+    let name = path.into_inner();
+    let triggered = state.scheduler.trigger(&name);
+    let result = if triggered { Ok(()) } else { Err("no such task".to_string()) };
+    state.audit.record(&actor(&http_req), "run_task", &name, result);
+
+    if triggered {
+        HttpResponse::Accepted().json(TasksResponse { tasks: state.scheduler.snapshot() })
+    } else {
+        HttpResponse::NotFound().json(ErrorResponse::new(ErrorCode::UnknownTask, "No task with this name"))
+    }
+}
+
+async fn list_watches(http_req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    if let Err(resp) = authorize(&http_req, &state) {
+        return resp;
+    }
+    HttpResponse::Ok().json(WatchListResponse { watches: state.watch_list.status(Utc::now()) })
+}
+
+/// Everything recorded for a watched pubkey, oldest first.
+async fn watch_records(
+    http_req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if let Err(resp) = authorize(&http_req, &state) {
+        return resp;
+    }
+    match state.watch_list.records(&path) {
+        Some(records) => HttpResponse::Ok().json(WatchRecordsResponse { records }),
+        None => HttpResponse::NotFound().json(ErrorResponse::new(ErrorCode::NotWatched, "This pubkey is not watched")),
+    }
+}
+
+/// Trace a pubkey's events through lookup, queueing and provider sends.
+async fn watch_pubkey(
+    http_req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    req: Option<web::Json<WatchRequest>>,
+) -> impl Responder {
+    if let Err(resp) = authorize(&http_req, &state) {
+        return resp;
+    }
+
+    let trade_pubkey = path.into_inner();
+    let secs = req
+        .and_then(|req| req.duration_secs)
+        .unwrap_or(DEFAULT_WATCH_SECS)
+        .min(MAX_WATCH_SECS);
+    let result = state.watch_list.watch(&trade_pubkey, chrono::Duration::seconds(secs as i64), Utc::now());
+    state.audit.record(
+        &actor(&http_req),
+        "watch",
+        &trade_pubkey,
+        result.as_ref().map(|_| ()).map_err(|e| e.to_string()),
+    );
+    match result {
+        Ok(expires_at) => {
+            info!("Watching {}... until {}", &trade_pubkey[..16.min(trade_pubkey.len())], expires_at);
+            HttpResponse::Ok().json(WatchResponse { trade_pubkey, expires_at })
+        }
+        Err(e) => HttpResponse::Conflict().json(ErrorResponse::new(ErrorCode::WatchListFull, e.to_string())),
+    }
+}
+
+async fn unwatch_pubkey(
+    http_req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if let Err(resp) = authorize(&http_req, &state) {
+        return resp;
+    }
+
+    let trade_pubkey = path.into_inner();
+    let removed = state.watch_list.unwatch(&trade_pubkey);
+    state.audit.record(
+        &actor(&http_req),
+        "unwatch",
+        &trade_pubkey,
+        if removed { Ok(()) } else { Err("not watched".to_string()) },
+    );
+    if removed {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().json(ErrorResponse::new(ErrorCode::NotWatched, "This pubkey is not watched"))
+    }
+}
+
+async fn list_flags(http_req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    if let Err(resp) = authorize(&http_req, &state) {
+        return resp;
+    }
+    HttpResponse::Ok().json(FlagsResponse { flags: state.flags.status() })
+}
+
+fn unknown_flag() -> HttpResponse {
+    HttpResponse::NotFound().json(ErrorResponse::new(ErrorCode::UnknownFlag, "No flag with this name"))
+}
+
+/// How a flag evaluates for a pubkey, without counting it in metrics.
+async fn evaluate_flag(
+    http_req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    if let Err(resp) = authorize(&http_req, &state) {
+        return resp;
+    }
+
+    let (flag, trade_pubkey) = path.into_inner();
+    if !FLAGS.contains(&flag.as_str()) {
+        return unknown_flag();
+    }
+    let (enabled, overridden) = state.flags.evaluate(&flag, &trade_pubkey);
+    HttpResponse::Ok().json(FlagEvaluation { flag, trade_pubkey, enabled, overridden })
+}
+
+/// Turn a flag on or off for one pubkey, whatever its rollout fraction.
+async fn override_flag(
+    http_req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    req: web::Json<FlagOverrideRequest>,
+) -> impl Responder {
+    if let Err(resp) = authorize(&http_req, &state) {
+        return resp;
+    }
+
+    let (flag, trade_pubkey) = path.into_inner();
+    let result = state.flags.set_override(&flag, &trade_pubkey, req.enabled);
+    state.audit.record(
+        &actor(&http_req),
+        &format!("flag_override:{}", flag),
+        &trade_pubkey,
+        result.clone().map_err(|e| e.to_string()),
+    );
+    match result {
+        Ok(()) => {
+            info!(
+                "Flag {} {} for {}...",
+                flag,
+                if req.enabled { "enabled" } else { "disabled" },
+                &trade_pubkey[..16.min(trade_pubkey.len())]
+            );
+            HttpResponse::Ok().json(FlagEvaluation { flag, trade_pubkey, enabled: req.enabled, overridden: true })
+        }
+        Err(FlagError::UnknownFlag(_)) => unknown_flag(),
+        Err(e) => HttpResponse::Conflict().json(ErrorResponse::new(ErrorCode::FlagOverridesFull, e.to_string())),
+    }
+}
+
+async fn clear_flag_override(
+    http_req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    if let Err(resp) = authorize(&http_req, &state) {
+        return resp;
+    }
+
+    let (flag, trade_pubkey) = path.into_inner();
+    let removed = state.flags.clear_override(&flag, &trade_pubkey);
+    state.audit.record(
+        &actor(&http_req),
+        &format!("flag_clear:{}", flag),
+        &trade_pubkey,
+        if removed { Ok(()) } else { Err("no override".to_string()) },
+    );
+    if removed {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().json(ErrorResponse::new(ErrorCode::NoFlagOverride, "This pubkey has no override for this flag"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::routes::tests::{register_body, test_state, TEST_TRADE_PUBKEY};
+    use crate::audit::{AuditEntry, AuditLog};
+    use std::sync::Arc;
+    use crate::crypto::Platform;
+    use crate::health::Readiness;
+    use crate::metrics::Metrics;
+    use crate::models::ConflictPolicy;
+    use crate::push::testing::MockPush;
+    use crate::push::{Dispatcher, PushService};
+    use actix_web::{test, App, HttpServer};
+
+    const ADMIN_TOKEN: &str = "admin-secret";
+
+    #[actix_web::test]
+    async fn test_eviction_is_audited() {
+        let readiness = Readiness::new(0);
+        readiness.mark_store_loaded();
+        let audit_path = std::env::temp_dir().join(format!("mostro-audit-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&audit_path);
+        let mut state = test_state(readiness);
+        state.admin_token = Some(ADMIN_TOKEN.to_string());
+        state.audit = Arc::new(AuditLog::new(Some(&audit_path), None).unwrap());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(crate::api::routes::configure)
+                .configure(configure),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/register")
+            .set_json(register_body(Platform::Android, "fcm-token"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/admin/registrations/{}", TEST_TRADE_PUBKEY))
+            .insert_header(("Authorization", format!("Bearer {}", ADMIN_TOKEN)))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+        assert!(state.token_store.get(TEST_TRADE_PUBKEY).await.is_none());
+
+        let content = std::fs::read_to_string(&audit_path).unwrap();
+        let entries: Vec<AuditEntry> = content.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].action, "evict");
+        assert_eq!(entries[0].target, TEST_TRADE_PUBKEY);
+        assert_eq!(entries[0].result, "ok");
+        assert!(entries[0].actor.starts_with("admin-token:"));
+        std::fs::remove_file(&audit_path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_annotations_require_admin_token() {
+        let readiness = Readiness::new(0);
+        readiness.mark_store_loaded();
+        let mut state = test_state(readiness);
+        state.admin_token = Some(ADMIN_TOKEN.to_string());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(crate::api::routes::configure)
+                .configure(configure),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/register")
+            .set_json(register_body(Platform::Android, "fcm-token"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+        let req = test::TestRequest::post()
+            .uri("/api/register")
+            .set_json(register_body(Platform::Android, "tablet-token"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+        let uri = format!("/admin/registrations/{}/annotations/note", TEST_TRADE_PUBKEY);
+        let req = test::TestRequest::put()
+            .uri(&uri)
+            .set_json(serde_json::json!({ "value": "beta tester" }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 401);
+
+        let req = test::TestRequest::put()
+            .uri(&uri)
+            .insert_header(("Authorization", "Bearer wrong"))
+            .set_json(serde_json::json!({ "value": "beta tester" }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 401);
+
+        let req = test::TestRequest::put()
+            .uri(&uri)
+            .insert_header(("Authorization", format!("Bearer {}", ADMIN_TOKEN)))
+            .set_json(serde_json::json!({ "value": "beta tester" }))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["annotations"]["note"], "beta tester");
+
+        // Annotations belong to the pubkey, so every device carries them
+        let devices = state.token_store.devices(TEST_TRADE_PUBKEY, state.max_devices_per_pubkey).await;
+        assert_eq!(devices.len(), 2);
+        assert!(devices.iter().all(|(_, token)| token.annotations["note"] == "beta tester"));
+
+        // Public endpoints never reveal annotations
+        for uri in ["/api/status", "/api/info", "/api/health", "/api/metrics"] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let body = test::call_and_read_body(&app, req).await;
+            assert!(!String::from_utf8_lossy(&body).contains("beta tester"), "{} leaked", uri);
+        }
+        let req = test::TestRequest::post()
+            .uri("/api/register")
+            .set_json(register_body(Platform::Android, "fcm-token"))
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert!(!String::from_utf8_lossy(&body).contains("beta tester"));
+
+        let req = test::TestRequest::delete()
+            .uri(&uri)
+            .insert_header(("Authorization", format!("Bearer {}", ADMIN_TOKEN)))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["message"], "Annotation removed");
+        let devices = state.token_store.devices(TEST_TRADE_PUBKEY, state.max_devices_per_pubkey).await;
+        assert!(devices.iter().all(|(_, token)| token.annotations.is_empty()));
+    }
+
+    #[actix_web::test]
+    async fn test_admin_api_disabled_without_token() {
+        let state = test_state(Readiness::new(0));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .configure(configure),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/admin/registrations/{}/annotations", TEST_TRADE_PUBKEY))
+            .insert_header(("Authorization", "Bearer "))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 401);
+    }
+
+    /// Run a real HTTP server for the state on a free local port.
+    fn start_server(state: AppState) -> String {
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(crate::api::routes::configure)
+                .configure(configure)
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let addr = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+        format!("http://{}", addr)
+    }
+
+    async fn post_migrate(target: &str, body: serde_json::Value) -> (u16, MigrationReport) {
+        let response = reqwest::Client::new()
+            .post(format!("{}/admin/migrate", target))
+            .bearer_auth("target-secret")
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        (response.status().as_u16(), response.json().await.unwrap())
+    }
+
+    #[actix_web::test]
+    async fn test_migration_between_instances() {
+        let pubkeys: Vec<String> = (0..3).map(|i| format!("{:064x}", i + 1)).collect();
+
+        let mut source = test_state(Readiness::new(0));
+        source.admin_token = Some("source-secret".to_string());
+        for (i, pubkey) in pubkeys.iter().enumerate() {
+            source.token_store
+                .register(pubkey.clone(), format!("source-token-{}", i), Platform::Android)
+                .await;
+        }
+        source.token_store.set_annotation(&pubkeys[0], "beta", "tester").await.unwrap();
+
+        // The target already knows one pubkey, with an older token
+        let mut target = test_state(Readiness::new(0));
+        target.admin_token = Some("target-secret".to_string());
+        target.token_store.register(pubkeys[0].clone(), "old-token".to_string(), Platform::Android).await;
+
+        let source_url = start_server(source);
+        let target_url = start_server(target.clone());
+
+        let (status, report) = post_migrate(&target_url, serde_json::json!({
+            "source_url": source_url,
+            "source_admin_token": "source-secret",
+            "conflict_policy": "overwrite",
+            "dry_run": true,
+        }))
+        .await;
+        assert_eq!(status, 200);
+        assert!(report.dry_run);
+        assert_eq!((report.inserted, report.overwritten, report.skipped), (2, 1, 0));
+        assert_eq!(target.token_store.count().await, 1);
+
+        let (status, report) = post_migrate(&target_url, serde_json::json!({
+            "source_url": source_url,
+            "source_admin_token": "source-secret",
+        }))
+        .await;
+        assert_eq!(status, 200, "{:?}", report);
+        assert_eq!((report.inserted, report.overwritten, report.skipped), (2, 0, 1));
+        assert_eq!(report.spot_checked, 2);
+        assert_eq!(target.token_store.get(&pubkeys[0]).await.unwrap().device_token, "old-token");
+
+        let (status, report) = post_migrate(&target_url, serde_json::json!({
+            "source_url": source_url,
+            "source_admin_token": "source-secret",
+            "conflict_policy": ConflictPolicy::Overwrite,
+        }))
+        .await;
+        assert_eq!(status, 200, "{:?}", report);
+        assert_eq!(report.overwritten, 3);
+        let migrated = target.token_store.get(&pubkeys[0]).await.unwrap();
+        assert_eq!(migrated.device_token, "source-token-0");
+        assert_eq!(migrated.annotations["beta"], "tester");
+        assert_eq!(target.token_store.count().await, 3);
+
+        let (status, report) = post_migrate(&target_url, serde_json::json!({
+            "source_url": source_url,
+            "source_admin_token": "wrong",
+        }))
+        .await;
+        assert_eq!(status, 502);
+        assert_eq!(report.error_code, Some(ErrorCode::MigrationFailed));
+    }
+
+    #[actix_web::test]
+    async fn test_send_reports_provider_result() {
+        let (android, sent) = MockPush::new();
+        let last_payload = android.last_payload.clone();
+        let (ios, _) = MockPush::new();
+        let services: Vec<Box<dyn PushService>> = vec![
+            Box::new(android.serving("fcm", Platform::Android)),
+            Box::new(ios.serving("apns", Platform::Ios).failing()),
+        ];
+        let mut state = AppState {
+            dispatcher: Arc::new(Dispatcher::new(
+                Arc::new(tokio::sync::RwLock::new(services)),
+                Arc::new(Metrics::new()),
+            )),
+            ..test_state(Readiness::new(0))
+        };
+        state.admin_token = Some(ADMIN_TOKEN.to_string());
+        let app = test::init_service(App::new().app_data(web::Data::new(state.clone())).configure(configure)).await;
+        let send = |platform: &str, token: Option<&str>| {
+            let mut req = test::TestRequest::post()
+                .uri("/admin/test-send")
+                .set_json(serde_json::json!({ "platform": platform, "device_token": "raw-device-token" }));
+            if let Some(token) = token {
+                req = req.insert_header(("Authorization", format!("Bearer {}", token)));
+            }
+            req.to_request()
+        };
+
+        assert_eq!(test::call_service(&app, send("android", None)).await.status(), 401);
+
+        let resp: TestSendResponse = test::call_and_read_body_json(&app, send("android", Some(ADMIN_TOKEN))).await;
+        assert_eq!(resp, TestSendResponse { success: true, provider: "fcm".to_string(), error: None });
+        assert_eq!(MockPush::sent(&sent), 1);
+        assert_eq!(last_payload.lock().unwrap().as_ref().unwrap().data["type"], "test");
+
+        let resp: TestSendResponse = test::call_and_read_body_json(&app, send("ios", Some(ADMIN_TOKEN))).await;
+        assert!(!resp.success);
+        assert_eq!(resp.provider, "apns");
+        assert_eq!(resp.error.as_deref(), Some("mock provider rejected the push"));
+
+        // Test sends don't count towards delivery stats
+        assert_eq!(state.dispatcher.delivery_stats(Duration::from_secs(300)).total.failed, 0);
+    }
+
+    /// Replace every `timestamp` value, which changes with each push.
+    fn mask_timestamps(value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    match key.as_str() {
+                        "timestamp" => *value = serde_json::json!("<timestamp>"),
+                        _ => mask_timestamps(value),
+                    }
+                }
+            }
+            serde_json::Value::Array(values) => values.iter_mut().for_each(mask_timestamps),
+            _ => {}
+        }
+    }
+
+    /// Compares each platform and mode's preview with its snapshot in
+    /// `tests/fixtures/preview`; run with `UPDATE_SNAPSHOTS=1` to rewrite them.
+    #[actix_web::test]
+    async fn test_preview_matches_snapshots() {
+        use crate::config::{Config, GroupingSource};
+        use crate::flags::{FeatureFlags, COLLAPSE_KEY};
+        use crate::push::{FcmPush, UnifiedPushService};
+        // A fixed key that is a valid point, as `p` tags must be
+        const PREVIEW_PUBKEY: &str = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+        let config = Config::for_tests();
+        let services: Vec<Box<dyn PushService>> = vec![
+            Box::new(Arc::new(FcmPush::new(config.clone()))),
+            Box::new(Arc::new(UnifiedPushService::new(config))),
+        ];
+        let state = AppState {
+            dispatcher: Arc::new(Dispatcher::new(
+                Arc::new(tokio::sync::RwLock::new(services)),
+                Arc::new(Metrics::new()),
+            )),
+            admin_token: Some(ADMIN_TOKEN.to_string()),
+            flags: Arc::new(FeatureFlags::new([(COLLAPSE_KEY.to_string(), 1.0)].into())),
+            grouping: Some(GroupingSource::Pubkey),
+            ..test_state(Readiness::new(0))
+        };
+        let app = test::init_service(App::new().app_data(web::Data::new(state.clone())).configure(configure)).await;
+        let preview = |body: serde_json::Value, token: Option<&str>| {
+            let mut req = test::TestRequest::post().uri("/admin/preview").set_json(body);
+            if let Some(token) = token {
+                req = req.insert_header(("Authorization", format!("Bearer {}", token)));
+            }
+            req.to_request()
+        };
+
+        let body = serde_json::json!({ "platform": "ios", "device_token": "device-token", "mode": "test" });
+        assert_eq!(test::call_service(&app, preview(body, None)).await.status(), 401);
+
+        let update = std::env::var_os("UPDATE_SNAPSHOTS").is_some();
+        for (platform, device_token) in [("android", "https://up.example/device-token"), ("ios", "device-token")] {
+            for mode in ["event", "catch_up", "test"] {
+                let body = serde_json::json!({
+                    "platform": platform,
+                    "device_token": device_token,
+                    "mode": mode,
+                    "trade_pubkey": PREVIEW_PUBKEY,
+                    "tags": [["category", "chat"]],
+                });
+                let resp = test::call_service(&app, preview(body, Some(ADMIN_TOKEN))).await;
+                assert_eq!(resp.status(), 200, "{}/{}", platform, mode);
+                let mut actual: serde_json::Value = test::read_body_json(resp).await;
+                mask_timestamps(&mut actual);
+
+                let path = format!("{}/tests/fixtures/preview/{}-{}.json", env!("CARGO_MANIFEST_DIR"), platform, mode);
+                if update {
+                    std::fs::write(&path, serde_json::to_string_pretty(&actual).unwrap() + "\n").unwrap();
+                }
+                let expected: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+                assert_eq!(actual, expected, "{}/{}", platform, mode);
+            }
+        }
+
+        // With a push key the data travels sealed; only the grouping id stays readable
+        let body = serde_json::json!({
+            "platform": "ios",
+            "device_token": "device-token",
+            "trade_pubkey": PREVIEW_PUBKEY,
+            "push_key": "11".repeat(32),
+        });
+        let resp: PreviewResponse = test::call_and_read_body_json(&app, preview(body, Some(ADMIN_TOKEN))).await;
+        assert!(resp.sealed);
+        let data = resp.requests[0].body["message"]["data"].as_object().unwrap();
+        assert_eq!(data.keys().collect::<Vec<_>>(), ["sealed", "thread_id"]);
+
+        let body = serde_json::json!({ "platform": "ios", "device_token": "t", "mode": "catch_up" });
+        let resp = test::call_service(&app, preview(body, Some(ADMIN_TOKEN))).await;
+        assert_eq!(resp.status(), 400);
+    }
+}
@@ -0,0 +1,128 @@
+use serde::Deserialize;
+use std::fmt;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Where the HTTP API listens: `host:port`, or `unix:/path/to.sock`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum BindAddress {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl FromStr for BindAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err("Unix socket address needs a path (unix:/path/to.sock)".to_string()),
+            Some(path) => Ok(BindAddress::Unix(PathBuf::from(path))),
+            None => Ok(BindAddress::Tcp(s.to_string())),
+        }
+    }
+}
+
+impl TryFrom<String> for BindAddress {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for BindAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BindAddress::Tcp(addr) => write!(f, "{}", addr),
+            BindAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Removes the socket file when dropped, so a clean shutdown leaves nothing behind.
+pub struct SocketGuard {
+    path: PathBuf,
+}
+
+impl Drop for SocketGuard {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Clear a socket left over from an unclean shutdown so the path can be bound
+/// again. Anything at the path that isn't a socket is left alone and reported.
+pub fn claim_socket(path: &Path) -> io::Result<SocketGuard> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    Ok(SocketGuard { path: path.to_path_buf() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::routes;
+    use crate::api::routes::tests::test_state;
+    use crate::health::Readiness;
+    use actix_web::{web, App, HttpServer};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+
+    #[test]
+    fn test_parse_bind_address() {
+        assert_eq!("0.0.0.0:8080".parse(), Ok(BindAddress::Tcp("0.0.0.0:8080".to_string())));
+        assert_eq!(
+            "unix:/run/mostro-push.sock".parse(),
+            Ok(BindAddress::Unix(PathBuf::from("/run/mostro-push.sock")))
+        );
+        assert!("unix:".parse::<BindAddress>().is_err());
+    }
+
+    /// Serves the API on a Unix socket, over a path holding a stale socket from
+    /// a previous run, and checks the socket is removed again afterwards.
+    #[actix_web::test]
+    async fn test_serves_over_unix_socket() {
+        let path = std::env::temp_dir().join(format!("mostro-push-{}.sock", std::process::id()));
+        std::os::unix::net::UnixListener::bind(&path).unwrap();
+
+        let guard = claim_socket(&path).unwrap();
+        let state = test_state(Readiness::new(0));
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(routes::configure)
+        })
+        .workers(1)
+        .bind_uds(&path)
+        .unwrap()
+        .run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET /api/health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+        handle.stop(true).await;
+        drop(guard);
+        assert!(!path.exists());
+    }
+
+}
@@ -0,0 +1,134 @@
+//! Operator dashboard: one HTML page with the server's live status, for
+//! self-hosters without a metrics stack. The page is rendered server-side so
+//! it works without JavaScript; with it, the page polls
+//! `/admin/dashboard/data` and updates in place.
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use askama::Template;
+use chrono::Utc;
+use log::error;
+
+use super::admin::authorize;
+use super::routes::AppState;
+use crate::metrics::Metrics;
+use crate::models::{DashboardSnapshot, DeliveryCounts};
+
+#[derive(Template)]
+#[template(path = "dashboard.html")]
+struct DashboardPage<'a> {
+    snapshot: &'a DashboardSnapshot,
+}
+
+impl DashboardPage<'_> {
+    fn rate(&self, counts: &DeliveryCounts) -> String {
+        counts
+            .success_rate
+            .map_or_else(|| "-".to_string(), |rate| format!("{:.1}%", rate * 100.0))
+    }
+}
+
+/// Current figures, all from the accessors behind the status and metrics endpoints.
+async fn snapshot(state: &AppState) -> DashboardSnapshot {
+    let metrics = &state.metrics;
+    DashboardSnapshot {
+        generated_at: Utc::now(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        tokens: state.token_store.get_stats().await,
+        registrations: Metrics::get(&metrics.registrations),
+        lifetime_registrations: metrics.lifetime().registrations,
+        relays: state.relay_health.snapshot(),
+        delivery: state.dispatcher.delivery_stats(state.delivery_stats_window),
+        write_queue_depth: Metrics::get(&metrics.register_write_queue_depth),
+        pushes_in_flight: metrics.pushes_in_flight(),
+        quotas: state.dispatcher.quota_status(),
+        recent_audit: state.audit.recent(),
+    }
+}
+
+pub(super) async fn dashboard(http_req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    if let Err(mut resp) = authorize(&http_req, &state) {
+        // Let a browser prompt for the token
+        resp.headers_mut().insert(
+            actix_web::http::header::WWW_AUTHENTICATE,
+            actix_web::http::header::HeaderValue::from_static("Basic realm=\"mostro-push admin\""),
+        );
+        return resp;
+    }
+
+    let snapshot = snapshot(&state).await;
+    match (DashboardPage { snapshot: &snapshot }).render() {
+        Ok(html) => HttpResponse::Ok().content_type("text/html; charset=utf-8").body(html),
+        Err(e) => {
+            error!("Failed to render dashboard: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub(super) async fn dashboard_data(http_req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    if let Err(resp) = authorize(&http_req, &state) {
+        return resp;
+    }
+    HttpResponse::Ok().json(snapshot(&state).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::admin::configure;
+    use crate::api::routes::tests::test_state;
+    use crate::health::Readiness;
+    use crate::crypto::Platform;
+    use crate::store::RegisteredToken;
+    use actix_web::{test, App};
+    use base64::Engine;
+
+    const ADMIN_TOKEN: &str = "dashboard-admin-token";
+
+    #[actix_web::test]
+    async fn test_dashboard_renders_snapshot_without_scripts() {
+        let state = AppState {
+            admin_token: Some(ADMIN_TOKEN.to_string()),
+            ..test_state(Readiness::new(0))
+        };
+        state.relay_health.set_connected("wss://relay.example", true);
+        state
+            .token_store
+            .register_token("<pubkey>".to_string(), RegisteredToken::new("t".to_string(), Platform::Android))
+            .await;
+        state.audit.record("admin-token:1234", "watch", "<script>alert(1)</script>", Ok(()));
+        let app = test::init_service(App::new().app_data(web::Data::new(state)).configure(configure)).await;
+
+        // A browser is asked for credentials
+        let req = test::TestRequest::get().uri("/admin/dashboard").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 401);
+        assert!(resp.headers().contains_key("WWW-Authenticate"));
+
+        let basic = base64::engine::general_purpose::STANDARD.encode(format!("admin:{}", ADMIN_TOKEN));
+        let req = test::TestRequest::get()
+            .uri("/admin/dashboard")
+            .insert_header(("Authorization", format!("Basic {}", basic)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        let html = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        // The snapshot is in the markup itself, with audit text escaped
+        assert!(html.contains("wss://relay.example"));
+        assert!(html.contains(r#"<td id="tokens-android">1</td>"#));
+        assert!(html.contains("&lt;script&gt;alert(1)&lt;/script&gt;"));
+        assert!(!html.contains("src=\"http") && !html.contains("href=\"http"));
+
+        let req = test::TestRequest::get()
+            .uri("/admin/dashboard/data")
+            .insert_header(("Authorization", format!("Bearer {}", ADMIN_TOKEN)))
+            .to_request();
+        let data: DashboardSnapshot = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(data.tokens.total, 1);
+        assert_eq!(data.relays.len(), 1);
+        assert_eq!(data.recent_audit[0].action, "watch");
+
+        let req = test::TestRequest::get().uri("/admin/dashboard/data").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 401);
+    }
+}
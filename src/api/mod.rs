@@ -1 +1,5 @@
+pub mod admin;
+pub mod bind;
+pub mod dashboard;
+pub mod replication;
 pub mod routes;
@@ -0,0 +1,59 @@
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use log::{debug, warn};
+
+use super::admin::constant_time_eq;
+use super::routes::{json_config, AppState};
+use crate::models::{ErrorCode, ErrorResponse};
+use crate::replication::ReplicationBatch;
+
+/// Endpoint the primary streams store changes to, authenticated with
+/// `Authorization: Bearer <REPLICATION_TOKEN>`.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/replication")
+            .app_data(json_config())
+            .route("/apply", web::post().to(apply_changes)),
+    );
+}
+
+async fn apply_changes(
+    http_req: HttpRequest,
+    state: web::Data<AppState>,
+    batch: web::Json<ReplicationBatch>,
+) -> impl Responder {
+    let provided = http_req
+        .headers()
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let authorized = matches!(
+        (&state.replication_token, provided),
+        (Some(expected), Some(provided)) if constant_time_eq(expected.as_bytes(), provided.as_bytes())
+    );
+    if !authorized {
+        warn!("Rejected unauthorized replication request");
+        return HttpResponse::Unauthorized().json(ErrorResponse::new(
+            ErrorCode::Unauthorized,
+            "Missing or invalid replication token",
+        ));
+    }
+
+    // Two leaders would overwrite each other's registrations
+    if state.leadership.is_leader() {
+        warn!("Rejected replication: this instance is the leader");
+        return HttpResponse::Conflict().json(ErrorResponse::new(
+            ErrorCode::IsLeader,
+            "This instance leads and does not accept replication",
+        ));
+    }
+
+    let batch = batch.into_inner();
+    debug!("Applying {} replicated changes (reset: {})", batch.changes.len(), batch.reset);
+    if batch.reset {
+        state.token_store.clear().await;
+    }
+    for change in batch.changes {
+        state.token_store.apply(change).await;
+    }
+    HttpResponse::NoContent().finish()
+}
@@ -1,98 +1,473 @@
-use actix_web::{web, HttpResponse, Responder};
+use actix_web::error::JsonPayloadError;
+use actix_web::{web, HttpRequest, HttpResponse, Responder, ResponseError};
 use base64::Engine;
-use serde::{Deserialize, Serialize};
 use log::{info, error, warn};
+use serde::Deserialize;
+use std::sync::atomic::Ordering;
+use std::collections::BTreeSet;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::crypto::{TokenCrypto, ENCRYPTED_TOKEN_SIZE};
-use crate::store::{TokenStore, TokenStoreStats};
-
-#[derive(Deserialize)]
-pub struct RegisterTokenRequest {
-    pub trade_pubkey: String,
-    pub encrypted_token: String,
-}
-
-#[derive(Deserialize)]
-pub struct UnregisterTokenRequest {
-    pub trade_pubkey: String,
-}
-
-#[derive(Serialize)]
-pub struct StatusResponse {
-    pub status: String,
-    pub version: String,
-    pub server_pubkey: String,
-    pub tokens: TokenStoreStats,
-}
-
-#[derive(Serialize)]
-pub struct RegisterResponse {
-    pub success: bool,
-    pub message: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub platform: Option<String>,
-}
+use super::admin::constant_time_eq;
+use crate::alerts::RegistrationAlerts;
+use crate::audit::AuditLog;
+use crate::config::GroupingSource;
+use crate::gc::Collector;
+use crate::crypto::ownership::{self, SignatureError};
+use crate::crypto::{self, DecryptedToken, Platform, TokenCrypto, ENCRYPTED_TOKEN_SIZE, ENVELOPE_V1, ENVELOPE_V2};
+use crate::health::{ProcessStart, Readiness, RelayHealth};
+use crate::metrics::Metrics;
+use crate::nostr::{CategoryClassifier, ReconnectControl};
+use crate::models::{
+    CapabilitiesResponse, ConfigReport, DiscoveryEndpoints, DiscoveryLimits, DiscoveryResponse, DiscoveryScheme, ErrorCode, ErrorResponse, HealthResponse, InfoResponse, OverviewResponse, ProviderOverview,
+    QueueDepths, ReencryptRequest, ReencryptResponse, RegisterResponse, RegisterTokenRequest, RegisteredCheckResponse, RelayOverview, RelaysResponse,
+    StatusResponse, TokenStoreStats, UnregisterResponse, UnregisterTokenRequest,
+};
+use crate::push::{payload, BackfillTracker, Dispatcher, LastNotified, PushPayload};
+use crate::replication::Leadership;
+use crate::scheduler::Scheduler;
+use crate::store::conflict::{check_platform_conflict, ConflictDecision};
+use crate::store::{device_key, store_key, DevicePolicy, PlatformConflictMode, PushEnvironment, ReencryptError, RegisteredToken, TokenStore, WriteQueue};
+use crate::utils::cache::TtlCache;
+use crate::utils::concurrency::ConcurrencyLimit;
+use crate::utils::rate::{RateLimiter, RequestLimiter};
+use crate::flags::FeatureFlags;
+use crate::watch::WatchList;
 
 #[derive(Clone)]
 pub struct AppState {
-    pub token_store: Arc<TokenStore>,
+    pub token_store: Arc<dyn TokenStore>,
     pub token_crypto: Arc<TokenCrypto>,
+    pub metrics: Arc<Metrics>,
+    pub readiness: Arc<Readiness>,
+    pub started: ProcessStart,
+    pub relay_health: Arc<RelayHealth>,
+    pub status_cache: Arc<TtlCache<TokenStoreStats>>,
+    pub info_cache: Arc<TtlCache<InfoResponse>>,
+    pub dispatcher: Arc<Dispatcher>,
+    pub backfill: Arc<BackfillTracker>,
+    pub registration_alerts: Arc<RegistrationAlerts>,
+    /// Bearer token for `/admin`; the admin API is disabled when unset
+    pub admin_token: Option<String>,
+    /// Required by the status and metrics endpoints when set
+    pub status_secret: Option<String>,
+    /// Trail of admin mutations
+    pub audit: Arc<AuditLog>,
+    /// Set in "accepted" write mode: registrations are queued and answered with 202
+    pub write_queue: Option<Arc<WriteQueue>>,
+    /// Window for `/api/stats/delivery` when the request doesn't give one
+    pub delivery_stats_window: Duration,
+    /// Standbys serve reads and redirect writes to the primary
+    pub leadership: Arc<Leadership>,
+    /// Bearer token the primary uses for `/replication`; replication is refused when unset
+    pub replication_token: Option<String>,
+    /// Platforms accepted at registration; any when unset
+    pub advertised_platforms: Option<Vec<Platform>>,
+    /// Refuse registrations no push service can deliver to, instead of flagging them
+    pub reject_undeliverable: bool,
+    /// Refuse registrations whose envelope isn't bound to their trade pubkey
+    pub require_token_binding: bool,
+    /// Refuse register, unregister and re-encrypt requests not signed by their trade key
+    pub require_ownership_signature: bool,
+    pub platform_conflict: PlatformConflictMode,
+    /// Distinct pubkeys one device token may be registered under; unlimited when 0
+    pub max_pubkeys_per_token: usize,
+    /// Devices one pubkey may register in each environment
+    pub max_devices_per_pubkey: usize,
+    pub device_policy: DevicePolicy,
+    /// Global decrypt budget, checked before any envelope is decrypted
+    pub decrypt_limiter: Arc<RateLimiter>,
+    /// Registrations processed at once
+    pub registration_limit: Arc<ConcurrencyLimit>,
+    /// Successful pushes per pubkey, shared with the listener
+    pub last_notified: Arc<LastNotified>,
+    /// Per-IP and per-pubkey limit on registration writes
+    pub request_limiter: Arc<dyn RequestLimiter>,
+    /// Pubkeys traced step by step, managed through `/admin/watch`
+    pub watch_list: Arc<WatchList>,
+    /// Rollout of risky dispatch changes, overridable through `/admin/flags`
+    pub flags: Arc<FeatureFlags>,
+    /// App ids accepted at registration; registrations must omit `app_id` when empty
+    pub app_ids: BTreeSet<String>,
+    /// Push grouping, from `NOTIFICATION_GROUPING`. Catch-up pushes cover
+    /// several events, so only pubkey grouping applies to them
+    pub grouping: Option<GroupingSource>,
+    /// The listener's classifier, for `/admin/preview`
+    pub classifier: Arc<CategoryClassifier>,
+    /// Startup configuration and security checks, for `/admin/config`
+    pub config_report: Arc<ConfigReport>,
+    /// Signals the listener to reconnect, for `/admin/reconnect`
+    pub reconnect: Arc<ReconnectControl>,
+    /// Background tasks, for `/admin/tasks`
+    pub scheduler: Arc<Scheduler>,
+    /// Retention of persisted auxiliary state, for `/admin/gc`
+    pub gc: Arc<Collector>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct DeliveryStatsQuery {
+    pub window_secs: Option<u64>,
 }
 
 pub fn configure(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/api")
+            .app_data(json_config())
             .route("/health", web::get().to(health_check))
             .route("/status", web::get().to(status))
             .route("/register", web::post().to(register_token))
             .route("/unregister", web::post().to(unregister_token))
+            .route("/reencrypt", web::post().to(reencrypt_token))
+            .route("/registered/{trade_pubkey}", web::get().to(registered))
             .route("/info", web::get().to(server_info))
+            .route("/capabilities", web::get().to(capabilities))
+            .route("/discovery", web::get().to(discovery))
+            .route("/metrics", web::get().to(metrics))
+            .route("/relays", web::get().to(relays))
+            .route("/overview", web::get().to(overview))
+            .route("/stats/delivery", web::get().to(delivery_stats))
     );
 }
 
-async fn health_check() -> impl Responder {
-    HttpResponse::Ok().json(serde_json::json!({"status": "ok"}))
+/// Body extraction that answers malformed JSON with `INVALID_JSON` and, where
+/// serde names it, the offending field, instead of actix's plain-text 400.
+pub(crate) fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default().error_handler(|err, _req| {
+        let (message, field) = match &err {
+            JsonPayloadError::Deserialize(e) => (format!("Invalid request body: {}", e), json_error_field(e)),
+            other => (other.to_string(), None),
+        };
+        let body = ErrorResponse { field, ..ErrorResponse::new(ErrorCode::InvalidJson, message) };
+        let response = HttpResponse::build(err.status_code()).json(body);
+        actix_web::error::InternalError::from_response(err, response).into()
+    })
+}
+
+/// The field a serde error names: missing, unknown and duplicate fields are
+/// quoted in its message. Type errors don't say which field they hit.
+fn json_error_field(err: &serde_json::Error) -> Option<String> {
+    let message = err.to_string();
+    ["missing field `", "unknown field `", "duplicate field `"].iter().find_map(|prefix| {
+        let rest = &message[message.find(prefix)? + prefix.len()..];
+        Some(rest[..rest.find('`')?].to_string())
+    })
+}
+
+async fn health_check(
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let ready = state.readiness.is_ready();
+    let relays_connected = state.readiness.relays_connected();
+    // A listener that keeps failing to connect makes the instance useless
+    if !state.readiness.is_listener_healthy() {
+        return HttpResponse::ServiceUnavailable().json(HealthResponse {
+            status: "unhealthy".to_string(),
+            ready,
+            relays_connected,
+        });
+    }
+    HttpResponse::Ok().json(HealthResponse {
+        status: if ready { "ok" } else { "warming_up" }.to_string(),
+        ready,
+        relays_connected,
+    })
+}
+
+/// Returns a 401 response if a status secret is configured and the request
+/// doesn't carry it as a bearer token.
+fn authorize_status(http_req: &HttpRequest, state: &AppState) -> Result<(), HttpResponse> {
+    let Some(expected) = &state.status_secret else {
+        return Ok(());
+    };
+    let provided = http_req
+        .headers()
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match provided {
+        Some(provided) if constant_time_eq(expected.as_bytes(), provided.as_bytes()) => Ok(()),
+        _ => Err(HttpResponse::Unauthorized().json(ErrorResponse::new(
+            ErrorCode::Unauthorized,
+            "Missing or invalid status secret",
+        ))),
+    }
 }
 
 async fn status(
+    http_req: HttpRequest,
     state: web::Data<AppState>,
 ) -> impl Responder {
-    let stats = state.token_store.get_stats().await;
-    
+    if let Err(response) = authorize_status(&http_req, &state) {
+        return response;
+    }
     HttpResponse::Ok().json(StatusResponse {
         status: "running".to_string(),
         version: env!("CARGO_PKG_VERSION").to_string(),
         server_pubkey: state.token_crypto.public_key_hex(),
-        tokens: stats,
+        tokens: token_stats(&state).await,
+        quotas: state.dispatcher.quota_status(),
+        warmup: state.readiness.warmup_status(),
+        uptime_seconds: state.started.uptime().as_secs(),
+        started_at: state.started.at,
     })
 }
 
+/// Serve recent stats without taking the store lock; any store mutation invalidates.
+async fn token_stats(state: &AppState) -> TokenStoreStats {
+    let generation = state.token_store.generation();
+    match state.status_cache.get(generation) {
+        Some(stats) => stats,
+        None => {
+            let stats = state.token_store.get_stats().await;
+            state.status_cache.put(stats.clone(), generation);
+            stats
+        }
+    }
+}
+
 async fn server_info(
     state: web::Data<AppState>,
 ) -> impl Responder {
-    HttpResponse::Ok().json(serde_json::json!({
-        "server_pubkey": state.token_crypto.public_key_hex(),
-        "version": env!("CARGO_PKG_VERSION"),
-        "encrypted_token_size": ENCRYPTED_TOKEN_SIZE,
-    }))
+    if let Some(info) = state.info_cache.get(0) {
+        return HttpResponse::Ok().json(info);
+    }
+
+    let info = InfoResponse {
+        server_pubkey: state.token_crypto.public_key_hex(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        encrypted_token_size: ENCRYPTED_TOKEN_SIZE,
+        accepted_token_sizes: accepted_token_sizes(),
+    };
+    state.info_cache.put(info.clone(), 0);
+    HttpResponse::Ok().json(info)
+}
+
+fn accepted_token_sizes() -> Vec<usize> {
+    crypto::SCHEMES.iter().map(|scheme| scheme.encrypted_size()).collect()
+}
+
+async fn capabilities(
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let platforms = match &state.advertised_platforms {
+        Some(platforms) => platforms.clone(),
+        None => state.dispatcher.supported_platforms().await,
+    };
+    HttpResponse::Ok().json(CapabilitiesResponse {
+        providers: state.dispatcher.providers().await,
+        platforms,
+    })
+}
+
+async fn discovery(
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let platforms = match &state.advertised_platforms {
+        Some(platforms) => platforms.clone(),
+        None => state.dispatcher.supported_platforms().await,
+    };
+    let schemes: Vec<DiscoveryScheme> = crypto::SCHEMES
+        .iter()
+        .map(|scheme| DiscoveryScheme {
+            name: scheme.name.to_string(),
+            curve: "secp256k1".to_string(),
+            kdf: "hkdf-sha256".to_string(),
+            cipher: scheme.cipher.name().to_string(),
+            nonce_size: scheme.cipher.nonce_size(),
+            encrypted_size: scheme.encrypted_size(),
+            max_token_length: scheme.max_token_length(false),
+            max_token_length_with_push_key: scheme.max_token_length(true),
+        })
+        .collect();
+    HttpResponse::Ok().json(DiscoveryResponse {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        server_pubkey: state.token_crypto.public_key_hex(),
+        payload_version: crypto::PAYLOAD_VERSION,
+        envelope_versions: if state.require_token_binding {
+            vec![ENVELOPE_V2]
+        } else {
+            vec![ENVELOPE_V1, ENVELOPE_V2]
+        },
+        limits: DiscoveryLimits {
+            max_token_length: schemes.iter().map(|scheme| scheme.max_token_length).max().unwrap_or(0),
+            max_batch_size: None,
+            max_pubkeys_per_token: (state.max_pubkeys_per_token > 0).then_some(state.max_pubkeys_per_token),
+            max_devices_per_pubkey: state.max_devices_per_pubkey,
+        },
+        schemes,
+        platforms,
+        providers: state.dispatcher.providers().await,
+        endpoints: DiscoveryEndpoints {
+            reencrypt: true,
+            registered: true,
+            batch_register: false,
+            validate: false,
+            events: false,
+        },
+    })
+}
+
+async fn metrics(
+    http_req: HttpRequest,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    if let Err(response) = authorize_status(&http_req, &state) {
+        return response;
+    }
+    // Exemplars are only valid in OpenMetrics, so serve it to scrapers that ask for it
+    let openmetrics = http_req
+        .headers()
+        .get("Accept")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/openmetrics-text"));
+    state.metrics.set_provider_quotas(state.dispatcher.quota_status());
+    state.metrics.decrypt_rate.store(state.decrypt_limiter.rate(Instant::now()), Ordering::Relaxed);
+    state.metrics.registrations_in_flight.store(state.registration_limit.in_flight(), Ordering::Relaxed);
+
+    if openmetrics {
+        HttpResponse::Ok()
+            .content_type("application/openmetrics-text; version=1.0.0; charset=utf-8")
+            .body(state.metrics.render_openmetrics())
+    } else {
+        HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4")
+            .body(state.metrics.render())
+    }
+}
+
+async fn relays(
+    state: web::Data<AppState>,
+) -> impl Responder {
+    HttpResponse::Ok().json(RelaysResponse {
+        relays: state.relay_health.snapshot(),
+    })
+}
+
+/// Relay, token, provider and queue state in one snapshot, so dashboards
+/// don't poll each endpoint separately.
+async fn overview(
+    http_req: HttpRequest,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    if let Err(response) = authorize_status(&http_req, &state) {
+        return response;
+    }
+    let relays = state.relay_health.snapshot();
+    let quotas = state.dispatcher.quota_status();
+    let providers = state.dispatcher.providers().await.into_iter()
+        .map(|provider| ProviderOverview {
+            quota: quotas.iter().find(|quota| quota.provider == provider).cloned(),
+            provider,
+        })
+        .collect();
+    let metrics = &state.metrics;
+
+    HttpResponse::Ok().json(OverviewResponse {
+        generated_at: chrono::Utc::now(),
+        relays: RelayOverview {
+            connected: relays.iter().filter(|relay| relay.connected).count(),
+            total: relays.len(),
+            relays,
+        },
+        tokens: token_stats(&state).await,
+        providers,
+        queues: QueueDepths {
+            register_writes: Metrics::get(&metrics.register_write_queue_depth),
+            pushes_in_flight: metrics.pushes_in_flight(),
+            quota_delayed: quotas.iter().map(|quota| quota.queued).sum(),
+        },
+    })
+}
+
+async fn delivery_stats(
+    state: web::Data<AppState>,
+    query: web::Query<DeliveryStatsQuery>,
+) -> impl Responder {
+    let window = query.window_secs
+        .map(Duration::from_secs)
+        .unwrap_or(state.delivery_stats_window);
+    HttpResponse::Ok().json(state.dispatcher.delivery_stats(window))
+}
+
+/// On a standby, the response pointing a write at the primary.
+fn redirect_to_leader(http_req: &HttpRequest, state: &AppState) -> Option<HttpResponse> {
+    if state.leadership.is_leader() {
+        return None;
+    }
+    let message = "This instance is a standby; send registrations to the primary";
+    Some(match state.leadership.primary_url() {
+        Some(primary) => HttpResponse::TemporaryRedirect()
+            .insert_header(("Location", format!("{}{}", primary.trim_end_matches('/'), http_req.path())))
+            .json(ErrorResponse::new(ErrorCode::NotLeader, message)),
+        None => HttpResponse::ServiceUnavailable()
+            .json(ErrorResponse::new(ErrorCode::NotLeader, message)),
+    })
+}
+
+/// The push covering events a pubkey missed while registering. It covers
+/// several events, so only pubkey grouping applies.
+pub(crate) fn catch_up_payload(state: &AppState, trade_pubkey: &str) -> PushPayload {
+    let payload = state.flags.wake_payload(trade_pubkey).data("backfill", "true");
+    match state.grouping {
+        Some(GroupingSource::Pubkey) => payload.thread_id(payload::grouping_id(trade_pubkey)),
+        _ => payload,
+    }
 }
 
 async fn register_token(
+    http_req: HttpRequest,
     state: web::Data<AppState>,
     req: web::Json<RegisterTokenRequest>,
 ) -> impl Responder {
+    if let Some(resp) = redirect_to_leader(&http_req, &state) {
+        return resp;
+    }
+
     info!("Registering token for trade_pubkey: {}...", 
         &req.trade_pubkey[..16.min(req.trade_pubkey.len())]);
 
+    // Reject until startup state is loaded and enough relays are connected
+    if !state.readiness.is_ready() {
+        warn!("Rejecting registration during startup warmup");
+        return HttpResponse::ServiceUnavailable()
+            .insert_header(("Retry-After", "5"))
+            .json(RegisterResponse::error(
+                ErrorCode::NotReady,
+                "Server is starting up, retry shortly",
+            ));
+    }
+
+    // Held until the response is built, bounding the crypto and store work in flight
+    let Some(_slot) = state.registration_limit.acquire().await else {
+        Metrics::inc(&state.metrics.registrations_shed);
+        warn!("Every registration slot stayed busy, shedding request");
+        return HttpResponse::ServiceUnavailable()
+            .insert_header(("Retry-After", "1"))
+            .json(RegisterResponse::error(ErrorCode::Overloaded, "Server is busy, retry shortly"));
+    };
+
     // Validate trade_pubkey format (should be 64 hex chars)
     if req.trade_pubkey.len() != 64 || hex::decode(&req.trade_pubkey).is_err() {
         warn!("Invalid trade_pubkey format");
-        return HttpResponse::BadRequest().json(RegisterResponse {
-            success: false,
-            message: "Invalid trade_pubkey format (expected 64 hex characters)".to_string(),
-            platform: None,
-        });
+        return HttpResponse::BadRequest().json(RegisterResponse::error(
+            ErrorCode::InvalidPubkey,
+            "Invalid trade_pubkey format (expected 64 hex characters)",
+        ));
+    }
+
+    if let Err(resp) = check_request_limits(&http_req, &state, &req.trade_pubkey).await {
+        return resp;
+    }
+
+    if let Some(app_id) = &req.app_id {
+        if !state.app_ids.contains(app_id) {
+            warn!("Rejecting registration for unknown app {:?}", app_id);
+            return HttpResponse::BadRequest().json(RegisterResponse::error(
+                ErrorCode::UnknownApp,
+                format!("Unknown app_id {:?}; this server accepts {:?}", app_id, state.app_ids),
+            ));
+        }
     }
 
     // Decode base64 encrypted token
@@ -102,51 +477,230 @@ async fn register_token(
         Ok(bytes) => bytes,
         Err(e) => {
             warn!("Invalid base64 in encrypted_token: {}", e);
-            return HttpResponse::BadRequest().json(RegisterResponse {
-                success: false,
-                message: "Invalid base64 encoding in encrypted_token".to_string(),
-                platform: None,
-            });
+            return HttpResponse::BadRequest().json(RegisterResponse::error(
+                ErrorCode::InvalidBase64,
+                "Invalid base64 encoding in encrypted_token",
+            ));
         }
     };
 
-    // Validate token size
-    if encrypted_token.len() != ENCRYPTED_TOKEN_SIZE {
-        warn!(
-            "Invalid encrypted token size: expected {}, got {}",
-            ENCRYPTED_TOKEN_SIZE,
-            encrypted_token.len()
-        );
-        return HttpResponse::BadRequest().json(RegisterResponse {
-            success: false,
-            message: format!(
-                "Invalid encrypted token size (expected {} bytes, got {})",
-                ENCRYPTED_TOKEN_SIZE,
-                encrypted_token.len()
+    // Refuse sizes no envelope scheme has before spending a decrypt on them
+    if crypto::scheme_for_size(encrypted_token.len()).is_err() {
+        warn!("Invalid encrypted token size: {} bytes", encrypted_token.len());
+        return HttpResponse::BadRequest().json(RegisterResponse::error(
+            ErrorCode::InvalidTokenSize,
+            format!(
+                "Invalid encrypted token size (got {} bytes, expected one of {:?})",
+                encrypted_token.len(),
+                accepted_token_sizes()
             ),
-            platform: None,
-        });
+        ));
     }
 
-    // Decrypt the token
-    let decrypted = match state.token_crypto.decrypt_token(&encrypted_token) {
+    if let Err(resp) = check_ownership(
+        &state,
+        &req.trade_pubkey,
+        &encrypted_token,
+        req.signature.as_deref(),
+        req.timestamp,
+    ) {
+        return resp;
+    }
+
+    if let Err(resp) = acquire_decrypts(&state, 1) {
+        return resp;
+    }
+
+    // Decrypt the token, accepting v2 envelopes bound to this trade pubkey
+    // and, unless binding is required, unbound v1 envelopes
+    let trade_pubkey_bytes = hex::decode(&req.trade_pubkey).unwrap_or_default();
+    let decrypted = if state.require_token_binding {
+        state.token_crypto.decrypt_token_with_aad(&encrypted_token, &trade_pubkey_bytes)
+    } else {
+        state.token_crypto.decrypt_token_for(&encrypted_token, &trade_pubkey_bytes)
+    };
+    let decrypted = match decrypted {
         Ok(token) => token,
         Err(e) => {
             error!("Failed to decrypt token: {}", e);
-            return HttpResponse::BadRequest().json(RegisterResponse {
-                success: false,
-                message: format!("Failed to decrypt token: {}", e),
-                platform: None,
-            });
+            return HttpResponse::BadRequest().json(RegisterResponse::error(
+                ErrorCode::DecryptionFailed,
+                format!("Failed to decrypt token: {}", e),
+            ));
         }
     };
 
-    // Store the token
-    state.token_store.register(
-        req.trade_pubkey.clone(),
-        decrypted.device_token,
-        decrypted.platform.clone(),
-    ).await;
+    state.metrics.record_decrypt_key_index(decrypted.key_index);
+
+    if let Some(advertised) = &state.advertised_platforms {
+        if !advertised.contains(&decrypted.platform) {
+            warn!("Rejecting registration for non-advertised platform {}", decrypted.platform);
+            return HttpResponse::BadRequest().json(RegisterResponse::error(
+                ErrorCode::UnsupportedPlatform,
+                format!("This server does not accept {} registrations", decrypted.platform),
+            ));
+        }
+    }
+
+    // A token no push service serves decrypts fine but would never be notified
+    let deliverable = state.dispatcher.supported_platforms().await.contains(&decrypted.platform);
+    if !deliverable {
+        Metrics::inc(&state.metrics.registrations_undeliverable);
+        if state.reject_undeliverable {
+            warn!("Rejecting {} registration: no push service is configured for it", decrypted.platform);
+            return HttpResponse::BadRequest().json(RegisterResponse::error(
+                ErrorCode::UnsupportedPlatform,
+                format!("No push service is configured for {}", decrypted.platform),
+            ));
+        }
+        warn!(
+            "Storing {} registration although no push service is configured for it; it won't be notified",
+            decrypted.platform
+        );
+    }
+
+    // Sandbox and production registrations of a pubkey are stored apart
+    let key = store_key(&req.trade_pubkey, req.environment);
+
+    // Re-registering a device refreshes its slot; a new device takes a free one
+    let devices = state.token_store.devices(&key, state.max_devices_per_pubkey).await;
+    let refresh = devices.iter().find(|(_, token)| token.device_token == decrypted.device_token);
+
+    // The pubkey's registrations in the other environment, for what belongs
+    // to the pubkey rather than one device: first-registration alerts and annotations
+    let mut elsewhere = Vec::new();
+    for environment in PushEnvironment::ALL.into_iter().filter(|environment| *environment != req.environment) {
+        let other_key = store_key(&req.trade_pubkey, environment);
+        elsewhere.extend(state.token_store.devices(&other_key, state.max_devices_per_pubkey).await);
+    }
+    let first_registration = devices.is_empty() && elsewhere.is_empty();
+
+    // A pubkey gaining a device on another platform may be a hijack with a leaked pubkey
+    let existing = match refresh {
+        Some(_) => None,
+        None => devices.iter().map(|(_, token)| token).find(|token| token.platform != decrypted.platform),
+    };
+    let decision = check_platform_conflict(state.platform_conflict, existing, &decrypted.platform, req.replace);
+    if let Some((action, result)) = decision.audit() {
+        state.audit.record("client", action, &req.trade_pubkey, result);
+    }
+    match decision {
+        ConflictDecision::Rejected => {
+            warn!("Rejecting registration that would change the platform of an existing registration");
+            return HttpResponse::Conflict().json(RegisterResponse::error(
+                ErrorCode::RegistrationConflict,
+                "A conflicting registration exists for this trade_pubkey; set replace to overwrite it",
+            ));
+        }
+        ConflictDecision::Warned => {
+            warn!("Registration changes the platform of an existing registration to {}", decrypted.platform);
+        }
+        ConflictDecision::Replaced | ConflictDecision::NoConflict => {}
+    }
+
+    // A new device takes the slot of the first device it replaces, per the
+    // device policy or `replace`, and otherwise a free one
+    let replaces = |token: &RegisteredToken| req.replace || state.device_policy.replaces(token, &decrypted.platform);
+    let slot_key = match refresh.or_else(|| devices.iter().find(|(_, token)| replaces(token))) {
+        Some((slot_key, _)) => slot_key.clone(),
+        None => {
+            let free = (0..state.max_devices_per_pubkey)
+                .map(|slot| device_key(&key, slot))
+                .find(|slot_key| devices.iter().all(|(taken, _)| taken != slot_key));
+            let Some(free) = free else {
+                warn!("Rejecting registration: pubkey already has {} devices", devices.len());
+                return HttpResponse::Conflict().json(RegisterResponse::error(
+                    ErrorCode::DeviceLimit,
+                    format!(
+                        "This trade_pubkey already has {} devices registered; unregister one or set replace",
+                        state.max_devices_per_pubkey
+                    ),
+                ));
+            };
+            free
+        }
+    };
+
+    // Many orders from one device are normal; hundreds suggest abuse
+    if state.max_pubkeys_per_token > 0 && refresh.is_none() {
+        let sharing = state.token_store.pubkeys_for_token(&decrypted.device_token).await;
+        if !sharing.contains(&req.trade_pubkey) && sharing.len() >= state.max_pubkeys_per_token {
+            warn!("Rejecting registration: device token already registered under {} pubkeys", sharing.len());
+            return HttpResponse::Conflict().json(RegisterResponse::error(
+                ErrorCode::TokenShareLimit,
+                format!(
+                    "This device token is already registered under {} trade pubkeys",
+                    state.max_pubkeys_per_token
+                ),
+            ));
+        }
+    }
+
+    // Store the token, or queue the write in accepted mode
+    let mut registration = RegisteredToken::from_decrypted(&decrypted)
+        .with_preferences(req.preferences.unwrap_or_default())
+        .with_app_id(req.app_id.clone())
+        .with_environment(req.environment);
+    // Annotations belong to the pubkey, so a new device starts with them
+    if let Some((_, sibling)) = devices.iter().chain(&elsewhere).next() {
+        registration.annotations = sibling.annotations.clone();
+    }
+    let durable = match &state.write_queue {
+        Some(queue) => {
+            if !queue.enqueue(slot_key.clone(), registration.clone(), first_registration) {
+                return HttpResponse::ServiceUnavailable().json(RegisterResponse::error(
+                    ErrorCode::NotReady,
+                    "Registration writes are unavailable, retry shortly",
+                ));
+            }
+            false
+        }
+        None => {
+            // A racing registration may have taken the slot since the lookup
+            let is_new = state.token_store.register_token(slot_key.clone(), registration.clone()).await;
+            state.registration_alerts.on_registered(&req.trade_pubkey, &decrypted.platform, first_registration && is_new);
+            true
+        }
+    };
+    for (other, _) in devices.iter().filter(|(other, token)| *other != slot_key && replaces(token)) {
+        state.token_store.unregister(other).await;
+    }
+    Metrics::inc(&state.metrics.registrations);
+
+    // Catch up on events that arrived while the client was still registering
+    let missed = state.backfill.take_missed(&req.trade_pubkey);
+    if !missed.is_empty() {
+        let token = if durable {
+            state.token_store.get(&slot_key).await
+        } else {
+            Some(registration)
+        };
+        if let Some(token) = token {
+            info!("Sending catch-up push for {} missed event(s)", missed.len());
+            let pushes = state.backfill.catch_up_pushes(missed);
+            let dispatcher = state.dispatcher.clone();
+            let last_notified = state.last_notified.clone();
+            let trade_pubkey = req.trade_pubkey.clone();
+            let payload = catch_up_payload(&state, &req.trade_pubkey);
+            actix_web::rt::spawn(async move {
+                for event_ids in pushes {
+                    if dispatcher.dispatch_for_events(&event_ids, &token, &payload, None).await == Some(true) {
+                        last_notified.record(&trade_pubkey, chrono::Utc::now());
+                    }
+                }
+            });
+        }
+    }
+
+    if !durable {
+        info!(
+            "Accepted {} token for trade_pubkey: {}... (write queued)",
+            decrypted.platform,
+            &req.trade_pubkey[..16]
+        );
+        return HttpResponse::Accepted()
+            .json(RegisterResponse::accepted(decrypted.platform).undeliverable(!deliverable));
+    }
 
     info!(
         "Successfully registered {} token for trade_pubkey: {}...",
@@ -154,40 +708,1499 @@ async fn register_token(
         &req.trade_pubkey[..16]
     );
 
-    HttpResponse::Ok().json(RegisterResponse {
-        success: true,
-        message: "Token registered successfully".to_string(),
-        platform: Some(decrypted.platform.to_string()),
-    })
+    HttpResponse::Ok().json(RegisterResponse::registered(decrypted.platform).undeliverable(!deliverable))
 }
 
 async fn unregister_token(
+    http_req: HttpRequest,
     state: web::Data<AppState>,
     req: web::Json<UnregisterTokenRequest>,
 ) -> impl Responder {
+    if let Some(resp) = redirect_to_leader(&http_req, &state) {
+        return resp;
+    }
+
     info!("Unregistering token for trade_pubkey: {}...", 
         &req.trade_pubkey[..16.min(req.trade_pubkey.len())]);
 
     // Validate trade_pubkey format
     if req.trade_pubkey.len() != 64 || hex::decode(&req.trade_pubkey).is_err() {
         warn!("Invalid trade_pubkey format");
-        return HttpResponse::BadRequest().json(serde_json::json!({
-            "success": false,
-            "message": "Invalid trade_pubkey format (expected 64 hex characters)"
-        }));
+        return HttpResponse::BadRequest().json(UnregisterResponse::error(
+            ErrorCode::InvalidPubkey,
+            "Invalid trade_pubkey format (expected 64 hex characters)",
+        ));
     }
 
-    let removed = state.token_store.unregister(&req.trade_pubkey).await;
+    if let Err(resp) = check_request_limits(&http_req, &state, &req.trade_pubkey).await {
+        return resp;
+    }
 
-    if removed {
-        HttpResponse::Ok().json(serde_json::json!({
-            "success": true,
-            "message": "Token unregistered successfully"
-        }))
+    let encrypted_token = match req.encrypted_token.as_ref().map(|e| base64::engine::general_purpose::STANDARD.decode(e)) {
+        Some(Ok(encrypted_token)) => Some(encrypted_token),
+        Some(Err(_)) => {
+            warn!("Invalid base64 in encrypted_token");
+            return HttpResponse::BadRequest().json(UnregisterResponse::error(
+                ErrorCode::InvalidBase64,
+                "Invalid base64 encoding in encrypted_token",
+            ));
+        }
+        None => None,
+    };
+
+    if let Err(resp) = check_ownership(
+        &state,
+        &req.trade_pubkey,
+        encrypted_token.as_deref().unwrap_or_default(),
+        req.signature.as_deref(),
+        req.timestamp,
+    ) {
+        return resp;
+    }
+
+    // With an envelope only the device it names is removed, otherwise all of them
+    let device_token = match encrypted_token {
+        Some(encrypted_token) => {
+            if let Err(resp) = acquire_decrypts(&state, 1) {
+                return resp;
+            }
+            let trade_pubkey_bytes = hex::decode(&req.trade_pubkey).unwrap_or_default();
+            match state.token_crypto.decrypt_token_for(&encrypted_token, &trade_pubkey_bytes) {
+                Ok(decrypted) => Some(decrypted.device_token),
+                Err(e) => {
+                    error!("Failed to decrypt token: {}", e);
+                    return HttpResponse::BadRequest().json(UnregisterResponse::error(
+                        ErrorCode::DecryptionFailed,
+                        format!("Failed to decrypt token: {}", e),
+                    ));
+                }
+            }
+        }
+        None => None,
+    };
+
+    let key = store_key(&req.trade_pubkey, req.environment);
+    let mut removed = Vec::new();
+    for (slot_key, token) in state.token_store.devices(&key, state.max_devices_per_pubkey).await {
+        if device_token.as_ref().is_some_and(|device_token| *device_token != token.device_token) {
+            continue;
+        }
+        if let Some(token) = state.token_store.unregister(&slot_key).await {
+            removed.push(token.platform);
+        }
+    }
+
+    if !removed.is_empty() {
+        info!("Removed {} device registration(s)", removed.len());
+        if registered_platforms(&state, &req.trade_pubkey).await.is_empty() {
+            state.last_notified.forget(&req.trade_pubkey);
+        }
+        HttpResponse::Ok().json(UnregisterResponse::ok("Token unregistered successfully").with_platforms(removed))
     } else {
-        HttpResponse::Ok().json(serde_json::json!({
-            "success": true,
-            "message": "Token not found (may have already been unregistered)"
-        }))
+        HttpResponse::Ok().json(UnregisterResponse::ok(
+            "Token not found (may have already been unregistered)",
+        ))
+    }
+}
+
+/// Whether `trade_pubkey` is registered, and when it was last pushed to.
+async fn registered(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let trade_pubkey = path.into_inner();
+    if trade_pubkey.len() != 64 || hex::decode(&trade_pubkey).is_err() {
+        return HttpResponse::BadRequest().json(ErrorResponse::new(
+            ErrorCode::InvalidPubkey,
+            "Invalid trade_pubkey format (expected 64 hex characters)",
+        ));
+    }
+    let platforms = registered_platforms(&state, &trade_pubkey).await;
+    HttpResponse::Ok().json(RegisteredCheckResponse {
+        registered: !platforms.is_empty(),
+        platforms,
+        last_notified_at: state.last_notified.get(&trade_pubkey),
+    })
+}
+
+/// Platforms `trade_pubkey` is registered for, one per device in each environment.
+async fn registered_platforms(state: &AppState, trade_pubkey: &str) -> Vec<Platform> {
+    let mut platforms = Vec::new();
+    for environment in PushEnvironment::ALL {
+        let key = store_key(trade_pubkey, environment);
+        for (_, token) in state.token_store.devices(&key, state.max_devices_per_pubkey).await {
+            platforms.push(token.platform);
+        }
+    }
+    platforms
+}
+
+/// Count the request against its client IP's and pubkey's limits, or build
+/// the 429 refusing it.
+async fn check_request_limits(http_req: &HttpRequest, state: &AppState, trade_pubkey: &str) -> Result<(), HttpResponse> {
+    let mut keys = vec![format!("pubkey:{}", trade_pubkey.to_lowercase())];
+    if let Some(addr) = http_req.peer_addr() {
+        keys.push(format!("ip:{}", addr.ip()));
+    }
+    for key in keys {
+        if let Err(retry_after) = state.request_limiter.check(&key).await {
+            Metrics::inc(&state.metrics.requests_rate_limited);
+            warn!("Rate limit exceeded for {}", key.split(':').next().unwrap_or_default());
+            return Err(HttpResponse::TooManyRequests()
+                .insert_header(("Retry-After", retry_after.as_secs().max(1).to_string()))
+                .json(ErrorResponse::new(ErrorCode::RateLimited, "Too many requests, retry later")));
+        }
+    }
+    Ok(())
+}
+
+/// Verify the request's signature by its trade key, or build the 401 refusing
+/// it. Unsigned requests pass unless signatures are required.
+fn check_ownership(
+    state: &AppState,
+    trade_pubkey: &str,
+    encrypted_token: &[u8],
+    signature: Option<&str>,
+    timestamp: Option<u64>,
+) -> Result<(), HttpResponse> {
+    let result = match (signature, timestamp) {
+        (None, _) if !state.require_ownership_signature => return Ok(()),
+        (None, _) => Err(SignatureError::Missing),
+        (Some(_), None) => Err(SignatureError::Malformed),
+        (Some(signature), Some(timestamp)) => {
+            let trade_pubkey: [u8; 32] = hex::decode(trade_pubkey)
+                .ok()
+                .and_then(|bytes| bytes.try_into().ok())
+                .unwrap_or_default();
+            let now = chrono::Utc::now().timestamp().max(0) as u64;
+            ownership::verify_ownership(&trade_pubkey, encrypted_token, timestamp, signature, now)
+        }
+    };
+    result.map_err(|e| {
+        Metrics::inc(&state.metrics.signatures_rejected);
+        warn!("Rejecting request without a valid ownership signature: {}", e);
+        HttpResponse::Unauthorized().json(ErrorResponse::new(ErrorCode::InvalidSignature, e.to_string()))
+    })
+}
+
+/// Take `count` decrypts from the global budget, or build the 503 that sheds
+/// the request before any crypto runs.
+fn acquire_decrypts(state: &AppState, count: usize) -> Result<(), HttpResponse> {
+    state.decrypt_limiter.try_acquire(Instant::now(), count).map_err(|retry_after| {
+        Metrics::inc(&state.metrics.decrypts_shed);
+        warn!("Global decrypt rate exceeded, shedding request");
+        HttpResponse::ServiceUnavailable()
+            .insert_header(("Retry-After", retry_after.as_secs().max(1).to_string()))
+            .json(ErrorResponse::new(ErrorCode::Overloaded, "Server is busy, retry shortly"))
+    })
+}
+
+async fn reencrypt_token(
+    http_req: HttpRequest,
+    state: web::Data<AppState>,
+    req: web::Json<ReencryptRequest>,
+) -> impl Responder {
+    if let Some(resp) = redirect_to_leader(&http_req, &state) {
+        return resp;
+    }
+
+    let trade_pubkey_bytes = match hex::decode(&req.trade_pubkey) {
+        Ok(bytes) if bytes.len() == 32 => bytes,
+        _ => {
+            warn!("Invalid trade_pubkey format");
+            return HttpResponse::BadRequest().json(ReencryptResponse::error(
+                ErrorCode::InvalidPubkey,
+                "Invalid trade_pubkey format (expected 64 hex characters)",
+            ));
+        }
+    };
+
+    if let Err(resp) = check_request_limits(&http_req, &state, &req.trade_pubkey).await {
+        return resp;
+    }
+
+    // Signed over the new envelope, as a registration with it would be;
+    // undecodable envelopes are refused once opened
+    let new_envelope = base64::engine::general_purpose::STANDARD.decode(&req.encrypted_token).unwrap_or_default();
+    if let Err(resp) = check_ownership(
+        &state,
+        &req.trade_pubkey,
+        &new_envelope,
+        req.signature.as_deref(),
+        req.timestamp,
+    ) {
+        return resp;
+    }
+
+    if let Err(resp) = acquire_decrypts(&state, 2) {
+        return resp;
+    }
+
+    let open = |encoded: &str| -> Result<DecryptedToken, HttpResponse> {
+        let bytes = base64::engine::general_purpose::STANDARD.decode(encoded).map_err(|_| {
+            HttpResponse::BadRequest().json(ReencryptResponse::error(
+                ErrorCode::InvalidBase64,
+                "Invalid base64 encoding in encrypted token",
+            ))
+        })?;
+        state.token_crypto.decrypt_token_for(&bytes, &trade_pubkey_bytes).map_err(|e| {
+            HttpResponse::BadRequest().json(ReencryptResponse::error(
+                ErrorCode::DecryptionFailed,
+                format!("Failed to decrypt token: {}", e),
+            ))
+        })
+    };
+    let old = match open(&req.old_encrypted_token) {
+        Ok(token) => token,
+        Err(resp) => return resp,
+    };
+    let new = match open(&req.encrypted_token) {
+        Ok(token) => token,
+        Err(resp) => return resp,
+    };
+
+    if new.envelope_version != ENVELOPE_V2 {
+        return HttpResponse::BadRequest().json(ReencryptResponse::error(
+            ErrorCode::UnsupportedEnvelope,
+            "encrypted_token must be a v2 envelope bound to trade_pubkey",
+        ));
+    }
+    if old.device_token != new.device_token || old.platform != new.platform {
+        warn!("Re-encryption rejected: envelopes carry different device tokens");
+        return HttpResponse::BadRequest().json(ReencryptResponse::error(
+            ErrorCode::TokenMismatch,
+            "Old and new envelopes carry different device tokens",
+        ));
+    }
+
+    // Upgrade the device the envelopes name; the first slot reports why when none matches
+    let key = store_key(&req.trade_pubkey, req.environment);
+    let slot_key = state
+        .token_store
+        .devices(&key, state.max_devices_per_pubkey)
+        .await
+        .into_iter()
+        .find(|(_, token)| token.device_token == new.device_token)
+        .map_or(key, |(slot_key, _)| slot_key);
+    match state.token_store.reencrypt(&slot_key, &new.device_token, new.envelope_version).await {
+        Ok(()) => {
+            Metrics::inc(&state.metrics.reencryptions);
+            info!(
+                "Re-encrypted registration for trade_pubkey: {}... to envelope v{}",
+                &req.trade_pubkey[..16],
+                new.envelope_version
+            );
+            HttpResponse::Ok().json(ReencryptResponse::ok(new.envelope_version))
+        }
+        Err(e @ ReencryptError::NotRegistered) => HttpResponse::NotFound()
+            .json(ReencryptResponse::error(ErrorCode::NotRegistered, e.to_string())),
+        Err(e @ ReencryptError::TokenMismatch) => HttpResponse::BadRequest()
+            .json(ReencryptResponse::error(ErrorCode::TokenMismatch, e.to_string())),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::crypto::tests::{create_test_encrypted_token, create_test_encrypted_token_v2};
+    use crate::crypto::Platform;
+    use crate::push::testing::MockPush;
+    use crate::nostr::classifier::CategoryRules;
+    use crate::push::PushService;
+    use crate::store::{MemoryTokenStore, PushEnvironment};
+    use crate::utils::rate::MemoryRequestLimiter;
+    use actix_web::{test, App};
+    use secp256k1::{PublicKey, Secp256k1, SecretKey};
+
+    const TEST_SECRET_KEY: &str = "ccc61d16dfd10fbcca1322fdf5fed6cb1863db4e27030ae164dbcbfcc263154d";
+    pub(crate) const TEST_TRADE_PUBKEY: &str = "a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2";
+
+    pub(crate) fn test_state(readiness: Readiness) -> AppState {
+        AppState {
+            token_store: Arc::new(MemoryTokenStore::new(48)),
+            token_crypto: Arc::new(TokenCrypto::new(TEST_SECRET_KEY).unwrap()),
+            metrics: Arc::new(Metrics::new()),
+            readiness: Arc::new(readiness),
+            started: ProcessStart::now(),
+            relay_health: Arc::new(RelayHealth::new()),
+            status_cache: Arc::new(TtlCache::new(Duration::from_secs(60))),
+            info_cache: Arc::new(TtlCache::new(Duration::from_secs(60))),
+            dispatcher: Arc::new(Dispatcher::new(
+                Arc::new(tokio::sync::RwLock::new(Vec::new())),
+                Arc::new(Metrics::new()),
+            )),
+            backfill: Arc::new(BackfillTracker::new(120, true)),
+            registration_alerts: Arc::new(RegistrationAlerts::new(false, None)),
+            admin_token: None,
+            status_secret: None,
+            audit: Arc::new(AuditLog::new(None, None).unwrap()),
+            write_queue: None,
+            delivery_stats_window: Duration::from_secs(3600),
+            leadership: Arc::new(Leadership::new(true, None)),
+            replication_token: None,
+            advertised_platforms: None,
+            reject_undeliverable: false,
+            require_token_binding: false,
+            require_ownership_signature: false,
+            platform_conflict: PlatformConflictMode::Warn,
+            max_pubkeys_per_token: 0,
+            max_devices_per_pubkey: 5,
+            device_policy: DevicePolicy::MultiDevice,
+            decrypt_limiter: Arc::new(RateLimiter::per_second(None)),
+            registration_limit: Arc::new(ConcurrencyLimit::new(0, Duration::ZERO)),
+            last_notified: Arc::new(LastNotified::default()),
+            request_limiter: Arc::new(MemoryRequestLimiter::per_minute(0)),
+            watch_list: Arc::new(WatchList::new(16)),
+            flags: Arc::new(FeatureFlags::default()),
+            grouping: None,
+            classifier: Arc::new(CategoryClassifier::new("category", CategoryRules::default())),
+            app_ids: BTreeSet::new(),
+            config_report: Arc::new(ConfigReport::default()),
+            reconnect: Arc::new(ReconnectControl::new()),
+            scheduler: Arc::new(Scheduler::new()),
+            gc: Arc::new(Collector::new(Arc::new(Metrics::new()))),
+        }
+    }
+
+    fn test_server_pubkey() -> PublicKey {
+        let secret = SecretKey::from_slice(&hex::decode(TEST_SECRET_KEY).unwrap()).unwrap();
+        PublicKey::from_secret_key(&Secp256k1::new(), &secret)
+    }
+
+    fn encode(bytes: Vec<u8>) -> String {
+        base64::engine::general_purpose::STANDARD.encode(bytes)
+    }
+
+    pub(crate) fn register_body(platform: Platform, device_token: &str) -> serde_json::Value {
+        let server_pubkey = test_server_pubkey();
+        let encrypted = create_test_encrypted_token(&server_pubkey, platform, device_token);
+        serde_json::json!({
+            "trade_pubkey": TEST_TRADE_PUBKEY,
+            "encrypted_token": encode(encrypted),
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_sandbox_and_production_registrations_are_independent() {
+        let readiness = Readiness::new(0);
+        readiness.mark_store_loaded();
+        let (production, production_sent) = MockPush::new();
+        let (sandbox, sandbox_sent) = MockPush::new();
+        let services: Vec<Box<dyn PushService>> = vec![
+            Box::new(production.for_environment(PushEnvironment::Production)),
+            Box::new(sandbox.for_environment(PushEnvironment::Sandbox)),
+        ];
+        let state = AppState {
+            dispatcher: Arc::new(Dispatcher::new(Arc::new(tokio::sync::RwLock::new(services)), Arc::new(Metrics::new()))),
+            platform_conflict: PlatformConflictMode::Enforce,
+            ..test_state(readiness)
+        };
+        let app = test::init_service(App::new().app_data(web::Data::new(state.clone())).configure(configure)).await;
+
+        // A sandbox registration for another platform is not a conflict
+        let req = test::TestRequest::post()
+            .uri("/api/register")
+            .set_json(register_body(Platform::Android, "prod-token"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+        let mut body = register_body(Platform::Ios, "sandbox-token");
+        body["environment"] = serde_json::json!("sandbox");
+        let req = test::TestRequest::post().uri("/api/register").set_json(&body).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+        let stored = state.token_store.get(TEST_TRADE_PUBKEY).await.unwrap();
+        assert_eq!((stored.device_token.as_str(), stored.environment), ("prod-token", PushEnvironment::Production));
+        let sandboxed = state
+            .token_store
+            .get(&store_key(TEST_TRADE_PUBKEY, PushEnvironment::Sandbox))
+            .await
+            .unwrap();
+        assert_eq!((sandboxed.device_token.as_str(), sandboxed.environment), ("sandbox-token", PushEnvironment::Sandbox));
+
+        // Each is pushed only through its environment's service
+        assert!(state.dispatcher.dispatch(&sandboxed, &PushPayload::silent_wake()).await);
+        assert_eq!((MockPush::sent(&production_sent), MockPush::sent(&sandbox_sent)), (0, 1));
+        assert!(state.dispatcher.dispatch(&stored, &PushPayload::silent_wake()).await);
+        assert_eq!((MockPush::sent(&production_sent), MockPush::sent(&sandbox_sent)), (1, 1));
+
+        // Unregistering the sandbox build leaves the release build registered
+        let req = test::TestRequest::post()
+            .uri("/api/unregister")
+            .set_json(serde_json::json!({ "trade_pubkey": TEST_TRADE_PUBKEY, "environment": "sandbox" }))
+            .to_request();
+        let resp: UnregisterResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp.platforms, vec![Platform::Ios]);
+        assert!(state.token_store.get(&store_key(TEST_TRADE_PUBKEY, PushEnvironment::Sandbox)).await.is_none());
+        assert!(state.token_store.get(TEST_TRADE_PUBKEY).await.is_some());
+    }
+
+    #[actix_web::test]
+    async fn test_registration_with_unknown_app_id_is_rejected() {
+        let readiness = Readiness::new(0);
+        readiness.mark_store_loaded();
+        let mut state = test_state(readiness);
+        state.app_ids = BTreeSet::from(["com.mostro.app".to_string()]);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+
+        let mut body = register_body(Platform::Android, "fcm-token");
+        body["app_id"] = serde_json::json!("com.example.unknown");
+        let req = test::TestRequest::post().uri("/api/register").set_json(&body).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+        let resp: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(resp["error_code"], "UNKNOWN_APP");
+        assert!(state.token_store.get(TEST_TRADE_PUBKEY).await.is_none());
+
+        body["app_id"] = serde_json::json!("com.mostro.app");
+        let req = test::TestRequest::post().uri("/api/register").set_json(&body).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+        let stored = state.token_store.get(TEST_TRADE_PUBKEY).await.unwrap();
+        assert_eq!(stored.app_id.as_deref(), Some("com.mostro.app"));
+
+        // Registrations without an app id keep working
+        let req = test::TestRequest::post()
+            .uri("/api/register")
+            .set_json(register_body(Platform::Android, "fcm-token"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+        assert_eq!(state.token_store.get(TEST_TRADE_PUBKEY).await.unwrap().app_id, None);
+    }
+
+    #[actix_web::test]
+    async fn test_registration_limited_to_advertised_platforms() {
+        let readiness = Readiness::new(0);
+        readiness.mark_store_loaded();
+        let mut state = test_state(readiness);
+        state.advertised_platforms = Some(vec![Platform::Android]);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/api/capabilities").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["platforms"], serde_json::json!(["android"]));
+
+        let req = test::TestRequest::post()
+            .uri("/api/register")
+            .set_json(register_body(Platform::Ios, "apns-token"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error_code"], "UNSUPPORTED_PLATFORM");
+        assert!(state.token_store.get(TEST_TRADE_PUBKEY).await.is_none());
+
+        let req = test::TestRequest::post()
+            .uri("/api/register")
+            .set_json(register_body(Platform::Android, "fcm-token"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn test_platform_conflict_policy() {
+        use crate::audit::AuditEntry;
+
+        let audit_path = std::env::temp_dir().join(format!("mostro-conflict-audit-{}.jsonl", std::process::id()));
+        for (mode, status, audited) in [
+            (PlatformConflictMode::Off, 200, None),
+            (PlatformConflictMode::Warn, 200, Some("ok")),
+            (PlatformConflictMode::Enforce, 409, Some("rejected")),
+        ] {
+            let _ = std::fs::remove_file(&audit_path);
+            let readiness = Readiness::new(0);
+            readiness.mark_store_loaded();
+            let mut state = test_state(readiness);
+            state.platform_conflict = mode;
+            state.audit = Arc::new(AuditLog::new(Some(&audit_path), None).unwrap());
+            let app = test::init_service(
+                App::new()
+                    .app_data(web::Data::new(state.clone()))
+                    .configure(configure),
+            )
+            .await;
+
+            for body in [register_body(Platform::Android, "fcm-token"), register_body(Platform::Android, "fcm-token-2")] {
+                let req = test::TestRequest::post().uri("/api/register").set_json(body).to_request();
+                assert_eq!(test::call_service(&app, req).await.status(), 200, "{:?}", mode);
+            }
+
+            let req = test::TestRequest::post()
+                .uri("/api/register")
+                .set_json(register_body(Platform::Ios, "apns-token"))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status, "{:?}", mode);
+            // Accepted, the iOS device joins the Android ones
+            let devices = state.token_store.devices(TEST_TRADE_PUBKEY, 5).await;
+            assert_eq!(devices.iter().any(|(_, token)| token.platform == Platform::Ios), status == 200, "{:?}", mode);
+
+            let entries: Vec<AuditEntry> = std::fs::read_to_string(&audit_path)
+                .unwrap_or_default()
+                .lines()
+                .map(|l| serde_json::from_str(l).unwrap())
+                .collect();
+            assert_eq!(entries.first().map(|e| e.result.as_str()), audited, "{:?}", mode);
+
+            if mode == PlatformConflictMode::Enforce {
+                let body: serde_json::Value = test::read_body_json(resp).await;
+                assert_eq!(body["error_code"], "REGISTRATION_CONFLICT");
+
+                // An explicit replace goes through, and is audited as such
+                let mut body = register_body(Platform::Ios, "apns-token");
+                body["replace"] = serde_json::json!(true);
+                let req = test::TestRequest::post().uri("/api/register").set_json(body).to_request();
+                assert_eq!(test::call_service(&app, req).await.status(), 200);
+                let devices = state.token_store.devices(TEST_TRADE_PUBKEY, 5).await;
+                assert_eq!(devices.len(), 1);
+                assert_eq!(devices[0].1.platform, Platform::Ios);
+                let content = std::fs::read_to_string(&audit_path).unwrap();
+                let last: AuditEntry = serde_json::from_str(content.lines().last().unwrap()).unwrap();
+                assert_eq!((last.action.as_str(), last.result.as_str()), ("platform_replace", "ok"));
+            }
+        }
+        let _ = std::fs::remove_file(&audit_path);
+    }
+
+    #[actix_web::test]
+    async fn test_registration_for_unserved_platform_is_flagged() {
+        let (android, _) = MockPush::new();
+        let services: Vec<Box<dyn PushService>> = vec![Box::new(android.serving("fcm", Platform::Android))];
+        let readiness = Readiness::new(0);
+        readiness.mark_store_loaded();
+        let mut state = AppState {
+            dispatcher: Arc::new(Dispatcher::new(
+                Arc::new(tokio::sync::RwLock::new(services)),
+                Arc::new(Metrics::new()),
+            )),
+            ..test_state(readiness)
+        };
+        let register = |state: &AppState, platform, token| {
+            let app = App::new().app_data(web::Data::new(state.clone())).configure(configure);
+            let req = test::TestRequest::post().uri("/api/register").set_json(register_body(platform, token));
+            async move { test::call_service(&test::init_service(app).await, req.to_request()).await }
+        };
+
+        let resp = register(&state, Platform::Android, "fcm-token").await;
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body.get("undeliverable"), None);
+
+        // No APNs service: stored, but flagged and counted
+        let resp = register(&state, Platform::Ios, "apns-token").await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["undeliverable"], true);
+        let devices = state.token_store.devices(TEST_TRADE_PUBKEY, 5).await;
+        assert_eq!(devices.last().unwrap().1.platform, Platform::Ios);
+        assert_eq!(Metrics::get(&state.metrics.registrations_undeliverable), 1);
+
+        state.reject_undeliverable = true;
+        state.token_store.unregister(TEST_TRADE_PUBKEY).await;
+        let resp = register(&state, Platform::Ios, "apns-token").await;
+        assert_eq!(resp.status(), 400);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error_code"], "UNSUPPORTED_PLATFORM");
+        assert!(state.token_store.get(TEST_TRADE_PUBKEY).await.is_none());
+        assert_eq!(Metrics::get(&state.metrics.registrations_undeliverable), 2);
+    }
+
+    #[actix_web::test]
+    async fn test_required_signature_proves_pubkey_ownership() {
+        use crate::crypto::ownership::tests::{sign_request, test_trade_keypair};
+
+        let readiness = Readiness::new(0);
+        readiness.mark_store_loaded();
+        let mut state = test_state(readiness);
+        state.require_ownership_signature = true;
+        let app = test::init_service(App::new().app_data(web::Data::new(state.clone())).configure(configure)).await;
+        let keypair = test_trade_keypair();
+        let trade_pubkey = ::hex::encode(keypair.x_only_public_key().0.serialize());
+        let envelope = create_test_encrypted_token(&test_server_pubkey(), Platform::Android, "fcm-token");
+        let now = chrono::Utc::now().timestamp() as u64;
+        let call = |uri: &str, body: serde_json::Value| test::TestRequest::post().uri(uri).set_json(body).to_request();
+
+        // Unsigned, or signed by another key than the claimed pubkey's
+        let unsigned = serde_json::json!({ "trade_pubkey": trade_pubkey, "encrypted_token": encode(envelope.clone()) });
+        let mut hijack = register_body(Platform::Android, "fcm-token");
+        hijack["signature"] = serde_json::json!(sign_request(&keypair, &envelope, now));
+        hijack["timestamp"] = serde_json::json!(now);
+        for body in [unsigned.clone(), hijack] {
+            let resp = test::call_service(&app, call("/api/register", body)).await;
+            assert_eq!(resp.status(), 401);
+            let body: ErrorResponse = test::read_body_json(resp).await;
+            assert_eq!(body.error_code, ErrorCode::InvalidSignature);
+        }
+        assert_eq!(Metrics::get(&state.metrics.signatures_rejected), 2);
+
+        let mut signed = unsigned;
+        signed["signature"] = serde_json::json!(sign_request(&keypair, &envelope, now));
+        signed["timestamp"] = serde_json::json!(now);
+        assert_eq!(test::call_service(&app, call("/api/register", signed)).await.status(), 200);
+
+        // Re-encrypting replaces the stored envelope, so it needs a signature too
+        let pubkey_bytes = keypair.x_only_public_key().0.serialize();
+        let v2 = create_test_encrypted_token_v2(&test_server_pubkey(), Platform::Android, "fcm-token", &pubkey_bytes);
+        let mut reencrypt = serde_json::json!({
+            "trade_pubkey": trade_pubkey,
+            "old_encrypted_token": encode(envelope.clone()),
+            "encrypted_token": encode(v2.clone()),
+        });
+        assert_eq!(test::call_service(&app, call("/api/reencrypt", reencrypt.clone())).await.status(), 401);
+        reencrypt["signature"] = serde_json::json!(sign_request(&keypair, &v2, now));
+        reencrypt["timestamp"] = serde_json::json!(now);
+        assert_eq!(test::call_service(&app, call("/api/reencrypt", reencrypt)).await.status(), 200);
+
+        // Unregistering without an envelope signs an empty one
+        let unregister = serde_json::json!({ "trade_pubkey": trade_pubkey });
+        assert_eq!(test::call_service(&app, call("/api/unregister", unregister.clone())).await.status(), 401);
+        let mut signed = unregister;
+        signed["signature"] = serde_json::json!(sign_request(&keypair, &[], now));
+        signed["timestamp"] = serde_json::json!(now);
+        assert_eq!(test::call_service(&app, call("/api/unregister", signed)).await.status(), 200);
+        assert!(state.token_store.get(&trade_pubkey).await.is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_pubkey_keeps_several_devices() {
+        let readiness = Readiness::new(0);
+        readiness.mark_store_loaded();
+        let mut state = test_state(readiness);
+        state.max_devices_per_pubkey = 2;
+        let app = test::init_service(App::new().app_data(web::Data::new(state.clone())).configure(configure)).await;
+        let register = |body| test::TestRequest::post().uri("/api/register").set_json(body).to_request();
+
+        // A phone and a tablet both register; re-registering the phone refreshes it
+        for token in ["phone-token", "tablet-token", "phone-token"] {
+            assert_eq!(test::call_service(&app, register(register_body(Platform::Android, token))).await.status(), 200);
+        }
+        let devices = state.token_store.devices(TEST_TRADE_PUBKEY, 2).await;
+        let tokens: Vec<_> = devices.iter().map(|(_, token)| token.device_token.as_str()).collect();
+        assert_eq!(tokens, ["phone-token", "tablet-token"]);
+        assert_eq!(state.token_store.get_stats().await.total, 2);
+
+        // A third device is over the cap
+        let resp = test::call_service(&app, register(register_body(Platform::Android, "watch-token"))).await;
+        assert_eq!(resp.status(), 409);
+        let body: RegisterResponse = test::read_body_json(resp).await;
+        assert_eq!(body.error_code, Some(ErrorCode::DeviceLimit));
+
+        // The tablet removes only itself, leaving room for the third device
+        let req = test::TestRequest::post()
+            .uri("/api/unregister")
+            .set_json(register_body(Platform::Android, "tablet-token"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+        let devices = state.token_store.devices(TEST_TRADE_PUBKEY, 2).await;
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].1.device_token, "phone-token");
+        assert_eq!(test::call_service(&app, register(register_body(Platform::Android, "watch-token"))).await.status(), 200);
+
+        // Without an envelope every device goes
+        let req = test::TestRequest::post()
+            .uri("/api/unregister")
+            .set_json(serde_json::json!({ "trade_pubkey": TEST_TRADE_PUBKEY }))
+            .to_request();
+        let body: UnregisterResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body.platforms, [Platform::Android, Platform::Android]);
+        assert!(state.token_store.devices(TEST_TRADE_PUBKEY, 2).await.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_further_devices_of_a_pubkey_are_not_first_registrations() {
+        let readiness = Readiness::new(0);
+        readiness.mark_store_loaded();
+        let mut state = test_state(readiness);
+        state.registration_alerts = Arc::new(RegistrationAlerts::new(true, None));
+        state.max_pubkeys_per_token = 1;
+        let app = test::init_service(App::new().app_data(web::Data::new(state.clone())).configure(configure)).await;
+        let register = |body| test::TestRequest::post().uri("/api/register").set_json(body).to_request();
+
+        assert_eq!(test::call_service(&app, register(register_body(Platform::Android, "phone-token"))).await.status(), 200);
+        assert_eq!(state.registration_alerts.fired(), 1);
+
+        // A second device, and the same phone in sandbox, belong to a known pubkey
+        assert_eq!(test::call_service(&app, register(register_body(Platform::Android, "tablet-token"))).await.status(), 200);
+        let mut sandbox = register_body(Platform::Android, "phone-token");
+        sandbox["environment"] = serde_json::json!("sandbox");
+        assert_eq!(test::call_service(&app, register(sandbox)).await.status(), 200);
+        assert_eq!(state.registration_alerts.fired(), 1);
+
+        // ...and count once against the token's pubkey cap
+        let sharing = state.token_store.pubkeys_for_token("phone-token").await;
+        assert_eq!(sharing.into_iter().collect::<Vec<_>>(), [TEST_TRADE_PUBKEY]);
+    }
+
+    #[actix_web::test]
+    async fn test_device_policy_decides_what_a_new_device_replaces() {
+        for (policy, expected) in [
+            (DevicePolicy::ReplaceAll, vec!["apns-token"]),
+            (DevicePolicy::PerPlatform, vec!["fcm-token-2", "apns-token"]),
+            (DevicePolicy::MultiDevice, vec!["fcm-token", "apns-token", "fcm-token-2"]),
+        ] {
+            let readiness = Readiness::new(0);
+            readiness.mark_store_loaded();
+            let mut state = test_state(readiness);
+            state.device_policy = policy;
+            let app = test::init_service(App::new().app_data(web::Data::new(state.clone())).configure(configure)).await;
+
+            // Android, then iOS, then another Android device
+            for (platform, token) in [(Platform::Android, "fcm-token"), (Platform::Ios, "apns-token")] {
+                let req = test::TestRequest::post().uri("/api/register").set_json(register_body(platform, token));
+                assert_eq!(test::call_service(&app, req.to_request()).await.status(), 200, "{:?}", policy);
+            }
+            if policy != DevicePolicy::ReplaceAll {
+                let req = test::TestRequest::post()
+                    .uri("/api/register")
+                    .set_json(register_body(Platform::Android, "fcm-token-2"));
+                assert_eq!(test::call_service(&app, req.to_request()).await.status(), 200, "{:?}", policy);
+            }
+
+            let devices = state.token_store.devices(TEST_TRADE_PUBKEY, 5).await;
+            let tokens: Vec<_> = devices.iter().map(|(_, token)| token.device_token.as_str()).collect();
+            assert_eq!(tokens, expected, "{:?}", policy);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_shared_token_cap() {
+        let readiness = Readiness::new(0);
+        readiness.mark_store_loaded();
+        let mut state = test_state(readiness);
+        state.max_pubkeys_per_token = 2;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+        let register = |pubkey: &str| {
+            let mut body = register_body(Platform::Android, "shared-fcm-token");
+            body["trade_pubkey"] = serde_json::json!(pubkey);
+            test::TestRequest::post().uri("/api/register").set_json(body).to_request()
+        };
+        let pubkeys: Vec<String> = ["a", "b", "c"].iter().map(|c| c.repeat(64)).collect();
+
+        assert_eq!(test::call_service(&app, register(&pubkeys[0])).await.status(), 200);
+        assert_eq!(test::call_service(&app, register(&pubkeys[1])).await.status(), 200);
+        let resp = test::call_service(&app, register(&pubkeys[2])).await;
+        assert_eq!(resp.status(), 409);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error_code"], "TOKEN_SHARE_LIMIT");
+        assert!(state.token_store.get(&pubkeys[2]).await.is_none());
+
+        // Refreshing a pubkey already on the token is not a new share
+        assert_eq!(test::call_service(&app, register(&pubkeys[0])).await.status(), 200);
+
+        // Unregistering frees a slot
+        state.token_store.unregister(&pubkeys[1]).await;
+        assert_eq!(test::call_service(&app, register(&pubkeys[2])).await.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn test_decrypt_burst_beyond_global_limit_is_shed() {
+        let readiness = Readiness::new(0);
+        readiness.mark_store_loaded();
+        let mut state = test_state(readiness);
+        state.decrypt_limiter = Arc::new(RateLimiter::per_second(Some(2)));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+
+        let mut statuses = Vec::new();
+        for i in 0..4 {
+            let req = test::TestRequest::post()
+                .uri("/api/register")
+                .set_json(register_body(Platform::Android, &format!("fcm-token-{}", i)))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            if resp.status() == 503 {
+                assert_eq!(resp.headers().get("Retry-After").unwrap(), "1");
+                let body: serde_json::Value = test::read_body_json(resp).await;
+                assert_eq!(body["error_code"], "OVERLOADED");
+                statuses.push(503);
+            } else {
+                statuses.push(resp.status().as_u16());
+            }
+        }
+        assert_eq!(statuses, vec![200, 200, 503, 503]);
+        assert_eq!(Metrics::get(&state.metrics.decrypts_shed), 2);
+
+        let req = test::TestRequest::get().uri("/api/metrics").to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert!(String::from_utf8_lossy(&body).contains("mostro_push_decrypt_rate 2\n"));
+    }
+
+    #[actix_web::test]
+    async fn test_status_secret_gates_status_and_metrics() {
+        let readiness = Readiness::new(0);
+        readiness.mark_store_loaded();
+        let mut state = test_state(readiness);
+        state.status_secret = Some("status-secret".to_string());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+
+        for uri in ["/api/status", "/api/metrics"] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), 401);
+
+            let req = test::TestRequest::get()
+                .uri(uri)
+                .insert_header(("Authorization", "Bearer wrong-secret"))
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), 401);
+
+            let req = test::TestRequest::get()
+                .uri(uri)
+                .insert_header(("Authorization", "Bearer status-secret"))
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), 200);
+        }
+
+        // Health stays public for load balancers and probes
+        let req = test::TestRequest::get().uri("/api/health").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn test_repeated_listener_failures_flip_health() {
+        let readiness = Readiness::new(0).with_listener_failure_threshold(3);
+        readiness.mark_store_loaded();
+        let state = test_state(readiness);
+        let app = test::init_service(App::new().app_data(web::Data::new(state.clone())).configure(configure)).await;
+        let health = || test::TestRequest::get().uri("/api/health").to_request();
+
+        assert!(!state.readiness.record_listener_failure());
+        assert!(!state.readiness.record_listener_failure());
+        assert_eq!(test::call_service(&app, health()).await.status(), 200);
+
+        assert!(state.readiness.record_listener_failure());
+        let resp = test::call_service(&app, health()).await;
+        assert_eq!(resp.status(), 503);
+        let body: HealthResponse = test::read_body_json(resp).await;
+        assert_eq!(body.status, "unhealthy");
+
+        // One good cycle restores it
+        state.readiness.record_listener_success();
+        let body: HealthResponse = test::call_and_read_body_json(&app, health()).await;
+        assert_eq!(body.status, "ok");
+
+        // Without a threshold the listener is never reported dead
+        let unlimited = Readiness::new(0);
+        assert!((0..100).all(|_| !unlimited.record_listener_failure()));
+        assert!(unlimited.is_listener_healthy());
+    }
+
+    #[actix_web::test]
+    async fn test_unregister_reports_removed_platform() {
+        let readiness = Readiness::new(0);
+        readiness.mark_store_loaded();
+        let state = test_state(readiness);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/register")
+            .set_json(register_body(Platform::Ios, "apns-token"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+        let unregister = || {
+            test::TestRequest::post()
+                .uri("/api/unregister")
+                .set_json(serde_json::json!({ "trade_pubkey": TEST_TRADE_PUBKEY }))
+                .to_request()
+        };
+        let body: UnregisterResponse = test::call_and_read_body_json(&app, unregister()).await;
+        assert!(body.success);
+        assert_eq!(body.platforms, vec![Platform::Ios]);
+
+        // Nothing left to remove, so no platform is reported
+        let body: serde_json::Value = test::call_and_read_body_json(&app, unregister()).await;
+        assert_eq!(body["success"], true);
+        assert!(body.get("platforms").is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_malformed_json_reports_invalid_json_with_field() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_state(Readiness::new(0))))
+                .configure(configure),
+        )
+        .await;
+        let register = |body: serde_json::Value| test::TestRequest::post().uri("/api/register").set_json(body).to_request();
+
+        // A missing field is named
+        let resp = test::call_service(&app, register(serde_json::json!({ "encrypted_token": "AAAA" }))).await;
+        assert_eq!(resp.status(), 400);
+        let body: ErrorResponse = test::read_body_json(resp).await;
+        assert_eq!(body.error_code, ErrorCode::InvalidJson);
+        assert_eq!(body.field.as_deref(), Some("trade_pubkey"));
+        assert!(body.message.contains("missing field `trade_pubkey`"), "{}", body.message);
+
+        // A mistyped one says what was expected and where
+        let mistyped = serde_json::json!({ "trade_pubkey": TEST_TRADE_PUBKEY, "encrypted_token": 42 });
+        let resp = test::call_service(&app, register(mistyped)).await;
+        assert_eq!(resp.status(), 400);
+        let body: ErrorResponse = test::read_body_json(resp).await;
+        assert_eq!(body.error_code, ErrorCode::InvalidJson);
+        assert!(body.message.contains("invalid type: integer `42`, expected a string"), "{}", body.message);
+        assert_eq!(body.field, None);
+
+        // So is a body that isn't JSON at all
+        let req = test::TestRequest::post()
+            .uri("/api/unregister")
+            .insert_header(("Content-Type", "application/json"))
+            .set_payload("{trade_pubkey")
+            .to_request();
+        let body: ErrorResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body.error_code, ErrorCode::InvalidJson);
+    }
+
+    #[actix_web::test]
+    async fn test_requests_over_limit_per_pubkey_or_ip_are_refused() {
+        let state = AppState {
+            request_limiter: Arc::new(MemoryRequestLimiter::per_minute(2)),
+            ..test_state(Readiness::new(0))
+        };
+        let app = test::init_service(App::new().app_data(web::Data::new(state.clone())).configure(configure)).await;
+        let unregister = |trade_pubkey: String, peer: &str| {
+            test::TestRequest::post()
+                .uri("/api/unregister")
+                .peer_addr(peer.parse().unwrap())
+                .set_json(serde_json::json!({ "trade_pubkey": trade_pubkey }))
+                .to_request()
+        };
+
+        // The pubkey's budget holds whichever address it comes from
+        let pubkey = TEST_TRADE_PUBKEY.to_string();
+        assert_eq!(test::call_service(&app, unregister(pubkey.clone(), "203.0.113.1:1000")).await.status(), 200);
+        assert_eq!(test::call_service(&app, unregister(pubkey.clone(), "203.0.113.2:1000")).await.status(), 200);
+        let resp = test::call_service(&app, unregister(pubkey, "203.0.113.3:1000")).await;
+        assert_eq!(resp.status(), 429);
+        assert!(resp.headers().contains_key("Retry-After"));
+        let body: ErrorResponse = test::read_body_json(resp).await;
+        assert_eq!(body.error_code, ErrorCode::RateLimited);
+
+        // And an address's budget holds across pubkeys
+        assert_eq!(test::call_service(&app, unregister("ab".repeat(32), "203.0.113.1:1000")).await.status(), 200);
+        assert_eq!(test::call_service(&app, unregister("cd".repeat(32), "203.0.113.1:1000")).await.status(), 429);
+        assert_eq!(Metrics::get(&state.metrics.requests_rate_limited), 2);
+    }
+
+    #[actix_web::test]
+    async fn test_status_reports_growing_uptime() {
+        let state = test_state(Readiness::new(0));
+        let app = test::init_service(App::new().app_data(web::Data::new(state.clone())).configure(configure)).await;
+        let status = || test::TestRequest::get().uri("/api/status").to_request();
+
+        let first: StatusResponse = test::call_and_read_body_json(&app, status()).await;
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let second: StatusResponse = test::call_and_read_body_json(&app, status()).await;
+        assert!(second.uptime_seconds > first.uptime_seconds);
+        // The start time doesn't move
+        assert_eq!(first.started_at, second.started_at);
+        assert_eq!(first.started_at, state.started.at);
+    }
+
+    #[actix_web::test]
+    async fn test_registrations_over_concurrency_limit_get_503() {
+        let readiness = Readiness::new(0);
+        readiness.mark_store_loaded();
+        let state = AppState {
+            registration_limit: Arc::new(ConcurrencyLimit::new(1, Duration::from_millis(50))),
+            ..test_state(readiness)
+        };
+        let app = test::init_service(App::new().app_data(web::Data::new(state.clone())).configure(configure)).await;
+        let register = || test::TestRequest::post().uri("/api/register").set_json(register_body(Platform::Android, "fcm-token")).to_request();
+
+        // A registration still in progress holds the only slot
+        let slot = state.registration_limit.acquire().await.unwrap();
+        let resp = test::call_service(&app, register()).await;
+        assert_eq!(resp.status(), 503);
+        assert_eq!(resp.headers().get("Retry-After").unwrap(), "1");
+        let body: RegisterResponse = test::read_body_json(resp).await;
+        assert_eq!(body.error_code, Some(ErrorCode::Overloaded));
+        assert_eq!(Metrics::get(&state.metrics.registrations_shed), 1);
+        let req = test::TestRequest::get().uri("/api/metrics").to_request();
+        let metrics = String::from_utf8(test::call_and_read_body(&app, req).await.to_vec()).unwrap();
+        assert!(metrics.contains("mostro_push_registrations_in_flight 1\n"));
+
+        // A registration waiting for the slot goes through once it frees up
+        let release = async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(slot);
+        };
+        let (resp, ()) = tokio::join!(test::call_service(&app, register()), release);
+        assert_eq!(resp.status(), 200);
+        assert_eq!(state.registration_limit.in_flight(), 0);
+    }
+
+    #[actix_web::test]
+    async fn test_overview_reflects_relay_and_store_state() {
+        let (android, _) = MockPush::new();
+        let services: Vec<Box<dyn PushService>> = vec![Box::new(android.serving("fcm", Platform::Android))];
+        let readiness = Readiness::new(0);
+        readiness.mark_store_loaded();
+        let state = AppState {
+            dispatcher: Arc::new(Dispatcher::new(Arc::new(tokio::sync::RwLock::new(services)), Arc::new(Metrics::new()))),
+            ..test_state(readiness)
+        };
+        state.relay_health.set_connected("wss://up.example", true);
+        state.relay_health.set_connected("wss://down.example", false);
+        let app = test::init_service(App::new().app_data(web::Data::new(state.clone())).configure(configure)).await;
+        let overview = || test::TestRequest::get().uri("/api/overview").to_request();
+
+        let before: OverviewResponse = test::call_and_read_body_json(&app, overview()).await;
+        assert_eq!((before.relays.connected, before.relays.total), (1, 2));
+        assert_eq!(before.tokens.total, 0);
+        assert_eq!(before.providers.len(), 1);
+        assert_eq!(before.providers[0].provider, "fcm");
+        assert_eq!(before.queues.register_writes, 0);
+
+        let req = test::TestRequest::post()
+            .uri("/api/register")
+            .set_json(register_body(Platform::Android, "fcm-token"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+        state.relay_health.set_connected("wss://down.example", true);
+
+        let after: OverviewResponse = test::call_and_read_body_json(&app, overview()).await;
+        assert_eq!(after.relays.connected, 2);
+        assert_eq!((after.tokens.total, after.tokens.android), (1, 1));
+    }
+
+    #[actix_web::test]
+    async fn test_register_accepts_every_scheme_size() {
+        let readiness = Readiness::new(0);
+        readiness.mark_store_loaded();
+        let state = test_state(readiness);
+        let app = test::init_service(App::new().app_data(web::Data::new(state.clone())).configure(configure)).await;
+        let register = |encrypted: Vec<u8>| {
+            test::TestRequest::post()
+                .uri("/api/register")
+                .set_json(serde_json::json!({ "trade_pubkey": TEST_TRADE_PUBKEY, "encrypted_token": encode(encrypted) }))
+                .to_request()
+        };
+
+        // A standard envelope and an extended one for a long endpoint URL
+        let long = format!("https://push.example/up/{}", "a".repeat(300));
+        let standard = crypto::encrypt_token(&test_server_pubkey(), &Platform::Android, "fcm-token").unwrap();
+        let extended = crypto::encrypt_token(&test_server_pubkey(), &Platform::Android, &long).unwrap();
+        assert_ne!(standard.len(), extended.len());
+        for encrypted in [standard, extended] {
+            assert_eq!(test::call_service(&app, register(encrypted)).await.status(), 200);
+        }
+        let stored = state.token_store.devices(&store_key(TEST_TRADE_PUBKEY, PushEnvironment::Production), 5).await;
+        assert_eq!(stored.last().unwrap().1.device_token, long);
+
+        let resp = test::call_service(&app, register(vec![0u8; ENCRYPTED_TOKEN_SIZE + 1])).await;
+        assert_eq!(resp.status(), 400);
+        let body: RegisterResponse = test::read_body_json(resp).await;
+        assert_eq!(body.error_code, Some(ErrorCode::InvalidTokenSize));
+    }
+
+    #[actix_web::test]
+    async fn test_register_rejected_during_warmup() {
+        let readiness = Readiness::new(1);
+        readiness.mark_store_loaded();
+        let state = test_state(readiness);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/register")
+            .set_json(register_body(Platform::Android, "fcm-token"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 503);
+        assert!(resp.headers().contains_key("Retry-After"));
+
+        let req = test::TestRequest::get().uri("/api/health").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["status"], "warming_up");
+
+        state.readiness.set_relay_connected("wss://relay.example.com", true);
+
+        let req = test::TestRequest::post()
+            .uri("/api/register")
+            .set_json(register_body(Platform::Android, "fcm-token"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert!(state.token_store.get(TEST_TRADE_PUBKEY).await.is_some());
+    }
+
+    #[actix_web::test]
+    async fn test_status_cache_invalidated_by_registration() {
+        let readiness = Readiness::new(0);
+        readiness.mark_store_loaded();
+        let state = test_state(readiness);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/api/status").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["tokens"]["total"], 0);
+
+        // Cached entry is reused while the store is unchanged
+        assert!(state.status_cache.get(state.token_store.generation()).is_some());
+
+        state.token_store.register(
+            TEST_TRADE_PUBKEY.to_string(),
+            "fcm-token".to_string(),
+            Platform::Android,
+        ).await;
+
+        let req = test::TestRequest::get().uri("/api/status").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["tokens"]["total"], 1);
+    }
+
+    #[actix_web::test]
+    async fn test_register_sends_catch_up_push_for_missed_event() {
+        let readiness = Readiness::new(0);
+        readiness.mark_store_loaded();
+        let (mock, sent) = MockPush::new();
+        let services: Vec<Box<dyn PushService>> = vec![Box::new(mock)];
+        let state = AppState {
+            dispatcher: Arc::new(Dispatcher::new(
+                Arc::new(tokio::sync::RwLock::new(services)),
+                Arc::new(Metrics::new()),
+            )),
+            ..test_state(readiness)
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+
+        state.backfill.record_missed(TEST_TRADE_PUBKEY, "e1");
+        state.backfill.record_missed(TEST_TRADE_PUBKEY, "e2");
+
+        let req = test::TestRequest::post()
+            .uri("/api/register")
+            .set_json(register_body(Platform::Android, "fcm-token"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+        for _ in 0..50 {
+            if MockPush::sent(&sent) > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // Coalesced into a single push
+        assert_eq!(MockPush::sent(&sent), 1);
+        assert!(state.backfill.take_missed(TEST_TRADE_PUBKEY).is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_registered_check_reports_last_notified() {
+        let readiness = Readiness::new(0);
+        readiness.mark_store_loaded();
+        let (mock, sent) = MockPush::new();
+        let services: Vec<Box<dyn PushService>> = vec![Box::new(mock)];
+        let state = AppState {
+            dispatcher: Arc::new(Dispatcher::new(Arc::new(tokio::sync::RwLock::new(services)), Arc::new(Metrics::new()))),
+            ..test_state(readiness)
+        };
+        let app = test::init_service(App::new().app_data(web::Data::new(state.clone())).configure(configure)).await;
+        let check = || test::TestRequest::get().uri(&format!("/api/registered/{}", TEST_TRADE_PUBKEY)).to_request();
+
+        let body: RegisteredCheckResponse = test::call_and_read_body_json(&app, check()).await;
+        assert!(!body.registered);
+        assert_eq!(body.last_notified_at, None);
+
+        // Registering sends a catch-up push for a missed event
+        state.backfill.record_missed(TEST_TRADE_PUBKEY, "e1");
+        let started = chrono::Utc::now();
+        let req = test::TestRequest::post()
+            .uri("/api/register")
+            .set_json(register_body(Platform::Android, "fcm-token"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+        let mut body: RegisteredCheckResponse = test::call_and_read_body_json(&app, check()).await;
+        for _ in 0..50 {
+            if body.last_notified_at.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            body = test::call_and_read_body_json(&app, check()).await;
+        }
+        assert_eq!(MockPush::sent(&sent), 1);
+        assert!(body.registered);
+        assert_eq!(body.platforms, vec![Platform::Android]);
+        assert!(body.last_notified_at.unwrap() >= started);
+
+
+        let req = test::TestRequest::get().uri("/api/registered/not-a-pubkey").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_register_durable_mode_reports_registered() {
+        let readiness = Readiness::new(0);
+        readiness.mark_store_loaded();
+        let state = test_state(readiness);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/register")
+            .set_json(register_body(Platform::Android, "fcm-token"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["status"], "registered");
+        assert!(state.token_store.get(TEST_TRADE_PUBKEY).await.is_some());
+    }
+
+    #[actix_web::test]
+    async fn test_register_accepted_mode_queues_write() {
+        let readiness = Readiness::new(0);
+        readiness.mark_store_loaded();
+        let state = test_state(readiness);
+        let state = AppState {
+            write_queue: Some(Arc::new(WriteQueue::start(
+                state.token_store.clone(),
+                state.metrics.clone(),
+                state.registration_alerts.clone(),
+            ))),
+            ..state
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/register")
+            .set_json(register_body(Platform::Ios, "apns-token"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 202);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["status"], "accepted");
+        assert_eq!(body["platform"], "ios");
+
+        let queue = state.write_queue.as_ref().unwrap();
+        for _ in 0..50 {
+            if queue.depth() == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(queue.depth(), 0);
+        let stored = state.token_store.get(TEST_TRADE_PUBKEY).await.unwrap();
+        assert_eq!(stored.device_token, "apns-token");
+        assert!(state.metrics.render().contains("mostro_push_register_write_queue_depth 0\n"));
+    }
+
+    #[actix_web::test]
+    async fn test_delivery_stats_reflect_outcomes() {
+        let (android, _) = MockPush::new();
+        let (ios, _) = MockPush::new();
+        let services: Vec<Box<dyn PushService>> = vec![
+            Box::new(android.serving("fcm", Platform::Android)),
+            Box::new(ios.serving("apns", Platform::Ios).failing()),
+        ];
+        let state = AppState {
+            dispatcher: Arc::new(Dispatcher::new(
+                Arc::new(tokio::sync::RwLock::new(services)),
+                Arc::new(Metrics::new()),
+            )),
+            ..test_state(Readiness::new(0))
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+
+        let android = RegisteredToken::new("fcm-token".to_string(), Platform::Android);
+        let ios = RegisteredToken::new("apns-token".to_string(), Platform::Ios);
+        for _ in 0..3 {
+            assert!(state.dispatcher.dispatch(&android, &PushPayload::silent_wake()).await);
+        }
+        assert!(!state.dispatcher.dispatch(&ios, &PushPayload::silent_wake()).await);
+
+        let req = test::TestRequest::get().uri("/api/stats/delivery?window_secs=300").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["window_secs"], 300);
+        assert_eq!(body["total"]["succeeded"], 3);
+        assert_eq!(body["total"]["failed"], 1);
+        assert_eq!(body["total"]["success_rate"], 0.75);
+        assert_eq!(body["platforms"]["android"]["success_rate"], 1.0);
+        assert_eq!(body["platforms"]["ios"]["success_rate"], 0.0);
+    }
+
+    #[actix_web::test]
+    async fn test_discovery_matches_what_the_server_accepts() {
+        let readiness = Readiness::new(0);
+        readiness.mark_store_loaded();
+        let mut state = test_state(readiness);
+        state.advertised_platforms = Some(vec![Platform::Android]);
+        let app = test::init_service(App::new().app_data(web::Data::new(state.clone())).configure(configure)).await;
+
+        let req = test::TestRequest::get().uri("/api/discovery").to_request();
+        let body: DiscoveryResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body.server_pubkey, state.token_crypto.public_key_hex());
+        assert_eq!(body.payload_version, crypto::PAYLOAD_VERSION);
+        assert_eq!(body.envelope_versions, vec![ENVELOPE_V1, ENVELOPE_V2]);
+        assert_eq!(body.platforms, vec![Platform::Android]);
+        assert!(body.endpoints.reencrypt && !body.endpoints.batch_register);
+        assert_eq!((body.limits.max_batch_size, body.limits.max_pubkeys_per_token), (None, None));
+
+        // Each advertised scheme takes a token of its advertised maximum at its advertised size
+        let sizes: Vec<usize> = body.schemes.iter().map(|scheme| scheme.encrypted_size).collect();
+        assert_eq!(sizes, accepted_token_sizes());
+        for (advertised, scheme) in body.schemes.iter().zip(crypto::SCHEMES) {
+            assert_eq!(advertised.cipher, scheme.cipher.name());
+            assert_eq!(advertised.nonce_size, scheme.cipher.nonce_size());
+            let longest = "t".repeat(advertised.max_token_length);
+            let encrypted =
+                crypto::encrypt_envelope_with(&test_server_pubkey(), &Platform::Android, &longest, None, None, scheme.cipher)
+                    .unwrap();
+            assert_eq!(encrypted.len(), advertised.encrypted_size, "{}", advertised.name);
+        }
+        assert_eq!(
+            body.limits.max_token_length,
+            body.schemes.iter().map(|scheme| scheme.max_token_length).max().unwrap()
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_required_binding_rejects_replayed_envelopes() {
+        let readiness = Readiness::new(0);
+        readiness.mark_store_loaded();
+        let mut state = test_state(readiness);
+        state.require_token_binding = true;
+        let app = test::init_service(App::new().app_data(web::Data::new(state.clone())).configure(configure)).await;
+        let register = |encrypted: Vec<u8>| {
+            test::TestRequest::post()
+                .uri("/api/register")
+                .set_json(serde_json::json!({ "trade_pubkey": TEST_TRADE_PUBKEY, "encrypted_token": encode(encrypted) }))
+                .to_request()
+        };
+
+        // Bound to another pubkey, or not bound at all, the envelope is refused
+        let server_pubkey = test_server_pubkey();
+        let other = create_test_encrypted_token_v2(&server_pubkey, Platform::Android, "fcm-token", &[0xcd; 32]);
+        let unbound = create_test_encrypted_token(&server_pubkey, Platform::Android, "fcm-token");
+        for encrypted in [other, unbound] {
+            let resp = test::call_service(&app, register(encrypted)).await;
+            assert_eq!(resp.status(), 400);
+            let body: RegisterResponse = test::read_body_json(resp).await;
+            assert_eq!(body.error_code, Some(ErrorCode::DecryptionFailed));
+        }
+
+        let pubkey_bytes = hex::decode(TEST_TRADE_PUBKEY).unwrap();
+        let bound = create_test_encrypted_token_v2(&server_pubkey, Platform::Android, "fcm-token", &pubkey_bytes);
+        assert_eq!(test::call_service(&app, register(bound)).await.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn test_reencrypt_upgrades_envelope_and_rejects_mismatch() {
+        let readiness = Readiness::new(0);
+        readiness.mark_store_loaded();
+        let state = test_state(readiness);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+
+        let old_body = register_body(Platform::Android, "fcm-token");
+        let req = test::TestRequest::post().uri("/api/register").set_json(&old_body).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+        let server_pubkey = test_server_pubkey();
+        let pubkey_bytes = hex::decode(TEST_TRADE_PUBKEY).unwrap();
+        let reencrypt = |device_token: &str| {
+            let v2 = create_test_encrypted_token_v2(&server_pubkey, Platform::Android, device_token, &pubkey_bytes);
+            test::TestRequest::post()
+                .uri("/api/reencrypt")
+                .set_json(serde_json::json!({
+                    "trade_pubkey": TEST_TRADE_PUBKEY,
+                    "old_encrypted_token": old_body["encrypted_token"],
+                    "encrypted_token": encode(v2),
+                }))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, reencrypt("other-token")).await;
+        assert_eq!(resp.status(), 400);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error_code"], "TOKEN_MISMATCH");
+
+        let resp = test::call_service(&app, reencrypt("fcm-token")).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["envelope_version"], 2);
+
+        let stored = state.token_store.get(TEST_TRADE_PUBKEY).await.unwrap();
+        assert_eq!(stored.envelope_version, ENVELOPE_V2);
+        assert_eq!(stored.envelope_history.len(), 1);
+        assert_eq!(stored.envelope_history[0].from, 1);
+        assert_eq!(state.token_store.get_stats().await.envelope_v2, 1);
+        assert_eq!(Metrics::get(&state.metrics.reencryptions), 1);
+
+        // Sandbox registrations are upgraded under their own key
+        let mut sandbox_body = register_body(Platform::Android, "sandbox-token");
+        sandbox_body["environment"] = serde_json::json!("sandbox");
+        let req = test::TestRequest::post().uri("/api/register").set_json(&sandbox_body).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+        let v2 = create_test_encrypted_token_v2(&server_pubkey, Platform::Android, "sandbox-token", &pubkey_bytes);
+        let req = test::TestRequest::post()
+            .uri("/api/reencrypt")
+            .set_json(serde_json::json!({
+                "trade_pubkey": TEST_TRADE_PUBKEY,
+                "old_encrypted_token": sandbox_body["encrypted_token"],
+                "encrypted_token": encode(v2),
+                "environment": "sandbox",
+            }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+        let sandbox = state.token_store.get(&store_key(TEST_TRADE_PUBKEY, PushEnvironment::Sandbox)).await;
+        assert_eq!(sandbox.unwrap().envelope_version, ENVELOPE_V2);
     }
 }
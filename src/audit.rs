@@ -0,0 +1,123 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::gc::{compact_jsonl, Compaction, Dataset, RetentionPolicy};
+use crate::utils::signing::SigningClient;
+
+/// Log target for audit entries, so they can be filtered apart from normal
+/// logs (e.g. `RUST_LOG=info,audit=info`).
+pub const AUDIT_TARGET: &str = "audit";
+/// Entries kept in memory for the admin dashboard.
+const RECENT_ENTRIES: usize = 20;
+
+/// One admin mutation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub at: DateTime<Utc>,
+    /// Who made the change, derived from the credentials presented
+    pub actor: String,
+    pub action: String,
+    pub target: String,
+    /// "ok", or why the action failed
+    pub result: String,
+}
+
+/// Audit trail for admin actions: always logged under `AUDIT_TARGET`, and
+/// optionally appended to a JSONL file and mirrored to a webhook.
+pub struct AuditLog {
+    file: Option<(PathBuf, Mutex<LineWriter<File>>)>,
+    webhook_url: Option<String>,
+    client: SigningClient,
+    recent: Mutex<VecDeque<AuditEntry>>,
+}
+
+impl AuditLog {
+    pub fn new(path: Option<&Path>, webhook_url: Option<String>) -> std::io::Result<Self> {
+        let file = match path {
+            Some(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                Some((path.to_path_buf(), Mutex::new(LineWriter::new(file))))
+            }
+            None => None,
+        };
+        Ok(Self {
+            file,
+            webhook_url,
+            client: SigningClient::default(),
+            recent: Mutex::new(VecDeque::with_capacity(RECENT_ENTRIES)),
+        })
+    }
+
+    /// Send webhooks through `client`, e.g. to sign them.
+    pub fn with_client(mut self, client: SigningClient) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// The latest entries, newest first.
+    pub fn recent(&self) -> Vec<AuditEntry> {
+        self.recent.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn record(&self, actor: &str, action: &str, target: &str, result: Result<(), String>) {
+        let entry = AuditEntry {
+            at: Utc::now(),
+            actor: actor.to_string(),
+            action: action.to_string(),
+            target: target.to_string(),
+            result: result.err().unwrap_or_else(|| "ok".to_string()),
+        };
+        let Ok(line) = serde_json::to_string(&entry) else {
+            return;
+        };
+        log::info!(target: AUDIT_TARGET, "{}", line);
+        {
+            let mut recent = self.recent.lock().unwrap();
+            if recent.len() == RECENT_ENTRIES {
+                recent.pop_back();
+            }
+            recent.push_front(entry.clone());
+        }
+
+        if let Some((_, file)) = &self.file {
+            if let Err(e) = writeln!(file.lock().unwrap(), "{}", line) {
+                warn!("Failed to write audit log: {}", e);
+            }
+        }
+
+        if let Some(url) = &self.webhook_url {
+            let client = self.client.clone();
+            let request = client.post(url).json(&entry);
+            tokio::spawn(async move {
+                match client.send(request).await {
+                    Ok(response) if !response.status().is_success() => {
+                        warn!("Audit webhook returned {}", response.status());
+                    }
+                    Err(e) => warn!("Audit webhook failed: {}", e),
+                    Ok(_) => {}
+                }
+            });
+        }
+    }
+}
+#[async_trait]
+impl Dataset for AuditLog {
+    fn name(&self) -> &'static str {
+        "audit"
+    }
+
+    async fn compact(&self, policy: &RetentionPolicy, now: DateTime<Utc>) -> Result<Compaction, String> {
+        let Some((path, file)) = &self.file else {
+            return Ok(Compaction::default());
+        };
+        compact_jsonl(path, &mut file.lock().unwrap(), policy, now).map_err(|e| e.to_string())
+    }
+}
+
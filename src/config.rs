@@ -1,5 +1,15 @@
 use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::api::bind::BindAddress;
+use crate::crypto::Platform;
+use crate::gc::RetentionPolicy;
+use crate::metrics;
+use crate::store::{DevicePolicy, PlatformConflictMode, StoreBackend, WriteMode};
+use crate::utils::rate::RateLimitBackend;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
@@ -9,14 +19,66 @@ pub struct Config {
     pub rate_limit: RateLimitConfig,
     pub crypto: CryptoConfig,
     pub store: StoreConfig,
+    pub metrics: MetricsConfig,
+    pub replication: ReplicationConfig,
+    pub digest: DigestConfig,
+    pub gc: GcConfig,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct NostrConfig {
     pub relays: Vec<String>,
+    /// Upper bound on `relays`, guarding against pasted or generated lists
+    pub max_relays: usize,
+    /// Accept `ws://` relays, e.g. a local test relay; only `wss://` otherwise
+    pub allow_insecure_relays: bool,
+    /// Per-relay usage, keyed by URL without a trailing slash; unlisted
+    /// relays are both read and written
+    pub relay_usage: HashMap<String, RelayUsage>,
     pub subscription_id: String,
     pub event_kinds: Vec<u64>,
     pub mostro_pubkey: String,
+    /// Tag name that marks an event as not worth a push (e.g. read receipts)
+    pub no_push_tag: Option<String>,
+    /// Required tag value; when unset, the tag's presence alone suppresses
+    pub no_push_tag_value: Option<String>,
+    /// Relays that must be connected before registrations are accepted
+    pub min_relays_connected: usize,
+    /// How long a reconnect waits for pushes dispatched from the old connection
+    pub drain_timeout_secs: u64,
+    /// Width of each historical fetch when catching up after a disconnect
+    pub catchup_chunk_secs: u64,
+    /// Events older than this are not caught up, however long the downtime
+    pub catchup_max_age_secs: u64,
+    /// NIP-66 monitors trusted to report relay health; relay hygiene is off when empty
+    pub relay_monitor_pubkeys: Vec<String>,
+    /// Also add healthy relays reported by monitors, not only drop failing ones
+    pub relay_monitor_auto_add: bool,
+    /// Ingest rate, in events per second, above which events are sampled; off when 0
+    pub overload_events_per_sec: u64,
+    /// Relay events handled at once; a slow lookup only holds up its own event
+    pub event_handler_concurrency: usize,
+    /// Consecutive failed connect cycles before the listener is reported
+    /// unhealthy; never when 0
+    pub listener_failure_threshold: u32,
+    pub listener_failure_action: ListenerFailureAction,
+    /// JSONL file recording every handled event's outcome, for `replay`
+    pub event_trace_path: Option<String>,
+    /// Tag carrying a stable message id; events sharing its value are pushed once
+    pub idempotency_tag: Option<String>,
+    /// Tag naming an event's category, matched against registration preferences
+    pub category_tag: String,
+    /// JSON rules categorizing events without the tag, re-read when it changes
+    pub category_rules_path: Option<String>,
+    /// Where the first-seen Mostro pubkey is pinned; pinning is off when unset
+    pub pin_path: Option<String>,
+    /// Start even if `mostro_pubkey` differs from the pinned one, re-pinning it
+    pub accept_mostro_key_change: bool,
+    /// Hex secret key the listener connects and publishes as; a fresh key
+    /// each start when unset
+    pub identity_key: Option<String>,
+    /// Seconds between publishing the registration log head; off when unset
+    pub wal_anchor_interval_secs: Option<u64>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -25,51 +87,553 @@ pub struct PushConfig {
     pub unifiedpush_enabled: bool,
     pub batch_delay_ms: u64,
     pub cooldown_ms: u64,
+    /// How far back unmatched events are remembered for catch-up pushes (0 disables)
+    pub backfill_window_secs: u64,
+    /// Send one catch-up push no matter how many events were missed
+    pub backfill_coalesce: bool,
+    /// How long an event's delivery to a device is remembered, so no other
+    /// path sends it again (0 disables the ledger)
+    pub ledger_max_age_secs: u64,
+    /// File persisting delivered intents across restarts; memory only when unset
+    pub ledger_path: Option<String>,
+    /// File keeping events no provider accepted, replayed on startup; off when unset
+    pub outbox_path: Option<String>,
+    pub outbox_max_entries: usize,
+    /// Undelivered events older than this are not replayed
+    pub outbox_ttl_secs: u64,
+    /// Requests/minute budgets per provider; 0 disables the quota
+    pub fcm_quota_per_minute: u32,
+    pub unifiedpush_quota_per_minute: u32,
+    /// How often a batch of stored tokens is validated with its provider and
+    /// the dead ones unregistered; off when unset
+    pub reaper_interval_secs: Option<u64>,
+    pub reaper_batch_size: usize,
+    /// Validation requests per second while reaping; 0 is unpaced
+    pub reaper_checks_per_sec: u32,
+    /// Identify device tokens in logs by a keyed hash instead of a prefix
+    pub log_token_hashes: bool,
+    /// Sends in flight across all push services, shared fairly between them; 0 is unbounded
+    pub dispatch_concurrency: usize,
+    /// In-flight dispatches per platform; `dispatch_concurrency` when unset
+    pub android_concurrency: Option<usize>,
+    pub ios_concurrency: Option<usize>,
+    /// Largest serialized payload sent per platform, in bytes; 0 is unchecked
+    pub android_payload_budget: usize,
+    pub ios_payload_budget: usize,
+    /// Platforms offered to clients and accepted at registration; every
+    /// platform a configured provider serves when unset
+    pub advertised_platforms: Option<Vec<Platform>>,
+    /// Refuse registrations for platforms no push service serves, instead of
+    /// storing and flagging them
+    pub reject_undeliverable: bool,
+    /// Enabled fraction per feature flag; flags not listed are off
+    pub feature_flags: BTreeMap<String, f64>,
+    /// Where pushes get their grouping id from; ungrouped when unset
+    pub grouping: Option<GroupingSource>,
+    /// App builds sharing this server, keyed by the `app_id` they register with
+    pub apps: BTreeMap<String, PushApp>,
+    /// Android notification style of registrations without their own app's
+    pub android_notification: AndroidNotificationStyle,
+    pub firebase_service_account_path: Option<String>,
+    /// Firebase project and credentials for sandbox registrations. When set,
+    /// the default project serves production registrations only.
+    pub sandbox_fcm_project_id: Option<String>,
+    pub sandbox_service_account_path: Option<String>,
+    /// Shared secret for signing requests to push gateways and webhooks; unsigned when unset
+    pub signing_key: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
+    /// Listen address; `host:port` unless `SERVER_BIND` names one, e.g. `unix:/run/mostro-push.sock`
+    pub bind: BindAddress,
+    /// Log (and optionally POST to a webhook) when a never-seen pubkey registers
+    pub first_registration_alert: bool,
+    pub first_registration_webhook_url: Option<String>,
+    /// Bearer token for the `/admin` API; disabled when unset
+    pub admin_token: Option<String>,
+    /// Bearer secret required by `/api/status` and `/api/metrics`; public when unset
+    pub status_secret: Option<String>,
+    /// JSONL file receiving an entry per admin mutation
+    pub audit_log_path: Option<String>,
+    pub audit_webhook_url: Option<String>,
+    pub status_cache_ttl_ms: u64,
+    pub info_cache_ttl_secs: u64,
+    /// Default window for `/api/stats/delivery`
+    pub delivery_stats_window_secs: u64,
+    /// Refuse to start when a security check fails, instead of warning
+    pub strict_security: bool,
+    /// Pubkeys `/admin/watch` can trace at once
+    pub watch_max_keys: usize,
+    /// Longest startup warmup may hold readiness back; warmup is off at 0
+    pub warmup_deadline_secs: u64,
+    /// Registrations processed at once; unlimited when 0
+    pub max_concurrent_registrations: usize,
+    /// How long a registration waits for a slot before it is refused
+    pub registration_wait_ms: u64,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct RateLimitConfig {
+    /// Requests per client IP and per pubkey a minute; unlimited at 0
     pub max_per_minute: u32,
+    pub backend: RateLimitBackend,
+    /// Redis for `RateLimitBackend::Redis`
+    pub redis_url: Option<String>,
+}
+
+/// Where the server secret key is read from.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub enum ServerKeySource {
+    /// Hex key given inline (`SERVER_PRIVATE_KEY`)
+    Hex(String),
+    /// File holding the hex key (`SERVER_PRIVATE_KEY_FILE`)
+    File(String),
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct CryptoConfig {
-    pub server_private_key: String,
+    pub server_private_key: ServerKeySource,
+    /// Previous server keys still accepted for decryption, newest first
+    pub retired_private_keys: Vec<String>,
+    /// Keys tried per registration, current key included
+    pub max_rotation_keys: usize,
+    /// Global cap on token decrypts per second; unlimited when unset
+    pub max_decrypts_per_sec: Option<u32>,
+    /// Only accept registrations whose envelope is bound to their trade pubkey (v2)
+    pub require_token_binding: bool,
+    /// Refuse register, unregister and re-encrypt requests not signed by their trade key
+    pub require_ownership_signature: bool,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct StoreConfig {
-    pub token_ttl_hours: u64,
-    pub cleanup_interval_hours: u64,
+    /// Registrations expire this long after they were last registered
+    pub token_ttl_secs: u64,
+    /// Minutes between sweeps of expired registrations, at low load
+    pub cleanup_interval_mins: u64,
+    /// Whether registration awaits the store write or responds once it is queued
+    pub write_mode: WriteMode,
+    /// Registrations that switch a pubkey to another platform
+    pub platform_conflict: PlatformConflictMode,
+    /// Registrations kept in the read-through cache; 0 disables it
+    pub cache_size: usize,
+    pub cache_ttl_secs: u64,
+    /// Distinct pubkeys one device token may be registered under; unlimited when 0
+    pub max_pubkeys_per_token: usize,
+    /// Devices one pubkey may register in each environment
+    pub max_devices_per_pubkey: usize,
+    /// Which existing devices a newly registered one replaces
+    pub device_policy: DevicePolicy,
+    /// Hash-chained log of registration changes; not kept when unset
+    pub wal_path: Option<String>,
+    pub backend: StoreBackend,
+    /// Redis for `StoreBackend::Redis`, apart from the rate limiter's
+    pub redis_url: Option<String>,
+    /// SQLite file registrations are persisted to; kept in memory only when unset
+    pub database_path: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReplicationConfig {
+    /// Start as a standby: serve reads, redirect writes, don't listen to relays
+    pub standby: bool,
+    /// Where a standby redirects registrations
+    pub primary_url: Option<String>,
+    /// Lead while this file exists, overriding the starting role
+    pub leader_file: Option<String>,
+    /// Base URLs of standbys to stream store changes to
+    pub standbys: Vec<String>,
+    /// Shared secret for `/replication`
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DigestConfig {
+    /// Seconds between operator digests; digests are off when unset
+    pub interval_secs: Option<u64>,
+    /// Rendered digests are appended to this file
+    pub path: Option<String>,
+    pub webhook_url: Option<String>,
+    /// npub (or hex pubkey) DMed each digest from the listener's identity
+    pub operator_npub: Option<String>,
+}
+
+/// Retention of persisted auxiliary state; each cap is unbounded when unset.
+#[derive(Debug, Clone, Deserialize)]
+pub struct GcConfig {
+    /// Seconds between GC passes; only `/admin/gc` runs one when 0
+    pub interval_secs: u64,
+    pub trace: RetentionPolicy,
+    pub audit: RetentionPolicy,
+    /// The ledger's age cap is `ledger_max_age_secs`
+    pub ledger_max_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MetricsConfig {
+    /// File holding lifetime counters across restarts; persistence is off when unset
+    pub checkpoint_path: Option<String>,
+    pub checkpoint_interval_secs: u64,
+    /// Histogram bucket bounds in seconds, per metric family
+    pub http_buckets: Vec<f64>,
+    pub dispatch_buckets: Vec<f64>,
+    /// Record W3C trace ids from incoming requests as histogram exemplars
+    pub exemplars: bool,
+    /// Seconds from relay receipt within which a push counts as on time
+    pub sli_threshold_secs: u64,
+    /// Delivery SLI window lengths in seconds
+    pub sli_windows: Vec<u64>,
+}
+
+/// Default for `MAX_RELAYS`.
+pub const DEFAULT_MAX_RELAYS: usize = 32;
+
+/// Parse a comma-separated relay list, ignoring blank entries. Fails unless
+/// it names between one and `max` relays.
+pub fn parse_relays(value: &str, max: usize) -> Result<Vec<String>, String> {
+    let relays: Vec<String> = value
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+    if relays.is_empty() {
+        return Err("NOSTR_RELAYS must name at least one relay".to_string());
+    }
+    if relays.len() > max {
+        return Err(format!(
+            "NOSTR_RELAYS names {} relays, more than MAX_RELAYS ({})",
+            relays.len(),
+            max
+        ));
+    }
+    Ok(relays)
+}
+
+/// Fail on relays not reached over `wss://`, unless `allow_insecure` lets
+/// `ws://` ones through with a warning, since they carry subscriptions in cleartext.
+pub fn check_relay_schemes(relays: &[String], allow_insecure: bool) -> Result<(), String> {
+    for relay in relays {
+        let scheme = relay.split_once("://").map(|(scheme, _)| scheme.to_ascii_lowercase());
+        match scheme.as_deref() {
+            Some("wss") => {}
+            Some("ws") if allow_insecure => {
+                log::warn!("Relay {} is not encrypted; its traffic can be read in transit", relay);
+            }
+            Some("ws") => {
+                return Err(format!(
+                    "Relay {} uses unencrypted ws://; use wss:// or set ALLOW_INSECURE_RELAYS=true",
+                    relay
+                ));
+            }
+            _ => return Err(format!("Relay {} is not a wss:// URL", relay)),
+        }
+    }
+    Ok(())
+}
+
+/// What happens once the listener has failed `listener_failure_threshold`
+/// connect cycles in a row. Either way it keeps retrying until it exits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerFailureAction {
+    /// Report unhealthy on `/health` until a connect cycle succeeds
+    Unhealthy,
+    /// Also exit with a non-zero code, for an orchestrator to restart us
+    Exit,
+}
+
+impl FromStr for ListenerFailureAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "unhealthy" => Ok(ListenerFailureAction::Unhealthy),
+            "exit" => Ok(ListenerFailureAction::Exit),
+            other => Err(format!("Unknown listener failure action '{}', expected unhealthy or exit", other)),
+        }
+    }
+}
+
+/// What a relay is used for: reading subscribes to events on it, writing
+/// publishes to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayUsage {
+    Read,
+    Write,
+    Both,
+}
+
+impl RelayUsage {
+    pub fn reads(self) -> bool {
+        matches!(self, RelayUsage::Read | RelayUsage::Both)
+    }
+
+    pub fn writes(self) -> bool {
+        matches!(self, RelayUsage::Write | RelayUsage::Both)
+    }
+}
+
+impl FromStr for RelayUsage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "read" => Ok(RelayUsage::Read),
+            "write" => Ok(RelayUsage::Write),
+            "both" => Ok(RelayUsage::Both),
+            other => Err(format!("Unknown relay usage '{}', expected read, write or both", other)),
+        }
+    }
+}
+
+/// Where one app build's pushes go. Apps without their own Firebase project
+/// send through the default one.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PushApp {
+    /// `apns-topic` of the app's iOS pushes, normally its bundle id
+    pub apns_topic: Option<String>,
+    pub fcm_project_id: Option<String>,
+    pub firebase_service_account_path: Option<String>,
+    /// How the app's visible Android notifications render; unset fields
+    /// fall back to the `ANDROID_NOTIFICATION_*` defaults
+    #[serde(default)]
+    pub android: AndroidNotificationStyle,
+}
+
+/// Resources of an Android app build that FCM applies to the notifications
+/// it displays. Omitted from the message when unset.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AndroidNotificationStyle {
+    /// Drawable resource name of the small icon
+    pub icon: Option<String>,
+    /// Icon color as `#rrggbb`
+    pub color: Option<String>,
+    /// Intent action started when the notification is tapped
+    pub click_action: Option<String>,
+}
+
+impl AndroidNotificationStyle {
+    /// This style, with unset fields taken from `fallback`.
+    pub fn or(&self, fallback: &Self) -> Self {
+        Self {
+            icon: self.icon.clone().or_else(|| fallback.icon.clone()),
+            color: self.color.clone().or_else(|| fallback.color.clone()),
+            click_action: self.click_action.clone().or_else(|| fallback.click_action.clone()),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        match &self.color {
+            Some(color) if !(color.len() == 7 && color.starts_with('#') && color[1..].chars().all(|c| c.is_ascii_hexdigit())) => {
+                Err(format!("Android notification color {:?} is not #rrggbb", color))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Parse the `PUSH_APPS_PATH` JSON object of app id to `PushApp`.
+pub fn parse_push_apps(json: &str) -> Result<BTreeMap<String, PushApp>, String> {
+    let apps: BTreeMap<String, PushApp> =
+        serde_json::from_str(json).map_err(|e| format!("Invalid PUSH_APPS_PATH: {}", e))?;
+    for (app_id, app) in &apps {
+        if app_id.is_empty() {
+            return Err("PUSH_APPS_PATH has an empty app id".to_string());
+        }
+        if app.fcm_project_id.is_some() != app.firebase_service_account_path.is_some() {
+            return Err(format!(
+                "PUSH_APPS_PATH app {} needs both fcm_project_id and firebase_service_account_path, or neither",
+                app_id
+            ));
+        }
+        app.android.validate().map_err(|e| format!("PUSH_APPS_PATH app {}: {}", app_id, e))?;
+    }
+    Ok(apps)
+}
+
+/// Where a push's grouping id comes from. Devices group notifications
+/// sharing an id (iOS `thread-id`, Android `tag`).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub enum GroupingSource {
+    /// The recipient's trade pubkey
+    Pubkey,
+    /// The value of this event tag, e.g. an order id
+    Tag(String),
+}
+
+impl FromStr for GroupingSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().split_once(':') {
+            None if s.trim().eq_ignore_ascii_case("pubkey") => Ok(GroupingSource::Pubkey),
+            Some((kind, tag)) if kind.eq_ignore_ascii_case("tag") && !tag.trim().is_empty() => {
+                Ok(GroupingSource::Tag(tag.trim().to_string()))
+            }
+            _ => Err(format!("Unknown grouping source '{}', expected pubkey or tag:<name>", s)),
+        }
+    }
+}
+
+/// Parse `url=usage` pairs for relays in `relays`. Fails on unknown relays
+/// or if no relay is left to read from.
+pub fn parse_relay_usage(value: &str, relays: &[String]) -> Result<HashMap<String, RelayUsage>, String> {
+    let mut usage = HashMap::new();
+    for entry in value.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (url, kind) = entry
+            .rsplit_once('=')
+            .ok_or_else(|| format!("NOSTR_RELAY_USAGE entry '{}' is not url=usage", entry))?;
+        let url = relay_key(url.trim());
+        if !relays.iter().any(|relay| relay_key(relay) == url) {
+            return Err(format!("NOSTR_RELAY_USAGE names {}, which is not in NOSTR_RELAYS", url));
+        }
+        usage.insert(url, kind.parse()?);
+    }
+    let reads = |relay: &String| usage.get(&relay_key(relay)).is_none_or(|u: &RelayUsage| u.reads());
+    if !relays.iter().any(reads) {
+        return Err("NOSTR_RELAY_USAGE leaves no relay to read events from".to_string());
+    }
+    Ok(usage)
+}
+
+/// Check `STORE_REDIS_URL` against the store backend: required and a Redis
+/// URL for `redis`, ignored otherwise.
+pub fn parse_store_redis_url(backend: StoreBackend, url: Option<String>) -> Result<Option<String>, String> {
+    match (backend, url) {
+        (StoreBackend::Redis, None) => Err("STORE_BACKEND=redis needs STORE_REDIS_URL".to_string()),
+        (StoreBackend::Redis, Some(url))
+            if !["redis://", "rediss://", "redis+unix://", "unix://"].iter().any(|scheme| url.starts_with(scheme)) =>
+        {
+            Err(format!("STORE_REDIS_URL {} is not a redis:// URL", url))
+        }
+        (_, url) => Ok(url),
+    }
+}
+
+/// Parse `flag=fraction` pairs, fractions between 0 and 1. Fails on flags
+/// the server doesn't have.
+pub fn parse_feature_flags(value: &str) -> Result<BTreeMap<String, f64>, String> {
+    let mut flags = BTreeMap::new();
+    for entry in value.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (flag, fraction) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("FEATURE_FLAGS entry {} is not flag=fraction", entry))?;
+        let flag = flag.trim();
+        if !crate::flags::FLAGS.contains(&flag) {
+            return Err(format!("FEATURE_FLAGS names unknown flag {}", flag));
+        }
+        let fraction: f64 = fraction
+            .trim()
+            .parse()
+            .ok()
+            .filter(|f| (0.0..=1.0).contains(f))
+            .ok_or_else(|| format!("FEATURE_FLAGS fraction for {} must be between 0 and 1", flag))?;
+        flags.insert(flag.to_string(), fraction);
+    }
+    Ok(flags)
+}
+
+fn relay_key(url: &str) -> String {
+    url.trim_end_matches('/').to_string()
+}
+
+impl NostrConfig {
+    pub fn relay_usage(&self, url: &str) -> RelayUsage {
+        self.relay_usage.get(&relay_key(url)).copied().unwrap_or(RelayUsage::Both)
+    }
 }
 
 impl Config {
     pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
-        let relays = env::var("NOSTR_RELAYS")?
-            .split(',')
-            .map(|s| s.trim().to_string())
-            .collect();
-
-        // Read Mostro instance public key from environment
-        let mostro_pubkey = env::var("MOSTRO_PUBKEY")
-            .unwrap_or_else(|_| {
-                // Default to the main Mostro instance pubkey
-                "82fa8cb978b43c79b2156585bac2c011176a21d2aead6d9f7c575c005be88390".to_string()
-            });
+        let max_relays: usize = env::var("MAX_RELAYS")
+            .unwrap_or_else(|_| DEFAULT_MAX_RELAYS.to_string())
+            .parse()?;
+        let relays = parse_relays(&env::var("NOSTR_RELAYS")?, max_relays)?;
+        let allow_insecure_relays: bool = env::var("ALLOW_INSECURE_RELAYS")
+            .unwrap_or_else(|_| "false".to_string())
+            .parse()?;
+        check_relay_schemes(&relays, allow_insecure_relays)?;
+        let relay_usage = parse_relay_usage(&env::var("NOSTR_RELAY_USAGE").unwrap_or_default(), &relays)?;
+
+        let host = env::var("SERVER_HOST")
+            .unwrap_or_else(|_| "0.0.0.0".to_string());
+        let port: u16 = env::var("SERVER_PORT")
+            .unwrap_or_else(|_| "8080".to_string())
+            .parse()?;
+
+        let store_backend: StoreBackend = env::var("STORE_BACKEND")
+            .unwrap_or_else(|_| "memory".to_string())
+            .parse()?;
+        let store_redis_url =
+            parse_store_redis_url(store_backend, env::var("STORE_REDIS_URL").ok().filter(|s| !s.is_empty()))?;
 
         Ok(Config {
             nostr: NostrConfig {
                 relays,
+                max_relays,
+                allow_insecure_relays,
+                relay_usage,
                 subscription_id: "mostro-push-listener".to_string(),
                 event_kinds: vec![1059],
                 mostro_pubkey: env::var("MOSTRO_PUBKEY")
                     .unwrap_or_else(|_| "dbe0b1be7aafd3cfba92d7463571bf438f09d24f4e021d9fe208ed0ab5823711".to_string()),
+                no_push_tag: env::var("NO_PUSH_TAG").ok().filter(|s| !s.is_empty()),
+                no_push_tag_value: env::var("NO_PUSH_TAG_VALUE").ok().filter(|s| !s.is_empty()),
+                min_relays_connected: env::var("MIN_RELAYS_CONNECTED")
+                    .unwrap_or_else(|_| "0".to_string())
+                    .parse()?,
+                drain_timeout_secs: env::var("RELAY_DRAIN_TIMEOUT_SECS")
+                    .unwrap_or_else(|_| "10".to_string())
+                    .parse()?,
+                catchup_chunk_secs: env::var("CATCHUP_CHUNK_SECS")
+                    .unwrap_or_else(|_| "600".to_string())
+                    .parse()?,
+                catchup_max_age_secs: env::var("CATCHUP_MAX_AGE_SECS")
+                    .unwrap_or_else(|_| "3600".to_string())
+                    .parse()?,
+                relay_monitor_pubkeys: env::var("RELAY_MONITOR_PUBKEYS")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect(),
+                relay_monitor_auto_add: env::var("RELAY_MONITOR_AUTO_ADD")
+                    .unwrap_or_else(|_| "false".to_string())
+                    .parse()?,
+                overload_events_per_sec: env::var("OVERLOAD_EVENTS_PER_SEC")
+                    .unwrap_or_else(|_| "0".to_string())
+                    .parse()?,
+                event_handler_concurrency: env::var("EVENT_HANDLER_CONCURRENCY")
+                    .unwrap_or_else(|_| "1".to_string())
+                    .parse()?,
+                listener_failure_threshold: env::var("LISTENER_FAILURE_THRESHOLD")
+                    .unwrap_or_else(|_| "0".to_string())
+                    .parse()?,
+                listener_failure_action: env::var("LISTENER_FAILURE_ACTION")
+                    .unwrap_or_else(|_| "unhealthy".to_string())
+                    .parse()?,
+                event_trace_path: env::var("EVENT_TRACE_PATH").ok().filter(|s| !s.is_empty()),
+                idempotency_tag: env::var("IDEMPOTENCY_TAG").ok().filter(|s| !s.is_empty()),
+                category_tag: env::var("CATEGORY_TAG").unwrap_or_else(|_| "category".to_string()),
+                category_rules_path: env::var("CATEGORY_RULES_PATH").ok().filter(|s| !s.is_empty()),
+                pin_path: match env::var("MOSTRO_PIN_PATH") {
+                    Ok(path) => Some(path).filter(|s| !s.is_empty()),
+                    Err(_) => Some("data/mostro_pin.json".to_string()),
+                },
+                accept_mostro_key_change: env::var("ACCEPT_MOSTRO_KEY_CHANGE")
+                    .unwrap_or_else(|_| "false".to_string())
+                    .parse()?,
+                identity_key: env::var("NOSTR_IDENTITY_KEY").ok().filter(|s| !s.is_empty()),
+                wal_anchor_interval_secs: match env::var("WAL_ANCHOR_INTERVAL_SECS") {
+                    Ok(s) if !s.is_empty() => Some(s.parse()?),
+                    _ => None,
+                },
             },
             push: PushConfig {
                 fcm_enabled: env::var("FCM_ENABLED")
@@ -84,31 +648,574 @@ impl Config {
                 cooldown_ms: env::var("COOLDOWN_MS")
                     .unwrap_or_else(|_| "60000".to_string())
                     .parse()?,
+                backfill_window_secs: env::var("BACKFILL_WINDOW_SECS")
+                    .unwrap_or_else(|_| "0".to_string())
+                    .parse()?,
+                backfill_coalesce: env::var("BACKFILL_COALESCE")
+                    .unwrap_or_else(|_| "true".to_string())
+                    .parse()?,
+                ledger_max_age_secs: env::var("DELIVERY_LEDGER_MAX_AGE_SECS")
+                    .unwrap_or_else(|_| "86400".to_string())
+                    .parse()?,
+                ledger_path: env::var("DELIVERY_LEDGER_PATH").ok().filter(|s| !s.is_empty()),
+                outbox_path: env::var("OUTBOX_PATH").ok().filter(|s| !s.is_empty()),
+                outbox_max_entries: env::var("OUTBOX_MAX_ENTRIES")
+                    .unwrap_or_else(|_| "10000".to_string())
+                    .parse()?,
+                outbox_ttl_secs: env::var("OUTBOX_TTL_SECS")
+                    .unwrap_or_else(|_| "3600".to_string())
+                    .parse()?,
+                fcm_quota_per_minute: env::var("FCM_QUOTA_PER_MINUTE")
+                    .unwrap_or_else(|_| "0".to_string())
+                    .parse()?,
+                unifiedpush_quota_per_minute: env::var("UNIFIEDPUSH_QUOTA_PER_MINUTE")
+                    .unwrap_or_else(|_| "0".to_string())
+                    .parse()?,
+                reaper_interval_secs: env::var("TOKEN_REAPER_INTERVAL_SECS")
+                    .ok()
+                    .filter(|s| !s.is_empty())
+                    .map(|s| s.parse())
+                    .transpose()?,
+                reaper_batch_size: env::var("TOKEN_REAPER_BATCH_SIZE")
+                    .unwrap_or_else(|_| "100".to_string())
+                    .parse()?,
+                reaper_checks_per_sec: env::var("TOKEN_REAPER_CHECKS_PER_SEC")
+                    .unwrap_or_else(|_| "5".to_string())
+                    .parse()?,
+                log_token_hashes: env::var("LOG_TOKEN_HASHES")
+                    .unwrap_or_else(|_| "false".to_string())
+                    .parse()?,
+                dispatch_concurrency: env::var("DISPATCH_CONCURRENCY")
+                    .unwrap_or_else(|_| "32".to_string())
+                    .parse()?,
+                android_concurrency: env::var("ANDROID_CONCURRENCY")
+                    .ok()
+                    .filter(|s| !s.is_empty())
+                    .map(|s| s.parse())
+                    .transpose()?,
+                ios_concurrency: env::var("IOS_CONCURRENCY")
+                    .ok()
+                    .filter(|s| !s.is_empty())
+                    .map(|s| s.parse())
+                    .transpose()?,
+                android_payload_budget: env::var("ANDROID_PAYLOAD_BUDGET")
+                    .unwrap_or_else(|_| "4096".to_string())
+                    .parse()?,
+                ios_payload_budget: env::var("IOS_PAYLOAD_BUDGET")
+                    .unwrap_or_else(|_| "4096".to_string())
+                    .parse()?,
+                advertised_platforms: match env::var("ADVERTISED_PLATFORMS") {
+                    Ok(value) if !value.trim().is_empty() => Some(
+                        value
+                            .split(',')
+                            .filter(|s| !s.trim().is_empty())
+                            .map(str::parse)
+                            .collect::<Result<_, _>>()?,
+                    ),
+                    _ => None,
+                },
+                firebase_service_account_path: env::var("FIREBASE_SERVICE_ACCOUNT_PATH")
+                    .ok()
+                    .filter(|s| !s.is_empty()),
+                sandbox_fcm_project_id: env::var("FIREBASE_SANDBOX_PROJECT_ID").ok().filter(|s| !s.is_empty()),
+                sandbox_service_account_path: env::var("FIREBASE_SANDBOX_SERVICE_ACCOUNT_PATH")
+                    .ok()
+                    .filter(|s| !s.is_empty()),
+                signing_key: env::var("OUTBOUND_SIGNING_KEY").ok().filter(|s| !s.is_empty()),
+                reject_undeliverable: env::var("REJECT_UNDELIVERABLE_PLATFORMS")
+                    .unwrap_or_else(|_| "false".to_string())
+                    .parse()?,
+                feature_flags: parse_feature_flags(&env::var("FEATURE_FLAGS").unwrap_or_default())?,
+                apps: match env::var("PUSH_APPS_PATH").ok().filter(|s| !s.is_empty()) {
+                    Some(path) => parse_push_apps(
+                        &std::fs::read_to_string(&path).map_err(|e| format!("Failed to read PUSH_APPS_PATH {}: {}", path, e))?,
+                    )?,
+                    None => BTreeMap::new(),
+                },
+                grouping: env::var("NOTIFICATION_GROUPING")
+                    .ok()
+                    .filter(|s| !s.is_empty())
+                    .map(|s| s.parse::<GroupingSource>())
+                    .transpose()?,
+                android_notification: {
+                    let style = AndroidNotificationStyle {
+                        icon: env::var("ANDROID_NOTIFICATION_ICON").ok().filter(|s| !s.is_empty()),
+                        color: env::var("ANDROID_NOTIFICATION_COLOR").ok().filter(|s| !s.is_empty()),
+                        click_action: env::var("ANDROID_NOTIFICATION_CLICK_ACTION").ok().filter(|s| !s.is_empty()),
+                    };
+                    style.validate().map_err(|e| format!("ANDROID_NOTIFICATION_COLOR: {}", e))?;
+                    style
+                },
             },
             server: ServerConfig {
-                host: env::var("SERVER_HOST")
-                    .unwrap_or_else(|_| "0.0.0.0".to_string()),
-                port: env::var("SERVER_PORT")
-                    .unwrap_or_else(|_| "8080".to_string())
+                bind: match env::var("SERVER_BIND").ok().filter(|s| !s.is_empty()) {
+                    Some(bind) => bind.parse()?,
+                    None => BindAddress::Tcp(format!("{}:{}", host, port)),
+                },
+                host,
+                port,
+                first_registration_alert: env::var("FIRST_REGISTRATION_ALERT")
+                    .unwrap_or_else(|_| "false".to_string())
+                    .parse()?,
+                first_registration_webhook_url: env::var("FIRST_REGISTRATION_WEBHOOK_URL")
+                    .ok()
+                    .filter(|s| !s.is_empty()),
+                admin_token: env::var("ADMIN_TOKEN").ok().filter(|s| !s.is_empty()),
+                status_secret: env::var("STATUS_SECRET").ok().filter(|s| !s.is_empty()),
+                audit_log_path: env::var("AUDIT_LOG_PATH").ok().filter(|s| !s.is_empty()),
+                audit_webhook_url: env::var("AUDIT_WEBHOOK_URL").ok().filter(|s| !s.is_empty()),
+                status_cache_ttl_ms: env::var("STATUS_CACHE_TTL_MS")
+                    .unwrap_or_else(|_| "2000".to_string())
+                    .parse()?,
+                info_cache_ttl_secs: env::var("INFO_CACHE_TTL_SECS")
+                    .unwrap_or_else(|_| "300".to_string())
+                    .parse()?,
+                delivery_stats_window_secs: env::var("DELIVERY_STATS_WINDOW_SECS")
+                    .unwrap_or_else(|_| "3600".to_string())
+                    .parse()?,
+                strict_security: env::var("STRICT_SECURITY")
+                    .unwrap_or_else(|_| "false".to_string())
+                    .parse()?,
+                watch_max_keys: env::var("WATCH_MAX_KEYS")
+                    .unwrap_or_else(|_| "16".to_string())
+                    .parse()?,
+                warmup_deadline_secs: env::var("WARMUP_DEADLINE_SECS")
+                    .unwrap_or_else(|_| "30".to_string())
+                    .parse()?,
+                max_concurrent_registrations: env::var("MAX_CONCURRENT_REGISTRATIONS")
+                    .unwrap_or_else(|_| "0".to_string())
+                    .parse()?,
+                registration_wait_ms: env::var("REGISTRATION_WAIT_MS")
+                    .unwrap_or_else(|_| "250".to_string())
                     .parse()?,
             },
             rate_limit: RateLimitConfig {
                 max_per_minute: env::var("RATE_LIMIT_PER_MINUTE")
                     .unwrap_or_else(|_| "60".to_string())
                     .parse()?,
+                backend: env::var("RATE_LIMIT_BACKEND")
+                    .unwrap_or_else(|_| "memory".to_string())
+                    .parse()?,
+                redis_url: env::var("REDIS_URL").ok().filter(|s| !s.is_empty()),
             },
             crypto: CryptoConfig {
-                server_private_key: env::var("SERVER_PRIVATE_KEY")
-                    .map_err(|_| "SERVER_PRIVATE_KEY environment variable is required")?,
+                server_private_key: match (
+                    env::var("SERVER_PRIVATE_KEY").ok().filter(|s| !s.is_empty()),
+                    env::var("SERVER_PRIVATE_KEY_FILE").ok().filter(|s| !s.is_empty()),
+                ) {
+                    (Some(key), None) => ServerKeySource::Hex(key),
+                    (None, Some(path)) => ServerKeySource::File(path),
+                    (Some(_), Some(_)) => return Err("Set SERVER_PRIVATE_KEY or SERVER_PRIVATE_KEY_FILE, not both".into()),
+                    (None, None) => return Err("SERVER_PRIVATE_KEY or SERVER_PRIVATE_KEY_FILE is required".into()),
+                },
+                retired_private_keys: env::var("SERVER_RETIRED_PRIVATE_KEYS")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect(),
+                max_rotation_keys: env::var("MAX_ROTATION_KEYS_ATTEMPTED")
+                    .unwrap_or_else(|_| "3".to_string())
+                    .parse()?,
+                max_decrypts_per_sec: match env::var("MAX_DECRYPTS_PER_SEC") {
+                    Ok(value) if !value.is_empty() => Some(value.parse()?),
+                    _ => None,
+                },
+                require_token_binding: env::var("REQUIRE_TOKEN_BINDING")
+                    .unwrap_or_else(|_| "false".to_string())
+                    .parse()?,
+                require_ownership_signature: env::var("REQUIRE_OWNERSHIP_SIGNATURE")
+                    .unwrap_or_else(|_| "false".to_string())
+                    .parse()?,
             },
             store: StoreConfig {
-                token_ttl_hours: env::var("TOKEN_TTL_HOURS")
-                    .unwrap_or_else(|_| "48".to_string())
+                token_ttl_secs: match env::var("TOKEN_TTL_SECONDS") {
+                    Ok(value) if !value.is_empty() => value.parse()?,
+                    _ => match env::var("TOKEN_TTL_HOURS") {
+                        Ok(hours) if !hours.is_empty() => hours.parse::<u64>()? * 3600,
+                        _ => 30 * 24 * 3600,
+                    },
+                },
+                cleanup_interval_mins: match env::var("CLEANUP_INTERVAL_MINUTES") {
+                    Ok(value) if !value.is_empty() => value.parse()?,
+                    _ => match env::var("CLEANUP_INTERVAL_HOURS") {
+                        Ok(hours) if !hours.is_empty() => hours.parse::<u64>()? * 60,
+                        _ => 60,
+                    },
+                },
+                write_mode: env::var("REGISTER_WRITE_MODE")
+                    .unwrap_or_else(|_| "durable".to_string())
                     .parse()?,
-                cleanup_interval_hours: env::var("CLEANUP_INTERVAL_HOURS")
-                    .unwrap_or_else(|_| "1".to_string())
+                platform_conflict: env::var("PLATFORM_CONFLICT_POLICY")
+                    .unwrap_or_else(|_| "warn".to_string())
+                    .parse()?,
+                cache_size: env::var("STORE_CACHE_SIZE")
+                    .unwrap_or_else(|_| "0".to_string())
+                    .parse()?,
+                cache_ttl_secs: env::var("STORE_CACHE_TTL_SECS")
+                    .unwrap_or_else(|_| "30".to_string())
+                    .parse()?,
+                max_pubkeys_per_token: env::var("MAX_PUBKEYS_PER_TOKEN")
+                    .unwrap_or_else(|_| "200".to_string())
+                    .parse()?,
+                max_devices_per_pubkey: env::var("MAX_DEVICES_PER_PUBKEY")
+                    .unwrap_or_else(|_| "5".to_string())
+                    .parse::<usize>()?
+                    .max(1),
+                device_policy: env::var("DEVICE_POLICY")
+                    .unwrap_or_else(|_| "multi_device".to_string())
+                    .parse()?,
+                wal_path: env::var("STORE_WAL_PATH").ok().filter(|s| !s.is_empty()),
+                backend: store_backend,
+                redis_url: store_redis_url,
+                database_path: env::var("DATABASE_PATH").ok().filter(|s| !s.is_empty()),
+            },
+            metrics: MetricsConfig {
+                checkpoint_path: env::var("METRICS_CHECKPOINT_PATH").ok().filter(|s| !s.is_empty()),
+                checkpoint_interval_secs: env::var("METRICS_CHECKPOINT_INTERVAL_SECS")
+                    .unwrap_or_else(|_| "60".to_string())
+                    .parse()?,
+                http_buckets: match env::var("METRICS_HTTP_BUCKETS") {
+                    Ok(value) => metrics::parse_buckets(&value)?,
+                    Err(_) => metrics::DEFAULT_HTTP_BUCKETS.to_vec(),
+                },
+                dispatch_buckets: match env::var("METRICS_DISPATCH_BUCKETS") {
+                    Ok(value) => metrics::parse_buckets(&value)?,
+                    Err(_) => metrics::DEFAULT_DISPATCH_BUCKETS.to_vec(),
+                },
+                exemplars: env::var("METRICS_EXEMPLARS")
+                    .unwrap_or_else(|_| "false".to_string())
                     .parse()?,
+                sli_threshold_secs: env::var("SLI_THRESHOLD_SECS")
+                    .unwrap_or_else(|_| "30".to_string())
+                    .parse()?,
+                sli_windows: env::var("SLI_WINDOWS")
+                    .unwrap_or_else(|_| "1h,24h,7d".to_string())
+                    .split(',')
+                    .map(parse_interval)
+                    .collect::<Result<_, _>>()?,
+            },
+            replication: ReplicationConfig {
+                standby: match env::var("INSTANCE_ROLE").unwrap_or_else(|_| "primary".to_string()).as_str() {
+                    "primary" => false,
+                    "standby" => true,
+                    other => {
+                        return Err(format!("Invalid INSTANCE_ROLE '{}' (expected primary or standby)", other).into())
+                    }
+                },
+                primary_url: env::var("PRIMARY_URL").ok().filter(|s| !s.is_empty()),
+                leader_file: env::var("LEADER_FILE").ok().filter(|s| !s.is_empty()),
+                standbys: env::var("REPLICATION_STANDBYS")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect(),
+                token: env::var("REPLICATION_TOKEN").ok().filter(|s| !s.is_empty()),
+            },
+            digest: DigestConfig {
+                interval_secs: match env::var("DIGEST_INTERVAL") {
+                    Ok(value) if !value.is_empty() => Some(parse_interval(&value)?),
+                    _ => None,
+                },
+                path: env::var("DIGEST_PATH").ok().filter(|s| !s.is_empty()),
+                webhook_url: env::var("DIGEST_WEBHOOK_URL").ok().filter(|s| !s.is_empty()),
+                operator_npub: env::var("DIGEST_OPERATOR_NPUB").ok().filter(|s| !s.is_empty()),
+            },
+            gc: GcConfig {
+                interval_secs: env::var("GC_INTERVAL_SECS")
+                    .unwrap_or_else(|_| "3600".to_string())
+                    .parse()?,
+                trace: retention_from_env("EVENT_TRACE")?,
+                audit: retention_from_env("AUDIT_LOG")?,
+                ledger_max_bytes: env::var("DELIVERY_LEDGER_MAX_BYTES")
+                    .ok()
+                    .filter(|s| !s.is_empty())
+                    .map(|s| s.parse())
+                    .transpose()?,
             },
         })
     }
 }
+
+/// `<prefix>_RETENTION` (an interval) and `<prefix>_MAX_BYTES`.
+fn retention_from_env(prefix: &str) -> Result<RetentionPolicy, Box<dyn std::error::Error>> {
+    let max_age = match env::var(format!("{}_RETENTION", prefix)) {
+        Ok(value) if !value.is_empty() => Some(Duration::from_secs(parse_interval(&value)?)),
+        _ => None,
+    };
+    let max_bytes = env::var(format!("{}_MAX_BYTES", prefix))
+        .ok()
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse())
+        .transpose()?;
+    Ok(RetentionPolicy { max_age, max_bytes })
+}
+
+/// Parse an interval such as `7d`, `12h`, `30m`, `90s` or plain seconds.
+pub fn parse_interval(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(split) => value.split_at(split),
+        None => (value, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86_400,
+        "w" => 7 * 86_400,
+        _ => return Err(format!("Invalid interval '{}' (expected e.g. 7d, 12h, 30m or 90s)", value)),
+    };
+    match number.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(n * multiplier),
+        _ => Err(format!("Invalid interval '{}' (expected e.g. 7d, 12h, 30m or 90s)", value)),
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// Minimal configuration for unit tests, independent of the environment.
+    pub fn for_tests() -> Self {
+        Config {
+            nostr: NostrConfig {
+                relays: vec!["wss://relay.example.com".to_string()],
+                max_relays: DEFAULT_MAX_RELAYS,
+                allow_insecure_relays: false,
+                relay_usage: HashMap::new(),
+                subscription_id: "mostro-push-listener".to_string(),
+                event_kinds: vec![1059],
+                mostro_pubkey: "dbe0b1be7aafd3cfba92d7463571bf438f09d24f4e021d9fe208ed0ab5823711".to_string(),
+                no_push_tag: None,
+                no_push_tag_value: None,
+                min_relays_connected: 0,
+                drain_timeout_secs: 10,
+                catchup_chunk_secs: 600,
+                catchup_max_age_secs: 3600,
+                relay_monitor_pubkeys: Vec::new(),
+                relay_monitor_auto_add: false,
+                overload_events_per_sec: 0,
+                event_handler_concurrency: 1,
+                listener_failure_threshold: 0,
+                listener_failure_action: ListenerFailureAction::Unhealthy,
+                event_trace_path: None,
+                idempotency_tag: None,
+                category_tag: "category".to_string(),
+                category_rules_path: None,
+                pin_path: None,
+                accept_mostro_key_change: false,
+                identity_key: None,
+                wal_anchor_interval_secs: None,
+            },
+            push: PushConfig {
+                fcm_enabled: false,
+                unifiedpush_enabled: false,
+                batch_delay_ms: 5000,
+                cooldown_ms: 60000,
+                backfill_window_secs: 0,
+                backfill_coalesce: true,
+                ledger_max_age_secs: 0,
+                ledger_path: None,
+                outbox_path: None,
+                outbox_max_entries: 10_000,
+                outbox_ttl_secs: 3600,
+                fcm_quota_per_minute: 0,
+                unifiedpush_quota_per_minute: 0,
+                reaper_interval_secs: None,
+                reaper_batch_size: 100,
+                reaper_checks_per_sec: 5,
+                log_token_hashes: false,
+                dispatch_concurrency: 0,
+                android_concurrency: None,
+                ios_concurrency: None,
+                android_payload_budget: 4096,
+                ios_payload_budget: 4096,
+                advertised_platforms: None,
+                firebase_service_account_path: None,
+                sandbox_fcm_project_id: None,
+                sandbox_service_account_path: None,
+                signing_key: None,
+                reject_undeliverable: false,
+                feature_flags: BTreeMap::new(),
+                grouping: None,
+                android_notification: AndroidNotificationStyle::default(),
+                apps: BTreeMap::new(),
+            },
+            server: ServerConfig {
+                host: "127.0.0.1".to_string(),
+                port: 8080,
+                bind: BindAddress::Tcp("127.0.0.1:8080".to_string()),
+                first_registration_alert: false,
+                first_registration_webhook_url: None,
+                admin_token: None,
+                status_secret: None,
+                audit_log_path: None,
+                audit_webhook_url: None,
+                status_cache_ttl_ms: 2000,
+                info_cache_ttl_secs: 300,
+                delivery_stats_window_secs: 3600,
+                strict_security: false,
+                watch_max_keys: 16,
+                warmup_deadline_secs: 0,
+                max_concurrent_registrations: 0,
+                registration_wait_ms: 250,
+            },
+            rate_limit: RateLimitConfig {
+                max_per_minute: 60,
+                backend: RateLimitBackend::Memory,
+                redis_url: None,
+            },
+            crypto: CryptoConfig {
+                server_private_key: ServerKeySource::Hex(
+                    "ccc61d16dfd10fbcca1322fdf5fed6cb1863db4e27030ae164dbcbfcc263154d".to_string(),
+                ),
+                retired_private_keys: Vec::new(),
+                max_rotation_keys: 3,
+                max_decrypts_per_sec: None,
+                require_token_binding: false,
+                require_ownership_signature: false,
+            },
+            store: StoreConfig {
+                token_ttl_secs: 30 * 24 * 3600,
+                cleanup_interval_mins: 60,
+                write_mode: WriteMode::Durable,
+                platform_conflict: PlatformConflictMode::Warn,
+                cache_size: 0,
+                cache_ttl_secs: 30,
+                max_pubkeys_per_token: 200,
+                max_devices_per_pubkey: 5,
+                device_policy: DevicePolicy::MultiDevice,
+                wal_path: None,
+                backend: StoreBackend::Memory,
+                redis_url: None,
+                database_path: None,
+            },
+            metrics: MetricsConfig {
+                checkpoint_path: None,
+                checkpoint_interval_secs: 60,
+                http_buckets: metrics::DEFAULT_HTTP_BUCKETS.to_vec(),
+                dispatch_buckets: metrics::DEFAULT_DISPATCH_BUCKETS.to_vec(),
+                exemplars: false,
+                sli_threshold_secs: 30,
+                sli_windows: crate::sli::DEFAULT_WINDOWS.to_vec(),
+            },
+            replication: ReplicationConfig {
+                standby: false,
+                primary_url: None,
+                leader_file: None,
+                standbys: Vec::new(),
+                token: None,
+            },
+            digest: DigestConfig {
+                interval_secs: None,
+                path: None,
+                webhook_url: None,
+                operator_npub: None,
+            },
+            gc: GcConfig {
+                interval_secs: 0,
+                trace: RetentionPolicy::default(),
+                audit: RetentionPolicy::default(),
+                ledger_max_bytes: None,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_relays_bounds() {
+        assert_eq!(
+            parse_relays("wss://a.example, ,wss://b.example,", 2),
+            Ok(vec!["wss://a.example".to_string(), "wss://b.example".to_string()])
+        );
+
+        let err = parse_relays("wss://a.example,wss://b.example,wss://c.example", 2).unwrap_err();
+        assert!(err.contains("3 relays"), "{}", err);
+
+        assert!(parse_relays("", 2).is_err());
+        assert!(parse_relays(" , ", 2).is_err());
+    }
+
+    #[test]
+    fn test_insecure_relays_need_the_flag() {
+        let secure = vec!["wss://relay.example".to_string(), "WSS://other.example".to_string()];
+        assert_eq!(check_relay_schemes(&secure, false), Ok(()));
+
+        let insecure = vec!["wss://relay.example".to_string(), "ws://localhost:7000".to_string()];
+        let err = check_relay_schemes(&insecure, false).unwrap_err();
+        assert!(err.contains("ws://localhost:7000"), "{}", err);
+        assert_eq!(check_relay_schemes(&insecure, true), Ok(()));
+
+        // Anything but a WebSocket URL is refused even with the flag
+        assert!(check_relay_schemes(&["https://relay.example".to_string()], true).is_err());
+        assert!(check_relay_schemes(&["relay.example".to_string()], true).is_err());
+    }
+
+    #[test]
+    fn test_parse_push_apps() {
+        let apps = parse_push_apps(
+            r#"{
+                "com.mostro.app": { "apns_topic": "com.mostro.app" },
+                "com.example.whitelabel": {
+                    "apns_topic": "com.example.whitelabel",
+                    "fcm_project_id": "whitelabel",
+                    "firebase_service_account_path": "/secrets/whitelabel.json"
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(apps["com.mostro.app"].apns_topic.as_deref(), Some("com.mostro.app"));
+        assert_eq!(apps["com.mostro.app"].fcm_project_id, None);
+        assert_eq!(apps["com.example.whitelabel"].fcm_project_id.as_deref(), Some("whitelabel"));
+
+        // A project without credentials can't send
+        assert!(parse_push_apps(r#"{ "a": { "fcm_project_id": "p" } }"#).unwrap_err().contains("both"));
+        assert!(parse_push_apps(r#"{ "a": { "apns_tpoic": "a" } }"#).is_err());
+        assert!(parse_push_apps(r#"{ "": {} }"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_feature_flags() {
+        let flags = parse_feature_flags("collapse_key=0.1, silent_push=1").unwrap();
+        assert_eq!(flags.get("collapse_key"), Some(&0.1));
+        assert_eq!(flags.get("silent_push"), Some(&1.0));
+
+        assert!(parse_feature_flags("").unwrap().is_empty());
+        assert!(parse_feature_flags("typo=0.1").unwrap_err().contains("unknown flag"));
+        assert!(parse_feature_flags("collapse_key=1.5").is_err());
+        assert!(parse_feature_flags("collapse_key").is_err());
+    }
+
+    #[test]
+    fn test_parse_relay_usage() {
+        let relays = vec!["wss://a.example".to_string(), "wss://b.example/".to_string()];
+        let usage = parse_relay_usage("wss://b.example=write", &relays).unwrap();
+        assert_eq!(usage.get("wss://b.example"), Some(&RelayUsage::Write));
+
+        assert!(parse_relay_usage("wss://c.example=read", &relays).unwrap_err().contains("not in NOSTR_RELAYS"));
+        assert!(parse_relay_usage("wss://a.example=write,wss://b.example=write", &relays).is_err());
+        assert!(parse_relay_usage("wss://a.example=sometimes", &relays).is_err());
+    }
+
+    #[test]
+    fn test_parse_interval() {
+        assert_eq!(parse_interval("7d"), Ok(604_800));
+        assert_eq!(parse_interval("12h"), Ok(43_200));
+        assert_eq!(parse_interval("90"), Ok(90));
+        assert!(parse_interval("0d").is_err());
+        assert!(parse_interval("weekly").is_err());
+    }
+
+    #[test]
+    fn test_parse_store_redis_url() {
+        let url = || Some("redis://redis:6379/1".to_string());
+        assert_eq!(parse_store_redis_url(StoreBackend::Redis, url()), Ok(url()));
+        assert!(parse_store_redis_url(StoreBackend::Redis, None).is_err());
+        assert!(parse_store_redis_url(StoreBackend::Redis, Some("redis:6379".to_string())).is_err());
+        assert_eq!(parse_store_redis_url(StoreBackend::Memory, None), Ok(None));
+    }
+}
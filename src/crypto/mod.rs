@@ -1,35 +1,193 @@
 use chacha20poly1305::{
-    aead::{Aead, KeyInit},
-    ChaCha20Poly1305, Nonce,
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Nonce, XChaCha20Poly1305, XNonce,
 };
 use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
 use log::{debug, error};
-use secp256k1::{PublicKey, SecretKey, Secp256k1};
+use rand::RngCore;
+use secp256k1::{PublicKey, SecretKey, Secp256k1, XOnlyPublicKey};
+use serde::{Deserialize, Serialize};
 use sha2::Sha256;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+pub mod ownership;
 
 const HKDF_SALT: &[u8] = b"mostro-push-v1";
 const HKDF_INFO: &[u8] = b"mostro-token-encryption";
+const SUBKEY_SALT: &[u8] = b"mostro-push-subkey-v1";
+const TOKEN_LOG_HASH_PURPOSE: &[u8] = b"device-token-log-hash";
 
 const PLATFORM_ANDROID: u8 = 0x02;
 const PLATFORM_IOS: u8 = 0x01;
+const PLATFORM_WEB: u8 = 0x03;
+/// Set on the platform byte when a push key follows the device token
+const PUSH_KEY_FLAG: u8 = 0x80;
+const PUSH_DATA_AAD: &[u8] = b"mostro-push-data-v1";
+
+/// First byte of every payload, so its layout can change without breaking
+/// clients still sending the old one
+pub const PAYLOAD_VERSION: u8 = 0x01;
+/// Version, platform and token length precede the token
+const PAYLOAD_HEADER_SIZE: usize = 4;
 
 const PADDED_PAYLOAD_SIZE: usize = 220;
+/// Padding of the extended scheme, for long device tokens such as
+/// UnifiedPush endpoint URLs carrying a push key
+const EXTENDED_PADDED_PAYLOAD_SIZE: usize = 476;
 const EPHEMERAL_PUBKEY_SIZE: usize = 33;
 const NONCE_SIZE: usize = 12;
+const XNONCE_SIZE: usize = 24;
 const AUTH_TAG_SIZE: usize = 16;
-pub const ENCRYPTED_TOKEN_SIZE: usize = EPHEMERAL_PUBKEY_SIZE + NONCE_SIZE + PADDED_PAYLOAD_SIZE + AUTH_TAG_SIZE;
+/// Size of a standard envelope, the one clients send unless their token doesn't fit
+pub const ENCRYPTED_TOKEN_SIZE: usize = STANDARD_SCHEME.encrypted_size();
+
+/// The AEAD sealing an envelope's payload.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EnvelopeCipher {
+    #[default]
+    ChaCha20Poly1305,
+    /// 24-byte random nonces, for clients encrypting many tokens to one
+    /// server key without nearing the 12-byte nonce's birthday bound
+    XChaCha20Poly1305,
+}
+
+impl EnvelopeCipher {
+    /// Name clients match on, e.g. in `/api/discovery`.
+    pub const fn name(self) -> &'static str {
+        match self {
+            EnvelopeCipher::ChaCha20Poly1305 => "chacha20-poly1305",
+            EnvelopeCipher::XChaCha20Poly1305 => "xchacha20-poly1305",
+        }
+    }
+
+    pub const fn nonce_size(self) -> usize {
+        match self {
+            EnvelopeCipher::ChaCha20Poly1305 => NONCE_SIZE,
+            EnvelopeCipher::XChaCha20Poly1305 => XNONCE_SIZE,
+        }
+    }
+
+    fn encrypt(self, key: &[u8; 32], nonce: &[u8], payload: Payload) -> Result<Vec<u8>, CryptoError> {
+        let sealed = match self {
+            EnvelopeCipher::ChaCha20Poly1305 => {
+                let nonce = <[u8; NONCE_SIZE]>::try_from(nonce).map_err(|_| CryptoError::CipherError)?;
+                ChaCha20Poly1305::new(key.into()).encrypt(&Nonce::from(nonce), payload)
+            }
+            EnvelopeCipher::XChaCha20Poly1305 => {
+                let nonce = <[u8; XNONCE_SIZE]>::try_from(nonce).map_err(|_| CryptoError::CipherError)?;
+                XChaCha20Poly1305::new(key.into()).encrypt(&XNonce::from(nonce), payload)
+            }
+        };
+        sealed.map_err(|_| CryptoError::CipherError)
+    }
+
+    /// None when the key, nonce or associated data don't match.
+    fn decrypt(self, key: &[u8; 32], nonce: &[u8], payload: Payload) -> Option<Vec<u8>> {
+        match self {
+            EnvelopeCipher::ChaCha20Poly1305 => {
+                let nonce = <[u8; NONCE_SIZE]>::try_from(nonce).ok()?;
+                ChaCha20Poly1305::new(key.into()).decrypt(&Nonce::from(nonce), payload)
+            }
+            EnvelopeCipher::XChaCha20Poly1305 => {
+                let nonce = <[u8; XNONCE_SIZE]>::try_from(nonce).ok()?;
+                XChaCha20Poly1305::new(key.into()).decrypt(&XNonce::from(nonce), payload)
+            }
+        }
+        .ok()
+    }
+}
+
+/// An envelope layout, told apart from the others by its encrypted size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnvelopeScheme {
+    pub name: &'static str,
+    pub padded_payload_size: usize,
+    pub cipher: EnvelopeCipher,
+}
+
+impl EnvelopeScheme {
+    pub const fn encrypted_size(&self) -> usize {
+        EPHEMERAL_PUBKEY_SIZE + self.cipher.nonce_size() + self.padded_payload_size + AUTH_TAG_SIZE
+    }
+
+    /// Longest device token the payload fits, with or without a push key.
+    pub fn max_token_length(&self, has_push_key: bool) -> usize {
+        self.padded_payload_size - PAYLOAD_HEADER_SIZE - if has_push_key { PUSH_KEY_SIZE } else { 0 }
+    }
+}
+
+const STANDARD_SCHEME: EnvelopeScheme = EnvelopeScheme {
+    name: "standard",
+    padded_payload_size: PADDED_PAYLOAD_SIZE,
+    cipher: EnvelopeCipher::ChaCha20Poly1305,
+};
+const EXTENDED_SCHEME: EnvelopeScheme = EnvelopeScheme {
+    name: "extended",
+    padded_payload_size: EXTENDED_PADDED_PAYLOAD_SIZE,
+    cipher: EnvelopeCipher::ChaCha20Poly1305,
+};
+const XCHACHA_SCHEME: EnvelopeScheme = EnvelopeScheme {
+    name: "xchacha",
+    padded_payload_size: PADDED_PAYLOAD_SIZE,
+    cipher: EnvelopeCipher::XChaCha20Poly1305,
+};
+const XCHACHA_EXTENDED_SCHEME: EnvelopeScheme = EnvelopeScheme {
+    name: "xchacha-extended",
+    padded_payload_size: EXTENDED_PADDED_PAYLOAD_SIZE,
+    cipher: EnvelopeCipher::XChaCha20Poly1305,
+};
+
+/// Every envelope layout the server decrypts, smallest first within each
+/// cipher. Every size is distinct, so the size alone selects the scheme.
+pub const SCHEMES: &[EnvelopeScheme] = &[STANDARD_SCHEME, EXTENDED_SCHEME, XCHACHA_SCHEME, XCHACHA_EXTENDED_SCHEME];
+
+/// The scheme an encrypted token of `size` bytes uses, or `InvalidTokenSize`
+/// when no scheme has that size. Cheap, so callers can check before decrypting.
+pub fn scheme_for_size(size: usize) -> Result<&'static EnvelopeScheme, CryptoError> {
+    SCHEMES
+        .iter()
+        .find(|scheme| scheme.encrypted_size() == size)
+        .ok_or(CryptoError::InvalidTokenSize)
+}
+/// How an envelope payload is filled after the device token.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Padding {
+    /// Random bytes, so payloads reveal nothing past the token. Clients must use this
+    #[default]
+    Random,
+    /// Zero bytes, so tests can assert the exact payload layout
+    Zeroed,
+}
+
+/// Per-registration key the client may append to the envelope payload;
+/// push data for that registration is then sealed to it.
+pub const PUSH_KEY_SIZE: usize = 32;
 
-#[derive(Debug, Clone, PartialEq)]
+/// Original envelope: the ciphertext is not bound to a trade pubkey.
+pub const ENVELOPE_V1: u8 = 1;
+/// Same layout, with the raw 32-byte trade pubkey as AEAD associated data.
+pub const ENVELOPE_V2: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Platform {
     Android,
     Ios,
+    /// Browsers, via the Web Push protocol; the device token is the
+    /// subscription object as JSON
+    Web,
 }
 
 impl Platform {
+    pub const ALL: [Platform; 3] = [Platform::Android, Platform::Ios, Platform::Web];
+
     pub fn from_byte(byte: u8) -> Option<Self> {
         match byte {
             PLATFORM_ANDROID => Some(Platform::Android),
             PLATFORM_IOS => Some(Platform::Ios),
+            PLATFORM_WEB => Some(Platform::Web),
             _ => None,
         }
     }
@@ -38,6 +196,20 @@ impl Platform {
         match self {
             Platform::Android => PLATFORM_ANDROID,
             Platform::Ios => PLATFORM_IOS,
+            Platform::Web => PLATFORM_WEB,
+        }
+    }
+}
+
+impl std::str::FromStr for Platform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "android" => Ok(Platform::Android),
+            "ios" => Ok(Platform::Ios),
+            "web" => Ok(Platform::Web),
+            other => Err(format!("Invalid platform '{}' (expected android, ios or web)", other)),
         }
     }
 }
@@ -47,6 +219,33 @@ impl std::fmt::Display for Platform {
         match self {
             Platform::Android => write!(f, "android"),
             Platform::Ios => write!(f, "ios"),
+            Platform::Web => write!(f, "web"),
+        }
+    }
+}
+
+/// How device tokens appear in logs.
+#[derive(Clone)]
+pub enum TokenRedaction {
+    /// First few characters of the token
+    Prefix,
+    /// Keyed hash: correlatable across log lines without exposing the token
+    Hash([u8; 32]),
+}
+
+impl TokenRedaction {
+    pub fn label(&self, device_token: &str) -> String {
+        match self {
+            TokenRedaction::Prefix => {
+                // Characters, not bytes: Web Push subscriptions are arbitrary JSON
+                format!("{}...", device_token.chars().take(12).collect::<String>())
+            }
+            TokenRedaction::Hash(key) => {
+                let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key)
+                    .expect("HMAC accepts any key length");
+                mac.update(device_token.as_bytes());
+                format!("h:{}", hex::encode(&mac.finalize().into_bytes()[..8]))
+            }
         }
     }
 }
@@ -55,51 +254,169 @@ impl std::fmt::Display for Platform {
 pub struct DecryptedToken {
     pub platform: Platform,
     pub device_token: String,
+    /// Position of the key that decrypted the token: 0 is the current key,
+    /// 1.. are retired keys, newest first
+    pub key_index: usize,
+    /// `ENVELOPE_V1` or `ENVELOPE_V2`
+    pub envelope_version: u8,
+    pub push_key: Option<[u8; PUSH_KEY_SIZE]>,
 }
 
 pub struct TokenCrypto {
     secret_key: SecretKey,
     public_key: PublicKey,
-    secp: Secp256k1<secp256k1::All>,
+    /// Previous server keys still accepted for decryption, newest first
+    retired_keys: Vec<SecretKey>,
+    /// Keys tried per token (current key included), bounding the cost of garbage blobs
+    max_keys_attempted: usize,
+    /// Degenerate ephemeral keys, compared by x coordinate so either parity matches:
+    /// the server's own keys and the generator
+    weak_ephemeral_keys: Vec<XOnlyPublicKey>,
 }
 
 impl TokenCrypto {
     pub fn new(secret_key_hex: &str) -> Result<Self, CryptoError> {
         let secp = Secp256k1::new();
         
-        let secret_key_bytes = hex::decode(secret_key_hex)
-            .map_err(|_| CryptoError::InvalidSecretKey)?;
+        let secret_key_bytes = Zeroizing::new(hex::decode(secret_key_hex)
+            .map_err(|_| CryptoError::InvalidSecretKey)?);
         
         let secret_key = SecretKey::from_slice(&secret_key_bytes)
             .map_err(|_| CryptoError::InvalidSecretKey)?;
         
         let public_key = PublicKey::from_secret_key(&secp, &secret_key);
+
+        let mut one = [0u8; 32];
+        one[31] = 1;
+        let generator = PublicKey::from_secret_key(&secp, &SecretKey::from_slice(&one).expect("1 is a valid secret key"));
         
         Ok(Self {
             secret_key,
             public_key,
-            secp,
+            retired_keys: Vec::new(),
+            max_keys_attempted: 1,
+            weak_ephemeral_keys: vec![public_key.x_only_public_key().0, generator.x_only_public_key().0],
         })
     }
 
+    /// Like `new`, reading the hex key from `path` so it stays out of the
+    /// environment and process listings. Surrounding whitespace is ignored.
+    /// On Unix the file must not be readable by group or others.
+    pub fn from_file(path: &Path) -> Result<Self, CryptoError> {
+        let file = std::fs::File::open(path).map_err(CryptoError::KeyFileError)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = file.metadata().map_err(CryptoError::KeyFileError)?.permissions().mode();
+            if mode & 0o077 != 0 {
+                return Err(CryptoError::InsecureKeyFile { mode: mode & 0o777 });
+            }
+        }
+        let mut secret_key_hex = Zeroizing::new(String::new());
+        std::io::Read::read_to_string(&mut &file, &mut secret_key_hex).map_err(CryptoError::KeyFileError)?;
+        Self::new(secret_key_hex.trim())
+    }
+
+    /// Like `new`, additionally accepting tokens encrypted to retired keys.
+    /// At most `max_keys_attempted` keys (current first) are tried per token.
+    pub fn with_rotation(
+        secret_key_hex: &str,
+        retired_key_hexes: &[String],
+        max_keys_attempted: usize,
+    ) -> Result<Self, CryptoError> {
+        Self::new(secret_key_hex)?.with_retired_keys(retired_key_hexes, max_keys_attempted)
+    }
+
+    /// Also accept tokens encrypted to `retired_key_hexes`, trying at most
+    /// `max_keys_attempted` keys (current first) per token.
+    pub fn with_retired_keys(mut self, retired_key_hexes: &[String], max_keys_attempted: usize) -> Result<Self, CryptoError> {
+        self.retired_keys = retired_key_hexes
+            .iter()
+            .map(|key| {
+                hex::decode(key)
+                    .ok()
+                    .map(Zeroizing::new)
+                    .and_then(|bytes| SecretKey::from_slice(&bytes).ok())
+                    .ok_or(CryptoError::InvalidSecretKey)
+            })
+            .collect::<Result<_, _>>()?;
+        let secp = Secp256k1::new();
+        self.weak_ephemeral_keys.extend(
+            self.retired_keys
+                .iter()
+                .map(|key| PublicKey::from_secret_key(&secp, key).x_only_public_key().0),
+        );
+        self.max_keys_attempted = max_keys_attempted.max(1);
+        Ok(self)
+    }
+
     pub fn public_key_hex(&self) -> String {
         hex::encode(self.public_key.serialize())
     }
 
+    /// Derive a key for a single purpose from the server secret, so the
+    /// secret itself is only ever used for ECDH.
+    pub fn derive_subkey(&self, purpose: &[u8]) -> [u8; 32] {
+        let hk = Hkdf::<Sha256>::new(Some(SUBKEY_SALT), Zeroizing::new(self.secret_key.secret_bytes()).as_slice());
+        let mut subkey = [0u8; 32];
+        hk.expand(purpose, &mut subkey)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        subkey
+    }
+
+    /// Redaction that labels device tokens with a hash keyed by this server.
+    pub fn token_hash_redaction(&self) -> TokenRedaction {
+        TokenRedaction::Hash(self.derive_subkey(TOKEN_LOG_HASH_PURPOSE))
+    }
+
+    /// Encrypt `device_token` to `recipient_pubkey` as a client would, for
+    /// integration tests and tools that hold a `TokenCrypto` anyway. Same as
+    /// the free `encrypt_token`.
+    pub fn encrypt_token(
+        &self,
+        recipient_pubkey: &PublicKey,
+        platform: Platform,
+        device_token: &str,
+    ) -> Result<Vec<u8>, CryptoError> {
+        encrypt_token(recipient_pubkey, &platform, device_token)
+    }
+
+    /// Decrypt a v1 envelope.
     pub fn decrypt_token(&self, encrypted_token: &[u8]) -> Result<DecryptedToken, CryptoError> {
-        if encrypted_token.len() != ENCRYPTED_TOKEN_SIZE {
-            error!(
-                "Invalid token size: expected {}, got {}",
-                ENCRYPTED_TOKEN_SIZE,
-                encrypted_token.len()
-            );
-            return Err(CryptoError::InvalidTokenSize);
-        }
+        self.decrypt(encrypted_token, None, true)
+    }
+
+    /// Decrypt a v2 envelope bound to `trade_pubkey`, falling back to v1.
+    pub fn decrypt_token_for(
+        &self,
+        encrypted_token: &[u8],
+        trade_pubkey: &[u8],
+    ) -> Result<DecryptedToken, CryptoError> {
+        self.decrypt(encrypted_token, Some(trade_pubkey), true)
+    }
+
+    /// Decrypt an envelope sealed with `aad` as associated data, without the
+    /// v1 fallback: unbound envelopes, and envelopes bound to anything else,
+    /// fail with `DecryptionFailed`.
+    pub fn decrypt_token_with_aad(&self, encrypted_token: &[u8], aad: &[u8]) -> Result<DecryptedToken, CryptoError> {
+        self.decrypt(encrypted_token, Some(aad), false)
+    }
+
+    fn decrypt(
+        &self,
+        encrypted_token: &[u8],
+        trade_pubkey: Option<&[u8]>,
+        allow_unbound: bool,
+    ) -> Result<DecryptedToken, CryptoError> {
+        let scheme = scheme_for_size(encrypted_token.len()).inspect_err(|_| {
+            error!("Invalid token size {}: no envelope scheme has it", encrypted_token.len());
+        })?;
 
         // Extract components
+        let nonce_end = EPHEMERAL_PUBKEY_SIZE + scheme.cipher.nonce_size();
         let ephemeral_pubkey_bytes = &encrypted_token[0..EPHEMERAL_PUBKEY_SIZE];
-        let nonce_bytes = &encrypted_token[EPHEMERAL_PUBKEY_SIZE..EPHEMERAL_PUBKEY_SIZE + NONCE_SIZE];
-        let ciphertext = &encrypted_token[EPHEMERAL_PUBKEY_SIZE + NONCE_SIZE..];
+        let nonce_bytes = &encrypted_token[EPHEMERAL_PUBKEY_SIZE..nonce_end];
+        let ciphertext = &encrypted_token[nonce_end..];
 
         debug!("Ephemeral pubkey: {}", hex::encode(ephemeral_pubkey_bytes));
         debug!("Nonce: {}", hex::encode(nonce_bytes));
@@ -111,66 +428,318 @@ impl TokenCrypto {
                 error!("Failed to parse ephemeral pubkey: {}", e);
                 CryptoError::InvalidEphemeralKey
             })?;
+        if self.weak_ephemeral_keys.contains(&ephemeral_pubkey.x_only_public_key().0) {
+            error!("Rejected degenerate ephemeral pubkey (server key or generator)");
+            return Err(CryptoError::InvalidEphemeralKey);
+        }
 
-        // Derive shared secret via ECDH
-        let shared_point = secp256k1::ecdh::SharedSecret::new(&ephemeral_pubkey, &self.secret_key);
-        let shared_x = shared_point.secret_bytes();
-
-        // Derive encryption key using HKDF
-        let hk = Hkdf::<Sha256>::new(Some(HKDF_SALT), &shared_x);
-        let mut encryption_key = [0u8; 32];
-        hk.expand(HKDF_INFO, &mut encryption_key)
-            .map_err(|_| CryptoError::HkdfError)?;
-
-        // Decrypt with ChaCha20-Poly1305
-        let cipher = ChaCha20Poly1305::new_from_slice(&encryption_key)
-            .map_err(|_| CryptoError::CipherError)?;
-        let nonce = Nonce::from_slice(nonce_bytes);
-
-        let padded_payload = cipher
-            .decrypt(nonce, ciphertext)
-            .map_err(|e| {
-                error!("Decryption failed: {}", e);
-                CryptoError::DecryptionFailed
-            })?;
+        // Try the current key, then retired ones, up to the configured limit
+        let keys = std::iter::once(&self.secret_key)
+            .chain(&self.retired_keys)
+            .take(self.max_keys_attempted);
+        let mut decrypted = None;
+        for (key_index, secret_key) in keys.enumerate() {
+            if let Some(opened) = Self::open(secret_key, &ephemeral_pubkey, scheme.cipher, nonce_bytes, ciphertext, trade_pubkey, allow_unbound)? {
+                decrypted = Some((key_index, opened));
+                break;
+            }
+        }
+        let Some((key_index, (padded_payload, envelope_version))) = decrypted else {
+            error!("Decryption failed with every attempted key");
+            return Err(CryptoError::DecryptionFailed);
+        };
 
-        if padded_payload.len() != PADDED_PAYLOAD_SIZE {
+        if padded_payload.len() != scheme.padded_payload_size {
             error!(
                 "Invalid payload size after decryption: expected {}, got {}",
-                PADDED_PAYLOAD_SIZE,
+                scheme.padded_payload_size,
                 padded_payload.len()
             );
             return Err(CryptoError::InvalidPayloadSize);
         }
 
         // Parse padded payload
-        let platform_byte = padded_payload[0];
-        let token_length = u16::from_be_bytes([padded_payload[1], padded_payload[2]]) as usize;
+        if padded_payload[0] != PAYLOAD_VERSION {
+            error!("Unsupported payload version {}", padded_payload[0]);
+            return Err(CryptoError::UnsupportedTokenVersion(padded_payload[0]));
+        }
+        let platform_byte = padded_payload[1] & !PUSH_KEY_FLAG;
+        let has_push_key = padded_payload[1] & PUSH_KEY_FLAG != 0;
+        let token_length = u16::from_be_bytes([padded_payload[2], padded_payload[3]]) as usize;
 
-        if token_length > PADDED_PAYLOAD_SIZE - 3 {
+        if token_length > scheme.max_token_length(has_push_key) {
             error!("Token length {} exceeds maximum", token_length);
             return Err(CryptoError::InvalidTokenLength);
         }
 
+        // No platform issues empty device tokens; this is a client bug
+        if token_length == 0 {
+            error!("Decrypted payload contains an empty device token");
+            return Err(CryptoError::EmptyToken);
+        }
+
         let platform = Platform::from_byte(platform_byte)
             .ok_or(CryptoError::InvalidPlatform)?;
 
-        let device_token_bytes = &padded_payload[3..3 + token_length];
+        let device_token_bytes = &padded_payload[PAYLOAD_HEADER_SIZE..PAYLOAD_HEADER_SIZE + token_length];
         let device_token = String::from_utf8(device_token_bytes.to_vec())
             .map_err(|_| CryptoError::InvalidTokenEncoding)?;
+        // A length that runs into the padding yields random bytes, never a token
+        if !is_plausible_token(&platform, &device_token) {
+            error!("Decrypted device token is not plausible for {}", platform);
+            return Err(CryptoError::ImplausibleToken);
+        }
+        let push_key = has_push_key.then(|| {
+            let start = PAYLOAD_HEADER_SIZE + token_length;
+            <[u8; PUSH_KEY_SIZE]>::try_from(&padded_payload[start..start + PUSH_KEY_SIZE])
+                .expect("slice is PUSH_KEY_SIZE long")
+        });
 
         debug!("Decrypted token for platform {:?}, length {}", platform, token_length);
 
         Ok(DecryptedToken {
             platform,
             device_token,
+            key_index,
+            envelope_version,
+            push_key,
         })
     }
+
+    /// ECDH + HKDF + `cipher` with one server key, trying the v2 binding to
+    /// `trade_pubkey` first when given, then v1 if `allow_unbound`. Returns the
+    /// payload and envelope version, or None if the blob wasn't encrypted to this key.
+    fn open(
+        secret_key: &SecretKey,
+        ephemeral_pubkey: &PublicKey,
+        cipher: EnvelopeCipher,
+        nonce: &[u8],
+        ciphertext: &[u8],
+        trade_pubkey: Option<&[u8]>,
+        allow_unbound: bool,
+    ) -> Result<Option<(Vec<u8>, u8)>, CryptoError> {
+        // Derive shared secret via ECDH; both it and the key are wiped on return
+        let mut shared_point = secp256k1::ecdh::SharedSecret::new(ephemeral_pubkey, secret_key);
+        let shared_x = Zeroizing::new(shared_point.secret_bytes());
+        shared_point.non_secure_erase();
+
+        // Derive encryption key using HKDF
+        let hk = Hkdf::<Sha256>::new(Some(HKDF_SALT), shared_x.as_slice());
+        let mut encryption_key = Zeroizing::new([0u8; 32]);
+        hk.expand(HKDF_INFO, encryption_key.as_mut_slice())
+            .map_err(|_| CryptoError::HkdfError)?;
+
+        if let Some(aad) = trade_pubkey {
+            if let Some(payload) = cipher.decrypt(&encryption_key, nonce, Payload { msg: ciphertext, aad }) {
+                return Ok(Some((payload, ENVELOPE_V2)));
+            }
+        }
+        if !allow_unbound {
+            return Ok(None);
+        }
+        Ok(cipher
+            .decrypt(&encryption_key, nonce, Payload { msg: ciphertext, aad: &[] })
+            .map(|payload| (payload, ENVELOPE_V1)))
+    }
+}
+
+/// The server keys are long-lived and decrypt untrusted input on every
+/// registration, so they are scrubbed rather than left for core dumps.
+/// secp256k1 only offers `non_secure_erase`, which the optimizer may elide,
+/// so this is best-effort and the type deliberately isn't `ZeroizeOnDrop`.
+impl Drop for TokenCrypto {
+    fn drop(&mut self) {
+        self.secret_key.non_secure_erase();
+        for key in &mut self.retired_keys {
+            key.non_secure_erase();
+        }
+    }
+}
+
+/// FCM and APNs tokens and UnifiedPush endpoint URLs are all printable ASCII
+/// without whitespace, which random padding almost never is. Web Push
+/// subscriptions are JSON objects naming their push service endpoint.
+fn is_plausible_token(platform: &Platform, device_token: &str) -> bool {
+    match platform {
+        Platform::Android | Platform::Ios => device_token.bytes().all(|b| b.is_ascii_graphic()),
+        Platform::Web => serde_json::from_str::<serde_json::Value>(device_token)
+            .is_ok_and(|subscription| subscription["endpoint"].is_string()),
+    }
+}
+
+/// Encrypt a device token to the server key, as clients do before registering.
+/// The result is a v1 envelope in the smallest scheme the token fits; a token
+/// no scheme fits is `InvalidTokenLength`.
+pub fn encrypt_token(
+    server_pubkey: &PublicKey,
+    platform: &Platform,
+    device_token: &str,
+) -> Result<Vec<u8>, CryptoError> {
+    encrypt_token_with(server_pubkey, platform, device_token, Padding::Random)
+}
+
+/// `encrypt_token` with the given padding.
+pub fn encrypt_token_with(
+    server_pubkey: &PublicKey,
+    platform: &Platform,
+    device_token: &str,
+    padding: Padding,
+) -> Result<Vec<u8>, CryptoError> {
+    build_envelope(server_pubkey, platform, device_token, None, None, EnvelopeCipher::default(), padding)
+}
+
+/// `encrypt_token`, producing a v2 envelope bound to the raw trade pubkey
+/// bytes when `trade_pubkey` is given, and carrying `push_key` when given.
+pub fn encrypt_envelope(
+    server_pubkey: &PublicKey,
+    platform: &Platform,
+    device_token: &str,
+    trade_pubkey: Option<&[u8]>,
+    push_key: Option<&[u8; PUSH_KEY_SIZE]>,
+) -> Result<Vec<u8>, CryptoError> {
+    encrypt_envelope_with(server_pubkey, platform, device_token, trade_pubkey, push_key, EnvelopeCipher::default())
+}
+
+/// `encrypt_envelope` sealed with `cipher`. High-volume clients should pick
+/// XChaCha20-Poly1305, whose random nonces can't realistically collide.
+pub fn encrypt_envelope_with(
+    server_pubkey: &PublicKey,
+    platform: &Platform,
+    device_token: &str,
+    trade_pubkey: Option<&[u8]>,
+    push_key: Option<&[u8; PUSH_KEY_SIZE]>,
+    cipher: EnvelopeCipher,
+) -> Result<Vec<u8>, CryptoError> {
+    build_envelope(server_pubkey, platform, device_token, trade_pubkey, push_key, cipher, Padding::Random)
+}
+
+fn build_envelope(
+    server_pubkey: &PublicKey,
+    platform: &Platform,
+    device_token: &str,
+    trade_pubkey: Option<&[u8]>,
+    push_key: Option<&[u8; PUSH_KEY_SIZE]>,
+    cipher: EnvelopeCipher,
+    padding: Padding,
+) -> Result<Vec<u8>, CryptoError> {
+    let token_bytes = device_token.as_bytes();
+    if token_bytes.is_empty() {
+        return Err(CryptoError::EmptyToken);
+    }
+    // The smallest scheme the token fits, so short tokens keep the standard size
+    let scheme = SCHEMES
+        .iter()
+        .filter(|scheme| scheme.cipher == cipher)
+        .find(|scheme| token_bytes.len() <= scheme.max_token_length(push_key.is_some()))
+        .ok_or(CryptoError::InvalidTokenLength)?;
+    if !is_plausible_token(platform, device_token) {
+        return Err(CryptoError::ImplausibleToken);
+    }
+    seal(server_pubkey, scheme.cipher, &pad_payload(scheme, platform, token_bytes, push_key, padding), trade_pubkey)
+}
+
+/// version || platform || token length (u16 BE) || token || [push key] || padding.
+/// The platform byte's high bit marks a push key.
+fn pad_payload(
+    scheme: &EnvelopeScheme,
+    platform: &Platform,
+    token_bytes: &[u8],
+    push_key: Option<&[u8; PUSH_KEY_SIZE]>,
+    padding: Padding,
+) -> Vec<u8> {
+    let mut padded_payload = vec![0u8; scheme.padded_payload_size];
+    padded_payload[0] = PAYLOAD_VERSION;
+    padded_payload[1] = platform.to_byte();
+    padded_payload[2..PAYLOAD_HEADER_SIZE].copy_from_slice(&(token_bytes.len() as u16).to_be_bytes());
+    let mut end = PAYLOAD_HEADER_SIZE + token_bytes.len();
+    padded_payload[PAYLOAD_HEADER_SIZE..end].copy_from_slice(token_bytes);
+    if let Some(push_key) = push_key {
+        padded_payload[1] |= PUSH_KEY_FLAG;
+        padded_payload[end..end + PUSH_KEY_SIZE].copy_from_slice(push_key);
+        end += PUSH_KEY_SIZE;
+    }
+    if padding == Padding::Random {
+        rand::thread_rng().fill_bytes(&mut padded_payload[end..]);
+    }
+    padded_payload
+}
+
+/// Seal push data to a registration's push key: nonce || ciphertext.
+pub fn seal_push_data(push_key: &[u8; PUSH_KEY_SIZE], plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let cipher = ChaCha20Poly1305::new_from_slice(push_key).map_err(|_| CryptoError::CipherError)?;
+    let mut nonce_bytes = [0u8; NONCE_SIZE];
+    rand::thread_rng().fill_bytes(&mut nonce_bytes);
+    let ciphertext = cipher
+        .encrypt(&Nonce::from(nonce_bytes), Payload { msg: plaintext, aad: PUSH_DATA_AAD })
+        .map_err(|_| CryptoError::CipherError)?;
+    let mut sealed = nonce_bytes.to_vec();
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+/// Inverse of `seal_push_data`, as the app does on receipt.
+pub fn open_push_data(push_key: &[u8; PUSH_KEY_SIZE], sealed: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    if sealed.len() < NONCE_SIZE + AUTH_TAG_SIZE {
+        return Err(CryptoError::DecryptionFailed);
+    }
+    let (nonce_bytes, ciphertext) = sealed.split_at(NONCE_SIZE);
+    let nonce = Nonce::from(<[u8; NONCE_SIZE]>::try_from(nonce_bytes).map_err(|_| CryptoError::DecryptionFailed)?);
+    ChaCha20Poly1305::new_from_slice(push_key)
+        .map_err(|_| CryptoError::CipherError)?
+        .decrypt(&nonce, Payload { msg: ciphertext, aad: PUSH_DATA_AAD })
+        .map_err(|_| CryptoError::DecryptionFailed)
+}
+
+/// Encrypt a padded payload to the server key under a fresh ephemeral key.
+fn seal(
+    server_pubkey: &PublicKey,
+    cipher: EnvelopeCipher,
+    padded_payload: &[u8],
+    aad: Option<&[u8]>,
+) -> Result<Vec<u8>, CryptoError> {
+    let secp = Secp256k1::new();
+    let mut rng = rand::thread_rng();
+
+    // Generate ephemeral keypair
+    let mut ephemeral_secret = SecretKey::new(&mut rng);
+    let ephemeral_pubkey = PublicKey::from_secret_key(&secp, &ephemeral_secret);
+
+    // Derive shared secret; it, the ephemeral secret and the key are wiped on return
+    let mut shared_point = secp256k1::ecdh::SharedSecret::new(server_pubkey, &ephemeral_secret);
+    ephemeral_secret.non_secure_erase();
+    let shared_x = Zeroizing::new(shared_point.secret_bytes());
+    shared_point.non_secure_erase();
+
+    // Derive encryption key
+    let hk = Hkdf::<Sha256>::new(Some(HKDF_SALT), shared_x.as_slice());
+    let mut encryption_key = Zeroizing::new([0u8; 32]);
+    hk.expand(HKDF_INFO, encryption_key.as_mut_slice())
+        .map_err(|_| CryptoError::HkdfError)?;
+
+    let mut nonce_bytes = vec![0u8; cipher.nonce_size()];
+    rng.fill_bytes(&mut nonce_bytes);
+
+    let ciphertext = cipher.encrypt(
+        &encryption_key,
+        &nonce_bytes,
+        Payload { msg: padded_payload, aad: aad.unwrap_or_default() },
+    )?;
+
+    // ephemeral_pubkey || nonce || ciphertext
+    let mut encrypted_token = Vec::with_capacity(EPHEMERAL_PUBKEY_SIZE + nonce_bytes.len() + ciphertext.len());
+    encrypted_token.extend_from_slice(&ephemeral_pubkey.serialize());
+    encrypted_token.extend_from_slice(&nonce_bytes);
+    encrypted_token.extend_from_slice(&ciphertext);
+    Ok(encrypted_token)
 }
 
 #[derive(Debug)]
 pub enum CryptoError {
     InvalidSecretKey,
+    /// The key file couldn't be read
+    KeyFileError(std::io::Error),
+    /// The key file is readable by group or others; `mode` is its permission bits
+    InsecureKeyFile { mode: u32 },
     InvalidTokenSize,
     InvalidEphemeralKey,
     HkdfError,
@@ -178,14 +747,22 @@ pub enum CryptoError {
     DecryptionFailed,
     InvalidPayloadSize,
     InvalidTokenLength,
+    EmptyToken,
     InvalidPlatform,
     InvalidTokenEncoding,
+    ImplausibleToken,
+    /// The payload's version byte is not one this server understands
+    UnsupportedTokenVersion(u8),
 }
 
 impl std::fmt::Display for CryptoError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             CryptoError::InvalidSecretKey => write!(f, "Invalid secret key"),
+            CryptoError::KeyFileError(e) => write!(f, "Failed to read secret key file: {}", e),
+            CryptoError::InsecureKeyFile { mode } => {
+                write!(f, "Secret key file is accessible by group or others (mode {:o}); chmod 600 it", mode)
+            }
             CryptoError::InvalidTokenSize => write!(f, "Invalid encrypted token size"),
             CryptoError::InvalidEphemeralKey => write!(f, "Invalid ephemeral public key"),
             CryptoError::HkdfError => write!(f, "HKDF derivation failed"),
@@ -193,66 +770,55 @@ impl std::fmt::Display for CryptoError {
             CryptoError::DecryptionFailed => write!(f, "Decryption failed"),
             CryptoError::InvalidPayloadSize => write!(f, "Invalid payload size after decryption"),
             CryptoError::InvalidTokenLength => write!(f, "Invalid token length in payload"),
+            CryptoError::EmptyToken => write!(f, "Empty device token in payload"),
             CryptoError::InvalidPlatform => write!(f, "Invalid platform identifier"),
             CryptoError::InvalidTokenEncoding => write!(f, "Invalid token encoding"),
+            CryptoError::ImplausibleToken => write!(f, "Device token is not plausible for its platform"),
+            CryptoError::UnsupportedTokenVersion(version) => write!(f, "Unsupported token payload version {}", version),
         }
     }
 }
 
-impl std::error::Error for CryptoError {}
+impl std::error::Error for CryptoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CryptoError::KeyFileError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
-    use rand::RngCore;
 
-    fn create_test_encrypted_token(
+    pub(crate) fn create_test_encrypted_token(
         server_pubkey: &PublicKey,
         platform: Platform,
         device_token: &str,
     ) -> Vec<u8> {
-        let secp = Secp256k1::new();
-        
-        // Generate ephemeral keypair
-        let mut rng = rand::thread_rng();
-        let ephemeral_secret = SecretKey::new(&mut rng);
-        let ephemeral_pubkey = PublicKey::from_secret_key(&secp, &ephemeral_secret);
-
-        // Derive shared secret
-        let shared_point = secp256k1::ecdh::SharedSecret::new(server_pubkey, &ephemeral_secret);
-        let shared_x = shared_point.secret_bytes();
-
-        // Derive encryption key
-        let hk = Hkdf::<Sha256>::new(Some(HKDF_SALT), &shared_x);
-        let mut encryption_key = [0u8; 32];
-        hk.expand(HKDF_INFO, &mut encryption_key).unwrap();
-
-        // Create padded payload
-        let token_bytes = device_token.as_bytes();
-        let mut padded_payload = vec![0u8; PADDED_PAYLOAD_SIZE];
-        padded_payload[0] = platform.to_byte();
-        padded_payload[1..3].copy_from_slice(&(token_bytes.len() as u16).to_be_bytes());
-        padded_payload[3..3 + token_bytes.len()].copy_from_slice(token_bytes);
-        
-        // Fill rest with random padding
-        rng.fill_bytes(&mut padded_payload[3 + token_bytes.len()..]);
-
-        // Generate random nonce
-        let mut nonce_bytes = [0u8; NONCE_SIZE];
-        rng.fill_bytes(&mut nonce_bytes);
-        let nonce = Nonce::from_slice(&nonce_bytes);
-
-        // Encrypt
-        let cipher = ChaCha20Poly1305::new_from_slice(&encryption_key).unwrap();
-        let ciphertext = cipher.encrypt(nonce, padded_payload.as_slice()).unwrap();
+        encrypt_test_envelope(server_pubkey, platform, device_token, None)
+    }
 
-        // Combine: ephemeral_pubkey || nonce || ciphertext
-        let mut encrypted_token = Vec::with_capacity(ENCRYPTED_TOKEN_SIZE);
-        encrypted_token.extend_from_slice(&ephemeral_pubkey.serialize());
-        encrypted_token.extend_from_slice(&nonce_bytes);
-        encrypted_token.extend_from_slice(&ciphertext);
+    /// v2 envelope bound to the raw trade pubkey bytes.
+    pub(crate) fn create_test_encrypted_token_v2(
+        server_pubkey: &PublicKey,
+        platform: Platform,
+        device_token: &str,
+        trade_pubkey: &[u8],
+    ) -> Vec<u8> {
+        encrypt_test_envelope(server_pubkey, platform, device_token, Some(trade_pubkey))
+    }
 
-        encrypted_token
+    fn encrypt_test_envelope(
+        server_pubkey: &PublicKey,
+        platform: Platform,
+        device_token: &str,
+        aad: Option<&[u8]>,
+    ) -> Vec<u8> {
+        // Skips `encrypt_envelope`'s validation so tests can build malformed tokens
+        let payload = pad_payload(&STANDARD_SCHEME, &platform, device_token.as_bytes(), None, Padding::Random);
+        seal(server_pubkey, STANDARD_SCHEME.cipher, &payload, aad).unwrap()
     }
 
     #[test]
@@ -271,4 +837,369 @@ mod tests {
         assert_eq!(decrypted.platform, Platform::Android);
         assert_eq!(decrypted.device_token, device_token);
     }
+
+    #[test]
+    fn test_web_push_subscriptions_round_trip() {
+        let secp = Secp256k1::new();
+        let server_secret = SecretKey::new(&mut rand::thread_rng());
+        let server_pubkey = PublicKey::from_secret_key(&secp, &server_secret);
+        let crypto = TokenCrypto::new(&hex::encode(server_secret.secret_bytes())).unwrap();
+        assert_eq!(Platform::from_byte(0x03), Some(Platform::Web));
+        assert_eq!((Platform::Web.to_byte(), Platform::Web.to_string()), (0x03, "web".to_string()));
+
+        // A browser subscription, too long for the standard envelope
+        let subscription = serde_json::json!({
+            "endpoint": format!("https://fcm.googleapis.com/fcm/send/{}", "d".repeat(150)),
+            "keys": { "p256dh": "B".repeat(87), "auth": "a".repeat(22) }
+        })
+        .to_string();
+        let encrypted = encrypt_token(&server_pubkey, &Platform::Web, &subscription).unwrap();
+        assert_eq!(encrypted.len(), EXTENDED_SCHEME.encrypted_size());
+        let decrypted = crypto.decrypt_token(&encrypted).unwrap();
+        assert_eq!((decrypted.platform, decrypted.device_token), (Platform::Web, subscription));
+
+        // Anything but a subscription object is refused
+        let encrypted = create_test_encrypted_token(&server_pubkey, Platform::Web, "fcm-token");
+        assert!(matches!(crypto.decrypt_token(&encrypted), Err(CryptoError::ImplausibleToken)));
+
+        // Non-ASCII JSON is accepted, and logged without splitting a character
+        let subscription = r#"{"üüüüüü":1,"endpoint":"https://push.example/x"}"#;
+        let encrypted = encrypt_token(&server_pubkey, &Platform::Web, subscription).unwrap();
+        let decrypted = crypto.decrypt_token(&encrypted).unwrap();
+        assert_eq!(TokenRedaction::Prefix.label(&decrypted.device_token), r#"{"üüüüüü":1,..."#);
+    }
+
+    #[test]
+    fn test_key_material_is_wiped_without_breaking_decryption() {
+        let secp = Secp256k1::new();
+        let server_secret = SecretKey::new(&mut rand::thread_rng());
+        let server_pubkey = PublicKey::from_secret_key(&secp, &server_secret);
+        let retired = hex::encode(SecretKey::new(&mut rand::thread_rng()).secret_bytes());
+        let crypto = TokenCrypto::new(&hex::encode(server_secret.secret_bytes()))
+            .and_then(|crypto| crypto.with_retired_keys(&[retired], 2))
+            .unwrap();
+
+        // Every decrypt derives and wipes a fresh key; none of them stick
+        for device_token in ["fcm-token-1", "fcm-token-2"] {
+            let encrypted = encrypt_token(&server_pubkey, &Platform::Android, device_token).unwrap();
+            assert_eq!(crypto.decrypt_token(&encrypted).unwrap().device_token, device_token);
+        }
+        drop(crypto);
+    }
+
+    #[test]
+    fn test_decrypt_rejects_server_key_as_ephemeral() {
+        let secp = Secp256k1::new();
+        let server_secret = SecretKey::new(&mut rand::thread_rng());
+        let server_pubkey = PublicKey::from_secret_key(&secp, &server_secret);
+        let retired_secret = SecretKey::new(&mut rand::thread_rng());
+        let crypto = TokenCrypto::with_rotation(
+            &hex::encode(server_secret.secret_bytes()),
+            &[hex::encode(retired_secret.secret_bytes())],
+            2,
+        )
+        .unwrap();
+
+        let encrypted = create_test_encrypted_token(&server_pubkey, Platform::Android, "fcm-token");
+        let with_ephemeral = |key: &PublicKey| {
+            let mut token = encrypted.clone();
+            token[..EPHEMERAL_PUBKEY_SIZE].copy_from_slice(&key.serialize());
+            token
+        };
+
+        let retired_pubkey = PublicKey::from_secret_key(&secp, &retired_secret);
+        let mut one = [0u8; 32];
+        one[31] = 1;
+        let generator = PublicKey::from_secret_key(&secp, &SecretKey::from_slice(&one).unwrap());
+        for weak in [server_pubkey, server_pubkey.negate(&secp), retired_pubkey, generator] {
+            assert!(matches!(
+                crypto.decrypt_token(&with_ephemeral(&weak)),
+                Err(CryptoError::InvalidEphemeralKey)
+            ));
+        }
+        // The point at infinity has no valid encoding
+        let mut infinity = encrypted.clone();
+        infinity[..EPHEMERAL_PUBKEY_SIZE].fill(0);
+        assert!(matches!(crypto.decrypt_token(&infinity), Err(CryptoError::InvalidEphemeralKey)));
+        assert!(crypto.decrypt_token(&encrypted).is_ok());
+    }
+
+    #[test]
+    fn test_decrypt_rejects_empty_token() {
+        let secp = Secp256k1::new();
+        let mut rng = rand::thread_rng();
+        let server_secret = SecretKey::new(&mut rng);
+        let server_pubkey = PublicKey::from_secret_key(&secp, &server_secret);
+
+        let crypto = TokenCrypto::new(&hex::encode(server_secret.secret_bytes())).unwrap();
+
+        let encrypted = create_test_encrypted_token(&server_pubkey, Platform::Android, "");
+
+        assert!(matches!(
+            crypto.decrypt_token(&encrypted),
+            Err(CryptoError::EmptyToken)
+        ));
+    }
+
+    #[test]
+    fn test_decrypt_rejects_garbage_token_region() {
+        let secp = Secp256k1::new();
+        let server_secret = SecretKey::new(&mut rand::thread_rng());
+        let server_pubkey = PublicKey::from_secret_key(&secp, &server_secret);
+        let crypto = TokenCrypto::new(&hex::encode(server_secret.secret_bytes())).unwrap();
+
+        // A real 12-byte token, but the declared length reaches 40 bytes into
+        // padding that happens to be valid UTF-8
+        let mut payload = pad_payload(&STANDARD_SCHEME, &Platform::Android, b"fcm-token-ok", None, Padding::Random);
+        payload[2..4].copy_from_slice(&40u16.to_be_bytes());
+        for byte in &mut payload[16..44] {
+            *byte = 0x07;
+        }
+        let encrypted = seal(&server_pubkey, STANDARD_SCHEME.cipher, &payload, None).unwrap();
+
+        assert!(matches!(crypto.decrypt_token(&encrypted), Err(CryptoError::ImplausibleToken)));
+    }
+
+    #[test]
+    fn test_decrypt_rejects_unknown_payload_version() {
+        let secp = Secp256k1::new();
+        let server_secret = SecretKey::new(&mut rand::thread_rng());
+        let server_pubkey = PublicKey::from_secret_key(&secp, &server_secret);
+        let crypto = TokenCrypto::new(&hex::encode(server_secret.secret_bytes())).unwrap();
+
+        let mut payload = pad_payload(&STANDARD_SCHEME, &Platform::Android, b"fcm-token", None, Padding::Random);
+        assert_eq!(payload[0], PAYLOAD_VERSION);
+        payload[0] = 0x02;
+        let encrypted = seal(&server_pubkey, STANDARD_SCHEME.cipher, &payload, None).unwrap();
+
+        assert!(matches!(
+            crypto.decrypt_token(&encrypted),
+            Err(CryptoError::UnsupportedTokenVersion(0x02))
+        ));
+    }
+
+    #[test]
+    fn test_token_hash_is_stable_and_distinct() {
+        let crypto = TokenCrypto::new(&"22".repeat(32)).unwrap();
+        let redaction = crypto.token_hash_redaction();
+
+        let token = "fcm-device-token-aaaaaaaaaaaaaaaa";
+        assert_eq!(redaction.label(token), redaction.label(token));
+        assert_ne!(redaction.label(token), redaction.label("fcm-device-token-bbbbbbbbbbbbbbbb"));
+        assert!(!redaction.label(token).contains("fcm-device"));
+
+        // Another server key hashes the same token differently
+        let other = TokenCrypto::new(&"11".repeat(32)).unwrap().token_hash_redaction();
+        assert_ne!(redaction.label(token), other.label(token));
+    }
+
+    #[test]
+    fn test_rotation_keys_bounded_per_request() {
+        let secp = Secp256k1::new();
+        let mut rng = rand::thread_rng();
+        let keys: Vec<SecretKey> = (0..3).map(|_| SecretKey::new(&mut rng)).collect();
+        let hexes: Vec<String> = keys.iter().map(|k| hex::encode(k.secret_bytes())).collect();
+
+        // Encrypted to the oldest retired key
+        let oldest_pubkey = PublicKey::from_secret_key(&secp, &keys[2]);
+        let encrypted = create_test_encrypted_token(&oldest_pubkey, Platform::Ios, "apns-token");
+
+        let crypto = TokenCrypto::with_rotation(&hexes[0], &hexes[1..], 3).unwrap();
+        let decrypted = crypto.decrypt_token(&encrypted).unwrap();
+        assert_eq!(decrypted.device_token, "apns-token");
+        assert_eq!(decrypted.key_index, 2);
+
+        // With a lower limit the oldest key is never tried
+        let crypto = TokenCrypto::with_rotation(&hexes[0], &hexes[1..], 2).unwrap();
+        assert!(matches!(crypto.decrypt_token(&encrypted), Err(CryptoError::DecryptionFailed)));
+    }
+
+    #[test]
+    fn test_secret_key_loads_from_private_file() {
+        let secret = SecretKey::new(&mut rand::thread_rng());
+        let dir = std::env::temp_dir().join(format!("mostro-push-key-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.key");
+        std::fs::write(&path, format!("  {}\n", hex::encode(secret.secret_bytes()))).unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+            assert!(matches!(TokenCrypto::from_file(&path), Err(CryptoError::InsecureKeyFile { mode: 0o644 })));
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        }
+        let crypto = TokenCrypto::from_file(&path).unwrap();
+        assert_eq!(crypto.public_key_hex(), TokenCrypto::new(&hex::encode(secret.secret_bytes())).unwrap().public_key_hex());
+
+        // A missing file and a malformed key fail differently
+        assert!(matches!(TokenCrypto::from_file(&dir.join("missing.key")), Err(CryptoError::KeyFileError(_))));
+        std::fs::write(&path, "not hex").unwrap();
+        assert!(matches!(TokenCrypto::from_file(&path), Err(CryptoError::InvalidSecretKey)));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_v2_envelope_is_bound_to_trade_pubkey() {
+        let secp = Secp256k1::new();
+        let server_secret = SecretKey::new(&mut rand::thread_rng());
+        let server_pubkey = PublicKey::from_secret_key(&secp, &server_secret);
+        let crypto = TokenCrypto::new(&hex::encode(server_secret.secret_bytes())).unwrap();
+        let trade_pubkey = [0xab; 32];
+
+        let v2 = create_test_encrypted_token_v2(&server_pubkey, Platform::Ios, "apns-token", &trade_pubkey);
+        let decrypted = crypto.decrypt_token_for(&v2, &trade_pubkey).unwrap();
+        assert_eq!(decrypted.envelope_version, ENVELOPE_V2);
+        assert_eq!(decrypted.device_token, "apns-token");
+
+        // Replayed under another pubkey, or read as v1, it doesn't open
+        assert!(crypto.decrypt_token_for(&v2, &[0xcd; 32]).is_err());
+        assert!(crypto.decrypt_token(&v2).is_err());
+
+        let v1 = create_test_encrypted_token(&server_pubkey, Platform::Ios, "apns-token");
+        assert_eq!(crypto.decrypt_token_for(&v1, &trade_pubkey).unwrap().envelope_version, ENVELOPE_V1);
+    }
+
+    #[test]
+    fn test_required_binding_rejects_other_pubkeys_and_v1() {
+        let secp = Secp256k1::new();
+        let server_secret = SecretKey::new(&mut rand::thread_rng());
+        let server_pubkey = PublicKey::from_secret_key(&secp, &server_secret);
+        let crypto = TokenCrypto::new(&hex::encode(server_secret.secret_bytes())).unwrap();
+        let (pubkey_a, pubkey_b) = ([0xa1; 32], [0xb2; 32]);
+
+        let bound = create_test_encrypted_token_v2(&server_pubkey, Platform::Android, "fcm-token", &pubkey_a);
+        assert_eq!(crypto.decrypt_token_with_aad(&bound, &pubkey_a).unwrap().device_token, "fcm-token");
+        assert!(matches!(
+            crypto.decrypt_token_with_aad(&bound, &pubkey_b),
+            Err(CryptoError::DecryptionFailed)
+        ));
+
+        // An unbound envelope could be replayed under any pubkey, so it is refused too
+        let unbound = create_test_encrypted_token(&server_pubkey, Platform::Android, "fcm-token");
+        assert!(matches!(
+            crypto.decrypt_token_with_aad(&unbound, &pubkey_a),
+            Err(CryptoError::DecryptionFailed)
+        ));
+    }
+
+    #[test]
+    fn test_envelope_carries_push_key() {
+        let secp = Secp256k1::new();
+        let server_secret = SecretKey::new(&mut rand::thread_rng());
+        let server_pubkey = PublicKey::from_secret_key(&secp, &server_secret);
+        let crypto = TokenCrypto::new(&hex::encode(server_secret.secret_bytes())).unwrap();
+        let push_key = [0x42; PUSH_KEY_SIZE];
+
+        let encrypted = encrypt_envelope(&server_pubkey, &Platform::Android, "fcm-token", None, Some(&push_key)).unwrap();
+        let decrypted = crypto.decrypt_token(&encrypted).unwrap();
+        assert_eq!(decrypted.platform, Platform::Android);
+        assert_eq!(decrypted.device_token, "fcm-token");
+        assert_eq!(decrypted.push_key, Some(push_key));
+
+        let plain = encrypt_token(&server_pubkey, &Platform::Android, "fcm-token").unwrap();
+        assert_eq!(crypto.decrypt_token(&plain).unwrap().push_key, None);
+
+        // The key takes room from the token
+        let longest = "t".repeat(EXTENDED_PADDED_PAYLOAD_SIZE - PAYLOAD_HEADER_SIZE - PUSH_KEY_SIZE);
+        assert!(encrypt_envelope(&server_pubkey, &Platform::Ios, &longest, None, Some(&push_key)).is_ok());
+        assert!(matches!(
+            encrypt_envelope(&server_pubkey, &Platform::Ios, &format!("{}t", longest), None, Some(&push_key)),
+            Err(CryptoError::InvalidTokenLength)
+        ));
+    }
+
+    #[test]
+    fn test_schemes_of_different_sizes_are_accepted() {
+        let secp = Secp256k1::new();
+        let server_secret = SecretKey::new(&mut rand::thread_rng());
+        let server_pubkey = PublicKey::from_secret_key(&secp, &server_secret);
+        let crypto = TokenCrypto::new(&hex::encode(server_secret.secret_bytes())).unwrap();
+        let push_key = [0x42; PUSH_KEY_SIZE];
+
+        // A token that fits the standard payload keeps the standard size; a
+        // longer UnifiedPush endpoint moves to the extended scheme
+        let short = "https://push.example/up/abc";
+        let long = format!("https://push.example/up/{}", "a".repeat(200));
+        let standard = encrypt_envelope(&server_pubkey, &Platform::Android, short, None, Some(&push_key)).unwrap();
+        let extended = encrypt_envelope(&server_pubkey, &Platform::Android, &long, None, Some(&push_key)).unwrap();
+        assert_eq!(standard.len(), ENCRYPTED_TOKEN_SIZE);
+        assert_eq!(extended.len(), EXTENDED_SCHEME.encrypted_size());
+        assert_eq!(crypto.decrypt_token(&standard).unwrap().device_token, short);
+        assert_eq!(crypto.decrypt_token(&extended).unwrap().device_token, long);
+        assert_eq!(scheme_for_size(extended.len()).unwrap().name, "extended");
+
+        // Any other size is refused before decrypting
+        for size in [0, ENCRYPTED_TOKEN_SIZE - 1, ENCRYPTED_TOKEN_SIZE + 1, EXTENDED_SCHEME.encrypted_size() + 1] {
+            assert!(matches!(scheme_for_size(size), Err(CryptoError::InvalidTokenSize)));
+            assert!(matches!(crypto.decrypt_token(&vec![0u8; size]), Err(CryptoError::InvalidTokenSize)));
+        }
+    }
+
+    #[test]
+    fn test_encrypt_token_round_trips_with_exact_layout() {
+        let secp = Secp256k1::new();
+        let server_secret = SecretKey::new(&mut rand::thread_rng());
+        let server_pubkey = PublicKey::from_secret_key(&secp, &server_secret);
+        let crypto = TokenCrypto::new(&hex::encode(server_secret.secret_bytes())).unwrap();
+
+        let encrypted = crypto.encrypt_token(&server_pubkey, Platform::Ios, "apns-token").unwrap();
+        assert_eq!(encrypted.len(), ENCRYPTED_TOKEN_SIZE);
+        let decrypted = crypto.decrypt_token(&encrypted).unwrap();
+        assert_eq!((decrypted.platform, decrypted.device_token.as_str()), (Platform::Ios, "apns-token"));
+
+        // Zeroed padding makes the whole payload predictable
+        let encrypted = encrypt_token_with(&server_pubkey, &Platform::Android, "fcm", Padding::Zeroed).unwrap();
+        let ephemeral = PublicKey::from_slice(&encrypted[..EPHEMERAL_PUBKEY_SIZE]).unwrap();
+        let (nonce, ciphertext) = encrypted[EPHEMERAL_PUBKEY_SIZE..].split_at(NONCE_SIZE);
+        let (payload, version) =
+            TokenCrypto::open(&server_secret, &ephemeral, EnvelopeCipher::ChaCha20Poly1305, nonce, ciphertext, None, true)
+                .unwrap()
+                .unwrap();
+        let mut expected = vec![0u8; PADDED_PAYLOAD_SIZE];
+        expected[..7].copy_from_slice(&[PAYLOAD_VERSION, PLATFORM_ANDROID, 0, 3, b'f', b'c', b'm']);
+        assert_eq!(payload, expected);
+        assert_eq!(version, ENVELOPE_V1);
+
+        // Too long for any scheme is an error, not a panic
+        let too_long = "t".repeat(EXTENDED_SCHEME.max_token_length(false) + 1);
+        assert!(matches!(
+            encrypt_token(&server_pubkey, &Platform::Android, &too_long),
+            Err(CryptoError::InvalidTokenLength)
+        ));
+    }
+
+    #[test]
+    fn test_xchacha_envelopes_round_trip_with_larger_nonce() {
+        let secp = Secp256k1::new();
+        let server_secret = SecretKey::new(&mut rand::thread_rng());
+        let server_pubkey = PublicKey::from_secret_key(&secp, &server_secret);
+        let crypto = TokenCrypto::new(&hex::encode(server_secret.secret_bytes())).unwrap();
+        let trade_pubkey = [7u8; 32];
+        let push_key = [9u8; PUSH_KEY_SIZE];
+
+        // The 24-byte nonce grows each frame by 12 bytes over its ChaCha twin
+        assert_eq!(XCHACHA_SCHEME.encrypted_size(), ENCRYPTED_TOKEN_SIZE + 12);
+        assert_eq!(XCHACHA_EXTENDED_SCHEME.encrypted_size(), EXTENDED_SCHEME.encrypted_size() + 12);
+
+        let cipher = EnvelopeCipher::XChaCha20Poly1305;
+        let standard = encrypt_envelope_with(&server_pubkey, &Platform::Ios, "apns-token", Some(&trade_pubkey), None, cipher).unwrap();
+        assert_eq!(standard.len(), XCHACHA_SCHEME.encrypted_size());
+        assert_eq!(scheme_for_size(standard.len()).unwrap().name, "xchacha");
+        let decrypted = crypto.decrypt_token_for(&standard, &trade_pubkey).unwrap();
+        assert_eq!(decrypted.device_token, "apns-token");
+        assert_eq!(decrypted.envelope_version, ENVELOPE_V2);
+
+        let long = format!("https://push.example/up/{}", "a".repeat(200));
+        let extended = encrypt_envelope_with(&server_pubkey, &Platform::Android, &long, None, Some(&push_key), cipher).unwrap();
+        assert_eq!(extended.len(), XCHACHA_EXTENDED_SCHEME.encrypted_size());
+        let decrypted = crypto.decrypt_token(&extended).unwrap();
+        assert_eq!((decrypted.device_token, decrypted.push_key), (long, Some(push_key)));
+
+        // Relabelling an XChaCha frame as ChaCha fails authentication
+        let truncated = [&standard[..EPHEMERAL_PUBKEY_SIZE + NONCE_SIZE], &standard[EPHEMERAL_PUBKEY_SIZE + XNONCE_SIZE..]].concat();
+        assert_eq!(truncated.len(), ENCRYPTED_TOKEN_SIZE);
+        assert!(matches!(crypto.decrypt_token(&truncated), Err(CryptoError::DecryptionFailed)));
+    }
 }
@@ -0,0 +1,133 @@
+//! Proof that a request comes from whoever holds the trade key: a BIP-340
+//! Schnorr signature, made with the trade key itself, over the SHA-256 of
+//! the 32-byte trade pubkey, the decoded envelope (empty when the request
+//! has none) and the signing time as 8 big-endian bytes of Unix seconds.
+//! Knowing a pubkey is then no longer enough to register under it.
+
+use secp256k1::schnorr::Signature;
+use secp256k1::{Message, Secp256k1, XOnlyPublicKey};
+use sha2::{Digest, Sha256};
+
+/// Signatures older than this, or as far ahead of the server's clock, are
+/// refused, so a captured request can't be replayed later.
+pub const MAX_SIGNATURE_AGE_SECS: u64 = 300;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureError {
+    /// No signature, when one is required
+    Missing,
+    /// Not hex, the wrong length, or a signature without a timestamp
+    Malformed,
+    /// The timestamp is outside `MAX_SIGNATURE_AGE_SECS` of now
+    Stale,
+    /// Doesn't verify against the trade pubkey
+    Invalid,
+}
+
+impl std::fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignatureError::Missing => write!(f, "A signature by trade_pubkey is required"),
+            SignatureError::Malformed => write!(f, "Malformed signature or timestamp"),
+            SignatureError::Stale => write!(
+                f,
+                "Signature timestamp is more than {} seconds from the server's time",
+                MAX_SIGNATURE_AGE_SECS
+            ),
+            SignatureError::Invalid => write!(f, "Signature does not match trade_pubkey"),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// The digest the trade key signs.
+pub fn signed_digest(trade_pubkey: &[u8; 32], encrypted_token: &[u8], timestamp: u64) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(trade_pubkey);
+    hasher.update(encrypted_token);
+    hasher.update(timestamp.to_be_bytes());
+    hasher.finalize().into()
+}
+
+/// Check a hex `signature` made at `timestamp` over the request, as of `now`
+/// in Unix seconds.
+pub fn verify_ownership(
+    trade_pubkey: &[u8; 32],
+    encrypted_token: &[u8],
+    timestamp: u64,
+    signature: &str,
+    now: u64,
+) -> Result<(), SignatureError> {
+    let signature = ::hex::decode(signature)
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        .ok_or(SignatureError::Malformed)?;
+    let pubkey = XOnlyPublicKey::from_slice(trade_pubkey).map_err(|_| SignatureError::Invalid)?;
+    if now.abs_diff(timestamp) > MAX_SIGNATURE_AGE_SECS {
+        return Err(SignatureError::Stale);
+    }
+    let message = Message::from_digest(signed_digest(trade_pubkey, encrypted_token, timestamp));
+    Secp256k1::verification_only()
+        .verify_schnorr(&signature, &message, &pubkey)
+        .map_err(|_| SignatureError::Invalid)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use secp256k1::{Keypair, SecretKey};
+
+    /// Trade key of the tests, and its x-only pubkey.
+    pub(crate) fn test_trade_keypair() -> Keypair {
+        let secret = SecretKey::from_slice(&[0x42; 32]).unwrap();
+        Keypair::from_secret_key(&Secp256k1::new(), &secret)
+    }
+
+    /// Hex signature by `keypair` over the request.
+    pub(crate) fn sign_request(keypair: &Keypair, encrypted_token: &[u8], timestamp: u64) -> String {
+        let trade_pubkey = keypair.x_only_public_key().0.serialize();
+        let message = Message::from_digest(signed_digest(&trade_pubkey, encrypted_token, timestamp));
+        ::hex::encode(Secp256k1::new().sign_schnorr_no_aux_rand(&message, keypair).serialize())
+    }
+
+    #[test]
+    fn test_signature_proves_ownership_of_the_exact_request() {
+        let keypair = test_trade_keypair();
+        let trade_pubkey = keypair.x_only_public_key().0.serialize();
+        let envelope = [7u8; 281];
+        let now = 1_700_000_000;
+        let signature = sign_request(&keypair, &envelope, now - 60);
+
+        assert_eq!(verify_ownership(&trade_pubkey, &envelope, now - 60, &signature, now), Ok(()));
+
+        // A tampered envelope, timestamp or pubkey no longer verifies
+        let mut tampered = envelope;
+        tampered[100] ^= 1;
+        assert_eq!(
+            verify_ownership(&trade_pubkey, &tampered, now - 60, &signature, now),
+            Err(SignatureError::Invalid)
+        );
+        assert_eq!(
+            verify_ownership(&trade_pubkey, &envelope, now - 59, &signature, now),
+            Err(SignatureError::Invalid)
+        );
+        let other = Keypair::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[0x43; 32]).unwrap());
+        let other_pubkey = other.x_only_public_key().0.serialize();
+        assert_eq!(
+            verify_ownership(&other_pubkey, &envelope, now - 60, &signature, now),
+            Err(SignatureError::Invalid)
+        );
+
+        // Replayed after the window, or garbled
+        let later = now + MAX_SIGNATURE_AGE_SECS;
+        assert_eq!(
+            verify_ownership(&trade_pubkey, &envelope, now - 60, &signature, later),
+            Err(SignatureError::Stale)
+        );
+        assert_eq!(
+            verify_ownership(&trade_pubkey, &envelope, now - 60, &signature[2..], now),
+            Err(SignatureError::Malformed)
+        );
+    }
+}
@@ -0,0 +1,315 @@
+//! Periodic operator summary for deployments without Prometheus, assembled
+//! from the same sources as `/api/status`, `/api/stats/delivery` and
+//! `/api/relays`.
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use nostr_sdk::prelude::{FromBech32, XOnlyPublicKey};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::health::RelayHealth;
+use crate::metrics::Metrics;
+use crate::models::{DeliveryCounts, DeliveryStatsResponse};
+use crate::nostr::NostrListener;
+use crate::push::delivery_stats::RETENTION;
+use crate::push::Dispatcher;
+use crate::scheduler::Task;
+use crate::store::TokenStore;
+use crate::utils::signing::SigningClient;
+
+/// Error classes listed in a digest.
+const TOP_ERRORS: usize = 5;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ErrorClassCount {
+    pub class: String,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Digest {
+    pub period_start: DateTime<Utc>,
+    pub period_end: DateTime<Utc>,
+    /// Registrations stored at the end of the period, and the change over it
+    pub registered: usize,
+    pub registered_delta: i64,
+    /// Register calls during the period, token refreshes included
+    pub registrations: u64,
+    pub pushes_sent: u64,
+    pub pushes_failed: u64,
+    /// Per-platform outcomes over the period, capped at the retained history
+    pub delivery: DeliveryStatsResponse,
+    pub top_errors: Vec<ErrorClassCount>,
+    pub relays_connected: usize,
+    pub relays_total: usize,
+    pub relays_down: Vec<String>,
+    /// Pushes held back by provider quotas, not yet sent
+    pub delayed_pushes: usize,
+}
+
+impl Digest {
+    /// Plain-text form for files and DMs.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "Mostro push digest, {} to {}",
+            self.period_start.format("%Y-%m-%d %H:%M UTC"),
+            self.period_end.format("%Y-%m-%d %H:%M UTC")
+        );
+        let _ = writeln!(
+            out,
+            "Registrations: {} ({:+}), {} register call(s)",
+            self.registered, self.registered_delta, self.registrations
+        );
+        let _ = writeln!(out, "Pushes: {} sent, {} failed", self.pushes_sent, self.pushes_failed);
+        let _ = write!(out, "Success rate (last {}h):", self.delivery.window_secs / 3600);
+        for (platform, counts) in &self.delivery.platforms {
+            let _ = write!(out, " {} {}", platform, format_rate(counts));
+        }
+        out.push('\n');
+        if self.top_errors.is_empty() {
+            out.push_str("Top errors: none\n");
+        } else {
+            out.push_str("Top errors:\n");
+            for error in &self.top_errors {
+                let _ = writeln!(out, "  {} ({})", error.class, error.count);
+            }
+        }
+        let _ = write!(out, "Relays: {}/{} connected", self.relays_connected, self.relays_total);
+        if !self.relays_down.is_empty() {
+            let _ = write!(out, " (down: {})", self.relays_down.join(", "));
+        }
+        out.push('\n');
+        let _ = writeln!(out, "Delayed pushes: {}", self.delayed_pushes);
+        out
+    }
+}
+
+fn format_rate(counts: &DeliveryCounts) -> String {
+    let total = counts.succeeded + counts.failed;
+    match counts.success_rate {
+        Some(rate) => format!("{:.1}% ({}/{})", rate * 100.0, counts.succeeded, total),
+        None => "n/a (0/0)".to_string(),
+    }
+}
+
+/// Everything a digest reads.
+pub struct DigestSources {
+    pub token_store: Arc<dyn TokenStore>,
+    pub metrics: Arc<Metrics>,
+    pub dispatcher: Arc<Dispatcher>,
+    pub relay_health: Arc<RelayHealth>,
+}
+
+/// Counter values at the start of the current period.
+struct Baseline {
+    at: DateTime<Utc>,
+    registered: usize,
+    registrations: u64,
+    pushes_sent: u64,
+    pushes_failed: u64,
+    errors: BTreeMap<String, u64>,
+}
+
+/// Assembles digests, each covering the time since the previous one.
+pub struct Digester {
+    sources: DigestSources,
+    baseline: Baseline,
+}
+
+impl Digester {
+    pub async fn new(sources: DigestSources, now: DateTime<Utc>) -> Self {
+        let baseline = Self::baseline(&sources, now).await;
+        Self { sources, baseline }
+    }
+
+    async fn baseline(sources: &DigestSources, now: DateTime<Utc>) -> Baseline {
+        Baseline {
+            at: now,
+            registered: sources.token_store.count().await,
+            registrations: Metrics::get(&sources.metrics.registrations),
+            pushes_sent: Metrics::get(&sources.metrics.pushes_sent),
+            pushes_failed: Metrics::get(&sources.metrics.pushes_failed),
+            errors: sources.metrics.push_errors(),
+        }
+    }
+
+    /// The digest for the period ending `now`, which starts the next period.
+    pub async fn assemble(&mut self, now: DateTime<Utc>) -> Digest {
+        let current = Self::baseline(&self.sources, now).await;
+        let previous = std::mem::replace(&mut self.baseline, current);
+        let current = &self.baseline;
+
+        let mut top_errors: Vec<ErrorClassCount> = current
+            .errors
+            .iter()
+            .map(|(class, count)| ErrorClassCount {
+                class: class.clone(),
+                count: count - previous.errors.get(class).copied().unwrap_or(0),
+            })
+            .filter(|error| error.count > 0)
+            .collect();
+        top_errors.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.class.cmp(&b.class)));
+        top_errors.truncate(TOP_ERRORS);
+
+        let relays = self.sources.relay_health.snapshot();
+        let mut relays_down: Vec<String> = relays.iter().filter(|r| !r.connected).map(|r| r.url.clone()).collect();
+        relays_down.sort();
+
+        let period = (now - previous.at).to_std().unwrap_or_default();
+        Digest {
+            period_start: previous.at,
+            period_end: now,
+            registered: current.registered,
+            registered_delta: current.registered as i64 - previous.registered as i64,
+            registrations: current.registrations - previous.registrations,
+            pushes_sent: current.pushes_sent - previous.pushes_sent,
+            pushes_failed: current.pushes_failed - previous.pushes_failed,
+            delivery: self.sources.dispatcher.delivery_stats(period.min(RETENTION)),
+            top_errors,
+            relays_connected: relays.len() - relays_down.len(),
+            relays_total: relays.len(),
+            relays_down,
+            delayed_pushes: self.sources.dispatcher.quota_status().iter().map(|q| q.queued).sum(),
+        }
+    }
+}
+
+/// The operator's pubkey, as an npub or hex.
+pub fn parse_operator(value: &str) -> Option<XOnlyPublicKey> {
+    XOnlyPublicKey::from_bech32(value)
+        .ok()
+        .or_else(|| XOnlyPublicKey::from_str(value).ok())
+}
+
+/// Where digests are sent; any combination may be configured.
+pub struct DigestDelivery {
+    /// Rendered digests are appended here
+    pub path: Option<PathBuf>,
+    /// Receives each digest as JSON
+    pub webhook_url: Option<String>,
+    /// Operator to DM from the listener's identity
+    pub dm: Option<(Arc<NostrListener>, XOnlyPublicKey)>,
+    pub client: SigningClient,
+}
+
+impl DigestDelivery {
+    pub async fn deliver(&self, digest: &Digest) {
+        let text = digest.render();
+
+        if let Some(path) = &self.path {
+            let written = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", text));
+            if let Err(e) = written {
+                warn!("Failed to write digest to {}: {}", path.display(), e);
+            }
+        }
+
+        if let Some(url) = &self.webhook_url {
+            match self.client.send(self.client.post(url).json(digest)).await {
+                Ok(response) if !response.status().is_success() => {
+                    warn!("Digest webhook returned {}", response.status());
+                }
+                Err(e) => warn!("Digest webhook failed: {}", e),
+                Ok(_) => {}
+            }
+        }
+
+        if let Some((listener, operator)) = &self.dm {
+            if let Err(e) = listener.send_direct_message(*operator, &text).await {
+                warn!("Failed to DM digest to operator: {}", e);
+            }
+        }
+    }
+}
+
+/// Assemble and deliver a digest every `interval`.
+pub fn digest_task(digester: Digester, delivery: DigestDelivery, interval: Duration) -> Task {
+    let state = Arc::new(tokio::sync::Mutex::new((digester, delivery)));
+    Task::every("digest", interval, move || {
+        let state = state.clone();
+        async move {
+            let (digester, delivery) = &mut *state.lock().await;
+            let digest = digester.assemble(Utc::now()).await;
+            info!(
+                "Sending digest: {} registered, {} pushes sent, {} failed",
+                digest.registered, digest.pushes_sent, digest.pushes_failed
+            );
+            delivery.deliver(&digest).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Platform;
+    use crate::push::testing::MockPush;
+    use crate::push::{PushPayload, PushService};
+    use crate::store::{MemoryTokenStore, RegisteredToken};
+    use tokio::sync::RwLock;
+
+    const PUBKEY_A: &str = "a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2";
+    const PUBKEY_B: &str = "b1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2";
+
+    #[tokio::test]
+    async fn test_digest_content() {
+        let (android, _) = MockPush::new();
+        let (ios, _) = MockPush::new();
+        let services: Vec<Box<dyn PushService>> = vec![
+            Box::new(android.serving("fcm", Platform::Android)),
+            Box::new(ios.serving("apns", Platform::Ios).failing()),
+        ];
+        let metrics = Arc::new(Metrics::new());
+        let dispatcher = Arc::new(Dispatcher::new(Arc::new(RwLock::new(services)), metrics.clone()));
+        let token_store = Arc::new(MemoryTokenStore::new(48));
+        let relay_health = Arc::new(RelayHealth::new());
+        relay_health.set_connected("wss://a.example", true);
+        relay_health.set_connected("wss://b.example", false);
+        token_store.register(PUBKEY_A.to_string(), "fcm-token".to_string(), Platform::Android).await;
+
+        let start = "2024-05-01T00:00:00Z".parse().unwrap();
+        let mut digester = Digester::new(
+            DigestSources { token_store: token_store.clone(), metrics: metrics.clone(), dispatcher: dispatcher.clone(), relay_health },
+            start,
+        )
+        .await;
+
+        token_store.register(PUBKEY_B.to_string(), "apns-token".to_string(), Platform::Ios).await;
+        Metrics::inc(&metrics.registrations);
+        let android_token = RegisteredToken::new("fcm-token".to_string(), Platform::Android);
+        let ios_token = RegisteredToken::new("apns-token".to_string(), Platform::Ios);
+        for _ in 0..3 {
+            dispatcher.dispatch(&android_token, &PushPayload::silent_wake()).await;
+        }
+        dispatcher.dispatch(&ios_token, &PushPayload::silent_wake()).await;
+
+        let digest = digester.assemble("2024-05-08T00:00:00Z".parse().unwrap()).await;
+        assert_eq!(
+            digest.render(),
+            "Mostro push digest, 2024-05-01 00:00 UTC to 2024-05-08 00:00 UTC\n\
+             Registrations: 2 (+1), 1 register call(s)\n\
+             Pushes: 3 sent, 1 failed\n\
+             Success rate (last 24h): android 100.0% (3/3) ios 0.0% (0/1)\n\
+             Top errors:\n  apns: mock provider rejected the push (1)\n\
+             Relays: 1/2 connected (down: wss://b.example)\n\
+             Delayed pushes: 0\n"
+        );
+
+        // The next period starts where this one ended
+        let digest = digester.assemble("2024-05-08T01:00:00Z".parse().unwrap()).await;
+        assert_eq!((digest.registered_delta, digest.pushes_sent), (0, 0));
+        assert!(digest.top_errors.is_empty());
+    }
+}
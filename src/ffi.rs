@@ -0,0 +1,184 @@
+//! C ABI for client-side token encryption, so mobile apps link the same code
+//! the server is tested against instead of reimplementing the envelope.
+//!
+//! Ownership: the library never allocates memory for the caller, and never
+//! keeps or frees a caller's pointer. Inputs are read during the call only.
+//! Output goes to a buffer the caller owns, sized with
+//! `mostro_push_envelope_size`, which fits the envelope of any token.
+
+use secp256k1::PublicKey;
+use std::panic::catch_unwind;
+
+use crate::crypto::{self, CryptoError, Platform, ENCRYPTED_TOKEN_SIZE};
+
+pub const MOSTRO_PUSH_OK: i32 = 0;
+/// A required pointer was null
+pub const MOSTRO_PUSH_ERR_NULL_POINTER: i32 = 1;
+/// The server public key is not a valid 33-byte compressed secp256k1 key
+pub const MOSTRO_PUSH_ERR_INVALID_PUBKEY: i32 = 2;
+/// Platform is not one of the `MOSTRO_PUSH_PLATFORM_*` constants
+pub const MOSTRO_PUSH_ERR_INVALID_PLATFORM: i32 = 3;
+/// The device token is empty, too long or not UTF-8
+pub const MOSTRO_PUSH_ERR_INVALID_TOKEN: i32 = 4;
+/// The output buffer is too small for the envelope; `mostro_push_envelope_size()` always fits
+pub const MOSTRO_PUSH_ERR_BUFFER_TOO_SMALL: i32 = 5;
+pub const MOSTRO_PUSH_ERR_INTERNAL: i32 = 6;
+
+pub const MOSTRO_PUSH_PLATFORM_IOS: u8 = 0x01;
+pub const MOSTRO_PUSH_PLATFORM_ANDROID: u8 = 0x02;
+/// Web Push; the device token is the browser's subscription as JSON
+pub const MOSTRO_PUSH_PLATFORM_WEB: u8 = 0x03;
+
+/// Largest encrypted token in bytes. Tokens too long for the standard
+/// envelope get a larger one, up to this size.
+#[no_mangle]
+pub extern "C" fn mostro_push_envelope_size() -> usize {
+    // Envelopes made here always use the default cipher
+    crypto::SCHEMES
+        .iter()
+        .filter(|scheme| scheme.cipher == crypto::EnvelopeCipher::default())
+        .map(|scheme| scheme.encrypted_size())
+        .max()
+        .unwrap_or(ENCRYPTED_TOKEN_SIZE)
+}
+
+/// Encrypt `device_token` to the server's compressed public key.
+///
+/// `trade_pubkey` may be null for a v1 envelope; otherwise it must point to
+/// the 32-byte trade pubkey, producing a v2 envelope bound to it. On success
+/// the envelope is written to `out`, at most `mostro_push_envelope_size()`
+/// bytes, and its length is stored in `written`. On error nothing is written
+/// to `out`.
+///
+/// # Safety
+///
+/// Every non-null pointer must be valid for the length given with it (32
+/// bytes for `trade_pubkey`) for the duration of the call, and `written`
+/// must be valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn mostro_push_encrypt_token(
+    server_pubkey: *const u8,
+    server_pubkey_len: usize,
+    platform: u8,
+    device_token: *const u8,
+    device_token_len: usize,
+    trade_pubkey: *const u8,
+    out: *mut u8,
+    out_len: usize,
+    written: *mut usize,
+) -> i32 {
+    if server_pubkey.is_null() || device_token.is_null() || out.is_null() || written.is_null() {
+        return MOSTRO_PUSH_ERR_NULL_POINTER;
+    }
+    if out_len < ENCRYPTED_TOKEN_SIZE {
+        return MOSTRO_PUSH_ERR_BUFFER_TOO_SMALL;
+    }
+
+    let server_pubkey = std::slice::from_raw_parts(server_pubkey, server_pubkey_len);
+    let device_token = std::slice::from_raw_parts(device_token, device_token_len);
+    let trade_pubkey = (!trade_pubkey.is_null()).then(|| std::slice::from_raw_parts(trade_pubkey, 32));
+
+    let result = catch_unwind(|| encrypt(server_pubkey, platform, device_token, trade_pubkey));
+    match result {
+        // A long token may need a larger envelope than the buffer holds
+        Ok(Ok(envelope)) if envelope.len() > out_len => MOSTRO_PUSH_ERR_BUFFER_TOO_SMALL,
+        Ok(Ok(envelope)) => {
+            std::ptr::copy_nonoverlapping(envelope.as_ptr(), out, envelope.len());
+            *written = envelope.len();
+            MOSTRO_PUSH_OK
+        }
+        Ok(Err(code)) => code,
+        Err(_) => MOSTRO_PUSH_ERR_INTERNAL,
+    }
+}
+
+fn encrypt(
+    server_pubkey: &[u8],
+    platform: u8,
+    device_token: &[u8],
+    trade_pubkey: Option<&[u8]>,
+) -> Result<Vec<u8>, i32> {
+    let server_pubkey = PublicKey::from_slice(server_pubkey).map_err(|_| MOSTRO_PUSH_ERR_INVALID_PUBKEY)?;
+    let platform = Platform::from_byte(platform).ok_or(MOSTRO_PUSH_ERR_INVALID_PLATFORM)?;
+    let device_token = std::str::from_utf8(device_token).map_err(|_| MOSTRO_PUSH_ERR_INVALID_TOKEN)?;
+
+    crypto::encrypt_envelope(&server_pubkey, &platform, device_token, trade_pubkey, None).map_err(|e| match e {
+        CryptoError::EmptyToken | CryptoError::InvalidTokenLength | CryptoError::ImplausibleToken => {
+            MOSTRO_PUSH_ERR_INVALID_TOKEN
+        }
+        _ => MOSTRO_PUSH_ERR_INTERNAL,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{TokenCrypto, ENVELOPE_V2};
+    use std::ptr;
+
+    const SERVER_KEY: &str = "ccc61d16dfd10fbcca1322fdf5fed6cb1863db4e27030ae164dbcbfcc263154d";
+
+    /// Round-trips through the C entry points and the server's decryption,
+    /// with the caller owning every buffer.
+    #[test]
+    fn test_encrypt_through_c_abi() {
+        let crypto = TokenCrypto::new(SERVER_KEY).unwrap();
+        let server_pubkey = hex::decode(crypto.public_key_hex()).unwrap();
+        let token = b"fcm-device-token";
+        let trade_pubkey = [7u8; 32];
+
+        let mut out = vec![0u8; mostro_push_envelope_size()];
+        let mut written = 0usize;
+        let code = unsafe {
+            mostro_push_encrypt_token(
+                server_pubkey.as_ptr(),
+                server_pubkey.len(),
+                MOSTRO_PUSH_PLATFORM_ANDROID,
+                token.as_ptr(),
+                token.len(),
+                trade_pubkey.as_ptr(),
+                out.as_mut_ptr(),
+                out.len(),
+                &mut written,
+            )
+        };
+        assert_eq!(code, MOSTRO_PUSH_OK);
+        assert_eq!(written, ENCRYPTED_TOKEN_SIZE);
+        let decrypted = crypto.decrypt_token_for(&out[..written], &trade_pubkey).unwrap();
+        assert_eq!(decrypted.device_token, "fcm-device-token");
+        assert_eq!(decrypted.envelope_version, ENVELOPE_V2);
+
+        let mut encrypt_into = |out: &mut [u8], platform: u8, token: &[u8]| unsafe {
+            mostro_push_encrypt_token(
+                server_pubkey.as_ptr(),
+                server_pubkey.len(),
+                platform,
+                token.as_ptr(),
+                token.len(),
+                ptr::null(),
+                out.as_mut_ptr(),
+                out.len(),
+                &mut written,
+            )
+        };
+        let mut short = vec![0u8; ENCRYPTED_TOKEN_SIZE - 1];
+        assert_eq!(encrypt_into(&mut short, MOSTRO_PUSH_PLATFORM_IOS, token), MOSTRO_PUSH_ERR_BUFFER_TOO_SMALL);
+        assert_eq!(encrypt_into(&mut out, 0x7f, token), MOSTRO_PUSH_ERR_INVALID_PLATFORM);
+        assert_eq!(encrypt_into(&mut out, MOSTRO_PUSH_PLATFORM_IOS, b""), MOSTRO_PUSH_ERR_INVALID_TOKEN);
+        assert_eq!(encrypt_into(&mut out, MOSTRO_PUSH_PLATFORM_IOS, &[0xff, 0xfe]), MOSTRO_PUSH_ERR_INVALID_TOKEN);
+        // A token too long for the standard envelope needs the full size
+        let long = "t".repeat(300);
+        let mut standard = vec![0u8; ENCRYPTED_TOKEN_SIZE];
+        assert_eq!(encrypt_into(&mut standard, MOSTRO_PUSH_PLATFORM_IOS, long.as_bytes()), MOSTRO_PUSH_ERR_BUFFER_TOO_SMALL);
+        assert_eq!(encrypt_into(&mut out, MOSTRO_PUSH_PLATFORM_IOS, long.as_bytes()), MOSTRO_PUSH_OK);
+        assert_eq!(crypto.decrypt_token(&out[..written]).unwrap().device_token, long);
+
+        let code = unsafe {
+            mostro_push_encrypt_token(
+                ptr::null(), 0, MOSTRO_PUSH_PLATFORM_IOS, token.as_ptr(), token.len(),
+                ptr::null(), out.as_mut_ptr(), out.len(), ptr::null_mut(),
+            )
+        };
+        assert_eq!(code, MOSTRO_PUSH_ERR_NULL_POINTER);
+    }
+}
@@ -0,0 +1,236 @@
+//! Feature flags for rolling risky dispatch changes out to a fraction of
+//! registrations. Each pubkey lands in a fixed bucket per flag, so a user
+//! keeps the same behavior for as long as the fraction doesn't move past it;
+//! per-pubkey overrides, set through `/admin/flags`, win over the fraction.
+
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use crate::metrics::Metrics;
+use crate::models::{FlagOverride, FlagStatus};
+use crate::push::{PushPayload, PushPriority};
+
+/// Collapse pending wake-ups for a device into one.
+pub const COLLAPSE_KEY: &str = "collapse_key";
+/// Send wake-ups at normal priority, which providers don't throttle for
+/// data-only messages the way they do high priority ones.
+pub const SILENT_PUSH: &str = "silent_push";
+
+/// Every flag the server knows; configuring or overriding others is refused.
+pub const FLAGS: &[&str] = &[COLLAPSE_KEY, SILENT_PUSH];
+
+/// Collapse key of wake-ups under `collapse_key`.
+const WAKE_COLLAPSE_KEY: &str = "silent_wake";
+/// Overrides kept per flag.
+const MAX_OVERRIDES: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FlagError {
+    UnknownFlag(String),
+    /// The flag has `MAX_OVERRIDES` overrides already
+    Full(usize),
+}
+
+impl std::fmt::Display for FlagError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FlagError::UnknownFlag(flag) => write!(f, "unknown flag {}", flag),
+            FlagError::Full(max) => write!(f, "already {} overrides for this flag", max),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct FeatureFlags {
+    /// Enabled fraction per flag, in [0, 1]
+    rollout: BTreeMap<String, f64>,
+    overrides: Mutex<HashMap<String, HashMap<String, bool>>>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl FeatureFlags {
+    pub fn new(rollout: BTreeMap<String, f64>) -> Self {
+        Self { rollout, ..Self::default() }
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Whether `flag` is on for `trade_pubkey`, counted in metrics.
+    pub fn is_enabled(&self, flag: &str, trade_pubkey: &str) -> bool {
+        let (enabled, _) = self.evaluate(flag, trade_pubkey);
+        if let Some(metrics) = &self.metrics {
+            metrics.record_flag_evaluation(flag, enabled);
+        }
+        enabled
+    }
+
+    /// Whether `flag` is on for `trade_pubkey`, and whether an override
+    /// decided it. Unknown flags are off.
+    pub fn evaluate(&self, flag: &str, trade_pubkey: &str) -> (bool, bool) {
+        let overridden = self
+            .overrides
+            .lock()
+            .unwrap()
+            .get(flag)
+            .and_then(|overrides| overrides.get(trade_pubkey).copied());
+        match overridden {
+            Some(enabled) => (enabled, true),
+            None => (bucket(flag, trade_pubkey) < self.fraction(flag), false),
+        }
+    }
+
+    pub fn fraction(&self, flag: &str) -> f64 {
+        self.rollout.get(flag).copied().unwrap_or(0.0)
+    }
+
+    pub fn set_override(&self, flag: &str, trade_pubkey: &str, enabled: bool) -> Result<(), FlagError> {
+        if !FLAGS.contains(&flag) {
+            return Err(FlagError::UnknownFlag(flag.to_string()));
+        }
+        let mut overrides = self.overrides.lock().unwrap();
+        let overrides = overrides.entry(flag.to_string()).or_default();
+        if !overrides.contains_key(trade_pubkey) && overrides.len() >= MAX_OVERRIDES {
+            return Err(FlagError::Full(MAX_OVERRIDES));
+        }
+        overrides.insert(trade_pubkey.to_string(), enabled);
+        Ok(())
+    }
+
+    /// Drop an override, returning the pubkey to the rollout fraction.
+    pub fn clear_override(&self, flag: &str, trade_pubkey: &str) -> bool {
+        self.overrides
+            .lock()
+            .unwrap()
+            .get_mut(flag)
+            .is_some_and(|overrides| overrides.remove(trade_pubkey).is_some())
+    }
+
+    pub fn status(&self) -> Vec<FlagStatus> {
+        let overrides = self.overrides.lock().unwrap();
+        FLAGS
+            .iter()
+            .map(|flag| {
+                let mut flag_overrides: Vec<_> = overrides
+                    .get(*flag)
+                    .into_iter()
+                    .flatten()
+                    .map(|(trade_pubkey, enabled)| FlagOverride {
+                        trade_pubkey: trade_pubkey.clone(),
+                        enabled: *enabled,
+                    })
+                    .collect();
+                flag_overrides.sort_by(|a, b| a.trade_pubkey.cmp(&b.trade_pubkey));
+                FlagStatus {
+                    flag: flag.to_string(),
+                    fraction: self.fraction(flag),
+                    overrides: flag_overrides,
+                }
+            })
+            .collect()
+    }
+
+    /// The wake-up sent for an event to `trade_pubkey`, with the dispatch
+    /// behaviors its flags turn on.
+    pub fn wake_payload(&self, trade_pubkey: &str) -> PushPayload {
+        let mut payload = PushPayload::silent_wake();
+        if self.is_enabled(COLLAPSE_KEY, trade_pubkey) {
+            payload = payload.collapse_key(WAKE_COLLAPSE_KEY);
+        }
+        if self.is_enabled(SILENT_PUSH, trade_pubkey) {
+            payload = payload.priority(PushPriority::Normal);
+        }
+        payload
+    }
+}
+
+/// Position of `trade_pubkey` in [0, 1) for `flag`. Hashing the flag in
+/// keeps each flag's 10% from being the same users.
+fn bucket(flag: &str, trade_pubkey: &str) -> f64 {
+    let mut hasher = Sha256::new();
+    hasher.update(flag.as_bytes());
+    hasher.update([0]);
+    hasher.update(trade_pubkey.as_bytes());
+    let digest = hasher.finalize();
+    let value = u64::from_be_bytes(digest[..8].try_into().unwrap());
+    (value >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pubkey(i: usize) -> String {
+        format!("{:064x}", i)
+    }
+
+    fn flags(collapse: f64) -> FeatureFlags {
+        FeatureFlags::new(BTreeMap::from([(COLLAPSE_KEY.to_string(), collapse)]))
+    }
+
+    #[test]
+    fn test_rollout_assignment_is_stable_and_proportional() {
+        let tenth = flags(0.1);
+        let enabled: Vec<_> = (0..10_000).filter(|i| tenth.evaluate(COLLAPSE_KEY, &pubkey(*i)).0).collect();
+        assert!((900..1100).contains(&enabled.len()), "{} of 10000 enabled", enabled.len());
+
+        // The same pubkeys every time, and raising the fraction only adds pubkeys
+        let again: Vec<_> = (0..10_000).filter(|i| flags(0.1).evaluate(COLLAPSE_KEY, &pubkey(*i)).0).collect();
+        assert_eq!(enabled, again);
+        let half = flags(0.5);
+        assert!(enabled.iter().all(|i| half.evaluate(COLLAPSE_KEY, &pubkey(*i)).0));
+
+        // Each flag picks its own users
+        let both = FeatureFlags::new(BTreeMap::from([
+            (COLLAPSE_KEY.to_string(), 0.1),
+            (SILENT_PUSH.to_string(), 0.1),
+        ]));
+        let silent: Vec<_> = (0..10_000).filter(|i| both.evaluate(SILENT_PUSH, &pubkey(*i)).0).collect();
+        assert_ne!(enabled, silent);
+
+        // The bounds are all or nothing, and unconfigured flags are off
+        assert!((0..1000).all(|i| flags(1.0).evaluate(COLLAPSE_KEY, &pubkey(i)).0));
+        assert!((0..1000).all(|i| !flags(0.0).evaluate(COLLAPSE_KEY, &pubkey(i)).0));
+        assert!((0..1000).all(|i| !flags(1.0).evaluate(SILENT_PUSH, &pubkey(i)).0));
+    }
+
+    #[test]
+    fn test_overrides_win_over_rollout() {
+        let metrics = Arc::new(Metrics::new());
+        let none = flags(0.0).with_metrics(metrics.clone());
+        let all = flags(1.0);
+        let user = pubkey(7);
+
+        assert!(none.set_override(COLLAPSE_KEY, &user, true).is_ok());
+        assert!(all.set_override(COLLAPSE_KEY, &user, false).is_ok());
+        assert_eq!(none.evaluate(COLLAPSE_KEY, &user), (true, true));
+        assert_eq!(all.evaluate(COLLAPSE_KEY, &user), (false, true));
+        // Overrides are per flag and per pubkey
+        assert_eq!(none.evaluate(SILENT_PUSH, &user), (false, false));
+        assert_eq!(none.evaluate(COLLAPSE_KEY, &pubkey(8)), (false, false));
+
+        // Enabling a flag through an override applies it to the payload, counted per arm
+        let payload = none.wake_payload(&user);
+        assert_eq!(payload.collapse_key.as_deref(), Some(WAKE_COLLAPSE_KEY));
+        assert_eq!(payload.priority, PushPriority::High);
+        let rendered = metrics.render();
+        assert!(rendered.contains("mostro_push_flag_evaluations_total{flag=\"collapse_key\",arm=\"on\"} 1"));
+        assert!(rendered.contains("mostro_push_flag_evaluations_total{flag=\"silent_push\",arm=\"off\"} 1"));
+
+        // Clearing returns the pubkey to the fraction
+        assert!(all.clear_override(COLLAPSE_KEY, &user));
+        assert!(!all.clear_override(COLLAPSE_KEY, &user));
+        assert_eq!(all.evaluate(COLLAPSE_KEY, &user), (true, false));
+
+        assert_eq!(
+            none.set_override("nonexistent", &user, true),
+            Err(FlagError::UnknownFlag("nonexistent".to_string()))
+        );
+        let status = none.status();
+        assert_eq!(status[0].overrides, vec![FlagOverride { trade_pubkey: user, enabled: true }]);
+        assert!(status[1].overrides.is_empty());
+    }
+}
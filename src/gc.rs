@@ -0,0 +1,214 @@
+//! Garbage collection of persisted auxiliary state: the delivery ledger, the
+//! event trace and the audit log. Each dataset has its own retention policy,
+//! an age and a size cap, and compacts its storage when entries go.
+//!
+//! Entries still referenced by in-flight work are never collected; each
+//! dataset knows its own references (e.g. ledger claims held for a push
+//! waiting in a quota queue).
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::Deserialize;
+use std::fs::{File, OpenOptions};
+use std::io::{LineWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::metrics::Metrics;
+use crate::models::GcReport;
+use crate::scheduler::Task;
+
+/// How much of a dataset to keep; unbounded on both axes by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+pub struct RetentionPolicy {
+    pub max_age: Option<Duration>,
+    /// Bytes of storage; the oldest entries go first
+    pub max_bytes: Option<u64>,
+}
+
+impl RetentionPolicy {
+    pub fn is_unbounded(&self) -> bool {
+        self.max_age.is_none() && self.max_bytes.is_none()
+    }
+
+    /// Entries from before this are too old, if an age is set.
+    pub fn cutoff(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let max_age = chrono::Duration::from_std(self.max_age?).ok()?;
+        Some(now.checked_sub_signed(max_age).unwrap_or(DateTime::<Utc>::MIN_UTC))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Compaction {
+    pub removed: u64,
+    pub reclaimed_bytes: u64,
+}
+
+#[async_trait]
+pub trait Dataset: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Drop entries outside `policy`, except those in-flight work still
+    /// references, and shrink the storage to match.
+    async fn compact(&self, policy: &RetentionPolicy, now: DateTime<Utc>) -> Result<Compaction, String>;
+}
+
+pub struct Collector {
+    datasets: Vec<(Arc<dyn Dataset>, RetentionPolicy)>,
+    metrics: Arc<Metrics>,
+}
+
+impl Collector {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self { datasets: Vec::new(), metrics }
+    }
+
+    pub fn with_dataset(mut self, dataset: Arc<dyn Dataset>, policy: RetentionPolicy) -> Self {
+        self.datasets.push((dataset, policy));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.datasets.is_empty()
+    }
+
+    /// Compact every dataset in turn; one failing doesn't stop the others.
+    pub async fn run(&self) -> Vec<GcReport> {
+        let now = Utc::now();
+        let mut reports = Vec::new();
+        for (dataset, policy) in &self.datasets {
+            let report = match dataset.compact(policy, now).await {
+                Ok(compaction) => {
+                    self.metrics.record_gc(dataset.name(), compaction.removed, compaction.reclaimed_bytes);
+                    if compaction.removed > 0 {
+                        info!(
+                            "GC removed {} {} entries, reclaiming {} bytes",
+                            compaction.removed,
+                            dataset.name(),
+                            compaction.reclaimed_bytes
+                        );
+                    }
+                    GcReport {
+                        dataset: dataset.name().to_string(),
+                        removed: compaction.removed,
+                        reclaimed_bytes: compaction.reclaimed_bytes,
+                        error: None,
+                    }
+                }
+                Err(e) => {
+                    warn!("GC of {} failed: {}", dataset.name(), e);
+                    GcReport {
+                        dataset: dataset.name().to_string(),
+                        removed: 0,
+                        reclaimed_bytes: 0,
+                        error: Some(e),
+                    }
+                }
+            };
+            reports.push(report);
+        }
+        reports
+    }
+}
+
+pub fn gc_task(collector: Arc<Collector>, interval: Duration) -> Task {
+    Task::every("gc", interval, move || {
+        let collector = collector.clone();
+        async move {
+            collector.run().await;
+        }
+    })
+}
+
+#[derive(Deserialize)]
+struct Stamped {
+    at: DateTime<Utc>,
+}
+
+/// Rewrite a JSON-lines file whose records carry an `at` time, keeping those
+/// within `policy`, then point `writer` at the new file. Callers hold the
+/// writer's lock throughout, so no append lands in the replaced file.
+pub fn compact_jsonl(
+    path: &Path,
+    writer: &mut LineWriter<File>,
+    policy: &RetentionPolicy,
+    now: DateTime<Utc>,
+) -> std::io::Result<Compaction> {
+    writer.flush()?;
+    let content = std::fs::read_to_string(path)?;
+    let lines: Vec<&str> = content.lines().filter(|line| !line.trim().is_empty()).collect();
+
+    // Lines without a readable time are kept rather than guessed at
+    let cutoff = policy.cutoff(now);
+    let mut kept: Vec<&str> = lines
+        .iter()
+        .copied()
+        .filter(|line| match (cutoff, serde_json::from_str::<Stamped>(line)) {
+            (Some(cutoff), Ok(stamped)) => stamped.at >= cutoff,
+            _ => true,
+        })
+        .collect();
+    if let Some(max_bytes) = policy.max_bytes {
+        let mut size = 0;
+        let first = kept
+            .iter()
+            .rposition(|line| {
+                size += line.len() as u64 + 1;
+                size > max_bytes
+            })
+            .map_or(0, |index| index + 1);
+        kept.drain(..first);
+    }
+
+    let compacted: String = kept.iter().flat_map(|line| [*line, "\n"]).collect();
+    if compacted.len() == content.len() {
+        return Ok(Compaction::default());
+    }
+    let temp_path = path.with_extension("gc.tmp");
+    std::fs::write(&temp_path, &compacted)?;
+    std::fs::rename(&temp_path, path)?;
+    *writer = LineWriter::new(OpenOptions::new().create(true).append(true).open(path)?);
+
+    Ok(Compaction {
+        removed: (lines.len() - kept.len()) as u64,
+        reclaimed_bytes: (content.len() - compacted.len()) as u64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jsonl_compaction_applies_age_then_size() {
+        let path = std::env::temp_dir().join(format!("mostro-push-gc-{}.jsonl", std::process::id()));
+        let now = Utc::now();
+        let line = |age_secs: i64, n: usize| {
+            format!("{{\"at\":\"{}\",\"n\":{}}}", (now - chrono::Duration::seconds(age_secs)).to_rfc3339(), n)
+        };
+        let lines = [line(7200, 0), "not json".to_string(), line(60, 2), line(30, 3), line(10, 4)];
+        std::fs::write(&path, lines.iter().map(|l| format!("{}\n", l)).collect::<String>()).unwrap();
+        let mut writer = LineWriter::new(OpenOptions::new().append(true).open(&path).unwrap());
+
+        // The hour-old line goes; the unreadable one stays
+        let policy = RetentionPolicy { max_age: Some(Duration::from_secs(3600)), max_bytes: None };
+        let compaction = compact_jsonl(&path, &mut writer, &policy, now).unwrap();
+        assert_eq!(compaction.removed, 1);
+        assert_eq!(compaction.reclaimed_bytes, lines[0].len() as u64 + 1);
+
+        // A size cap keeps the newest lines that fit
+        let fits = (lines[3].len() + lines[4].len() + 2) as u64;
+        let policy = RetentionPolicy { max_age: None, max_bytes: Some(fits) };
+        let compaction = compact_jsonl(&path, &mut writer, &policy, now).unwrap();
+        assert_eq!(compaction.removed, 2);
+        assert_eq!(compact_jsonl(&path, &mut writer, &policy, now).unwrap(), Compaction::default());
+
+        // Appends land in the compacted file
+        writeln!(writer, "{}", line(0, 5)).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(content, format!("{}\n{}\n{}\n", lines[3], lines[4], line(0, 5)));
+    }
+}
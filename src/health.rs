@@ -0,0 +1,308 @@
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::models::{RelayInfo, RelayReason, RelayReasonKind, WarmupState, WarmupStatus};
+
+/// Reconnect delay after a relay says we are rate limited.
+pub const RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(60);
+
+/// When the process started, for the uptime `/status` reports.
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessStart {
+    instant: Instant,
+    pub at: DateTime<Utc>,
+}
+
+impl ProcessStart {
+    pub fn now() -> Self {
+        Self { instant: Instant::now(), at: Utc::now() }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.instant.elapsed()
+    }
+}
+
+/// Startup readiness shared between the HTTP API and the Nostr listener.
+///
+/// Registrations are only accepted once persisted state has been loaded, any
+/// warmup has finished and at least `min_relays_connected` relays are up.
+#[derive(Debug)]
+pub struct Readiness {
+    store_loaded: AtomicBool,
+    warmed_up: AtomicBool,
+    min_relays_connected: usize,
+    connected_relays: Mutex<HashSet<String>>,
+    warmup: Mutex<Option<WarmupStatus>>,
+    /// Failed listener connect cycles since the last successful one
+    listener_failures: AtomicU32,
+    /// Failures after which the listener counts as dead; never when 0
+    listener_failure_threshold: u32,
+}
+
+impl Readiness {
+    pub fn new(min_relays_connected: usize) -> Self {
+        Self {
+            store_loaded: AtomicBool::new(false),
+            warmed_up: AtomicBool::new(true),
+            min_relays_connected,
+            connected_relays: Mutex::new(HashSet::new()),
+            warmup: Mutex::new(None),
+            listener_failures: AtomicU32::new(0),
+            listener_failure_threshold: 0,
+        }
+    }
+
+    pub fn with_listener_failure_threshold(mut self, threshold: u32) -> Self {
+        self.listener_failure_threshold = threshold;
+        self
+    }
+
+    /// Hold readiness back until `finish_warmup`.
+    pub fn begin_warmup(&self, status: WarmupStatus) {
+        self.warmed_up.store(false, Ordering::Relaxed);
+        *self.warmup.lock().unwrap() = Some(status);
+    }
+
+    pub fn update_warmup(&self, update: impl FnOnce(&mut WarmupStatus)) {
+        if let Some(status) = self.warmup.lock().unwrap().as_mut() {
+            update(status);
+        }
+    }
+
+    /// Record how warmup ended and let readiness flip.
+    pub fn finish_warmup(&self, duration: Duration, state: impl FnOnce(&WarmupStatus) -> WarmupState) {
+        if let Some(status) = self.warmup.lock().unwrap().as_mut() {
+            status.state = state(status);
+            status.duration_ms = Some(duration.as_millis() as u64);
+        }
+        self.warmed_up.store(true, Ordering::Relaxed);
+    }
+
+    pub fn warmup_status(&self) -> Option<WarmupStatus> {
+        self.warmup.lock().unwrap().clone()
+    }
+
+    pub fn mark_store_loaded(&self) {
+        self.store_loaded.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_relay_connected(&self, relay_url: &str, connected: bool) {
+        let mut relays = self.connected_relays.lock().unwrap();
+        if connected {
+            relays.insert(relay_url.to_string());
+        } else {
+            relays.remove(relay_url);
+        }
+    }
+
+    /// Forget all relay states, e.g. when the listener rebuilds its client.
+    pub fn reset_relays(&self) {
+        self.connected_relays.lock().unwrap().clear();
+    }
+
+    pub fn relays_connected(&self) -> usize {
+        self.connected_relays.lock().unwrap().len()
+    }
+
+    /// Count a failed listener connect cycle. Returns true once the failures
+    /// in a row reach the threshold.
+    pub fn record_listener_failure(&self) -> bool {
+        let failures = self.listener_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        self.listener_failure_threshold > 0 && failures >= self.listener_failure_threshold
+    }
+
+    pub fn record_listener_success(&self) {
+        self.listener_failures.store(0, Ordering::Relaxed);
+    }
+
+    pub fn listener_failures(&self) -> u32 {
+        self.listener_failures.load(Ordering::Relaxed)
+    }
+
+    /// False while the listener has failed `listener_failure_threshold`
+    /// connect cycles in a row.
+    pub fn is_listener_healthy(&self) -> bool {
+        self.listener_failure_threshold == 0 || self.listener_failures() < self.listener_failure_threshold
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.store_loaded.load(Ordering::Relaxed)
+            && self.warmed_up.load(Ordering::Relaxed)
+            && self.relays_connected() >= self.min_relays_connected
+    }
+}
+
+/// Parse the machine-readable prefix of a NOTICE/CLOSED message,
+/// e.g. "rate-limited: slow down" or "pow: difficulty 20 required".
+pub fn parse_reason(message: &str) -> RelayReasonKind {
+    let prefix = match message.split_once(':') {
+        Some((prefix, _)) => prefix,
+        None => message,
+    };
+    match prefix.trim().to_lowercase().as_str() {
+        "rate-limited" | "rate limited" => RelayReasonKind::RateLimited,
+        "pow" | "pow required" | "pow-required" => RelayReasonKind::PowRequired,
+        "restricted" => RelayReasonKind::Restricted,
+        "auth-required" => RelayReasonKind::AuthRequired,
+        "blocked" => RelayReasonKind::Blocked,
+        "invalid" => RelayReasonKind::Invalid,
+        "duplicate" => RelayReasonKind::Duplicate,
+        "error" => RelayReasonKind::Error,
+        _ => RelayReasonKind::Other,
+    }
+}
+
+/// What the listener should change in response to a relay reason.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ReasonAction {
+    /// Wait at least this long before reconnecting
+    pub backoff: Option<Duration>,
+    /// The relay won't serve us without NIP-42 auth
+    pub requires_auth: bool,
+}
+
+pub fn reason_action(kind: RelayReasonKind) -> ReasonAction {
+    match kind {
+        RelayReasonKind::RateLimited => ReasonAction {
+            backoff: Some(RATE_LIMIT_BACKOFF),
+            requires_auth: false,
+        },
+        RelayReasonKind::Restricted | RelayReasonKind::AuthRequired => ReasonAction {
+            backoff: None,
+            requires_auth: true,
+        },
+        _ => ReasonAction::default(),
+    }
+}
+
+#[derive(Debug, Default)]
+struct RelayState {
+    connected: bool,
+    requires_auth: bool,
+    last_reason: Option<RelayReason>,
+    backoff_until: Option<DateTime<Utc>>,
+}
+
+/// Per-relay connection state and the last NOTICE/CLOSED reason, for `/api/relays`.
+#[derive(Debug, Default)]
+pub struct RelayHealth {
+    relays: Mutex<BTreeMap<String, RelayState>>,
+}
+
+impl RelayHealth {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_connected(&self, relay_url: &str, connected: bool) {
+        self.relays.lock().unwrap().entry(relay_url.to_string()).or_default().connected = connected;
+    }
+
+    /// Record a NOTICE/CLOSED message and apply its behavior mapping.
+    pub fn record_reason(&self, relay_url: &str, message: &str, now: DateTime<Utc>) -> ReasonAction {
+        let kind = parse_reason(message);
+        let action = reason_action(kind);
+
+        let mut relays = self.relays.lock().unwrap();
+        let state = relays.entry(relay_url.to_string()).or_default();
+        state.last_reason = Some(RelayReason {
+            kind,
+            message: message.to_string(),
+            at: now,
+        });
+        state.requires_auth |= action.requires_auth;
+        if let Some(backoff) = action.backoff {
+            state.backoff_until = chrono::Duration::from_std(backoff).ok().map(|b| now + b);
+        }
+        action
+    }
+
+    /// Longest remaining backoff across relays.
+    pub fn backoff_remaining(&self, now: DateTime<Utc>) -> Option<Duration> {
+        self.relays
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(|state| state.backoff_until)
+            .filter_map(|until| (until - now).to_std().ok())
+            .max()
+    }
+
+    /// Forget rate-limit backoffs, e.g. when the operator forces a reconnect.
+    pub fn clear_backoff(&self) {
+        for state in self.relays.lock().unwrap().values_mut() {
+            state.backoff_until = None;
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<RelayInfo> {
+        self.relays
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(url, state)| RelayInfo {
+                url: url.clone(),
+                connected: state.connected,
+                requires_auth: state.requires_auth,
+                last_reason: state.last_reason.clone(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_reason_prefixes() {
+        let cases = [
+            ("rate-limited: you are noting too much", RelayReasonKind::RateLimited),
+            ("pow: difficulty 26 is required", RelayReasonKind::PowRequired),
+            ("pow required", RelayReasonKind::PowRequired),
+            ("restricted: not on the whitelist", RelayReasonKind::Restricted),
+            ("auth-required: we only serve authenticated users", RelayReasonKind::AuthRequired),
+            ("blocked: you are banned", RelayReasonKind::Blocked),
+            ("error: could not connect to the database", RelayReasonKind::Error),
+            ("Invalid: bad filter", RelayReasonKind::Invalid),
+            ("something went wrong", RelayReasonKind::Other),
+            ("", RelayReasonKind::Other),
+        ];
+        for (message, kind) in cases {
+            assert_eq!(parse_reason(message), kind, "{:?}", message);
+        }
+    }
+
+    #[test]
+    fn test_reason_behavior_mapping() {
+        assert_eq!(reason_action(RelayReasonKind::RateLimited).backoff, Some(RATE_LIMIT_BACKOFF));
+        assert!(reason_action(RelayReasonKind::Restricted).requires_auth);
+        assert!(reason_action(RelayReasonKind::AuthRequired).requires_auth);
+        assert_eq!(reason_action(RelayReasonKind::PowRequired), ReasonAction::default());
+        assert_eq!(reason_action(RelayReasonKind::Other), ReasonAction::default());
+    }
+
+    #[test]
+    fn test_relay_health_records_last_reason() {
+        let health = RelayHealth::new();
+        let now = Utc::now();
+        health.set_connected("wss://relay.one", true);
+
+        health.record_reason("wss://relay.one", "rate-limited: slow down", now);
+        assert_eq!(health.backoff_remaining(now), Some(RATE_LIMIT_BACKOFF));
+        assert_eq!(health.backoff_remaining(now + chrono::Duration::seconds(61)), None);
+
+        health.record_reason("wss://relay.one", "restricted: paid relay", now);
+        let relays = health.snapshot();
+        assert_eq!(relays.len(), 1);
+        assert!(relays[0].connected);
+        assert!(relays[0].requires_auth);
+        let reason = relays[0].last_reason.as_ref().unwrap();
+        assert_eq!(reason.kind, RelayReasonKind::Restricted);
+        assert_eq!(reason.at, now);
+    }
+}
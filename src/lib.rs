@@ -0,0 +1,23 @@
+pub mod alerts;
+pub mod audit;
+pub mod api;
+pub mod config;
+pub mod crypto;
+pub mod digest;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod flags;
+pub mod gc;
+pub mod health;
+pub mod metrics;
+pub mod models;
+pub mod nostr;
+pub mod push;
+pub mod replication;
+pub mod scheduler;
+pub mod security;
+pub mod sli;
+pub mod store;
+pub mod utils;
+pub mod warmup;
+pub mod watch;
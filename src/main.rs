@@ -1,50 +1,167 @@
+use actix_web::dev::Service;
 use actix_web::{web, App, HttpServer};
 use log::info;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-
-mod config;
-mod crypto;
-mod nostr;
-mod push;
-mod api;
-mod store;
-mod utils;
-
-use api::routes::AppState;
-use config::Config;
-use crypto::TokenCrypto;
-use nostr::NostrListener;
-use push::{PushService, FcmPush, UnifiedPushService};
-use store::TokenStore;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+use mostro_push_backend::{api, digest, gc, metrics, replication, security, store};
+use mostro_push_backend::replication::Leadership;
+use mostro_push_backend::scheduler::{Scheduler, Task};
+use mostro_push_backend::alerts::RegistrationAlerts;
+use mostro_push_backend::audit::AuditLog;
+use mostro_push_backend::gc::{Collector, RetentionPolicy};
+use mostro_push_backend::api::bind::{self, BindAddress};
+use mostro_push_backend::api::routes::AppState;
+use mostro_push_backend::config::{Config, ServerKeySource};
+use mostro_push_backend::crypto::{TokenCrypto, TokenRedaction};
+use mostro_push_backend::digest::{DigestDelivery, DigestSources, Digester};
+use mostro_push_backend::health::{ProcessStart, Readiness, RelayHealth};
+use mostro_push_backend::metrics::Metrics;
+use mostro_push_backend::nostr::{classifier, pin, replay, trace, CategoryClassifier, NostrListener, ReconnectControl};
+use mostro_push_backend::nostr::pin::PinCheck;
+use mostro_push_backend::nostr::replay::ReplayPush;
+use mostro_push_backend::push::{
+    dispatcher, ledger, ledger::PersistedLedger, outbox, reaper, LastNotified, Outbox, BackfillTracker, DeliveryLedger, Dispatcher, FairScheduler, PayloadBudget, PlatformLimits, PushService, FcmPush, ProviderQuota, SystemClock,
+    TokenReaper, UnifiedPushService,
+};
+use mostro_push_backend::store::wal::{self, Wal};
+use mostro_push_backend::store::{CachedTokenStore, MemoryTokenStore, PushEnvironment, TokenStore, WriteMode, WriteQueue};
+use mostro_push_backend::utils::cache::TtlCache;
+use mostro_push_backend::utils::concurrency::ConcurrencyLimit;
+use mostro_push_backend::utils::rate::{self, RateLimiter};
+use mostro_push_backend::utils::signing::SigningClient;
+use mostro_push_backend::warmup::Warmup;
+use mostro_push_backend::flags::FeatureFlags;
+use mostro_push_backend::watch::WatchList;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    let started = ProcessStart::now();
     env_logger::init();
     dotenv::dotenv().ok();
 
-    info!("Starting Mostro Push Backend v{}...", env!("CARGO_PKG_VERSION"));
-
     // Load configuration
     let config = Config::from_env().expect("Failed to load configuration");
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("replay") {
+        return run_replay(config, &args[1..]).await;
+    }
+    if args.first().map(String::as_str) == Some("verify-wal") {
+        return run_verify_wal(config, &args[1..]).await;
+    }
+
+    info!("Starting Mostro Push Backend v{}...", env!("CARGO_PKG_VERSION"));
+
+    let config_report = Arc::new(security::config_report(&config));
+    let security = &config_report.security;
+    let violations: Vec<_> = security.violations().collect();
+    if !violations.is_empty() {
+        for violation in &violations {
+            log::warn!(
+                "Security check {} failed: {} ({})",
+                violation.rule,
+                violation.problem.as_deref().unwrap_or_default(),
+                violation.description
+            );
+        }
+        if security.strict {
+            log::error!("STRICT_SECURITY is set; refusing to start with {} failed check(s):", violations.len());
+            for violation in &violations {
+                log::error!("  [ ] {}: {}", violation.description, violation.problem.as_deref().unwrap_or_default());
+            }
+            std::process::exit(1);
+        }
+    }
+
     // Initialize token crypto
-    let token_crypto = Arc::new(
-        TokenCrypto::new(&config.crypto.server_private_key)
-            .expect("Failed to initialize token crypto - check SERVER_PRIVATE_KEY")
-    );
+    let token_crypto = match &config.crypto.server_private_key {
+        ServerKeySource::Hex(key) => TokenCrypto::new(key),
+        ServerKeySource::File(path) => TokenCrypto::from_file(std::path::Path::new(path)),
+    }
+    .and_then(|crypto| crypto.with_retired_keys(&config.crypto.retired_private_keys, config.crypto.max_rotation_keys));
+    let token_crypto = Arc::new(token_crypto.unwrap_or_else(|e| {
+        panic!("Failed to initialize token crypto - check SERVER_PRIVATE_KEY(_FILE) and SERVER_RETIRED_PRIVATE_KEYS: {}", e)
+    }));
     info!("Server public key: {}", token_crypto.public_key_hex());
 
+    let metrics = Arc::new(
+        Metrics::builder()
+            .http_buckets(config.metrics.http_buckets.clone())
+            .dispatch_buckets(config.metrics.dispatch_buckets.clone())
+            .exemplars(config.metrics.exemplars)
+            .delivery_sli(
+                Duration::from_secs(config.metrics.sli_threshold_secs),
+                config.metrics.sli_windows.clone(),
+            )
+            .build()
+    );
+    // Runs the periodic background tasks
+    let tasks = Arc::new(Scheduler::new());
+    // Datasets are added as they are set up
+    let mut gc = Collector::new(metrics.clone());
+
+    let checkpoint_path = config.metrics.checkpoint_path.as_ref().map(PathBuf::from);
+    if let Some(path) = &checkpoint_path {
+        metrics.restore(metrics::load_checkpoint(path).await);
+        tasks.register(metrics::checkpoint_task(
+            metrics.clone(),
+            path.clone(),
+            config.metrics.checkpoint_interval_secs,
+        ));
+        info!("Metrics checkpoint enabled at {}", path.display());
+    }
+
+    // Registrations stay closed until persisted state is loaded and enough relays are up
+    let readiness = Arc::new(
+        Readiness::new(config.nostr.min_relays_connected)
+            .with_listener_failure_threshold(config.nostr.listener_failure_threshold),
+    );
+    let relay_health = Arc::new(RelayHealth::new());
+
     // Initialize token store
-    let token_store = Arc::new(TokenStore::new(config.store.token_ttl_hours));
+    let mut registration_log = None;
+    let mut token_store = store::open_store(&config.store)
+        .await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    info!("Token store backend: {:?}", config.store.backend);
+    if config.store.cache_size > 0 {
+        let cache = Arc::new(CachedTokenStore::new(
+            token_store,
+            config.store.cache_size,
+            Duration::from_secs(config.store.cache_ttl_secs),
+            metrics.clone(),
+        ));
+        store::cache::spawn_invalidation(cache.clone());
+        info!("Store cache enabled ({} entries, TTL: {}s)", config.store.cache_size, config.store.cache_ttl_secs);
+        token_store = cache;
+    }
     
+    if let Some(path) = &config.store.wal_path {
+        let wal = Arc::new(
+            Wal::open(std::path::Path::new(path)).expect("Failed to open registration log - check STORE_WAL_PATH"),
+        );
+        wal::spawn_recorder(wal.clone(), token_store.clone());
+        info!("Recording registration changes to {}", path);
+        registration_log = Some(wal);
+    }
+
     // Start cleanup task
-    store::start_cleanup_task(token_store.clone(), config.store.cleanup_interval_hours);
-    info!("Token store initialized (TTL: {}h, cleanup interval: {}h)", 
-        config.store.token_ttl_hours, 
-        config.store.cleanup_interval_hours
+    tasks.register(store::cleanup_task(token_store.clone(), config.store.cleanup_interval_mins));
+    info!("Token store initialized (TTL: {}s, cleanup interval: {}m)", 
+        config.store.token_ttl_secs, 
+        config.store.cleanup_interval_mins
     );
 
+    // Push services sign their own requests; webhooks share this client
+    let signing_client = SigningClient::new(config.push.signing_key.as_deref());
+    if config.push.signing_key.is_some() {
+        info!("Signing outbound push and webhook requests");
+    }
+
     // Initialize push services
     let mut push_services: Vec<Box<dyn PushService>> = Vec::new();
 
@@ -55,11 +172,17 @@ async fn main() -> std::io::Result<()> {
     if let Err(e) = unifiedpush_service.load_endpoints().await {
         log::error!("Failed to load UnifiedPush endpoints: {}", e);
     }
+    readiness.mark_store_loaded();
 
     // Initialize FCM service if enabled
     if config.push.fcm_enabled {
         info!("Initializing FCM push service");
-        let fcm_service = Arc::new(FcmPush::new(config.clone()));
+        let sandbox_service = FcmPush::sandbox(&config);
+        let mut fcm_service = FcmPush::new(config.clone());
+        if sandbox_service.is_some() {
+            fcm_service = fcm_service.with_environment(PushEnvironment::Production);
+        }
+        let fcm_service = Arc::new(fcm_service);
 
         // Try to initialize FCM authentication (optional - may fail if no credentials)
         match fcm_service.init().await {
@@ -72,6 +195,19 @@ async fn main() -> std::io::Result<()> {
                 log::warn!("FCM notifications will be disabled. Set FIREBASE_SERVICE_ACCOUNT_PATH to enable.");
             }
         }
+
+        if let Some(sandbox_service) = sandbox_service {
+            match sandbox_service.init().await {
+                Ok(_) => {
+                    info!("FCM sandbox service initialized successfully");
+                    push_services.push(Box::new(Arc::new(sandbox_service)));
+                }
+                Err(e) => {
+                    log::warn!("Failed to initialize FCM sandbox service: {}", e);
+                    log::warn!("Sandbox notifications will be disabled. Set FIREBASE_SANDBOX_SERVICE_ACCOUNT_PATH to enable.");
+                }
+            }
+        }
     }
 
     if config.push.unifiedpush_enabled {
@@ -79,41 +215,504 @@ async fn main() -> std::io::Result<()> {
         push_services.push(Box::new(Arc::clone(&unifiedpush_service)));
     }
 
-    let push_services = Arc::new(Mutex::new(push_services));
+    let scheduler = (config.push.dispatch_concurrency > 0).then(|| {
+        info!("Dispatch concurrency: {} sends, shared across services", config.push.dispatch_concurrency);
+        FairScheduler::for_services(config.push.dispatch_concurrency, &push_services)
+    });
+    let push_services = Arc::new(RwLock::new(push_services));
+    let quotas: Vec<ProviderQuota> = [
+        ("fcm", config.push.fcm_quota_per_minute),
+        ("unifiedpush", config.push.unifiedpush_quota_per_minute),
+    ]
+    .into_iter()
+    .filter(|(_, limit)| *limit > 0)
+    .map(|(provider, limit)| {
+        info!("{} quota: {} requests/minute", provider, limit);
+        ProviderQuota::new(provider, limit)
+    })
+    .collect();
+    let has_quotas = !quotas.is_empty();
+    let mut dispatcher = Dispatcher::with_quotas(
+        push_services,
+        metrics.clone(),
+        quotas,
+        Arc::new(SystemClock),
+    ).with_token_redaction(if config.push.log_token_hashes {
+        token_crypto.token_hash_redaction()
+    } else {
+        TokenRedaction::Prefix
+    });
+    if let Some(scheduler) = scheduler {
+        dispatcher = dispatcher.with_scheduler(scheduler);
+    }
+    if let Some(limits) = PlatformLimits::from_config(
+        config.push.dispatch_concurrency,
+        config.push.android_concurrency,
+        config.push.ios_concurrency,
+    ) {
+        dispatcher = dispatcher.with_platform_limits(limits);
+    }
+    dispatcher = dispatcher.with_payload_budget(PayloadBudget {
+        android: config.push.android_payload_budget,
+        ios: config.push.ios_payload_budget,
+        ..PayloadBudget::default()
+    });
+    if config.push.ledger_max_age_secs > 0 {
+        let ledger = Arc::new(DeliveryLedger::new(Duration::from_secs(config.push.ledger_max_age_secs)));
+        if let Some(path) = config.push.ledger_path.as_ref().map(PathBuf::from) {
+            ledger::load(&ledger, &path).await;
+            tasks.register(ledger::persist_task(ledger.clone(), path.clone()));
+            info!("Delivery ledger persisted at {}", path.display());
+            let policy = RetentionPolicy {
+                max_age: Some(Duration::from_secs(config.push.ledger_max_age_secs)),
+                max_bytes: config.gc.ledger_max_bytes,
+            };
+            gc = gc.with_dataset(Arc::new(PersistedLedger { ledger: ledger.clone(), path }), policy);
+        }
+        dispatcher = dispatcher.with_ledger(ledger);
+    }
+    let dispatcher = Arc::new(dispatcher);
+    if has_quotas {
+        dispatcher::start_drain_task(dispatcher.clone());
+    }
+    let backfill = Arc::new(BackfillTracker::new(
+        config.push.backfill_window_secs,
+        config.push.backfill_coalesce,
+    ));
+
+    let leadership = Arc::new(Leadership::new(
+        !config.replication.standby,
+        config.replication.primary_url.clone(),
+    ));
+    if let Some(path) = &config.replication.leader_file {
+        info!("Leadership follows {}", path);
+        leadership.watch_file(path.into(), Duration::from_secs(1));
+    }
+    if let Some(interval_secs) = config.push.reaper_interval_secs {
+        info!(
+            "Validating {} stored token(s) every {}s, removing dead ones",
+            config.push.reaper_batch_size, interval_secs
+        );
+        let reaper = TokenReaper::new(
+            token_store.clone(),
+            dispatcher.clone(),
+            metrics.clone(),
+            config.push.reaper_batch_size,
+            config.push.reaper_checks_per_sec,
+        )
+        .with_leadership(leadership.clone());
+        tasks.register(reaper::reaper_task(Arc::new(reaper), Duration::from_secs(interval_secs)));
+    }
+    if !config.replication.standbys.is_empty() {
+        match &config.replication.token {
+            Some(token) => replication::start_replication(
+                token_store.clone(),
+                config.replication.standbys.clone(),
+                token.clone(),
+            ),
+            None => log::error!("REPLICATION_STANDBYS is set without REPLICATION_TOKEN, not replicating"),
+        }
+    }
 
-    // Start Nostr listener in background
-    let nostr_listener = NostrListener::new(
-        config.clone(), 
-        push_services.clone(),
+    // Start Nostr listener in background, once this instance leads
+    let reconnect = Arc::new(ReconnectControl::new());
+    let watch_list = Arc::new(WatchList::new(config.server.watch_max_keys));
+    let flags = Arc::new(FeatureFlags::new(config.push.feature_flags.clone()).with_metrics(metrics.clone()));
+    let classifier = Arc::new(
+        CategoryClassifier::load(
+            config.nostr.category_tag.clone(),
+            config.nostr.category_rules_path.as_ref().map(PathBuf::from),
+        )
+        .expect("Failed to load category rules - check CATEGORY_RULES_PATH"),
+    );
+    if config.nostr.category_rules_path.is_some() {
+        tasks.register(classifier::reload_task(classifier.clone(), Duration::from_secs(30)));
+    }
+    let last_notified = Arc::new(LastNotified::default());
+    let mut nostr_listener = NostrListener::new(
+        config.clone(),
+        dispatcher.clone(),
+        backfill.clone(),
         token_store.clone(),
-    ).expect("Failed to initialize Nostr listener - check MOSTRO_PUBKEY");
+        metrics.clone(),
+        readiness.clone(),
+        relay_health.clone(),
+    ).expect("Failed to initialize Nostr listener - check MOSTRO_PUBKEY")
+    .with_leadership(leadership.clone())
+    .with_reconnect(reconnect.clone())
+    .with_watch_list(watch_list.clone())
+    .with_flags(flags.clone())
+    .with_classifier(classifier.clone())
+    .with_last_notified(last_notified.clone());
+    if let Some(path) = config.push.outbox_path.as_ref().map(PathBuf::from) {
+        let outbox = Arc::new(Outbox::new(
+            config.push.outbox_max_entries,
+            Duration::from_secs(config.push.outbox_ttl_secs),
+        ));
+        outbox::load(&outbox, &path).await;
+        tasks.register(outbox::persist_task(outbox.clone(), path.clone()));
+        info!("Undelivered events kept in {}", path.display());
+        nostr_listener = nostr_listener.with_outbox(outbox);
+    }
+    if let Some(trace) = nostr_listener.trace() {
+        gc = gc.with_dataset(trace, config.gc.trace);
+    }
+
+    // Refuse to follow a different Mostro than the one first deployed against
+    if let Some(path) = &config.nostr.pin_path {
+        let accept_change = config.nostr.accept_mostro_key_change
+            || std::env::args().any(|arg| arg == "--accept-mostro-key-change");
+        match pin::check_pin(
+            std::path::Path::new(path),
+            &config.nostr.mostro_pubkey,
+            &config.nostr.relays,
+            accept_change,
+        ) {
+            Ok(PinCheck::Pinned) => info!("Pinned Mostro pubkey in {}", path),
+            Ok(_) => {}
+            Err(e) => {
+                log::error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
     
+    let nostr_listener = Arc::new(nostr_listener);
+    match (config.nostr.wal_anchor_interval_secs, &registration_log) {
+        (Some(interval_secs), Some(wal)) => {
+            if config.nostr.identity_key.is_none() {
+                log::warn!("WAL anchors are signed with a key generated this run; set NOSTR_IDENTITY_KEY so they can be verified later");
+            }
+            info!("Anchoring the registration log every {}s as {}", interval_secs, nostr_listener.identity_pubkey());
+            let (listener, wal) = (nostr_listener.clone(), wal.clone());
+            tasks.register(Task::every("wal_anchor", Duration::from_secs(interval_secs), move || {
+                let (listener, wal) = (listener.clone(), wal.clone());
+                async move {
+                    if let Err(e) = listener.publish_wal_anchor(&wal).await {
+                        log::warn!("Failed to anchor registration log: {}", e);
+                    }
+                }
+            }));
+        }
+        (Some(_), None) => log::warn!("WAL_ANCHOR_INTERVAL_SECS is set without STORE_WAL_PATH, not anchoring"),
+        _ => {}
+    }
+    if let Some(interval_secs) = config.digest.interval_secs {
+        let dm = match &config.digest.operator_npub {
+            Some(npub) => {
+                let Some(operator) = digest::parse_operator(npub) else {
+                    log::error!("Invalid DIGEST_OPERATOR_NPUB '{}'", npub);
+                    std::process::exit(1);
+                };
+                info!("Digests are DMed from {}", nostr_listener.identity_pubkey());
+                Some((nostr_listener.clone(), operator))
+            }
+            None => None,
+        };
+        let sources = DigestSources {
+            token_store: token_store.clone(),
+            metrics: metrics.clone(),
+            dispatcher: dispatcher.clone(),
+            relay_health: relay_health.clone(),
+        };
+        let delivery = DigestDelivery {
+            path: config.digest.path.as_ref().map(PathBuf::from),
+            webhook_url: config.digest.webhook_url.clone(),
+            dm,
+            client: signing_client.clone(),
+        };
+        info!("Sending an operator digest every {}s", interval_secs);
+        tasks.register(digest::digest_task(
+            Digester::new(sources, chrono::Utc::now()).await,
+            delivery,
+            Duration::from_secs(interval_secs),
+        ));
+    }
+
+    let listener_leadership = leadership.clone();
     tokio::spawn(async move {
+        if !listener_leadership.is_leader() {
+            info!("Running as standby, waiting for leadership before listening to relays");
+        }
+        listener_leadership.wait_until_leader().await;
         nostr_listener.start().await;
     });
 
+    let registration_alerts = Arc::new(
+        RegistrationAlerts::new(
+            config.server.first_registration_alert,
+            config.server.first_registration_webhook_url.clone(),
+        )
+        .with_client(signing_client.clone()),
+    );
+    let write_queue = match config.store.write_mode {
+        WriteMode::Durable => None,
+        WriteMode::Accepted => {
+            info!("Registration write mode: accepted (writes are queued)");
+            Some(Arc::new(WriteQueue::start(
+                token_store.clone(),
+                metrics.clone(),
+                registration_alerts.clone(),
+            )))
+        }
+    };
+
+    let request_limiter = rate::request_limiter(&config.rate_limit)
+        .await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    info!(
+        "Request limit: {} per minute per IP and pubkey ({:?} backend)",
+        config.rate_limit.max_per_minute, config.rate_limit.backend
+    );
+
+    let audit = Arc::new(
+        AuditLog::new(
+            config.server.audit_log_path.as_deref().map(Path::new),
+            config.server.audit_webhook_url.clone(),
+        )?
+        .with_client(signing_client.clone()),
+    );
+    if config.server.audit_log_path.is_some() {
+        gc = gc.with_dataset(audit.clone(), config.gc.audit);
+    }
+    let gc = Arc::new(gc);
+    if config.gc.interval_secs > 0 && !gc.is_empty() {
+        tasks.register(gc::gc_task(gc.clone(), Duration::from_secs(config.gc.interval_secs)));
+    }
+
+    // Registrations wait until the first pushes won't pay for cold connections
+    let status_cache = Arc::new(TtlCache::new(Duration::from_millis(config.server.status_cache_ttl_ms)));
+    if config.server.warmup_deadline_secs > 0 {
+        let warm_dispatcher = dispatcher.clone();
+        let warm_store = token_store.clone();
+        let warm_cache = status_cache.clone();
+        let warm_metrics = metrics.clone();
+        Warmup::new(Duration::from_secs(config.server.warmup_deadline_secs))
+            .step("push_providers", async move { warm_dispatcher.warm_up().await })
+            .step("store", async move {
+                warm_store.cleanup_expired().await;
+                let generation = warm_store.generation();
+                warm_cache.put(warm_store.get_stats().await, generation);
+                Ok(())
+            })
+            .step("metrics", async move {
+                warm_metrics.render();
+                Ok(())
+            })
+            .spawn(readiness.clone());
+    }
+
     // Create app state for HTTP handlers
     let app_state = AppState {
         token_store: token_store.clone(),
         token_crypto: token_crypto.clone(),
+        metrics: metrics.clone(),
+        readiness: readiness.clone(),
+        started,
+        relay_health: relay_health.clone(),
+        status_cache: status_cache.clone(),
+        info_cache: Arc::new(TtlCache::new(Duration::from_secs(config.server.info_cache_ttl_secs))),
+        dispatcher: dispatcher.clone(),
+        backfill: backfill.clone(),
+        registration_alerts,
+        admin_token: config.server.admin_token.clone(),
+        status_secret: config.server.status_secret.clone(),
+        audit,
+        write_queue,
+        delivery_stats_window: Duration::from_secs(config.server.delivery_stats_window_secs),
+        leadership,
+        replication_token: config.replication.token.clone(),
+        advertised_platforms: config.push.advertised_platforms.clone(),
+        reject_undeliverable: config.push.reject_undeliverable,
+        require_token_binding: config.crypto.require_token_binding,
+        require_ownership_signature: config.crypto.require_ownership_signature,
+        platform_conflict: config.store.platform_conflict,
+        max_pubkeys_per_token: config.store.max_pubkeys_per_token,
+        max_devices_per_pubkey: config.store.max_devices_per_pubkey,
+        device_policy: config.store.device_policy,
+        decrypt_limiter: Arc::new(RateLimiter::per_second(config.crypto.max_decrypts_per_sec)),
+        last_notified,
+        registration_limit: Arc::new(ConcurrencyLimit::new(
+            config.server.max_concurrent_registrations,
+            Duration::from_millis(config.server.registration_wait_ms),
+        )),
+        request_limiter,
+        watch_list,
+        flags,
+        grouping: config.push.grouping.clone(),
+        classifier,
+        app_ids: config.push.apps.keys().cloned().collect(),
+        config_report,
+        reconnect,
+        scheduler: tasks,
+        gc,
     };
 
     // Start HTTP API server
-    let server_addr = format!("{}:{}", config.server.host, config.server.port);
-    info!("Starting HTTP server on {}", server_addr);
+    let bind = config.server.bind.clone();
+    info!("Starting HTTP server on {}", bind);
+    // Held until shutdown, so the socket file is removed afterwards
+    let _socket_guard = match &bind {
+        BindAddress::Unix(path) => Some(bind::claim_socket(path)?),
+        BindAddress::Tcp(_) => None,
+    };
     info!("API endpoints:");
     info!("  GET  /api/health    - Health check");
     info!("  GET  /api/status    - Server status with token stats");
     info!("  GET  /api/info      - Server public key info");
+    info!("  GET  /api/capabilities - Providers and accepted platforms");
+    info!("  GET  /api/discovery - Envelope schemes, versions, endpoints and limits");
+    info!("  GET  /api/metrics   - Prometheus metrics");
+    info!("  GET  /api/relays    - Relay connection state and last NOTICE/CLOSED reason");
+    info!("  GET  /api/stats/delivery - Push success rate over a recent window");
     info!("  POST /api/register  - Register encrypted token");
     info!("  POST /api/unregister - Unregister token");
+    if config.server.admin_token.is_some() {
+        info!("  GET/PUT/DELETE /admin/registrations/{{pubkey}}/annotations - Registration annotations");
+        info!("  DELETE /admin/registrations/{{pubkey}} - Evict a registration");
+        info!("  GET  /admin/export  - Export all registrations");
+        info!("  POST /admin/migrate - Import registrations from another instance");
+        info!("  POST /admin/leadership - Promote or demote this instance");
+        info!("  GET  /admin/config  - Effective configuration and security checks");
+        info!("  POST /admin/reconnect - Reconnect to relays now");
+        info!("  GET  /admin/tasks   - Background task schedule");
+        info!("  POST /admin/tasks/{{name}}/run - Run a background task now");
+        info!("  GET/PUT/DELETE /admin/watch/{{pubkey}} - Trace one pubkey's notifications");
+    }
 
-    HttpServer::new(move || {
+    let http_metrics = metrics.clone();
+    let server = HttpServer::new(move || {
+        let http_metrics = http_metrics.clone();
         App::new()
             .app_data(web::Data::new(app_state.clone()))
+            .wrap_fn(move |req, srv| {
+                // Per-route latency, with the request's trace id as exemplar
+                let started = Instant::now();
+                let route = req.match_pattern();
+                let trace_id = req.headers()
+                    .get("traceparent")
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(metrics::trace_id_from_traceparent)
+                    .map(str::to_string);
+                let metrics = http_metrics.clone();
+                let fut = srv.call(req);
+                async move {
+                    let res = fut.await;
+                    if let Some(route) = route {
+                        metrics.observe_http(&route, started.elapsed().as_secs_f64(), trace_id.as_deref());
+                    }
+                    res
+                }
+            })
             .configure(api::routes::configure)
-    })
-    .bind(server_addr)?
-    .run()
-    .await
+            .configure(api::admin::configure)
+            .configure(api::replication::configure)
+    });
+    let server = match &bind {
+        BindAddress::Tcp(addr) => server.bind(addr)?,
+        BindAddress::Unix(path) => server.bind_uds(path)?,
+    };
+    server.run().await?;
+
+    // Persist lifetime counters one last time on shutdown
+    if let Some(path) = &checkpoint_path {
+        if let Err(e) = metrics::save_checkpoint(&metrics, path).await {
+            log::error!("Failed to write final metrics checkpoint: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+/// `replay <trace.jsonl> [--speed <factor>]`: feed a captured event trace
+/// through the pipeline with providers stubbed out, and print how outcomes
+/// compare to the original run. `--speed 0` replays without waiting.
+async fn run_replay(config: Config, args: &[String]) -> std::io::Result<()> {
+    let usage = || std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        "usage: mostro-push-backend replay <trace.jsonl> [--speed <factor>]",
+    );
+    let path = args.first().ok_or_else(usage)?;
+    let speed = match args.get(1).map(String::as_str) {
+        Some("--speed") => args.get(2).and_then(|s| s.parse().ok()).ok_or_else(usage)?,
+        Some(_) => return Err(usage()),
+        None => 1.0,
+    };
+
+    let records = trace::read_trace(std::path::Path::new(path))
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+    info!("Replaying {} events from {} at {}x", records.len(), path, speed);
+
+    let mut config = config;
+    // Don't trace the replay into the file being replayed
+    config.nostr.event_trace_path = None;
+    let metrics = Arc::new(Metrics::new());
+    let token_store: Arc<dyn TokenStore> = Arc::new(MemoryTokenStore::with_ttl_secs(config.store.token_ttl_secs));
+    let services: Vec<Box<dyn PushService>> = vec![Box::new(ReplayPush)];
+    let listener = NostrListener::new(
+        config.clone(),
+        Arc::new(Dispatcher::new(Arc::new(RwLock::new(services)), metrics.clone())),
+        Arc::new(BackfillTracker::new(config.push.backfill_window_secs, config.push.backfill_coalesce)),
+        token_store.clone(),
+        metrics,
+        Arc::new(Readiness::new(0)),
+        Arc::new(RelayHealth::new()),
+    )
+    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+
+    let report = replay::replay(&listener, token_store.as_ref(), &records, speed).await;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    if !report.mismatches.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// `verify-wal <wal.jsonl> [--anchors]`: check the registration log's hash
+/// chain and, with `--anchors`, every head published by the configured
+/// `NOSTR_IDENTITY_KEY`. Prints a report and exits non-zero on any failure.
+async fn run_verify_wal(config: Config, args: &[String]) -> std::io::Result<()> {
+    let usage = || std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        "usage: mostro-push-backend verify-wal <wal.jsonl> [--anchors]",
+    );
+    let path = args.first().ok_or_else(usage)?;
+    let check_anchors = match args.get(1).map(String::as_str) {
+        Some("--anchors") => true,
+        Some(_) => return Err(usage()),
+        None => false,
+    };
+    let invalid = |e: Box<dyn std::error::Error>| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string());
+
+    let records = wal::read_wal(std::path::Path::new(path)).map_err(invalid)?;
+    let anchors = if check_anchors {
+        let key = config.nostr.identity_key.as_deref().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "--anchors needs NOSTR_IDENTITY_KEY")
+        })?;
+        let author = nostr_sdk::Keys::new(nostr_sdk::prelude::SecretKey::from_str(key).map_err(|e| invalid(e.into()))?).public_key();
+        let client = nostr_sdk::Client::new(nostr_sdk::Keys::generate());
+        for relay in &config.nostr.relays {
+            client.add_relay(relay.as_str()).await.map_err(|e| invalid(e.into()))?;
+        }
+        client.connect().await;
+        let filter = nostr_sdk::Filter::new()
+            .kind(nostr_sdk::Kind::TextNote)
+            .author(author)
+            .hashtag(wal::ANCHOR_HASHTAG);
+        let events = client
+            .get_events_of(vec![filter], Some(Duration::from_secs(30)))
+            .await
+            .map_err(|e| invalid(e.into()))?;
+        client.disconnect().await.ok();
+        events.iter().filter_map(|event| wal::parse_anchor(event, &author)).collect()
+    } else {
+        Vec::new()
+    };
+
+    let report = wal::verify(&records, &anchors);
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    if !report.is_valid() {
+        std::process::exit(1);
+    }
+    Ok(())
 }
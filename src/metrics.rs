@@ -0,0 +1,960 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::fs;
+
+use crate::crypto::Platform;
+use crate::models::ProviderQuotaStatus;
+use crate::scheduler::Task;
+use crate::sli::{DeliverySli, SliBucket};
+
+/// HTTP latency buckets in seconds, with a boundary at the 250ms registration SLO.
+pub const DEFAULT_HTTP_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+/// Push dispatch latency buckets in seconds, with a boundary at the 2s dispatch SLO.
+pub const DEFAULT_DISPATCH_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.0, 5.0, 10.0];
+
+/// Distinct push error classes tracked; further classes count as "other".
+const MAX_PUSH_ERROR_CLASSES: usize = 64;
+
+/// Routes that get a latency histogram; other paths are not recorded to bound cardinality.
+pub const HTTP_ROUTES: &[&str] = &[
+    "/api/health",
+    "/api/status",
+    "/api/register",
+    "/api/unregister",
+    "/api/info",
+    "/api/capabilities",
+    "/api/metrics",
+    "/api/relays",
+    "/api/stats/delivery",
+    "/api/reencrypt",
+];
+
+/// Process-wide counters and histograms, rendered in the Prometheus text exposition format.
+#[derive(Debug)]
+pub struct Metrics {
+    pub events_received: AtomicU64,
+    pub events_suppressed: AtomicU64,
+    /// Events dropped unhandled while in overload mode
+    pub events_sampled_out: AtomicU64,
+    /// 1 while events are being sampled because of a flood
+    pub overload_mode: AtomicU64,
+    pub overload_transitions: AtomicU64,
+    pub pushes_sent: AtomicU64,
+    pub pushes_failed: AtomicU64,
+    /// Pushes queued because their provider was over quota
+    pub pushes_delayed: AtomicU64,
+    /// Pushes skipped because another path already delivered the event
+    pub pushes_deduplicated: AtomicU64,
+    pub registrations: AtomicU64,
+    /// Registrations for a platform no push service is configured for
+    pub registrations_undeliverable: AtomicU64,
+    /// Register, unregister and re-encrypt requests refused for a missing or bad ownership signature
+    pub signatures_rejected: AtomicU64,
+    /// Registrations moved to a newer envelope version
+    pub reencryptions: AtomicU64,
+    /// Registrations accepted but not yet written to the store
+    pub register_write_queue_depth: AtomicU64,
+    /// Decrypts in the last second, as of the last scrape
+    pub decrypt_rate: AtomicU64,
+    pub registrations_in_flight: AtomicU64,
+    pub registrations_shed: AtomicU64,
+    /// Registrations refused before decrypting because of `MAX_DECRYPTS_PER_SEC`
+    pub decrypts_shed: AtomicU64,
+    /// Requests refused for exceeding `RATE_LIMIT_PER_MINUTE`
+    pub requests_rate_limited: AtomicU64,
+    /// Registrations removed because their provider reported the token invalid
+    pub tokens_reaped: AtomicU64,
+    /// Registration lookups answered by the store cache, and those that went to the backend
+    pub store_cache_hits: AtomicU64,
+    pub store_cache_misses: AtomicU64,
+    /// Payload decorator timeouts, panics and oversized outputs
+    pub decorator_violations: AtomicU64,
+    /// Pushes currently being dispatched, per platform
+    pub in_flight_android: AtomicU64,
+    pub in_flight_ios: AtomicU64,
+    pub in_flight_web: AtomicU64,
+    /// Pushes not sent for exceeding the platform's payload budget
+    pub payloads_oversized_android: AtomicU64,
+    pub payloads_oversized_ios: AtomicU64,
+    pub payloads_oversized_web: AtomicU64,
+    /// Successful decrypts by rotation key index (0 = current key)
+    decrypt_key_index: Mutex<BTreeMap<usize, u64>>,
+    /// Feature flag evaluations by flag and whether it was on
+    flag_evaluations: Mutex<BTreeMap<(String, bool), u64>>,
+    /// Events received by relay and whether that relay delivered them first
+    relay_deliveries: Mutex<BTreeMap<(String, bool), u64>>,
+    /// Entries removed and bytes reclaimed by GC, per dataset
+    gc_collected: Mutex<BTreeMap<String, (u64, u64)>>,
+    /// Failed sends by provider and error, for operator summaries; not
+    /// exported to Prometheus, whose label cardinality this would blow up
+    push_errors: Mutex<BTreeMap<String, u64>>,
+    /// Provider quota usage as of the last scrape
+    provider_quotas: Mutex<Vec<ProviderQuotaStatus>>,
+    /// Request latency per route, keyed by route pattern
+    pub http_latency: BTreeMap<&'static str, Histogram>,
+    /// Time from dispatch start until a provider accepted or all failed
+    pub dispatch_latency: Histogram,
+    /// Share of matched events accepted by a provider in time
+    pub delivery_sli: DeliverySli,
+    /// Attach trace ids from incoming requests as exemplars
+    exemplars: bool,
+    /// Lifetime totals restored from the last checkpoint, before this process started
+    lifetime_base: Mutex<LifetimeCounters>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+/// Configures histogram buckets per metric family before creating `Metrics`.
+pub struct MetricsBuilder {
+    http_buckets: Vec<f64>,
+    dispatch_buckets: Vec<f64>,
+    exemplars: bool,
+    sli_threshold: Duration,
+    sli_windows: Vec<u64>,
+}
+
+impl MetricsBuilder {
+    pub fn http_buckets(mut self, buckets: Vec<f64>) -> Self {
+        self.http_buckets = buckets;
+        self
+    }
+
+    pub fn dispatch_buckets(mut self, buckets: Vec<f64>) -> Self {
+        self.dispatch_buckets = buckets;
+        self
+    }
+
+    pub fn exemplars(mut self, enabled: bool) -> Self {
+        self.exemplars = enabled;
+        self
+    }
+
+    /// Delivery SLI threshold and window lengths in seconds.
+    pub fn delivery_sli(mut self, threshold: Duration, windows: Vec<u64>) -> Self {
+        self.sli_threshold = threshold;
+        self.sli_windows = windows;
+        self
+    }
+
+    pub fn build(self) -> Metrics {
+        let http_latency = HTTP_ROUTES
+            .iter()
+            .map(|route| (*route, Histogram::new(&self.http_buckets)))
+            .collect();
+
+        Metrics {
+            events_received: AtomicU64::new(0),
+            events_suppressed: AtomicU64::new(0),
+            events_sampled_out: AtomicU64::new(0),
+            overload_mode: AtomicU64::new(0),
+            overload_transitions: AtomicU64::new(0),
+            pushes_sent: AtomicU64::new(0),
+            pushes_failed: AtomicU64::new(0),
+            pushes_delayed: AtomicU64::new(0),
+            pushes_deduplicated: AtomicU64::new(0),
+            registrations: AtomicU64::new(0),
+            registrations_undeliverable: AtomicU64::new(0),
+            signatures_rejected: AtomicU64::new(0),
+            reencryptions: AtomicU64::new(0),
+            register_write_queue_depth: AtomicU64::new(0),
+            decrypt_rate: AtomicU64::new(0),
+            registrations_in_flight: AtomicU64::new(0),
+            registrations_shed: AtomicU64::new(0),
+            decrypts_shed: AtomicU64::new(0),
+            requests_rate_limited: AtomicU64::new(0),
+            tokens_reaped: AtomicU64::new(0),
+            store_cache_hits: AtomicU64::new(0),
+            store_cache_misses: AtomicU64::new(0),
+            decorator_violations: AtomicU64::new(0),
+            in_flight_android: AtomicU64::new(0),
+            in_flight_ios: AtomicU64::new(0),
+            in_flight_web: AtomicU64::new(0),
+            payloads_oversized_android: AtomicU64::new(0),
+            payloads_oversized_ios: AtomicU64::new(0),
+            payloads_oversized_web: AtomicU64::new(0),
+            decrypt_key_index: Mutex::new(BTreeMap::new()),
+            flag_evaluations: Mutex::new(BTreeMap::new()),
+            relay_deliveries: Mutex::new(BTreeMap::new()),
+            gc_collected: Mutex::new(BTreeMap::new()),
+            push_errors: Mutex::new(BTreeMap::new()),
+            provider_quotas: Mutex::new(Vec::new()),
+            http_latency,
+            dispatch_latency: Histogram::new(&self.dispatch_buckets),
+            delivery_sli: DeliverySli::new(self.sli_threshold, self.sli_windows),
+            exemplars: self.exemplars,
+            lifetime_base: Mutex::new(LifetimeCounters::default()),
+        }
+    }
+}
+
+/// Trace id attached to the most recent observation in a bucket.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exemplar {
+    pub trace_id: String,
+    pub value: f64,
+    pub timestamp: f64,
+}
+
+/// Cumulative histogram with fixed upper bounds (in seconds).
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: Vec<f64>,
+    state: Mutex<HistogramState>,
+}
+
+#[derive(Debug)]
+struct HistogramState {
+    /// Per-bucket (non-cumulative) counts; the last entry is +Inf
+    counts: Vec<u64>,
+    sum: f64,
+    exemplars: Vec<Option<Exemplar>>,
+}
+
+impl Histogram {
+    pub fn new(bounds: &[f64]) -> Self {
+        let mut bounds: Vec<f64> = bounds.iter().copied().filter(|b| b.is_finite()).collect();
+        bounds.sort_by(|a, b| a.total_cmp(b));
+        bounds.dedup();
+
+        let buckets = bounds.len() + 1;
+        Self {
+            bounds,
+            state: Mutex::new(HistogramState {
+                counts: vec![0; buckets],
+                sum: 0.0,
+                exemplars: vec![None; buckets],
+            }),
+        }
+    }
+
+    /// Upper bounds of the finite buckets; +Inf is implicit.
+    pub fn bounds(&self) -> &[f64] {
+        &self.bounds
+    }
+
+    pub fn observe(&self, value: f64) {
+        self.observe_with_exemplar(value, None);
+    }
+
+    pub fn observe_with_exemplar(&self, value: f64, trace_id: Option<&str>) {
+        let index = self.bounds.partition_point(|bound| *bound < value);
+        let mut state = self.state.lock().unwrap();
+        state.counts[index] += 1;
+        state.sum += value;
+        if let Some(trace_id) = trace_id {
+            state.exemplars[index] = Some(Exemplar {
+                trace_id: trace_id.to_string(),
+                value,
+                timestamp: chrono::Utc::now().timestamp_millis() as f64 / 1000.0,
+            });
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.state.lock().unwrap().counts.iter().sum()
+    }
+
+    /// Exemplar of the bucket whose upper bound is `le` (`f64::INFINITY` for +Inf).
+    pub fn exemplar(&self, le: f64) -> Option<Exemplar> {
+        let index = self.bounds.iter().position(|b| *b == le).unwrap_or(self.bounds.len());
+        self.state.lock().unwrap().exemplars[index].clone()
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str, openmetrics: bool) {
+        let state = self.state.lock().unwrap();
+        let mut cumulative = 0;
+        for (index, count) in state.counts.iter().enumerate() {
+            cumulative += count;
+            let le = match self.bounds.get(index) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = write!(out, "{}_bucket{{{}le=\"{}\"}} {}", name, labels, le, cumulative);
+            if let (true, Some(exemplar)) = (openmetrics, &state.exemplars[index]) {
+                let _ = write!(
+                    out,
+                    " # {{trace_id=\"{}\"}} {} {}",
+                    exemplar.trace_id, exemplar.value, exemplar.timestamp
+                );
+            }
+            out.push('\n');
+        }
+        let labels = labels.trim_end_matches(',');
+        let labels = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels) };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, state.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, cumulative);
+    }
+}
+
+/// Monotonic counters that survive restarts via the checkpoint file.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LifetimeCounters {
+    pub pushes_sent: u64,
+    pub registrations: u64,
+}
+
+/// Contents of the checkpoint file.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    #[serde(flatten)]
+    pub lifetime: LifetimeCounters,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub delivery_sli: Vec<SliBucket>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn builder() -> MetricsBuilder {
+        MetricsBuilder {
+            http_buckets: DEFAULT_HTTP_BUCKETS.to_vec(),
+            dispatch_buckets: DEFAULT_DISPATCH_BUCKETS.to_vec(),
+            exemplars: false,
+            sli_threshold: crate::sli::DEFAULT_THRESHOLD,
+            sli_windows: crate::sli::DEFAULT_WINDOWS.to_vec(),
+        }
+    }
+
+    /// Record a request on a pre-registered route. The trace id is kept as an
+    /// exemplar only when exemplars are enabled.
+    pub fn observe_http(&self, route: &str, seconds: f64, trace_id: Option<&str>) {
+        if let Some(histogram) = self.http_latency.get(route) {
+            histogram.observe_with_exemplar(seconds, trace_id.filter(|_| self.exemplars));
+        }
+    }
+
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(counter: &AtomicU64) -> u64 {
+        counter.load(Ordering::Relaxed)
+    }
+
+    /// Gauge of pushes being dispatched to `platform`.
+    pub fn in_flight(&self, platform: &Platform) -> &AtomicU64 {
+        match platform {
+            Platform::Android => &self.in_flight_android,
+            Platform::Ios => &self.in_flight_ios,
+            Platform::Web => &self.in_flight_web,
+        }
+    }
+
+    /// Pushes being dispatched across every platform.
+    pub fn pushes_in_flight(&self) -> u64 {
+        Platform::ALL.iter().map(|platform| Self::get(self.in_flight(platform))).sum()
+    }
+
+    /// Seed lifetime totals with values persisted by a previous process.
+    pub fn restore_lifetime(&self, base: LifetimeCounters) {
+        *self.lifetime_base.lock().unwrap() = base;
+    }
+
+    /// Restore everything a previous process checkpointed.
+    pub fn restore(&self, checkpoint: Checkpoint) {
+        self.restore_lifetime(checkpoint.lifetime);
+        self.delivery_sli.restore(&checkpoint.delivery_sli);
+    }
+
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            lifetime: self.lifetime(),
+            delivery_sli: self.delivery_sli.buckets(),
+        }
+    }
+
+    /// Lifetime totals: restored base plus what this process has counted.
+    pub fn lifetime(&self) -> LifetimeCounters {
+        let base = *self.lifetime_base.lock().unwrap();
+        LifetimeCounters {
+            pushes_sent: base.pushes_sent + Self::get(&self.pushes_sent),
+            registrations: base.registrations + Self::get(&self.registrations),
+        }
+    }
+
+    /// Record which rotation key decrypted a registration, so retired keys
+    /// can be dropped once they stop being used.
+    pub fn record_decrypt_key_index(&self, key_index: usize) {
+        *self.decrypt_key_index.lock().unwrap().entry(key_index).or_insert(0) += 1;
+    }
+
+    pub fn record_flag_evaluation(&self, flag: &str, enabled: bool) {
+        *self.flag_evaluations.lock().unwrap().entry((flag.to_string(), enabled)).or_insert(0) += 1;
+    }
+
+    /// Count an event from `relay`; `first` unless another relay delivered it earlier.
+    pub fn record_relay_delivery(&self, relay: &str, first: bool) {
+        *self.relay_deliveries.lock().unwrap().entry((relay.to_string(), first)).or_insert(0) += 1;
+    }
+
+    pub fn record_gc(&self, dataset: &str, removed: u64, reclaimed_bytes: u64) {
+        let mut collected = self.gc_collected.lock().unwrap();
+        let totals = collected.entry(dataset.to_string()).or_insert((0, 0));
+        totals.0 += removed;
+        totals.1 += reclaimed_bytes;
+    }
+
+    /// Count a failed send under `provider: error`.
+    pub fn record_push_error(&self, provider: &str, error: &str) {
+        let mut class = format!("{}: {}", provider, error.lines().next().unwrap_or_default());
+        if class.len() > 80 {
+            let mut end = 80;
+            while !class.is_char_boundary(end) {
+                end -= 1;
+            }
+            class.truncate(end);
+        }
+        let mut errors = self.push_errors.lock().unwrap();
+        if !errors.contains_key(&class) && errors.len() >= MAX_PUSH_ERROR_CLASSES {
+            class = "other".to_string();
+        }
+        *errors.entry(class).or_insert(0) += 1;
+    }
+
+    pub fn push_errors(&self) -> BTreeMap<String, u64> {
+        self.push_errors.lock().unwrap().clone()
+    }
+
+    /// Snapshot of provider quota usage to include in the next render.
+    pub fn set_provider_quotas(&self, quotas: Vec<ProviderQuotaStatus>) {
+        *self.provider_quotas.lock().unwrap() = quotas;
+    }
+
+    /// Prometheus text format (0.0.4), without exemplars.
+    pub fn render(&self) -> String {
+        self.render_format(false)
+    }
+
+    /// OpenMetrics text format, including exemplars on histogram buckets.
+    pub fn render_openmetrics(&self) -> String {
+        let mut out = self.render_format(true);
+        out.push_str("# EOF\n");
+        out
+    }
+
+    fn render_format(&self, openmetrics: bool) -> String {
+        let mut out = String::new();
+        write_counter(
+            &mut out,
+            "mostro_push_events_received_total",
+            "Kind 1059 events received from relays",
+            Self::get(&self.events_received),
+        );
+        write_counter(
+            &mut out,
+            "mostro_push_events_suppressed_total",
+            "Events skipped because they carried the no-push marker",
+            Self::get(&self.events_suppressed),
+        );
+        write_counter(
+            &mut out,
+            "mostro_push_events_sampled_out_total",
+            "Events dropped unhandled while in overload mode",
+            Self::get(&self.events_sampled_out),
+        );
+        write_gauge(
+            &mut out,
+            "mostro_push_overload_mode",
+            "1 while relay events are sampled because ingest exceeds OVERLOAD_EVENTS_PER_SEC",
+            Self::get(&self.overload_mode),
+        );
+        write_counter(
+            &mut out,
+            "mostro_push_overload_transitions_total",
+            "Times overload mode was entered or left",
+            Self::get(&self.overload_transitions),
+        );
+        write_counter(
+            &mut out,
+            "mostro_push_pushes_sent_total",
+            "Push notifications accepted by a provider",
+            Self::get(&self.pushes_sent),
+        );
+        write_counter(
+            &mut out,
+            "mostro_push_pushes_failed_total",
+            "Push notifications that no provider accepted",
+            Self::get(&self.pushes_failed),
+        );
+        write_counter(
+            &mut out,
+            "mostro_push_pushes_delayed_total",
+            "Pushes queued because their provider was over quota",
+            Self::get(&self.pushes_delayed),
+        );
+        write_counter(
+            &mut out,
+            "mostro_push_pushes_deduplicated_total",
+            "Pushes skipped because another path already delivered the event",
+            Self::get(&self.pushes_deduplicated),
+        );
+        write_counter(
+            &mut out,
+            "mostro_push_registrations_total",
+            "Successful token registrations",
+            Self::get(&self.registrations),
+        );
+        write_counter(
+            &mut out,
+            "mostro_push_registrations_undeliverable_total",
+            "Registrations for a platform no push service is configured for",
+            Self::get(&self.registrations_undeliverable),
+        );
+        write_counter(
+            &mut out,
+            "mostro_push_signatures_rejected_total",
+            "Register, unregister and re-encrypt requests refused for a missing or bad ownership signature",
+            Self::get(&self.signatures_rejected),
+        );
+        write_counter(
+            &mut out,
+            "mostro_push_reencryptions_total",
+            "Registrations moved to a newer envelope version via /api/reencrypt",
+            Self::get(&self.reencryptions),
+        );
+        write_gauge(
+            &mut out,
+            "mostro_push_register_write_queue_depth",
+            "Registrations accepted but not yet written to the store",
+            Self::get(&self.register_write_queue_depth),
+        );
+        write_gauge(
+            &mut out,
+            "mostro_push_decrypt_rate",
+            "Token decrypt operations in the last second",
+            Self::get(&self.decrypt_rate),
+        );
+        write_gauge(
+            &mut out,
+            "mostro_push_registrations_in_flight",
+            "Registrations being processed right now",
+            Self::get(&self.registrations_in_flight),
+        );
+        write_counter(
+            &mut out,
+            "mostro_push_registrations_shed_total",
+            "Registrations refused because every concurrent registration slot stayed busy",
+            Self::get(&self.registrations_shed),
+        );
+        write_counter(
+            &mut out,
+            "mostro_push_decrypts_shed_total",
+            "Requests refused before decrypting because the global decrypt rate was exceeded",
+            Self::get(&self.decrypts_shed),
+        );
+        write_counter(
+            &mut out,
+            "mostro_push_requests_rate_limited_total",
+            "Requests refused for exceeding the per-IP or per-pubkey rate limit",
+            Self::get(&self.requests_rate_limited),
+        );
+        write_counter(
+            &mut out,
+            "mostro_push_tokens_reaped_total",
+            "Registrations removed because their provider reported the device token invalid",
+            Self::get(&self.tokens_reaped),
+        );
+        write_counter(
+            &mut out,
+            "mostro_push_store_cache_hits_total",
+            "Registration lookups answered by the store cache",
+            Self::get(&self.store_cache_hits),
+        );
+        write_counter(
+            &mut out,
+            "mostro_push_store_cache_misses_total",
+            "Registration lookups that went to the store backend",
+            Self::get(&self.store_cache_misses),
+        );
+        write_counter(
+            &mut out,
+            "mostro_push_decorator_violations_total",
+            "Payload decorator calls that timed out, panicked or produced oversized output",
+            Self::get(&self.decorator_violations),
+        );
+        write_labeled_gauge(
+            &mut out,
+            "mostro_push_dispatch_in_flight",
+            "Pushes currently being dispatched",
+            "platform",
+            [
+                ("android", Self::get(&self.in_flight_android)),
+                ("ios", Self::get(&self.in_flight_ios)),
+                ("web", Self::get(&self.in_flight_web)),
+            ]
+            .into_iter(),
+        );
+        let name = "mostro_push_payloads_oversized_total";
+        write_header(&mut out, name, "Pushes not sent for exceeding the platform's payload size budget", "counter");
+        for (platform, count) in [
+            ("android", Self::get(&self.payloads_oversized_android)),
+            ("ios", Self::get(&self.payloads_oversized_ios)),
+            ("web", Self::get(&self.payloads_oversized_web)),
+        ] {
+            let _ = writeln!(out, "{}{{platform=\"{}\"}} {}", name, platform, count);
+        }
+
+        // Lifetime values are gauges so rate() keeps working on the process-local counters
+        let lifetime = self.lifetime();
+        write_gauge(
+            &mut out,
+            "mostro_push_lifetime_pushes_sent",
+            "Push notifications sent across restarts",
+            lifetime.pushes_sent,
+        );
+        write_gauge(
+            &mut out,
+            "mostro_push_lifetime_registrations",
+            "Token registrations across restarts",
+            lifetime.registrations,
+        );
+
+        let name = "mostro_push_decrypt_key_index_total";
+        write_header(&mut out, name, "Successful token decrypts by rotation key index (0 = current)", "counter");
+        for (key_index, count) in self.decrypt_key_index.lock().unwrap().iter() {
+            let _ = writeln!(out, "{}{{key_index=\"{}\"}} {}", name, key_index, count);
+        }
+
+        let name = "mostro_push_flag_evaluations_total";
+        write_header(&mut out, name, "Feature flag evaluations by flag and arm", "counter");
+        for ((flag, enabled), count) in self.flag_evaluations.lock().unwrap().iter() {
+            let arm = if *enabled { "on" } else { "off" };
+            let _ = writeln!(out, "{}{{flag=\"{}\",arm=\"{}\"}} {}", name, flag, arm, count);
+        }
+
+        let name = "mostro_push_relay_event_deliveries_total";
+        write_header(
+            &mut out,
+            name,
+            "Kind 1059 events by relay, and whether it delivered them before any other relay",
+            "counter",
+        );
+        for ((relay, first), count) in self.relay_deliveries.lock().unwrap().iter() {
+            let _ = writeln!(out, "{}{{relay=\"{}\",first=\"{}\"}} {}", name, relay, first, count);
+        }
+
+        let collected = self.gc_collected.lock().unwrap().clone();
+        let name = "mostro_push_gc_removed_entries_total";
+        write_header(&mut out, name, "Entries removed by GC per persisted dataset", "counter");
+        for (dataset, (removed, _)) in &collected {
+            let _ = writeln!(out, "{}{{dataset=\"{}\"}} {}", name, dataset, removed);
+        }
+        let name = "mostro_push_gc_reclaimed_bytes_total";
+        write_header(&mut out, name, "Storage bytes reclaimed by GC per persisted dataset", "counter");
+        for (dataset, (_, reclaimed)) in &collected {
+            let _ = writeln!(out, "{}{{dataset=\"{}\"}} {}", name, dataset, reclaimed);
+        }
+
+        let quotas = self.provider_quotas.lock().unwrap().clone();
+        if !quotas.is_empty() {
+            write_provider_gauge(
+                &mut out,
+                "mostro_push_provider_quota_limit",
+                "Provider requests/minute budget",
+                quotas.iter().map(|q| (q.provider.as_str(), q.limit_per_minute as u64)),
+            );
+            write_provider_gauge(
+                &mut out,
+                "mostro_push_provider_quota_used",
+                "Provider requests in the last minute",
+                quotas.iter().map(|q| (q.provider.as_str(), q.used as u64)),
+            );
+            write_provider_gauge(
+                &mut out,
+                "mostro_push_provider_queued",
+                "Pushes waiting for provider budget",
+                quotas.iter().map(|q| (q.provider.as_str(), q.queued as u64)),
+            );
+        }
+
+        let sli = self.delivery_sli.report(chrono::Utc::now());
+        let name = "mostro_push_delivery_sli_ratio";
+        write_header(
+            &mut out,
+            name,
+            "Share of matched events a provider accepted within the SLI threshold",
+            "gauge",
+        );
+        for window in sli.windows.iter() {
+            if let Some(ratio) = window.ratio {
+                let _ = writeln!(out, "{}{{window=\"{}\"}} {}", name, window.window, ratio);
+            }
+        }
+        let name = "mostro_push_delivery_sli_events";
+        write_header(&mut out, name, "Resolved matched events in the SLI window, by outcome", "gauge");
+        for window in sli.windows.iter() {
+            let _ = writeln!(out, "{}{{window=\"{}\",outcome=\"good\"}} {}", name, window.window, window.good);
+            let _ = writeln!(
+                out,
+                "{}{{window=\"{}\",outcome=\"bad\"}} {}",
+                name,
+                window.window,
+                window.total - window.good
+            );
+        }
+
+        let name = "mostro_push_http_request_duration_seconds";
+        write_header(&mut out, name, "HTTP request latency by route", "histogram");
+        for (route, histogram) in &self.http_latency {
+            histogram.render(&mut out, name, &format!("route=\"{}\",", route), openmetrics);
+        }
+
+        let name = "mostro_push_dispatch_duration_seconds";
+        write_header(&mut out, name, "Push dispatch latency", "histogram");
+        self.dispatch_latency.render(&mut out, name, "", openmetrics);
+
+        if openmetrics {
+            // OpenMetrics names counter families without the _total suffix
+            out = out
+                .lines()
+                .map(|line| match line.strip_suffix(" counter") {
+                    Some(rest) if line.starts_with("# TYPE ") => {
+                        format!("{} counter\n", rest.trim_end_matches("_total"))
+                    }
+                    _ if line.starts_with("# HELP ") && line.contains("_total ") => {
+                        format!("{}\n", line.replacen("_total ", " ", 1))
+                    }
+                    _ => format!("{}\n", line),
+                })
+                .collect();
+        }
+        out
+    }
+}
+
+fn write_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    write_metric(out, name, help, "counter", value);
+}
+
+fn write_gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    write_metric(out, name, help, "gauge", value);
+}
+
+fn write_metric(out: &mut String, name: &str, help: &str, kind: &str, value: u64) {
+    write_header(out, name, help, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn write_provider_gauge<'a>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    values: impl Iterator<Item = (&'a str, u64)>,
+) {
+    write_labeled_gauge(out, name, help, "provider", values);
+}
+
+fn write_labeled_gauge<'a>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    label: &str,
+    values: impl Iterator<Item = (&'a str, u64)>,
+) {
+    write_header(out, name, help, "gauge");
+    for (label_value, value) in values {
+        let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, label_value, value);
+    }
+}
+
+fn write_header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Extract the trace id from a W3C `traceparent` header value.
+pub fn trace_id_from_traceparent(value: &str) -> Option<&str> {
+    let mut parts = value.split('-');
+    let (_version, trace_id) = (parts.next()?, parts.next()?);
+    (trace_id.len() == 32 && trace_id.bytes().all(|b| b.is_ascii_hexdigit()))
+        .then_some(trace_id)
+}
+
+/// Parse a comma-separated list of bucket bounds in seconds, e.g. "0.1,0.25,1".
+pub fn parse_buckets(value: &str) -> Result<Vec<f64>, std::num::ParseFloatError> {
+    value.split(',').map(|b| b.trim().parse()).collect()
+}
+
+/// Load lifetime counters and SLI aggregates from a checkpoint file.
+/// A missing or unreadable checkpoint starts the counters from zero.
+pub async fn load_checkpoint(path: &Path) -> Checkpoint {
+    let content = match fs::read_to_string(path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            info!("No metrics checkpoint at {}, starting from zero", path.display());
+            return Checkpoint::default();
+        }
+        Err(e) => {
+            warn!("Failed to read metrics checkpoint {}: {}", path.display(), e);
+            return Checkpoint::default();
+        }
+    };
+
+    match serde_json::from_str(&content) {
+        Ok(checkpoint) => checkpoint,
+        Err(e) => {
+            warn!("Ignoring corrupt metrics checkpoint {}: {}", path.display(), e);
+            Checkpoint::default()
+        }
+    }
+}
+
+/// Write the current lifetime counters and SLI aggregates to disk.
+pub async fn save_checkpoint(metrics: &Metrics, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    let content = serde_json::to_string_pretty(&metrics.checkpoint())?;
+
+    // Write to temporary file first, then rename for atomic write
+    let temp_path = path.with_extension("tmp");
+    fs::write(&temp_path, content).await?;
+    fs::rename(&temp_path, path).await?;
+
+    Ok(())
+}
+
+pub fn checkpoint_task(metrics: Arc<Metrics>, path: PathBuf, interval_secs: u64) -> Task {
+    Task::every("metrics_checkpoint", Duration::from_secs(interval_secs), move || {
+        let (metrics, path) = (metrics.clone(), path.clone());
+        async move {
+            if let Err(e) = save_checkpoint(&metrics, &path).await {
+                warn!("Failed to write metrics checkpoint: {}", e);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_checkpoint_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "mostro-push-{}-{}-{}.json",
+            name,
+            std::process::id(),
+            chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default()
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_lifetime_counters_survive_restart() {
+        let path = temp_checkpoint_path("restart");
+
+        let first = Metrics::new();
+        Metrics::inc(&first.pushes_sent);
+        Metrics::inc(&first.pushes_sent);
+        Metrics::inc(&first.registrations);
+        save_checkpoint(&first, &path).await.unwrap();
+
+        // Simulated restart: process-local counters start over, lifetime continues
+        let second = Metrics::new();
+        second.restore(load_checkpoint(&path).await);
+        Metrics::inc(&second.pushes_sent);
+
+        assert_eq!(Metrics::get(&second.pushes_sent), 1);
+        assert_eq!(
+            second.lifetime(),
+            LifetimeCounters { pushes_sent: 3, registrations: 1 }
+        );
+
+        let rendered = second.render();
+        assert!(rendered.contains("mostro_push_pushes_sent_total 1\n"));
+        assert!(rendered.contains("mostro_push_lifetime_pushes_sent 3\n"));
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_missing_or_corrupt_checkpoint_starts_from_zero() {
+        let path = temp_checkpoint_path("corrupt");
+        assert_eq!(load_checkpoint(&path).await, Checkpoint::default());
+
+        std::fs::write(&path, "{not json").unwrap();
+        assert_eq!(load_checkpoint(&path).await, Checkpoint::default());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_histogram_bucket_boundaries() {
+        let metrics = Metrics::builder()
+            .dispatch_buckets(vec![2.0, 0.5, 1.0])
+            .build();
+        assert_eq!(metrics.dispatch_latency.bounds(), &[0.5, 1.0, 2.0]);
+        assert!(metrics.http_latency["/api/register"].bounds().contains(&0.25));
+
+        // An observation equal to a bound falls into that bucket
+        metrics.dispatch_latency.observe(2.0);
+        metrics.dispatch_latency.observe(3.0);
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("mostro_push_dispatch_duration_seconds_bucket{le=\"1\"} 0\n"));
+        assert!(rendered.contains("mostro_push_dispatch_duration_seconds_bucket{le=\"2\"} 1\n"));
+        assert!(rendered.contains("mostro_push_dispatch_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(rendered.contains("mostro_push_dispatch_duration_seconds_count 2\n"));
+    }
+
+    #[test]
+    fn test_exemplar_attached_to_observation() {
+        let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+        let metrics = Metrics::builder().exemplars(true).build();
+        metrics.observe_http("/api/register", 0.2, Some(trace_id));
+
+        let histogram = &metrics.http_latency["/api/register"];
+        let exemplar = histogram.exemplar(0.25).unwrap();
+        assert_eq!(exemplar.trace_id, trace_id);
+        assert_eq!(exemplar.value, 0.2);
+        assert!(histogram.exemplar(0.1).is_none());
+
+        let rendered = metrics.render_openmetrics();
+        assert!(rendered.contains(&format!(
+            "mostro_push_http_request_duration_seconds_bucket{{route=\"/api/register\",le=\"0.25\"}} 1 # {{trace_id=\"{}\"}} 0.2",
+            trace_id
+        )));
+        assert!(rendered.contains("# TYPE mostro_push_pushes_sent counter\n"));
+        assert!(rendered.ends_with("# EOF\n"));
+        // Plain Prometheus text never carries exemplars
+        assert!(!metrics.render().contains("trace_id"));
+
+        // Disabled exemplars still record the observation
+        let metrics = Metrics::new();
+        metrics.observe_http("/api/register", 0.2, Some(trace_id));
+        assert_eq!(metrics.http_latency["/api/register"].count(), 1);
+        assert!(metrics.http_latency["/api/register"].exemplar(0.25).is_none());
+    }
+
+    #[test]
+    fn test_trace_id_from_traceparent() {
+        assert_eq!(
+            trace_id_from_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+            Some("4bf92f3577b34da6a3ce929d0e0e4736")
+        );
+        assert_eq!(trace_id_from_traceparent("garbage"), None);
+    }
+
+    #[test]
+    fn test_decrypt_key_index_rendered() {
+        let metrics = Metrics::new();
+        metrics.record_decrypt_key_index(0);
+        metrics.record_decrypt_key_index(2);
+        metrics.record_decrypt_key_index(0);
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("mostro_push_decrypt_key_index_total{key_index=\"0\"} 2\n"));
+        assert!(rendered.contains("mostro_push_decrypt_key_index_total{key_index=\"2\"} 1\n"));
+    }
+}
@@ -0,0 +1,1140 @@
+//! Public request/response types of the HTTP API.
+//!
+//! The routes serialize exactly these types, so external tooling can depend
+//! on this crate and deserialize responses without redefining them.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub use crate::crypto::Platform;
+pub use crate::store::PushEnvironment;
+use crate::nostr::{EventCategory, NotificationPreferences};
+
+/// Machine-readable reason attached to failed API calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+#[non_exhaustive]
+pub enum ErrorCode {
+    InvalidPubkey,
+    InvalidBase64,
+    InvalidTokenSize,
+    DecryptionFailed,
+    NotReady,
+    Unauthorized,
+    NotRegistered,
+    InvalidAnnotation,
+    MigrationFailed,
+    TokenMismatch,
+    UnsupportedEnvelope,
+    /// Write sent to a standby instance
+    NotLeader,
+    /// Replication sent to the instance that currently leads
+    IsLeader,
+    /// Registration for a platform this server doesn't advertise
+    UnsupportedPlatform,
+    /// Registration would replace one for another platform without `replace`
+    RegistrationConflict,
+    /// Over the global decrypt rate; retry after `Retry-After`
+    Overloaded,
+    /// The device token is registered under `MAX_PUBKEYS_PER_TOKEN` pubkeys already
+    TokenShareLimit,
+    /// The pubkey has `MAX_DEVICES_PER_PUBKEY` devices registered already
+    DeviceLimit,
+    /// Missing, stale or wrong signature by the trade key (401)
+    InvalidSignature,
+    /// Every `/admin/watch` slot is in use
+    WatchListFull,
+    /// `/admin/watch` request for a pubkey that isn't watched
+    NotWatched,
+    /// Admin request naming a background task that doesn't exist
+    UnknownTask,
+    /// Registration for an app id not in `PUSH_APPS_PATH`
+    UnknownApp,
+    /// `/admin/flags` request naming a flag the server doesn't have
+    UnknownFlag,
+    /// The flag has as many `/admin/flags` overrides as it keeps
+    FlagOverridesFull,
+    /// `/admin/flags` request for a pubkey without an override
+    NoFlagOverride,
+    /// `/admin/preview` couldn't build the push, e.g. for a malformed push key
+    PreviewFailed,
+    /// The request body isn't JSON of the expected shape
+    InvalidJson,
+    /// Over `RATE_LIMIT_PER_MINUTE` for the client IP or pubkey; retry after `Retry-After`
+    RateLimited,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct RegisterTokenRequest {
+    pub trade_pubkey: String,
+    pub encrypted_token: String,
+    /// Categories to push for; all when omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preferences: Option<NotificationPreferences>,
+    /// Replace a registration for another platform; see `PLATFORM_CONFLICT_POLICY`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub replace: bool,
+    /// App build registering, one of the server's `PUSH_APPS_PATH` entries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub app_id: Option<String>,
+    /// Sandbox builds register apart from release builds of the same pubkey
+    #[serde(default, skip_serializing_if = "PushEnvironment::is_production")]
+    pub environment: PushEnvironment,
+    /// Hex BIP-340 signature by the trade key; see `crypto::ownership`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    /// Unix seconds the signature was made at
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct UnregisterTokenRequest {
+    pub trade_pubkey: String,
+    #[serde(default, skip_serializing_if = "PushEnvironment::is_production")]
+    pub environment: PushEnvironment,
+    /// Envelope of the one device to remove; every device of the pubkey
+    /// is removed when omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypted_token: Option<String>,
+    /// Hex BIP-340 signature by the trade key; see `crypto::ownership`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    /// Unix seconds the signature was made at
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+}
+
+/// Upgrade a registration to the v2 envelope. The v1 envelope it was
+/// registered with proves ownership; both must carry the same device token.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct ReencryptRequest {
+    pub trade_pubkey: String,
+    pub old_encrypted_token: String,
+    pub encrypted_token: String,
+    /// Environment of the registration to upgrade
+    #[serde(default, skip_serializing_if = "PushEnvironment::is_production")]
+    pub environment: PushEnvironment,
+    /// Hex BIP-340 signature by the trade key over the new envelope; see `crypto::ownership`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    /// Unix seconds the signature was made at
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct HealthResponse {
+    pub status: String,
+    /// Releases before readiness checks only answered `{"status": "ok"}`
+    #[serde(default = "default_ready")]
+    pub ready: bool,
+    #[serde(default)]
+    pub relays_connected: usize,
+}
+
+fn default_ready() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct InfoResponse {
+    pub server_pubkey: String,
+    pub version: String,
+    /// Size of a standard envelope
+    pub encrypted_token_size: usize,
+    /// Every envelope size the server decrypts, standard first
+    #[serde(default)]
+    pub accepted_token_sizes: Vec<usize>,
+}
+
+/// What this server offers clients, for deciding whether and how to register.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct CapabilitiesResponse {
+    /// Push providers that initialized, e.g. "fcm", "unifiedpush"
+    pub providers: Vec<String>,
+    /// Platforms registrations are accepted for
+    pub platforms: Vec<Platform>,
+}
+
+/// Everything a client needs to integrate with this server, in one
+/// document, so clients can configure themselves per deployment.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct DiscoveryResponse {
+    pub version: String,
+    pub server_pubkey: String,
+    /// Version byte clients put first in the envelope payload
+    pub payload_version: u8,
+    /// Envelope versions accepted at registration: 1 is unbound, 2 is bound to the trade pubkey
+    pub envelope_versions: Vec<u8>,
+    /// Every envelope layout the server decrypts, standard first
+    pub schemes: Vec<DiscoveryScheme>,
+    /// Platforms registrations are accepted for
+    pub platforms: Vec<Platform>,
+    /// Push providers that initialized
+    pub providers: Vec<String>,
+    pub endpoints: DiscoveryEndpoints,
+    pub limits: DiscoveryLimits,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct DiscoveryScheme {
+    pub name: String,
+    /// Curve of the server key and the client's ephemeral key
+    pub curve: String,
+    pub kdf: String,
+    pub cipher: String,
+    pub nonce_size: usize,
+    pub encrypted_size: usize,
+    pub max_token_length: usize,
+    /// Longest device token when a push key follows it
+    pub max_token_length_with_push_key: usize,
+}
+
+/// Which optional endpoints this server serves.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct DiscoveryEndpoints {
+    pub reencrypt: bool,
+    pub registered: bool,
+    pub batch_register: bool,
+    pub validate: bool,
+    /// Server-sent event stream
+    pub events: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct DiscoveryLimits {
+    /// Longest device token any scheme carries
+    pub max_token_length: usize,
+    /// Registrations per batch request; null without a batch endpoint
+    pub max_batch_size: Option<usize>,
+    /// Pubkeys one device token may be registered under; null when unlimited
+    pub max_pubkeys_per_token: Option<usize>,
+    /// Devices one pubkey may register in each environment
+    pub max_devices_per_pubkey: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct TokenStoreStats {
+    pub total: usize,
+    pub android: usize,
+    pub ios: usize,
+    #[serde(default)]
+    pub web: usize,
+    /// Registrations using the pubkey-bound v2 envelope, to track client migration
+    #[serde(default)]
+    pub envelope_v2: usize,
+    /// Registrations removed for outliving `TOKEN_TTL_SECONDS` since start;
+    /// null for stores that expire registrations themselves (Redis)
+    #[serde(default)]
+    pub expired: Option<u64>,
+}
+
+/// Push outcomes over a window; `success_rate` is null when nothing was sent.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct DeliveryCounts {
+    pub succeeded: u64,
+    pub failed: u64,
+    pub success_rate: Option<f64>,
+}
+
+impl DeliveryCounts {
+    pub fn new(succeeded: u64, failed: u64) -> Self {
+        let total = succeeded + failed;
+        Self {
+            succeeded,
+            failed,
+            success_rate: (total > 0).then(|| succeeded as f64 / total as f64),
+        }
+    }
+}
+
+/// Delivery SLI over one rolling window; `ratio` is null when no event
+/// has resolved in it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct SliWindow {
+    /// e.g. `1h`, `7d`
+    pub window: String,
+    pub window_secs: u64,
+    /// Events a provider accepted within the threshold
+    pub good: u64,
+    pub total: u64,
+    pub ratio: Option<f64>,
+}
+
+impl SliWindow {
+    pub fn new(window: String, window_secs: u64, good: u64, bad: u64) -> Self {
+        let total = good + bad;
+        Self {
+            window,
+            window_secs,
+            good,
+            total,
+            ratio: (total > 0).then(|| good as f64 / total as f64),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct SliReport {
+    pub threshold_secs: u64,
+    /// Matched events still inside the threshold, not counted yet
+    pub in_flight: u64,
+    pub windows: Vec<SliWindow>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct DeliveryStatsResponse {
+    pub window_secs: u64,
+    pub total: DeliveryCounts,
+    /// Keyed by platform name
+    pub platforms: BTreeMap<String, DeliveryCounts>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct StatusResponse {
+    pub status: String,
+    pub version: String,
+    pub server_pubkey: String,
+    pub tokens: TokenStoreStats,
+    /// Only present for providers with a configured quota
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub quotas: Vec<ProviderQuotaStatus>,
+    /// Startup warmup progress; absent when warmup is disabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub warmup: Option<WarmupStatus>,
+    #[serde(default)]
+    pub uptime_seconds: u64,
+    /// When this process started
+    #[serde(default)]
+    pub started_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarmupState {
+    Running,
+    Complete,
+    /// The deadline passed or a step failed; the server went ready anyway
+    Degraded,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct WarmupStatus {
+    pub state: WarmupState,
+    pub pending: Vec<String>,
+    pub completed: Vec<String>,
+    pub failed: Vec<String>,
+    /// Set once warmup has finished
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+}
+
+/// Sliding-window usage of a push provider's requests/minute budget.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct ProviderQuotaStatus {
+    pub provider: String,
+    pub limit_per_minute: u32,
+    pub used: u32,
+    /// Pushes delayed until budget frees up
+    pub queued: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct RegisterResponse {
+    pub success: bool,
+    pub message: String,
+    /// Whether the registration is stored, or only queued for writing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<RegisterStatus>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Platform>,
+    /// Stored, but no push service is configured for the platform
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub undeliverable: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<ErrorCode>,
+}
+
+impl RegisterResponse {
+    pub fn registered(platform: Platform) -> Self {
+        Self {
+            success: true,
+            message: "Token registered successfully".to_string(),
+            status: Some(RegisterStatus::Registered),
+            platform: Some(platform),
+            undeliverable: false,
+            error_code: None,
+        }
+    }
+
+    pub fn accepted(platform: Platform) -> Self {
+        Self {
+            success: true,
+            message: "Token accepted, registration will be stored shortly".to_string(),
+            status: Some(RegisterStatus::Accepted),
+            platform: Some(platform),
+            undeliverable: false,
+            error_code: None,
+        }
+    }
+
+    pub fn undeliverable(mut self, undeliverable: bool) -> Self {
+        self.undeliverable = undeliverable;
+        self
+    }
+
+    pub fn error(error_code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            success: false,
+            message: message.into(),
+            status: None,
+            platform: None,
+            undeliverable: false,
+            error_code: Some(error_code),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegisterStatus {
+    /// Written to the store before responding
+    Registered,
+    /// Queued for writing; not yet visible to lookups
+    Accepted,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct ReencryptResponse {
+    pub success: bool,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub envelope_version: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<ErrorCode>,
+}
+
+impl ReencryptResponse {
+    pub fn ok(envelope_version: u8) -> Self {
+        Self {
+            success: true,
+            message: "Registration re-encrypted".to_string(),
+            envelope_version: Some(envelope_version),
+            error_code: None,
+        }
+    }
+
+    pub fn error(error_code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            success: false,
+            message: message.into(),
+            envelope_version: None,
+            error_code: Some(error_code),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct UnregisterResponse {
+    pub success: bool,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<ErrorCode>,
+    /// Platforms of the removed registrations; absent when nothing was removed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub platforms: Vec<Platform>,
+}
+
+impl UnregisterResponse {
+    pub fn ok(message: impl Into<String>) -> Self {
+        Self {
+            success: true,
+            message: message.into(),
+            error_code: None,
+            platforms: Vec::new(),
+        }
+    }
+
+    pub fn error(error_code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            success: false,
+            message: message.into(),
+            error_code: Some(error_code),
+            platforms: Vec::new(),
+        }
+    }
+
+    pub fn with_platforms(mut self, platforms: Vec<Platform>) -> Self {
+        self.platforms = platforms;
+        self
+    }
+}
+
+/// Machine-readable prefix of a relay NOTICE/CLOSED message (NIP-01).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum RelayReasonKind {
+    RateLimited,
+    PowRequired,
+    Restricted,
+    AuthRequired,
+    Blocked,
+    Invalid,
+    Duplicate,
+    Error,
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct RelayReason {
+    pub kind: RelayReasonKind,
+    pub message: String,
+    pub at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct RelayInfo {
+    pub url: String,
+    pub connected: bool,
+    pub requires_auth: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_reason: Option<RelayReason>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct RelaysResponse {
+    pub relays: Vec<RelayInfo>,
+}
+
+/// Whether a pubkey has registrations, from `/api/registered/{trade_pubkey}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct RegisteredCheckResponse {
+    pub registered: bool,
+    /// One per environment the pubkey is registered in
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub platforms: Vec<Platform>,
+    /// Last successful push to the pubkey since this process started
+    #[serde(default)]
+    pub last_notified_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Everything a dashboard polls for, in one response from `/api/overview`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct OverviewResponse {
+    pub generated_at: chrono::DateTime<chrono::Utc>,
+    pub relays: RelayOverview,
+    pub tokens: TokenStoreStats,
+    pub providers: Vec<ProviderOverview>,
+    pub queues: QueueDepths,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct RelayOverview {
+    pub connected: usize,
+    pub total: usize,
+    pub relays: Vec<RelayInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct ProviderOverview {
+    pub provider: String,
+    /// Absent when the provider has no configured quota
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota: Option<ProviderQuotaStatus>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct QueueDepths {
+    /// Registrations accepted but not yet written to the store
+    pub register_writes: u64,
+    /// Pushes being dispatched right now
+    pub pushes_in_flight: u64,
+    /// Pushes delayed by provider quotas
+    pub quota_delayed: usize,
+}
+
+/// Body of failures that have no endpoint-specific response type.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct ErrorResponse {
+    pub success: bool,
+    pub message: String,
+    pub error_code: ErrorCode,
+    /// Request field the error is about, when known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+}
+
+impl ErrorResponse {
+    pub fn new(error_code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            success: false,
+            message: message.into(),
+            error_code,
+            field: None,
+        }
+    }
+}
+
+/// What a migration does when the target already has a registration for a pubkey.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    /// Keep the target's registration
+    #[default]
+    Skip,
+    /// Replace it with the source's
+    Overwrite,
+    /// Keep whichever was registered more recently
+    Newest,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct MigrateRequest {
+    /// Base URL of the instance to pull registrations from
+    pub source_url: String,
+    /// That instance's `ADMIN_TOKEN`
+    pub source_admin_token: String,
+    #[serde(default)]
+    pub conflict_policy: ConflictPolicy,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct SecurityFinding {
+    pub rule: String,
+    pub description: String,
+    pub passed: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub problem: Option<String>,
+}
+
+/// Outcome of the startup security checks.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct SecurityReport {
+    /// Whether violations stop the server from starting
+    pub strict: bool,
+    pub findings: Vec<SecurityFinding>,
+}
+
+impl SecurityReport {
+    pub fn violations(&self) -> impl Iterator<Item = &SecurityFinding> {
+        self.findings.iter().filter(|f| !f.passed)
+    }
+}
+
+/// Effective non-secret settings, from `/admin/config`. Secrets are only
+/// reported as configured or not.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct ConfigReport {
+    pub version: String,
+    pub bind: String,
+    pub relays: Vec<String>,
+    pub mostro_pubkey: String,
+    pub fcm_enabled: bool,
+    pub unifiedpush_enabled: bool,
+    pub advertised_platforms: Option<Vec<Platform>>,
+    pub rate_limit_per_minute: u32,
+    pub standby: bool,
+    pub admin_token_set: bool,
+    pub replication_token_set: bool,
+    pub security: SecurityReport,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct MigrationReport {
+    pub success: bool,
+    pub message: String,
+    pub dry_run: bool,
+    pub source_total: usize,
+    pub inserted: usize,
+    pub overwritten: usize,
+    pub skipped: usize,
+    /// Entries re-read from the target and compared against the source
+    pub spot_checked: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<ErrorCode>,
+}
+
+/// One registration as served by `GET /admin/export`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct ExportedRegistration {
+    pub trade_pubkey: String,
+    #[serde(flatten)]
+    pub token: crate::store::RegisteredToken,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct ExportResponse {
+    pub registrations: Vec<ExportedRegistration>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct LeadershipRequest {
+    pub leader: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct LeadershipResponse {
+    pub leader: bool,
+}
+
+/// A background task's schedule, for `/admin/tasks`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct TaskStatus {
+    pub name: String,
+    pub min_interval_secs: u64,
+    pub max_interval_secs: u64,
+    /// Interval chosen for the next run from the current load
+    pub interval_secs: u64,
+    pub last_run: Option<chrono::DateTime<chrono::Utc>>,
+    pub next_run: Option<chrono::DateTime<chrono::Utc>>,
+    pub runs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct TasksResponse {
+    pub tasks: Vec<TaskStatus>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct WatchRequest {
+    /// How long to watch; one hour when omitted, at most a day
+    pub duration_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct WatchResponse {
+    pub trade_pubkey: String,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// A step in handling an event for a watched pubkey.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct WatchRecord {
+    pub at: chrono::DateTime<chrono::Utc>,
+    /// Pipeline step: `event`, `lookup`, `skipped`, `queued` or `send`
+    pub stage: String,
+    pub detail: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct WatchStatus {
+    pub trade_pubkey: String,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+    /// False once expired; records stay readable until the slot is reused
+    pub active: bool,
+    pub records: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct WatchListResponse {
+    pub watches: Vec<WatchStatus>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct WatchRecordsResponse {
+    pub records: Vec<WatchRecord>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct FlagOverride {
+    pub trade_pubkey: String,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct FlagStatus {
+    pub flag: String,
+    /// Share of pubkeys the flag is on for, overrides aside
+    pub fraction: f64,
+    pub overrides: Vec<FlagOverride>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct FlagsResponse {
+    pub flags: Vec<FlagStatus>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct FlagOverrideRequest {
+    pub enabled: bool,
+}
+
+/// How a flag evaluates for one pubkey.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct FlagEvaluation {
+    pub flag: String,
+    pub trade_pubkey: String,
+    pub enabled: bool,
+    /// Decided by an override rather than the rollout fraction
+    pub overridden: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct TestSendRequest {
+    pub platform: Platform,
+    pub device_token: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub app_id: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct TestSendResponse {
+    /// Whether the provider accepted the push
+    pub success: bool,
+    pub provider: String,
+    /// The provider's error when it rejected the push
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// What one GC pass did to a dataset.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct GcReport {
+    pub dataset: String,
+    pub removed: u64,
+    pub reclaimed_bytes: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct GcResponse {
+    pub datasets: Vec<GcReport>,
+}
+
+/// Which push `/admin/preview` builds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PreviewMode {
+    /// Wake-up for a live event
+    #[default]
+    Event,
+    /// Push for events missed while the device was registering
+    CatchUp,
+    /// The `/admin/test-send` notification
+    Test,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct PreviewRequest {
+    pub platform: Platform,
+    /// Device token, or endpoint URL for UnifiedPush, the requests would go to
+    pub device_token: String,
+    #[serde(default)]
+    pub mode: PreviewMode,
+    /// Recipient of the synthetic event; decides flag rollouts and grouping
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trade_pubkey: Option<String>,
+    /// Tags of the synthetic event besides its `p` tag, as `[name, value, ...]`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub app_id: Option<String>,
+    #[serde(default, skip_serializing_if = "PushEnvironment::is_production")]
+    pub environment: PushEnvironment,
+    /// Hex push key; the payload is sealed to it, as for registrations with one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub push_key: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct PreviewResponse {
+    /// Category the synthetic event was classified into
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<EventCategory>,
+    pub sealed: bool,
+    /// In the order dispatch tries the services; later ones are only used
+    /// when the ones before fail
+    pub requests: Vec<ProviderRequestPreview>,
+}
+
+/// A provider request as it would be sent, credentials aside.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct ProviderRequestPreview {
+    pub provider: String,
+    pub method: String,
+    pub url: String,
+    pub headers: BTreeMap<String, String>,
+    pub body: serde_json::Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct ReconnectResponse {
+    /// Whether the listener reconnected before the response was sent
+    pub reconnected: bool,
+    pub relays: Vec<RelayInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct SetAnnotationRequest {
+    pub value: String,
+}
+
+/// Admin view of a registration's annotations.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct AnnotationsResponse {
+    pub success: bool,
+    pub message: String,
+    #[serde(default)]
+    pub annotations: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<ErrorCode>,
+}
+
+impl AnnotationsResponse {
+    pub fn ok(message: impl Into<String>, annotations: BTreeMap<String, String>) -> Self {
+        Self {
+            success: true,
+            message: message.into(),
+            annotations,
+            error_code: None,
+        }
+    }
+
+    pub fn error(error_code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            success: false,
+            message: message.into(),
+            annotations: BTreeMap::new(),
+            error_code: Some(error_code),
+        }
+    }
+}
+
+/// Everything `/admin/dashboard` shows, also served as JSON for polling.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct DashboardSnapshot {
+    pub generated_at: chrono::DateTime<chrono::Utc>,
+    pub version: String,
+    pub tokens: TokenStoreStats,
+    /// Registrations since this process started
+    pub registrations: u64,
+    /// Registrations across restarts
+    pub lifetime_registrations: u64,
+    pub relays: Vec<RelayInfo>,
+    pub delivery: DeliveryStatsResponse,
+    /// Registrations accepted but not yet written to the store
+    pub write_queue_depth: u64,
+    /// Pushes being dispatched right now
+    pub pushes_in_flight: u64,
+    /// Only present for providers with a configured quota
+    pub quotas: Vec<ProviderQuotaStatus>,
+    /// Newest first
+    pub recent_audit: Vec<crate::audit::AuditEntry>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T>(value: &T) -> T
+    where
+        T: Serialize + for<'de> Deserialize<'de>,
+    {
+        serde_json::from_str(&serde_json::to_string(value).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_register_response_round_trip() {
+        let ok = RegisterResponse::registered(Platform::Ios);
+        assert_eq!(round_trip(&ok), ok);
+
+        let accepted = RegisterResponse::accepted(Platform::Android);
+        assert_eq!(round_trip(&accepted), accepted);
+
+        let err = RegisterResponse::error(ErrorCode::InvalidBase64, "bad base64");
+        assert_eq!(round_trip(&err), err);
+    }
+
+    #[test]
+    fn test_status_response_round_trip() {
+        let status = StatusResponse {
+            status: "running".to_string(),
+            version: "0.2.0".to_string(),
+            server_pubkey: "02ab".to_string(),
+            tokens: TokenStoreStats { total: 3, android: 2, ios: 1, web: 0, envelope_v2: 1, expired: Some(0) },
+            quotas: vec![ProviderQuotaStatus {
+                provider: "fcm".to_string(),
+                limit_per_minute: 600,
+                used: 42,
+                queued: 0,
+            }],
+            warmup: Some(WarmupStatus {
+                state: WarmupState::Complete,
+                pending: Vec::new(),
+                completed: vec!["push_providers".to_string()],
+                failed: Vec::new(),
+                duration_ms: Some(850),
+            }),
+            uptime_seconds: 3600,
+            started_at: chrono::DateTime::from_timestamp(1_714_564_800, 0).unwrap(),
+        };
+        assert_eq!(round_trip(&status), status);
+    }
+
+    /// Bodies captured from a release, which clients and tooling built
+    /// against it still send or parse. New fixtures go in a directory per
+    /// release; existing ones never change.
+    macro_rules! fixture {
+        ($release:literal, $name:literal) => {
+            include_str!(concat!("../tests/fixtures/contract/", $release, "/", $name, ".json"))
+        };
+    }
+
+    fn parse<T: for<'de> Deserialize<'de>>(json: &str) -> T {
+        serde_json::from_str(json).unwrap_or_else(|e| panic!("{}: {}", e, json))
+    }
+
+    /// Field names in a serialized body, nested ones included.
+    fn field_names(value: &serde_json::Value, names: &mut Vec<String>) {
+        match value {
+            serde_json::Value::Object(map) => {
+                for (key, value) in map {
+                    names.push(key.clone());
+                    field_names(value, names);
+                }
+            }
+            serde_json::Value::Array(items) => items.iter().for_each(|item| field_names(item, names)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn test_previous_release_bodies_still_parse() {
+        let register: RegisterTokenRequest = parse(fixture!("0.2.0", "register_request"));
+        assert_eq!((register.preferences, register.replace, register.environment), (None, false, PushEnvironment::Production));
+        let unregister: UnregisterTokenRequest = parse(fixture!("0.2.0", "unregister_request"));
+        assert_eq!(unregister.trade_pubkey, register.trade_pubkey);
+
+        let registered: RegisterResponse = parse(fixture!("0.2.0", "register_response"));
+        assert_eq!((registered.success, registered.platform), (true, Some(Platform::Android)));
+        let failed: RegisterResponse = parse(fixture!("0.2.0", "register_error_response"));
+        assert_eq!((failed.success, failed.error_code), (false, None));
+        let unregistered: UnregisterResponse = parse(fixture!("0.2.0", "unregister_response"));
+        assert!(unregistered.platforms.is_empty());
+        let status: StatusResponse = parse(fixture!("0.2.0", "status_response"));
+        assert_eq!((status.tokens.total, status.tokens.envelope_v2), (3, 0));
+        let info: InfoResponse = parse(fixture!("0.2.0", "info_response"));
+        assert_eq!(info.encrypted_token_size, 281);
+        let health: HealthResponse = parse(fixture!("0.2.0", "health_response"));
+        assert!(health.ready);
+
+        // Current bodies only use snake_case names
+        let mut names = Vec::new();
+        for value in [
+            serde_json::to_value(&register).unwrap(),
+            serde_json::to_value(RegisterResponse::accepted(Platform::Ios).undeliverable(true)).unwrap(),
+            serde_json::to_value(&status).unwrap(),
+            serde_json::to_value(&health).unwrap(),
+        ] {
+            field_names(&value, &mut names);
+        }
+        for name in names {
+            assert!(name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_'), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_wire_representations() {
+        let json = serde_json::to_value(RegisterResponse::registered(Platform::Android)).unwrap();
+        assert_eq!(json["platform"], "android");
+        assert!(json.get("error_code").is_none());
+
+        let json = serde_json::to_value(UnregisterResponse::error(ErrorCode::InvalidPubkey, "bad")).unwrap();
+        assert_eq!(json["error_code"], "INVALID_PUBKEY");
+    }
+}
@@ -0,0 +1,67 @@
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// What an event is about, as far as the server can tell without decrypting it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventCategory {
+    /// Order and trade status updates
+    Trade,
+    /// Messages from the counterparty
+    Chat,
+    Dispute,
+    Other,
+}
+
+impl EventCategory {
+    pub const ALL: [EventCategory; 4] = [Self::Trade, Self::Chat, Self::Dispute, Self::Other];
+
+    /// This category's bit in `NotificationPreferences`.
+    pub fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+impl FromStr for EventCategory {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "trade" => Ok(Self::Trade),
+            "chat" => Ok(Self::Chat),
+            "dispute" => Ok(Self::Dispute),
+            "other" => Ok(Self::Other),
+            _ => Err(format!("unknown event category '{}'", s)),
+        }
+    }
+}
+
+/// Bitmask of the categories a registration wants pushes for, one bit per
+/// `EventCategory::bit`. Every category is notified by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct NotificationPreferences(pub u32);
+
+impl Default for NotificationPreferences {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl NotificationPreferences {
+    pub const ALL: Self = Self(u32::MAX);
+
+    pub fn is_all(&self) -> bool {
+        *self == Self::ALL
+    }
+
+    /// Whether to push for an event of `category`. Uncategorized events
+    /// are always pushed.
+    pub fn allows(&self, category: Option<EventCategory>) -> bool {
+        category.is_none_or(|category| self.0 & category.bit() != 0)
+    }
+
+    pub fn without(self, category: EventCategory) -> Self {
+        Self(self.0 & !category.bit())
+    }
+}
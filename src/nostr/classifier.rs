@@ -0,0 +1,231 @@
+//! Decides an event's category from what is visible without decrypting it:
+//! the category tag Mostro sets, then operator rules in order, then a default.
+
+use log::{info, warn};
+use nostr_sdk::Event;
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::scheduler::Task;
+use super::category::EventCategory;
+
+/// One rule from the rules file. Every condition it sets must hold.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CategoryRule {
+    pub category: EventCategory,
+    #[serde(default)]
+    pub kind: Option<u64>,
+    /// The event carries a tag with this name
+    #[serde(default)]
+    pub tag: Option<String>,
+    /// ...whose first value is this
+    #[serde(default)]
+    pub tag_value: Option<String>,
+    /// Hex pubkeys; the event is signed by one of them
+    #[serde(default)]
+    pub authors: Vec<String>,
+    /// Content length band in bytes, inclusive
+    #[serde(default)]
+    pub min_size: Option<usize>,
+    #[serde(default)]
+    pub max_size: Option<usize>,
+}
+
+impl CategoryRule {
+    fn matches(&self, event: &Event) -> bool {
+        let size = event.content.len();
+        self.kind.is_none_or(|kind| event.kind.as_u64() == kind)
+            && self.tag.as_ref().is_none_or(|name| {
+                event.tags.iter().any(|tag| {
+                    let tag_vec = tag.as_vec();
+                    tag_vec[0] == *name
+                        && self.tag_value.as_ref().is_none_or(|value| tag_vec.get(1) == Some(value))
+                })
+            })
+            && (self.authors.is_empty() || self.authors.contains(&event.pubkey.to_string()))
+            && self.min_size.is_none_or(|min| size >= min)
+            && self.max_size.is_none_or(|max| size <= max)
+    }
+}
+
+/// Contents of `CATEGORY_RULES_PATH`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CategoryRules {
+    #[serde(default)]
+    pub rules: Vec<CategoryRule>,
+    /// Category of events no rule matches; they stay uncategorized, and are
+    /// always pushed, when unset
+    #[serde(default)]
+    pub default: Option<EventCategory>,
+}
+
+impl CategoryRules {
+    fn parse(content: &str) -> Result<Self, String> {
+        let mut rules: Self = serde_json::from_str(content).map_err(|e| format!("invalid category rules: {}", e))?;
+        for rule in &mut rules.rules {
+            rule.authors.iter_mut().for_each(|author| author.make_ascii_lowercase());
+        }
+        Ok(rules)
+    }
+}
+
+pub struct CategoryClassifier {
+    category_tag: String,
+    path: Option<PathBuf>,
+    rules: RwLock<Arc<CategoryRules>>,
+    /// Modification time of the rules file when last loaded
+    loaded_version: Mutex<Option<SystemTime>>,
+}
+
+impl CategoryClassifier {
+    pub fn new(category_tag: impl Into<String>, rules: CategoryRules) -> Self {
+        Self {
+            category_tag: category_tag.into(),
+            path: None,
+            rules: RwLock::new(Arc::new(rules)),
+            loaded_version: Mutex::new(None),
+        }
+    }
+
+    /// Read rules from `path`, if set. `reload` picks up later edits.
+    pub fn load(category_tag: impl Into<String>, path: Option<PathBuf>) -> Result<Self, String> {
+        let classifier = Self {
+            path,
+            ..Self::new(category_tag, CategoryRules::default())
+        };
+        classifier.reload()?;
+        Ok(classifier)
+    }
+
+    /// Re-read the rules file if it changed since it was last loaded.
+    /// Returns true if new rules took effect; on error the old ones stay.
+    pub fn reload(&self) -> Result<bool, String> {
+        let Some(path) = &self.path else {
+            return Ok(false);
+        };
+        let modified = std::fs::metadata(path)
+            .and_then(|meta| meta.modified())
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        if *self.loaded_version.lock().unwrap() == Some(modified) {
+            return Ok(false);
+        }
+
+        let content = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let rules = CategoryRules::parse(&content)?;
+        info!("Loaded {} category rule(s) from {}", rules.rules.len(), path.display());
+        *self.rules.write().unwrap() = Arc::new(rules);
+        *self.loaded_version.lock().unwrap() = Some(modified);
+        Ok(true)
+    }
+
+    pub fn classify(&self, event: &Event) -> Option<EventCategory> {
+        let tagged = event.tags.iter().find_map(|tag| {
+            let tag_vec = tag.as_vec();
+            (tag_vec[0] == self.category_tag)
+                .then(|| tag_vec.get(1)?.parse().ok())
+                .flatten()
+        });
+        if tagged.is_some() {
+            return tagged;
+        }
+
+        let rules = self.rules.read().unwrap().clone();
+        rules
+            .rules
+            .iter()
+            .find(|rule| rule.matches(event))
+            .map(|rule| rule.category)
+            .or(rules.default)
+    }
+}
+
+/// Pick up edits to the rules file every `interval`.
+pub fn reload_task(classifier: Arc<CategoryClassifier>, interval: Duration) -> Task {
+    Task::every("category_rules", interval, move || {
+        let classifier = classifier.clone();
+        async move {
+            if let Err(e) = classifier.reload() {
+                warn!("Keeping previous category rules: {}", e);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_sdk::prelude::*;
+
+    fn event(kind: u64, tags: Vec<Vec<&str>>, size: usize, keys: &Keys) -> Event {
+        let tags: Vec<Tag> = tags.into_iter().map(|tag| Tag::parse(tag).unwrap()).collect();
+        EventBuilder::new(Kind::Custom(kind), "x".repeat(size), tags)
+            .to_event(keys)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_classification_table() {
+        let solver = Keys::generate();
+        let solver_hex = solver.public_key().to_string();
+        let rules = CategoryRules::parse(&format!(
+            r#"{{
+                "rules": [
+                    {{ "category": "dispute", "authors": ["{}"] }},
+                    {{ "category": "dispute", "tag": "s", "tag_value": "dispute" }},
+                    {{ "category": "chat", "kind": 1059, "min_size": 2000 }},
+                    {{ "category": "trade", "kind": 1059, "max_size": 1999 }}
+                ],
+                "default": "other"
+            }}"#,
+            solver_hex.to_uppercase()
+        ))
+        .unwrap();
+        let classifier = CategoryClassifier::new("category", rules);
+        let anyone = Keys::generate();
+
+        let table = [
+            ("explicit tag wins over rules", event(1059, vec![vec!["category", "chat"]], 10, &solver), Some(EventCategory::Chat)),
+            ("unknown tag value falls through", event(1059, vec![vec!["category", "x"]], 10, &anyone), Some(EventCategory::Trade)),
+            ("configured author", event(1059, vec![], 10, &solver), Some(EventCategory::Dispute)),
+            ("tag with value", event(1059, vec![vec!["s", "dispute"]], 10, &anyone), Some(EventCategory::Dispute)),
+            ("tag with other value", event(1059, vec![vec!["s", "open"]], 3000, &anyone), Some(EventCategory::Chat)),
+            ("large gift wrap", event(1059, vec![], 2000, &anyone), Some(EventCategory::Chat)),
+            ("small gift wrap", event(1059, vec![], 1999, &anyone), Some(EventCategory::Trade)),
+            ("other kind uses the default", event(4, vec![], 10, &anyone), Some(EventCategory::Other)),
+        ];
+        for (name, event, expected) in table {
+            assert_eq!(classifier.classify(&event), expected, "{}", name);
+        }
+
+        // Without rules only the tag counts and the rest stay uncategorized
+        let plain = CategoryClassifier::new("category", CategoryRules::default());
+        assert_eq!(plain.classify(&event(1059, vec![], 10, &anyone)), None);
+    }
+
+    #[test]
+    fn test_rules_reload_when_file_changes() {
+        let path = std::env::temp_dir().join(format!("category-rules-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{ "default": "trade" }"#).unwrap();
+        let classifier = CategoryClassifier::load("category", Some(path.clone())).unwrap();
+        let gift_wrap = event(1059, vec![], 10, &Keys::generate());
+        assert_eq!(classifier.classify(&gift_wrap), Some(EventCategory::Trade));
+        assert!(!classifier.reload().unwrap());
+
+        // A broken edit keeps the previous rules
+        std::fs::write(&path, "{ not json").unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(5)).unwrap();
+        assert!(classifier.reload().is_err());
+        assert_eq!(classifier.classify(&gift_wrap), Some(EventCategory::Trade));
+
+        std::fs::write(&path, r#"{ "default": "chat" }"#).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        assert!(classifier.reload().unwrap());
+        assert_eq!(classifier.classify(&gift_wrap), Some(EventCategory::Chat));
+        std::fs::remove_file(&path).ok();
+    }
+}
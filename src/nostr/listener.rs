@@ -1,26 +1,99 @@
+use futures::StreamExt;
 use log::{info, error, warn, debug};
 use nostr_sdk::prelude::*;
+use std::future::Future;
 use std::str::FromStr;
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use tokio::time::{sleep, Duration};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout, Duration};
 
-use crate::config::Config;
-use crate::push::PushService;
-use crate::store::TokenStore;
+use crate::config::{Config, GroupingSource, ListenerFailureAction};
+use crate::flags::FeatureFlags;
+use crate::health::{Readiness, RelayHealth};
+use crate::metrics::Metrics;
+use crate::push::payload;
+use crate::push::outbox::{Outbox, UndeliveredEvent};
+use crate::push::{BackfillTracker, Dispatcher, LastNotified, PushPayload};
+use crate::replication::Leadership;
+use crate::store::wal::{self, Wal};
+use crate::store::{store_key, PushEnvironment, RegisteredToken, TokenStore};
+use crate::watch::{WatchHandle, WatchList};
+use super::category::EventCategory;
+use super::classifier::{CategoryClassifier, CategoryRules};
+use super::overload::OverloadGuard;
+use super::reconnect::ReconnectControl;
+use super::relay_monitor::{RelayAction, RelayMonitor, RELAY_DISCOVERY_KIND};
+use super::trace::{EventOutcome, TraceRecord, TraceWriter};
+
+/// The parts of a kind 1059 event the handling pipeline depends on, whether
+/// it came from a relay or was injected (e.g. by `replay`).
+#[derive(Debug, Clone)]
+pub struct InboundEvent {
+    pub event_id: String,
+    /// Recipient from the `p` tag
+    pub trade_pubkey: Option<String>,
+    /// Carries the configured no-push marker
+    pub no_push: bool,
+    /// From the configured category tag
+    pub category: Option<EventCategory>,
+    /// Groups the push on the device, from `NOTIFICATION_GROUPING`
+    pub grouping_id: Option<String>,
+    /// Relay that delivered the event first; unknown for catch-up fetches
+    pub relay: Option<String>,
+}
 
 pub struct NostrListener {
     config: Config,
-    push_services: Arc<Mutex<Vec<Box<dyn PushService>>>>,
-    token_store: Arc<TokenStore>,
+    dispatcher: Arc<Dispatcher>,
+    backfill: Arc<BackfillTracker>,
+    token_store: Arc<dyn TokenStore>,
+    metrics: Arc<Metrics>,
+    readiness: Arc<Readiness>,
+    relay_health: Arc<RelayHealth>,
     mostro_pubkey: String,
+    /// Pushes dispatched from the current connection, drained before reconnecting
+    in_flight: Mutex<JoinSet<()>>,
+    /// Relay set, adjusted by NIP-66 monitor reports when enabled
+    relay_monitor: RelayMonitor,
+    /// Per-event outcomes for later replay, when `EVENT_TRACE_PATH` is set
+    trace: Option<Arc<TraceWriter>>,
+    /// Events are only handled while this instance leads; always when unset
+    leadership: Option<Arc<Leadership>>,
+    /// Identity used on every relay connection for the life of the process
+    keys: Keys,
+    /// Client of the current connection, for sending (e.g. operator DMs)
+    client: Mutex<Option<Client>>,
+    /// Operator-requested reconnects
+    reconnect: Arc<ReconnectControl>,
+    /// Pubkeys whose handling is recorded step by step
+    watch_list: Option<Arc<WatchList>>,
+    /// Decide which dispatch behaviors each recipient gets
+    flags: Arc<FeatureFlags>,
+    /// The one place events get their category
+    classifier: Arc<CategoryClassifier>,
+    /// When the previous connection ended; the next one catches up from here
+    disconnected_at: Mutex<Option<Timestamp>>,
+    /// Samples relay events during floods, when `OVERLOAD_EVENTS_PER_SEC` is set
+    overload: Option<OverloadGuard>,
+    /// Events no provider accepted, replayed on startup, when `OUTBOX_PATH` is set
+    outbox: Option<Arc<Outbox>>,
+    /// Successful pushes per pubkey, for `/api/registered`
+    last_notified: Arc<LastNotified>,
 }
 
+/// How long one catch-up chunk may take to reach EOSE.
+const CATCHUP_FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
 impl NostrListener {
     pub fn new(
         config: Config,
-        push_services: Arc<Mutex<Vec<Box<dyn PushService>>>>,
-        token_store: Arc<TokenStore>,
+        dispatcher: Arc<Dispatcher>,
+        backfill: Arc<BackfillTracker>,
+        token_store: Arc<dyn TokenStore>,
+        metrics: Arc<Metrics>,
+        readiness: Arc<Readiness>,
+        relay_health: Arc<RelayHealth>,
     ) -> Result<Self, Box<dyn std::error::Error>> {
         // Validate the pubkey format
         let mostro_pubkey = config.nostr.mostro_pubkey.clone();
@@ -30,121 +103,1249 @@ impl NostrListener {
         // Validate it's valid hex by trying to parse it
         XOnlyPublicKey::from_str(&mostro_pubkey)
             .map_err(|_| "Invalid MOSTRO_PUBKEY (not a valid public key)")?;
+
+        let relay_monitor = RelayMonitor::new(
+            &config.nostr.relay_monitor_pubkeys,
+            config.nostr.relay_monitor_auto_add,
+            config.nostr.relays.clone(),
+        )?;
+
+        let keys = match &config.nostr.identity_key {
+            Some(key) => Keys::new(SecretKey::from_str(key).map_err(|_| "Invalid NOSTR_IDENTITY_KEY (expected a hex secret key)")?),
+            None => Keys::generate(),
+        };
+
+        let trace = match &config.nostr.event_trace_path {
+            Some(path) => {
+                info!("Writing event trace to {}", path);
+                Some(Arc::new(TraceWriter::open(std::path::Path::new(path))?))
+            }
+            None => None,
+        };
         
+        let overload = (config.nostr.overload_events_per_sec > 0)
+            .then(|| OverloadGuard::new(config.nostr.overload_events_per_sec, metrics.clone()));
+
+        let classifier = Arc::new(CategoryClassifier::new(
+            config.nostr.category_tag.clone(),
+            CategoryRules::default(),
+        ));
+
         Ok(Self {
             config,
-            push_services,
+            dispatcher,
+            backfill,
             token_store,
+            metrics,
+            readiness,
+            relay_health,
             mostro_pubkey,
+            in_flight: Mutex::new(JoinSet::new()),
+            relay_monitor,
+            trace,
+            leadership: None,
+            keys,
+            client: Mutex::new(None),
+            reconnect: Arc::new(ReconnectControl::new()),
+            watch_list: None,
+            flags: Arc::new(FeatureFlags::default()),
+            classifier,
+            disconnected_at: Mutex::new(None),
+            overload,
+            outbox: None,
+            last_notified: Arc::new(LastNotified::default()),
         })
     }
 
+    pub fn with_last_notified(mut self, last_notified: Arc<LastNotified>) -> Self {
+        self.last_notified = last_notified;
+        self
+    }
+
+    pub fn with_outbox(mut self, outbox: Arc<Outbox>) -> Self {
+        self.outbox = Some(outbox);
+        self
+    }
+
+    pub fn with_watch_list(mut self, watch_list: Arc<WatchList>) -> Self {
+        self.watch_list = Some(watch_list);
+        self
+    }
+
+    pub fn with_flags(mut self, flags: Arc<FeatureFlags>) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub fn with_classifier(mut self, classifier: Arc<CategoryClassifier>) -> Self {
+        self.classifier = classifier;
+        self
+    }
+
+    pub fn with_reconnect(mut self, reconnect: Arc<ReconnectControl>) -> Self {
+        self.reconnect = reconnect;
+        self
+    }
+
+    /// The event trace writer, when `EVENT_TRACE_PATH` is set.
+    pub fn trace(&self) -> Option<Arc<TraceWriter>> {
+        self.trace.clone()
+    }
+
+    /// Public key the listener connects to relays as.
+    pub fn identity_pubkey(&self) -> XOnlyPublicKey {
+        self.keys.public_key()
+    }
+
+    /// Send a NIP-04 DM from the listener's identity over the current connection.
+    pub async fn send_direct_message(
+        &self,
+        recipient: XOnlyPublicKey,
+        content: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let client = self.client.lock().unwrap().clone().ok_or("not connected to any relay")?;
+        client.send_direct_msg(recipient, content, None).await?;
+        Ok(())
+    }
+
+    /// Publish the registration log's head as a note from the listener's
+    /// identity, for `verify-wal` to check the log against later.
+    pub async fn publish_wal_anchor(&self, wal: &Wal) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let Some((seq, head)) = wal.head() else {
+            return Ok(());
+        };
+        let client = self.client.lock().unwrap().clone().ok_or("not connected to any relay")?;
+        client.send_event(wal::anchor_event(&self.keys, seq, &head)?).await?;
+        info!("Anchored registration log at record {} ({})", seq, head);
+        Ok(())
+    }
+
+    pub fn with_leadership(mut self, leadership: Arc<Leadership>) -> Self {
+        self.leadership = Some(leadership);
+        self
+    }
+
     pub async fn start(&self) {
+        self.replay_outbox().await;
         loop {
-            match self.connect_and_listen().await {
-                Ok(_) => {
+            let result = self.connect_and_listen().await;
+            match &result {
+                Ok(_) => self.readiness.record_listener_success(),
+                Err(_) => self.escalate_failure(),
+            }
+            match result {
+                // Reconnect right away, without the usual delay
+                Ok(true) => continue,
+                Ok(false) => {
                     warn!("Nostr connection closed, reconnecting in 5 seconds...");
                 }
                 Err(e) => {
                     error!("Error in Nostr listener: {}, reconnecting in 10 seconds...", e);
-                    sleep(Duration::from_secs(10)).await;
+                    if self.backoff(Duration::from_secs(10)).await {
+                        continue;
+                    }
                 }
             }
-            sleep(Duration::from_secs(5)).await;
+            // Relays that told us we're rate limited get a longer break
+            let delay = self.relay_health
+                .backoff_remaining(chrono::Utc::now())
+                .unwrap_or_default()
+                .max(Duration::from_secs(5));
+            self.backoff(delay).await;
+        }
+    }
+
+    /// Count a failed connect cycle, and past the threshold report the
+    /// listener dead or exit for a restart.
+    fn escalate_failure(&self) {
+        if !self.readiness.record_listener_failure() {
+            return;
+        }
+        let failures = self.readiness.listener_failures();
+        match self.config.nostr.listener_failure_action {
+            ListenerFailureAction::Unhealthy => {
+                error!("Nostr listener failed {} connect cycles in a row, reporting unhealthy", failures);
+            }
+            ListenerFailureAction::Exit => {
+                error!("Nostr listener failed {} connect cycles in a row, exiting", failures);
+                std::process::exit(1);
+            }
         }
     }
 
-    async fn connect_and_listen(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// Wait `delay` before reconnecting, unless a reconnect is requested
+    /// first. Returns true if the wait was cut short.
+    async fn backoff(&self, delay: Duration) -> bool {
+        tokio::select! {
+            _ = sleep(delay) => false,
+            _ = self.reconnect.requested() => {
+                info!("Reconnect requested, skipping backoff");
+                self.relay_health.clear_backoff();
+                true
+            }
+        }
+    }
+
+    /// Returns true if the connection was dropped because a reconnect was requested.
+    async fn connect_and_listen(&self) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
         info!("Connecting to Nostr relays...");
 
         // Create Nostr client
-        let keys = Keys::generate();
-        let client = Client::new(&keys);
+        let client = Client::new(&self.keys);
 
         // Add relays
-        for relay_url in &self.config.nostr.relays {
-            client.add_relay(relay_url.clone()).await?;
-            info!("Added relay: {}", relay_url);
-        }
+        self.add_relays(&client).await?;
 
         // Connect to all relays
+        self.readiness.reset_relays();
         client.connect().await;
+        for (url, relay) in client.relays().await {
+            let connected = relay.status().await == RelayStatus::Connected;
+            // Only relays we read events from count towards readiness
+            if self.config.nostr.relay_usage(url.as_str()).reads() {
+                self.readiness.set_relay_connected(url.as_str(), connected);
+            }
+            self.relay_health.set_connected(url.as_str(), connected);
+        }
+        *self.client.lock().unwrap() = Some(client.clone());
 
         // Create filter for kind 1059 events from Mostro
-        let since = Timestamp::now() - Duration::from_secs(60);
+        let now = Timestamp::now();
+        let mut since = now - Duration::from_secs(60);
         let mostro_pubkey = XOnlyPublicKey::from_str(&self.mostro_pubkey)
             .map_err(|e| format!("Invalid mostro pubkey: {}", e))?;
         let filter = Filter::new()
             .kinds(vec![Kind::Custom(1059)])
-            .author(mostro_pubkey)
-            .since(since);
+            .author(mostro_pubkey);
+
+        // After a long disconnect, fetch what was missed in bounded chunks
+        // rather than one subscription relays may truncate
+        let disconnected_at = *self.disconnected_at.lock().unwrap();
+        if let Some(from) = disconnected_at.filter(|from| *from < since) {
+            let fetch = |from: Timestamp, until: Timestamp| {
+                let client = client.clone();
+                let filter = filter.clone().since(from).until(until);
+                async move {
+                    client
+                        .get_events_of(vec![filter], Some(CATCHUP_FETCH_TIMEOUT))
+                        .await
+                        .map_err(Into::into)
+                }
+            };
+            self.catch_up(from, now, fetch).await;
+            since = now;
+        }
+        let filter = filter.since(since);
 
         // Subscribe to events
-        client.subscribe(vec![filter]).await;
+        let mut filters = vec![filter];
+        if self.relay_monitor.is_enabled() {
+            filters.push(
+                Filter::new()
+                    .kind(Kind::Custom(RELAY_DISCOVERY_KIND))
+                    .authors(self.relay_monitor.monitors())
+                    .since(since),
+            );
+        }
+        client.subscribe(filters).await;
         info!("Subscribed to kind 1059 events from Mostro: {}", self.config.nostr.mostro_pubkey);
+        self.reconnect.mark_connected();
 
-        // Handle incoming events
-        let token_store = self.token_store.clone();
-        let push_services = self.push_services.clone();
-
-        client
-            .handle_notifications(|notification| async {
-                if let RelayPoolNotification::Event { event, .. } = notification {
-                    if event.kind == Kind::Custom(1059) {
-                        debug!("Received kind 1059 event: {}", event.id);
-
-                        // Extract recipient from 'p' tag
-                        let recipient_pubkey = event.tags.iter()
-                            .find_map(|tag| {
-                                let tag_vec = tag.as_vec();
-                                if tag_vec.len() >= 2 && tag_vec[0] == "p" {
-                                    Some(tag_vec[1].clone())
-                                } else {
-                                    None
+        // Kind 1059 events go to a bounded pool of handlers so a slow lookup
+        // doesn't stall the stream; while the queue is full the stream waits
+        let (events_tx, events_rx) = mpsc::channel(self.config.nostr.event_handler_concurrency.max(1));
+        // Queued events were never claimed, so the handlers finish them after
+        // the stream ends, once the sender is dropped with `listen`
+        let listen = async {
+            let events_tx = events_tx;
+            // Handle incoming events until the connection ends or a reconnect is requested
+            let notifications = client
+                .handle_notifications(|notification| async {
+                    match notification {
+                        RelayPoolNotification::Event { relay_url, event } if event.kind == Kind::Custom(1059) => {
+                            // Only fails once the handlers are gone, as the connection ends
+                            let _ = events_tx.send((event, relay_url.to_string())).await;
+                        }
+                        RelayPoolNotification::Event { event, .. }
+                            if event.kind == Kind::Custom(RELAY_DISCOVERY_KIND) =>
+                        {
+                            self.apply_monitor_event(&client, &event).await;
+                        }
+                        RelayPoolNotification::RelayStatus { relay_url, status } => {
+                            debug!("Relay {} is now {}", relay_url, status);
+                            let connected = status == RelayStatus::Connected;
+                            if self.config.nostr.relay_usage(relay_url.as_str()).reads() {
+                                self.readiness.set_relay_connected(relay_url.as_str(), connected);
+                            }
+                            self.relay_health.set_connected(relay_url.as_str(), connected);
+                        }
+                        RelayPoolNotification::Message { relay_url, message } => match message {
+                            RelayMessage::Notice { message } => {
+                                info!("NOTICE from {}: {}", relay_url, message);
+                                self.relay_health.record_reason(relay_url.as_str(), &message, chrono::Utc::now());
+                            }
+                            RelayMessage::Closed { subscription_id, message } => {
+                                warn!("Relay {} closed subscription {}: {}", relay_url, subscription_id, message);
+                                let action = self.relay_health.record_reason(
+                                    relay_url.as_str(),
+                                    &message,
+                                    chrono::Utc::now(),
+                                );
+                                if let Some(backoff) = action.backoff {
+                                    warn!("Relay {} is rate limiting us, backing off {:?}", relay_url, backoff);
+                                }
+                                if action.requires_auth {
+                                    warn!("Relay {} requires authentication to serve this subscription", relay_url);
                                 }
-                            });
+                            }
+                            _ => {}
+                        },
+                        _ => {}
+                    }
+                    Ok(false)
+                });
+            tokio::select! {
+                result = notifications => result.map(|_| false),
+                _ = self.reconnect.requested() => {
+                    info!("Reconnect requested, dropping relay connections");
+                    self.relay_health.clear_backoff();
+                    Ok(true)
+                }
+            }
+        };
+        let (result, ()) = tokio::join!(listen, self.handle_events(events_rx));
 
-                        if let Some(trade_pubkey) = recipient_pubkey {
-                            debug!("Event recipient: {}...", &trade_pubkey[..16]);
+        self.finish_connection(client).await;
+        Ok(result?)
+    }
 
-                            // Look up token in store
-                            if let Some(registered_token) = token_store.get(&trade_pubkey).await {
-                                info!(
-                                    "Found registered token for {}..., sending push to {} device",
-                                    &trade_pubkey[..16],
-                                    registered_token.platform
-                                );
+    /// Let pushes triggered by this connection's events complete, bounded by
+    /// the drain timeout, before the client is torn down.
+    async fn finish_connection(&self, client: Client) {
+        self.client.lock().unwrap().take();
+        *self.disconnected_at.lock().unwrap() = Some(Timestamp::now());
+        let mut in_flight = std::mem::take(&mut *self.in_flight.lock().unwrap());
+        if !in_flight.is_empty() {
+            let pending = in_flight.len();
+            info!("Waiting for {} in-flight push(es) before reconnecting", pending);
+            let drain_timeout = Duration::from_secs(self.config.nostr.drain_timeout_secs);
+            let drained = timeout(drain_timeout, async {
+                while in_flight.join_next().await.is_some() {}
+            })
+            .await;
+            if drained.is_err() {
+                warn!(
+                    "Abandoning {} push(es) still in flight after {:?}",
+                    in_flight.len(),
+                    drain_timeout
+                );
+            }
+        }
 
-                                // Send push notification to the specific device
-                                let services = push_services.lock().await;
-                                for service in services.iter() {
-                                    if service.supports_platform(&registered_token.platform) {
-                                        match service.send_to_token(
-                                            &registered_token.device_token,
-                                            &registered_token.platform,
-                                        ).await {
-                                            Ok(_) => {
-                                                info!("Push sent successfully for event {}", event.id);
-                                                break; // Only need one service to succeed
-                                            }
-                                            Err(e) => {
-                                                error!("Failed to send push: {}", e);
-                                            }
-                                        }
-                                    }
-                                }
-                            } else {
-                                debug!("No registered token for {}...", &trade_pubkey[..16]);
-                            }
-                        } else {
-                            debug!("No 'p' tag found in event {}", event.id);
+        if let Err(e) = client.shutdown().await {
+            debug!("Error shutting down Nostr client: {}", e);
+        }
+    }
+
+    /// Handle queued relay events, up to `event_handler_concurrency` at once,
+    /// until the sender is dropped. The dedup claim is atomic in every store,
+    /// so concurrent copies of one event still push once.
+    async fn handle_events(&self, events: mpsc::Receiver<(Event, String)>) {
+        let concurrency = self.config.nostr.event_handler_concurrency.max(1);
+        futures::stream::unfold(events, |mut events| async move { events.recv().await.map(|event| (event, events)) })
+            .for_each_concurrent(concurrency, |(event, relay)| async move {
+                self.handle_event(&event, Some(&relay)).await;
+            })
+            .await;
+    }
+
+    /// Fetch events from `since` to `until` in `catchup_chunk_secs` windows,
+    /// oldest first, and handle them like live ones (deduplicated as usual).
+    /// Nothing older than `catchup_max_age_secs` is pushed. Returns how many
+    /// events were fetched.
+    async fn catch_up<F, Fut>(&self, since: Timestamp, until: Timestamp, mut fetch: F) -> usize
+    where
+        F: FnMut(Timestamp, Timestamp) -> Fut,
+        Fut: Future<Output = Result<Vec<Event>, Box<dyn std::error::Error + Send + Sync>>>,
+    {
+        let oldest = until.as_u64().saturating_sub(self.config.nostr.catchup_max_age_secs);
+        let since = since.as_u64().max(oldest);
+        let until = until.as_u64();
+        let chunk = self.config.nostr.catchup_chunk_secs.max(1);
+        let chunks = (until.saturating_sub(since)).div_ceil(chunk).max(1);
+        info!("Catching up on {}s of missed events in {} chunk(s)", until.saturating_sub(since), chunks);
+
+        let mut fetched = 0;
+        let mut from = since;
+        for index in 1..=chunks {
+            let to = (from + chunk).min(until);
+            match fetch(Timestamp::from(from), Timestamp::from(to)).await {
+                Ok(mut events) => {
+                    events.sort_by_key(|event| event.created_at);
+                    info!("Catch-up chunk {}/{}: {} event(s) up to {}", index, chunks, events.len(), to);
+                    for event in &events {
+                        // Relays are free to ignore `since`
+                        if event.created_at.as_u64() < oldest {
+                            debug!("Event {} is too old to catch up, skipping", event.id);
+                            continue;
                         }
+                        self.handle_event(event, None).await;
                     }
+                    fetched += events.len();
                 }
-                Ok(false)
-            })
-            .await?;
+                Err(e) => warn!("Catch-up chunk {}/{} failed: {}", index, chunks, e),
+            }
+            from = to;
+        }
+        info!("Catch-up finished: {} event(s) fetched", fetched);
+        fetched
+    }
 
+    /// Add the current relay set to `client`, each with the options for its
+    /// configured usage. Write-only relays refuse subscriptions, so events
+    /// are only requested from relays we read.
+    async fn add_relays(&self, client: &Client) -> Result<(), nostr_sdk::client::Error> {
+        for relay_url in &self.relay_monitor.relays() {
+            client.add_relay_with_opts(relay_url.clone(), self.relay_options(relay_url)).await?;
+            info!("Added relay: {} ({:?})", relay_url, self.config.nostr.relay_usage(relay_url));
+        }
         Ok(())
     }
+
+    fn relay_options(&self, url: &str) -> RelayOptions {
+        let usage = self.config.nostr.relay_usage(url);
+        RelayOptions::new().read(usage.reads()).write(usage.writes())
+    }
+
+    /// Mirror relay set changes from a NIP-66 monitor report on the live client.
+    async fn apply_monitor_event(&self, client: &Client, event: &Event) {
+        let result = match self.relay_monitor.handle_event(event) {
+            Some(RelayAction::Remove(url)) => {
+                self.readiness.set_relay_connected(&url, false);
+                client.remove_relay(url.as_str()).await
+            }
+            Some(RelayAction::Add(url)) => match client.add_relay_with_opts(url.as_str(), self.relay_options(&url)).await {
+                Ok(_) => client.connect_relay(url.as_str()).await,
+                Err(e) => Err(e),
+            },
+            None => Ok(()),
+        };
+        if let Err(e) = result {
+            warn!("Failed to apply relay monitor update: {}", e);
+        }
+    }
+
+    /// Returns true if the event carries the configured no-push marker tag.
+    fn is_no_push(&self, event: &Event) -> bool {
+        let Some(tag_name) = &self.config.nostr.no_push_tag else {
+            return false;
+        };
+        let tag_value = self.config.nostr.no_push_tag_value.as_deref();
+
+        event.tags.iter().any(|tag| {
+            let tag_vec = tag.as_vec();
+            tag_vec[0] == *tag_name
+                && tag_value.is_none_or(|v| tag_vec.get(1).map(String::as_str) == Some(v))
+        })
+    }
+
+    /// Deduplication key: the configured idempotency tag's value when the event
+    /// carries it, so re-wrapped copies of one message collapse; else the event id.
+    fn dedup_key(&self, event: &Event) -> String {
+        let tagged = self.config.nostr.idempotency_tag.as_ref().and_then(|name| {
+            event.tags.iter().find_map(|tag| {
+                let tag_vec = tag.as_vec();
+                (tag_vec[0] == *name && tag_vec.len() >= 2).then(|| format!("{}:{}", name, tag_vec[1]))
+            })
+        });
+        tagged.unwrap_or_else(|| event.id.to_hex())
+    }
+
+    /// Handle an event `relay` delivered, None when it was fetched rather
+    /// than pushed to us.
+    async fn handle_event(&self, event: &Event, relay: Option<&str>) {
+        debug!("Received kind 1059 event: {}", event.id);
+        let received_at = chrono::Utc::now();
+
+        // A demoted instance keeps its subscription until it reconnects
+        if self.leadership.as_ref().is_some_and(|l| !l.is_leader()) {
+            debug!("Not the leader, ignoring event {}", event.id);
+            return;
+        }
+        // While a relay floods us, shed events no registered device is waiting for
+        if let Some(overload) = &self.overload {
+            let registered = || async {
+                match recipient(event) {
+                    Some(trade_pubkey) => !self.devices_of(&trade_pubkey).await.is_empty(),
+                    None => false,
+                }
+            };
+            if !overload.admit(std::time::Instant::now(), registered).await {
+                debug!("Overloaded, dropping event {}", event.id);
+                return;
+            }
+        }
+        // Relays, reconnects and a previous leader may all hand us the same event
+        if !self.token_store.claim_event(&self.dedup_key(event)).await {
+            debug!("Event {} was already handled, skipping", event.id);
+            if let Some(relay) = relay {
+                self.metrics.record_relay_delivery(relay, false);
+            }
+            return;
+        }
+        // The claim went to whichever relay handed us the event first
+        if let Some(relay) = relay {
+            self.metrics.record_relay_delivery(relay, true);
+        }
+
+        let recipient_pubkey = recipient(event);
+        let inbound = InboundEvent {
+            event_id: event.id.to_hex(),
+            grouping_id: grouping_id(self.config.push.grouping.as_ref(), event, recipient_pubkey.as_deref()),
+            trade_pubkey: recipient_pubkey,
+            no_push: self.is_no_push(event),
+            category: self.classifier.classify(event),
+            relay: relay.map(str::to_string),
+        };
+
+        let watch = self.watch_handle(&inbound);
+        let registered_tokens = match self.prepare(&inbound, watch.as_ref()).await {
+            Ok(tokens) => tokens,
+            Err(outcome) => {
+                self.record_trace(&inbound, None, outcome);
+                return;
+            }
+        };
+
+        // Send push notification to the specific devices without blocking the
+        // notification loop; the task is tracked so a reconnect can drain it
+        let sli_ticket = self.metrics.delivery_sli.start(received_at);
+        let payload = wake_payload(&self.flags, &inbound);
+        let dispatcher = self.dispatcher.clone();
+        let metrics = self.metrics.clone();
+        let trace = self.trace.clone();
+        let outbox = self.outbox.clone();
+        let last_notified = self.last_notified.clone();
+        let mut in_flight = self.in_flight.lock().unwrap();
+        // Reap finished tasks so the set doesn't grow on long-lived connections
+        while in_flight.try_join_next().is_some() {}
+        in_flight.spawn(async move {
+            let outcome = deliver_all(&dispatcher, Some(&inbound.event_id), &registered_tokens, &payload, watch.as_ref()).await;
+            let accepted = matches!(outcome, EventOutcome::Delivered | EventOutcome::AlreadyDelivered);
+            metrics.delivery_sli.finish(sli_ticket, chrono::Utc::now(), accepted);
+            if outcome == EventOutcome::Delivered {
+                info!("Push sent successfully for event {}", inbound.event_id);
+                if let Some(trade_pubkey) = &inbound.trade_pubkey {
+                    last_notified.record(trade_pubkey, chrono::Utc::now());
+                }
+            }
+            if let Some(outbox) = outbox {
+                settle_outbox(&outbox, &inbound, received_at, outcome);
+            }
+            if let Some(trace) = trace {
+                trace.record(&trace_record(&inbound, Some(registered_tokens[0].platform.clone()), outcome));
+            }
+        });
+    }
+
+    /// Push the events left in the outbox by a previous process to their
+    /// recipients' current registrations, as many at once as live events.
+    /// Events past the catch-up age limit are dropped unsent. Returns how
+    /// many were delivered.
+    pub async fn replay_outbox(&self) -> usize {
+        let Some(outbox) = &self.outbox else {
+            return 0;
+        };
+        let now = chrono::Utc::now();
+        let oldest = now - chrono::Duration::seconds(self.config.nostr.catchup_max_age_secs.min(i64::MAX as u64) as i64);
+        let pending = outbox.pending(now);
+        if pending.is_empty() {
+            return 0;
+        }
+        info!("Replaying {} undelivered event(s) from the outbox", pending.len());
+
+        let delivered = std::sync::atomic::AtomicUsize::new(0);
+        futures::stream::iter(pending)
+            .for_each_concurrent(self.config.nostr.event_handler_concurrency.max(1), |event| {
+                let delivered = &delivered;
+                async move {
+                    if event.received_at < oldest {
+                        debug!("Undelivered event {} is too old to replay, dropping", event.event_id);
+                        outbox.remove(&event.event_id);
+                        return;
+                    }
+                    let inbound = InboundEvent {
+                        event_id: event.event_id,
+                        trade_pubkey: Some(event.trade_pubkey),
+                        no_push: false,
+                        category: event.category,
+                        grouping_id: event.grouping_id,
+                        relay: None,
+                    };
+                    let watch = self.watch_handle(&inbound);
+                    let outcome = match self.prepare(&inbound, watch.as_ref()).await {
+                        Ok(tokens) => {
+                            let payload = wake_payload(&self.flags, &inbound);
+                            deliver_all(&self.dispatcher, Some(&inbound.event_id), &tokens, &payload, watch.as_ref()).await
+                        }
+                        Err(outcome) => outcome,
+                    };
+                    if outcome == EventOutcome::Delivered {
+                        delivered.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        if let Some(trade_pubkey) = &inbound.trade_pubkey {
+                            self.last_notified.record(trade_pubkey, chrono::Utc::now());
+                        }
+                    }
+                    settle_outbox(outbox, &inbound, event.received_at, outcome);
+                    self.record_trace(&inbound, None, outcome);
+                }
+            })
+            .await;
+        let delivered = delivered.into_inner();
+        info!("Outbox replay delivered {} event(s), {} still undelivered", delivered, outbox.len());
+        delivered
+    }
+
+    /// Handle an event and wait for the push it dispatched, if any.
+    #[cfg(test)]
+    pub(crate) async fn handle_and_wait(&self, event: &Event) {
+        self.handle_event(event, None).await;
+        let mut in_flight = std::mem::take(&mut *self.in_flight.lock().unwrap());
+        while in_flight.join_next().await.is_some() {}
+    }
+
+    /// Run an event through the same pipeline as relay events, waiting for the
+    /// push to complete. This is the injection hook used by `replay`; unlike
+    /// relay events, injected ones are not deduplicated or checked against
+    /// the delivery ledger.
+    pub async fn inject(&self, inbound: InboundEvent) -> EventOutcome {
+        let watch = self.watch_handle(&inbound);
+        match self.prepare(&inbound, watch.as_ref()).await {
+            Ok(tokens) => deliver_all(&self.dispatcher, None, &tokens, &wake_payload(&self.flags, &inbound), watch.as_ref()).await,
+            Err(outcome) => outcome,
+        }
+    }
+
+    /// Start recording the event's progress if its recipient is watched.
+    fn watch_handle(&self, inbound: &InboundEvent) -> Option<WatchHandle> {
+        let watch = self.watch_list.as_ref()?.handle(inbound.trade_pubkey.as_deref()?, chrono::Utc::now())?;
+        watch.record(
+            "event",
+            format!("event_id={} no_push={} category={:?}", inbound.event_id, inbound.no_push, inbound.category),
+        );
+        Some(watch)
+    }
+
+    /// The recipient's devices in each environment.
+    async fn devices_of(&self, trade_pubkey: &str) -> Vec<RegisteredToken> {
+        let mut found = Vec::new();
+        for environment in PushEnvironment::ALL {
+            let key = store_key(trade_pubkey, environment);
+            let devices = self.token_store.devices(&key, self.config.store.max_devices_per_pubkey).await;
+            found.extend(devices.into_iter().map(|(_, token)| token));
+        }
+        found
+    }
+
+    /// Everything before dispatch: metrics, the no-push marker and the token
+    /// lookup. Returns the tokens to push to, one per environment the
+    /// recipient registered in, or why there is nothing to send.
+    async fn prepare(&self, inbound: &InboundEvent, watch: Option<&WatchHandle>) -> Result<Vec<RegisteredToken>, EventOutcome> {
+        Metrics::inc(&self.metrics.events_received);
+
+        if inbound.no_push {
+            debug!("Event {} carries the no-push marker, skipping dispatch", inbound.event_id);
+            Metrics::inc(&self.metrics.events_suppressed);
+            return Err(EventOutcome::Suppressed);
+        }
+
+        let Some(trade_pubkey) = &inbound.trade_pubkey else {
+            debug!("No 'p' tag found in event {}", inbound.event_id);
+            return Err(EventOutcome::NoRecipient);
+        };
+        debug!("Event recipient: {}...", &trade_pubkey[..16.min(trade_pubkey.len())]);
+
+        let mut found = self.devices_of(trade_pubkey).await;
+        if let Some(watch) = watch {
+            if found.is_empty() {
+                watch.record("lookup", "not registered");
+            }
+            for token in &found {
+                watch.record(
+                    "lookup",
+                    format!(
+                        "found platform={} environment={} preferences={:#x}",
+                        token.platform, token.environment, token.preferences.0
+                    ),
+                );
+            }
+        }
+        if found.is_empty() {
+            debug!("No registered token for {}...", &trade_pubkey[..16.min(trade_pubkey.len())]);
+            // Remember it so a registration arriving shortly after can catch up
+            self.backfill.record_missed(trade_pubkey, &inbound.event_id);
+            return Err(EventOutcome::NotRegistered);
+        }
+
+        found.retain(|token| token.preferences.allows(inbound.category));
+        if found.is_empty() {
+            debug!("Recipient opted out of {:?} events, skipping {}", inbound.category, inbound.event_id);
+            if let Some(watch) = watch {
+                watch.record("skipped", "opted out of the event's category");
+            }
+            return Err(EventOutcome::OptedOut);
+        }
+
+        for token in &found {
+            info!(
+                "Found registered token for {}..., sending push to {} {} device{}",
+                &trade_pubkey[..16.min(trade_pubkey.len())],
+                token.environment,
+                token.platform,
+                token.annotations_label()
+            );
+        }
+        Ok(found)
+    }
+
+    fn record_trace(&self, inbound: &InboundEvent, platform: Option<crate::crypto::Platform>, outcome: EventOutcome) {
+        if let Some(trace) = &self.trace {
+            trace.record(&trace_record(inbound, platform, outcome));
+        }
+    }
+}
+
+/// Keep an event no provider accepted for replay; forget it otherwise.
+fn settle_outbox(outbox: &Outbox, inbound: &InboundEvent, received_at: chrono::DateTime<chrono::Utc>, outcome: EventOutcome) {
+    match (&inbound.trade_pubkey, outcome) {
+        (Some(trade_pubkey), EventOutcome::Failed) => outbox.record(UndeliveredEvent {
+            event_id: inbound.event_id.clone(),
+            trade_pubkey: trade_pubkey.clone(),
+            category: inbound.category,
+            grouping_id: inbound.grouping_id.clone(),
+            received_at,
+        }),
+        _ => outbox.remove(&inbound.event_id),
+    }
+}
+
+/// Recipient from the event's `p` tag.
+pub fn recipient(event: &Event) -> Option<String> {
+    event.tags.iter().find_map(|tag| {
+        let tag_vec = tag.as_vec();
+        (tag_vec.len() >= 2 && tag_vec[0] == "p").then(|| tag_vec[1].clone())
+    })
+}
+
+/// Hashed grouping id for the event under `source`, if it has that value.
+pub fn grouping_id(source: Option<&GroupingSource>, event: &Event, trade_pubkey: Option<&str>) -> Option<String> {
+    let value = match source? {
+        GroupingSource::Pubkey => trade_pubkey?.to_string(),
+        GroupingSource::Tag(name) => event.tags.iter().find_map(|tag| {
+            let tag_vec = tag.as_vec();
+            (tag_vec[0] == *name && tag_vec.len() >= 2).then(|| tag_vec[1].clone())
+        })?,
+    };
+    Some(payload::grouping_id(&value))
+}
+
+/// The push for an event, as built for live and injected events alike.
+pub fn wake_payload(flags: &FeatureFlags, inbound: &InboundEvent) -> PushPayload {
+    let payload = match &inbound.trade_pubkey {
+        Some(trade_pubkey) => flags.wake_payload(trade_pubkey),
+        None => PushPayload::silent_wake(),
+    };
+    match &inbound.grouping_id {
+        Some(grouping_id) => payload.thread_id(grouping_id),
+        None => payload,
+    }
+}
+
+/// Push for an event, through the delivery ledger unless `event_id` is None.
+async fn deliver(
+    dispatcher: &Dispatcher,
+    event_id: Option<&str>,
+    token: &RegisteredToken,
+    payload: &PushPayload,
+    watch: Option<&WatchHandle>,
+) -> EventOutcome {
+    let delivered = match event_id {
+        Some(event_id) => dispatcher.dispatch_for_events(&[event_id.to_string()], token, payload, watch).await,
+        None => Some(dispatcher.dispatch_watched(token, payload, watch).await),
+    };
+    match delivered {
+        Some(true) => EventOutcome::Delivered,
+        Some(false) => EventOutcome::Failed,
+        None => EventOutcome::AlreadyDelivered,
+    }
+}
+
+/// Push for an event to each of the recipient's registrations. Delivered if
+/// any device was reached.
+async fn deliver_all(
+    dispatcher: &Dispatcher,
+    event_id: Option<&str>,
+    tokens: &[RegisteredToken],
+    payload: &PushPayload,
+    watch: Option<&WatchHandle>,
+) -> EventOutcome {
+    let mut outcome = EventOutcome::Failed;
+    for token in tokens {
+        match deliver(dispatcher, event_id, token, payload, watch).await {
+            EventOutcome::Delivered => outcome = EventOutcome::Delivered,
+            EventOutcome::AlreadyDelivered if outcome == EventOutcome::Failed => outcome = EventOutcome::AlreadyDelivered,
+            _ => {}
+        }
+    }
+    outcome
+}
+
+fn trace_record(
+    inbound: &InboundEvent,
+    platform: Option<crate::crypto::Platform>,
+    outcome: EventOutcome,
+) -> TraceRecord {
+    TraceRecord {
+        at: chrono::Utc::now(),
+        event_id: inbound.event_id.clone(),
+        trade_pubkey: inbound.trade_pubkey.clone(),
+        no_push: inbound.no_push,
+        category: inbound.category,
+        platform,
+        outcome,
+        relay: inbound.relay.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Platform;
+    use crate::nostr::NotificationPreferences;
+    use crate::watch::WatchList;
+    use crate::push::testing::MockPush;
+    use crate::push::PushService;
+    use crate::store::testing::MockTokenStore;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::sync::RwLock as AsyncRwLock;
+
+    fn test_listener(config: Config) -> (NostrListener, Arc<MockTokenStore>, Arc<AtomicUsize>) {
+        let (mock, sent) = MockPush::new();
+        let (listener, store) = test_listener_with(config, mock);
+        (listener, store, sent)
+    }
+
+    fn test_listener_with(config: Config, mock: MockPush) -> (NostrListener, Arc<MockTokenStore>) {
+        test_listener_over(config, mock, MockTokenStore::new())
+    }
+
+    fn test_listener_over(config: Config, mock: MockPush, store: MockTokenStore) -> (NostrListener, Arc<MockTokenStore>) {
+        let services: Vec<Box<dyn PushService>> = vec![Box::new(mock)];
+        let metrics = Arc::new(Metrics::new());
+        let store = Arc::new(store);
+        let listener = NostrListener::new(
+            config,
+            Arc::new(Dispatcher::new(Arc::new(AsyncRwLock::new(services)), metrics.clone())),
+            Arc::new(BackfillTracker::new(120, true)),
+            store.clone(),
+            metrics,
+            Arc::new(Readiness::new(0)),
+            Arc::new(RelayHealth::new()),
+        )
+        .unwrap();
+        (listener, store)
+    }
+
+    async fn handle_and_wait(listener: &NostrListener, event: &Event) {
+        listener.handle_and_wait(event).await;
+    }
+
+    fn gift_wrap_to(recipient: &str, extra_tags: Vec<Vec<&str>>) -> Event {
+        let mut tags = vec![Tag::parse(vec!["p", recipient]).unwrap()];
+        for tag in extra_tags {
+            tags.push(Tag::parse(tag).unwrap());
+        }
+        EventBuilder::new(Kind::Custom(1059), "", tags)
+            .to_event(&Keys::generate())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_no_push_marker_suppresses_dispatch() {
+        let mut config = Config::for_tests();
+        config.nostr.no_push_tag = Some("no-push".to_string());
+        let (listener, store, sent) = test_listener(config);
+
+        let trade_pubkey = Keys::generate().public_key().to_string();
+        store.register(trade_pubkey.clone(), "device-token".to_string(), Platform::Android).await;
+
+        handle_and_wait(&listener, &gift_wrap_to(&trade_pubkey, vec![vec!["no-push"]])).await;
+        assert_eq!(MockPush::sent(&sent), 0);
+        assert_eq!(Metrics::get(&listener.metrics.events_suppressed), 1);
+        // Suppressed before the store is consulted
+        assert!(store.lookups().is_empty());
+
+        handle_and_wait(&listener, &gift_wrap_to(&trade_pubkey, vec![])).await;
+        assert_eq!(MockPush::sent(&sent), 1);
+        // Both environments' registrations are looked up
+        assert_eq!(
+            store.lookups(),
+            vec![trade_pubkey.clone(), store_key(&trade_pubkey, PushEnvironment::Sandbox)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_no_push_marker_requires_configured_value() {
+        let mut config = Config::for_tests();
+        config.nostr.no_push_tag = Some("push".to_string());
+        config.nostr.no_push_tag_value = Some("none".to_string());
+        let (listener, store, sent) = test_listener(config);
+
+        let trade_pubkey = Keys::generate().public_key().to_string();
+        store.register(trade_pubkey.clone(), "device-token".to_string(), Platform::Android).await;
+
+        handle_and_wait(&listener, &gift_wrap_to(&trade_pubkey, vec![vec!["push", "high"]])).await;
+        assert_eq!(MockPush::sent(&sent), 1);
+
+        handle_and_wait(&listener, &gift_wrap_to(&trade_pubkey, vec![vec!["push", "none"]])).await;
+        assert_eq!(MockPush::sent(&sent), 1);
+        assert_eq!(Metrics::get(&listener.metrics.events_suppressed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_tag_collapses_rewrapped_events() {
+        let mut config = Config::for_tests();
+        config.nostr.idempotency_tag = Some("mid".to_string());
+        let (listener, store, sent) = test_listener(config);
+
+        let trade_pubkey = Keys::generate().public_key().to_string();
+        store.register(trade_pubkey.clone(), "device-token".to_string(), Platform::Android).await;
+
+        // Distinct event ids, same logical message
+        handle_and_wait(&listener, &gift_wrap_to(&trade_pubkey, vec![vec!["mid", "msg-1"]])).await;
+        handle_and_wait(&listener, &gift_wrap_to(&trade_pubkey, vec![vec!["mid", "msg-1"]])).await;
+        assert_eq!(MockPush::sent(&sent), 1);
+
+        // Without the tag, the event id is the key
+        let untagged = gift_wrap_to(&trade_pubkey, vec![]);
+        handle_and_wait(&listener, &untagged).await;
+        handle_and_wait(&listener, &untagged).await;
+        assert_eq!(MockPush::sent(&sent), 2);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_handlers_drop_no_events() {
+        let mut config = Config::for_tests();
+        config.nostr.event_handler_concurrency = 8;
+        let (mock, sent) = MockPush::with_delay(Duration::from_millis(50));
+        let store = MockTokenStore::new().with_lookup_delay(Duration::from_millis(20));
+        let (listener, store) = test_listener_over(config, mock, store);
+
+        let mut events = Vec::new();
+        for i in 0..30 {
+            let trade_pubkey = Keys::generate().public_key().to_string();
+            store.register(trade_pubkey.clone(), format!("device-token-{}", i), Platform::Android).await;
+            events.push(gift_wrap_to(&trade_pubkey, vec![]));
+        }
+        // Some events arrive twice, e.g. from two relays, while handlers run
+        let duplicates = events[..5].to_vec();
+
+        // Events arrive faster than lookups and dispatch complete
+        let started = std::time::Instant::now();
+        let (events_tx, events_rx) = mpsc::channel(8);
+        let feed = async move {
+            for event in events.into_iter().chain(duplicates) {
+                events_tx.send((event, "wss://relay.example".to_string())).await.unwrap();
+            }
+        };
+        tokio::join!(feed, listener.handle_events(events_rx));
+        // Serially, the lookups alone would take over a second
+        assert!(started.elapsed() < Duration::from_millis(600), "{:?}", started.elapsed());
+
+        let mut in_flight = std::mem::take(&mut *listener.in_flight.lock().unwrap());
+        while in_flight.join_next().await.is_some() {}
+        assert_eq!(MockPush::sent(&sent), 30);
+        assert_eq!(Metrics::get(&listener.metrics.events_received), 30);
+    }
+
+    #[tokio::test]
+    async fn test_undelivered_event_is_replayed_on_start() {
+        let path = std::env::temp_dir().join(format!("mostro-push-outbox-{}.json", std::process::id()));
+        let mut config = Config::for_tests();
+        config.nostr.catchup_max_age_secs = 600;
+        let trade_pubkey = Keys::generate().public_key().to_string();
+
+        // The provider is down: the push fails and the event is kept
+        let (failing, _) = MockPush::new();
+        let outbox = Arc::new(Outbox::new(100, Duration::from_secs(3600)));
+        let (listener, store) = test_listener_with(config.clone(), failing.failing());
+        let listener = listener.with_outbox(outbox.clone());
+        store.register(trade_pubkey.clone(), "old-device".to_string(), Platform::Android).await;
+        handle_and_wait(&listener, &gift_wrap_to(&trade_pubkey, vec![])).await;
+        assert_eq!(outbox.len(), 1);
+        // An event past the catch-up age limit is kept by the TTL, but not replayed
+        outbox.record(UndeliveredEvent {
+            event_id: "stale".to_string(),
+            trade_pubkey: trade_pubkey.clone(),
+            category: None,
+            grouping_id: None,
+            received_at: chrono::Utc::now() - chrono::Duration::seconds(1200),
+        });
+        crate::push::outbox::save(&outbox, &path).await.unwrap();
+
+        // After a restart the recipient's current registration gets the push
+        let (mock, sent) = MockPush::new();
+        let outbox = Arc::new(Outbox::new(100, Duration::from_secs(3600)));
+        crate::push::outbox::load(&outbox, &path).await;
+        assert_eq!(outbox.len(), 2);
+        let (listener, store) = test_listener_with(config, mock);
+        let listener = listener.with_outbox(outbox.clone());
+        store.register(trade_pubkey.clone(), "new-device".to_string(), Platform::Android).await;
+        assert_eq!(listener.replay_outbox().await, 1);
+        assert_eq!(MockPush::sent(&sent), 1);
+        assert!(outbox.is_empty());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_first_delivering_relay_is_attributed() {
+        let path = std::env::temp_dir().join(format!("mostro-push-relay-trace-{}.jsonl", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let mut config = Config::for_tests();
+        config.nostr.event_trace_path = Some(path.display().to_string());
+        let (listener, store, sent) = test_listener(config);
+
+        let trade_pubkey = Keys::generate().public_key().to_string();
+        store.register(trade_pubkey.clone(), "device-token".to_string(), Platform::Android).await;
+
+        // Both relays deliver the same event; the slower one is a duplicate
+        let event = gift_wrap_to(&trade_pubkey, vec![]);
+        listener.handle_event(&event, Some("wss://fast.example")).await;
+        listener.handle_event(&event, Some("wss://slow.example")).await;
+        listener.handle_event(&gift_wrap_to(&trade_pubkey, vec![]), Some("wss://slow.example")).await;
+        let mut in_flight = std::mem::take(&mut *listener.in_flight.lock().unwrap());
+        while in_flight.join_next().await.is_some() {}
+        assert_eq!(MockPush::sent(&sent), 2);
+
+        let rendered = listener.metrics.render();
+        let name = "mostro_push_relay_event_deliveries_total";
+        assert!(rendered.contains(&format!("{}{{relay=\"wss://fast.example\",first=\"true\"}} 1", name)));
+        assert!(rendered.contains(&format!("{}{{relay=\"wss://slow.example\",first=\"false\"}} 1", name)));
+        assert!(rendered.contains(&format!("{}{{relay=\"wss://slow.example\",first=\"true\"}} 1", name)));
+
+        let trace = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        let first: TraceRecord = serde_json::from_str(trace.lines().next().unwrap()).unwrap();
+        assert_eq!((first.event_id, first.relay.as_deref()), (event.id.to_hex(), Some("wss://fast.example")));
+    }
+
+    #[tokio::test]
+    async fn test_grouping_id_comes_from_configured_source() {
+        let trade_pubkey = Keys::generate().public_key().to_string();
+        let event = || gift_wrap_to(&trade_pubkey, vec![vec!["order", "order-42"]]);
+        let sent_thread_id = |source: Option<GroupingSource>| {
+            let mut config = Config::for_tests();
+            config.push.grouping = source;
+            let (mock, _) = MockPush::new();
+            let last_payload = mock.last_payload.clone();
+            let (listener, store) = test_listener_with(config, mock);
+            let (trade_pubkey, event) = (trade_pubkey.clone(), event());
+            async move {
+                store.register(trade_pubkey, "device-token".to_string(), Platform::Android).await;
+                handle_and_wait(&listener, &event).await;
+                let payload = last_payload.lock().unwrap().take().unwrap();
+                payload.thread_id
+            }
+        };
+
+        assert_eq!(sent_thread_id(None).await, None);
+        assert_eq!(sent_thread_id(Some(GroupingSource::Pubkey)).await, Some(payload::grouping_id(&trade_pubkey)));
+        let by_order = sent_thread_id(Some(GroupingSource::Tag("order".to_string()))).await;
+        assert_eq!(by_order, Some(payload::grouping_id("order-42")));
+        // An event without the tag goes out ungrouped
+        assert_eq!(sent_thread_id(Some(GroupingSource::Tag("trade".to_string()))).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_write_only_relay_is_not_subscribed() {
+        let mut config = Config::for_tests();
+        config.nostr.relays = vec!["wss://read.example".to_string(), "wss://write.example".to_string()];
+        config.nostr.relay_usage = crate::config::parse_relay_usage("wss://write.example=write", &config.nostr.relays).unwrap();
+        let (listener, _, _) = test_listener(config);
+
+        let client = Client::new(Keys::generate());
+        listener.add_relays(&client).await.unwrap();
+        let relays = client.relays().await;
+        assert_eq!(relays.len(), 2);
+
+        let filter = Filter::new().kinds(vec![Kind::Custom(1059)]);
+        let write_only = &relays[&Url::parse("wss://write.example").unwrap()];
+        assert!(matches!(
+            write_only.subscribe(vec![filter], None).await,
+            Err(nostr_sdk::relay::Error::ReadDisabled)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_catch_up_advances_through_chunks() {
+        let mut config = Config::for_tests();
+        config.nostr.catchup_chunk_secs = 600;
+        config.nostr.catchup_max_age_secs = 3600;
+        let (listener, store, sent) = test_listener(config);
+
+        let trade_pubkey = Keys::generate().public_key().to_string();
+        store.register(trade_pubkey.clone(), "device-token".to_string(), Platform::Android).await;
+
+        let now = Timestamp::now();
+        let at = |ago: u64| {
+            EventBuilder::new(Kind::Custom(1059), "", [Tag::parse(vec!["p", &trade_pubkey]).unwrap()])
+                .custom_created_at(now - Duration::from_secs(ago))
+                .to_event(&Keys::generate())
+                .unwrap()
+        };
+        let history = [at(4000), at(3000), at(1500), at(200)];
+
+        let windows = Arc::new(Mutex::new(Vec::new()));
+        let fetch = |from: Timestamp, until: Timestamp| {
+            windows.lock().unwrap().push((from, until));
+            // Chunk boundaries overlap by a second, and this relay also
+            // ignores `since`, sending the too-old event every time
+            let events: Vec<Event> = history
+                .iter()
+                .filter(|e| e.created_at <= until && (e.created_at >= from || e.created_at == history[0].created_at))
+                .cloned()
+                .collect();
+            async move { Ok(events) }
+        };
+        // Down for two hours, but only the last hour is caught up
+        listener.catch_up(now - Duration::from_secs(7200), now, fetch).await;
+        let mut in_flight = std::mem::take(&mut *listener.in_flight.lock().unwrap());
+        while in_flight.join_next().await.is_some() {}
+
+        let windows = windows.lock().unwrap().clone();
+        assert_eq!(windows.len(), 6);
+        assert_eq!(windows[0].0, now - Duration::from_secs(3600));
+        assert!(windows.windows(2).all(|pair| pair[0].1 == pair[1].0));
+        assert_eq!(windows[5].1, now);
+        assert_eq!(MockPush::sent(&sent), 3);
+    }
+
+    #[tokio::test]
+    async fn test_unmatched_event_is_recorded_for_backfill() {
+        let (listener, _store, sent) = test_listener(Config::for_tests());
+        let trade_pubkey = Keys::generate().public_key().to_string();
+
+        handle_and_wait(&listener, &gift_wrap_to(&trade_pubkey, vec![])).await;
+        assert_eq!(MockPush::sent(&sent), 0);
+        assert_eq!(listener.backfill.take_missed(&trade_pubkey).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_during_dispatch_completes_push() {
+        let (mock, sent) = MockPush::with_delay(Duration::from_millis(200));
+        let (listener, store) = test_listener_with(Config::for_tests(), mock);
+
+        let trade_pubkey = Keys::generate().public_key().to_string();
+        store.register(trade_pubkey.clone(), "device-token".to_string(), Platform::Android).await;
+
+        listener.handle_event(&gift_wrap_to(&trade_pubkey, vec![]), None).await;
+        assert_eq!(MockPush::sent(&sent), 0, "dispatch should still be in flight");
+
+        // Relay dropped: the reconnect path tears the client down
+        listener.finish_connection(Client::new(Keys::generate())).await;
+        assert_eq!(MockPush::sent(&sent), 1);
+    }
+
+    #[tokio::test]
+    async fn test_drain_is_bounded_by_timeout() {
+        let mut config = Config::for_tests();
+        config.nostr.drain_timeout_secs = 0;
+        let (mock, sent) = MockPush::with_delay(Duration::from_secs(30));
+        let (listener, store) = test_listener_with(config, mock);
+
+        let trade_pubkey = Keys::generate().public_key().to_string();
+        store.register(trade_pubkey.clone(), "device-token".to_string(), Platform::Android).await;
+        listener.handle_event(&gift_wrap_to(&trade_pubkey, vec![]), None).await;
+
+        let started = std::time::Instant::now();
+        listener.finish_connection(Client::new(Keys::generate())).await;
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(MockPush::sent(&sent), 0);
+    }
+
+    #[tokio::test]
+    async fn test_opted_out_category_is_skipped() {
+        let (listener, store, sent) = test_listener(Config::for_tests());
+        let trade_pubkey = Keys::generate().public_key().to_string();
+        let token = RegisteredToken::new("device-token".to_string(), Platform::Android)
+            .with_preferences(NotificationPreferences::ALL.without(EventCategory::Chat));
+        store.register_token(trade_pubkey.clone(), token).await;
+
+        handle_and_wait(&listener, &gift_wrap_to(&trade_pubkey, vec![vec!["category", "chat"]])).await;
+        assert_eq!(MockPush::sent(&sent), 0);
+
+        // Other categories, and events without one, still notify
+        handle_and_wait(&listener, &gift_wrap_to(&trade_pubkey, vec![vec!["category", "trade"]])).await;
+        handle_and_wait(&listener, &gift_wrap_to(&trade_pubkey, vec![])).await;
+        assert_eq!(MockPush::sent(&sent), 2);
+    }
+
+    #[tokio::test]
+    async fn test_only_watched_pubkeys_are_recorded() {
+        let (listener, store, sent) = test_listener(Config::for_tests());
+        let watch_list = Arc::new(WatchList::new(4));
+        let listener = listener.with_watch_list(watch_list.clone());
+        let watched = Keys::generate().public_key().to_string();
+        let other = Keys::generate().public_key().to_string();
+        let unregistered = Keys::generate().public_key().to_string();
+        for pubkey in [&watched, &other] {
+            store.register(pubkey.clone(), "device-token".to_string(), Platform::Android).await;
+        }
+        for pubkey in [&watched, &unregistered] {
+            watch_list.watch(pubkey, chrono::Duration::hours(1), chrono::Utc::now()).unwrap();
+        }
+
+        for pubkey in [&watched, &other, &unregistered] {
+            handle_and_wait(&listener, &gift_wrap_to(pubkey, vec![])).await;
+        }
+        assert_eq!(MockPush::sent(&sent), 2);
+
+        let stages = |pubkey: &str| -> Vec<String> {
+            watch_list.records(pubkey).unwrap_or_default().into_iter().map(|r| r.stage).collect()
+        };
+        assert_eq!(stages(&watched), vec!["event", "lookup", "send"]);
+        assert_eq!(stages(&unregistered), vec!["event", "lookup"]);
+        assert!(watch_list.records(&other).is_none());
+        assert!(watch_list.records(&watched).unwrap()[2].detail.contains("result=ok"));
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_request_skips_backoff() {
+        let mut config = Config::for_tests();
+        config.nostr.relays = vec!["ws://127.0.0.1:1".to_string()];
+        let (listener, _, _) = test_listener(config);
+        let listener = Arc::new(listener);
+        listener.relay_health.record_reason("ws://127.0.0.1:1", "rate-limited: slow down", chrono::Utc::now());
+
+        // A pending backoff sleep ends as soon as a reconnect is requested
+        let waiting = tokio::spawn({
+            let listener = listener.clone();
+            async move { listener.backoff(Duration::from_secs(3600)).await }
+        });
+        tokio::task::yield_now().await;
+        listener.reconnect.request();
+        assert!(timeout(Duration::from_secs(5), waiting).await.unwrap().unwrap());
+        assert_eq!(listener.relay_health.backoff_remaining(chrono::Utc::now()), None);
+
+        // A connected listener drops its connection and connects again
+        let mut connections = listener.reconnect.subscribe();
+        tokio::spawn({
+            let listener = listener.clone();
+            async move { listener.start().await }
+        });
+        timeout(Duration::from_secs(5), connections.wait_for(|count| *count == 1)).await.unwrap().unwrap();
+        listener.reconnect.request();
+        timeout(Duration::from_secs(5), connections.wait_for(|count| *count == 2)).await.unwrap().unwrap();
+    }
 }
@@ -1,3 +1,14 @@
+pub mod category;
+pub mod classifier;
 pub mod listener;
+pub mod overload;
+pub mod pin;
+pub mod reconnect;
+pub mod relay_monitor;
+pub mod replay;
+pub mod trace;
 
-pub use listener::NostrListener;
+pub use category::{EventCategory, NotificationPreferences};
+pub use classifier::CategoryClassifier;
+pub use listener::{InboundEvent, NostrListener};
+pub use reconnect::ReconnectControl;
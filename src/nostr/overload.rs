@@ -0,0 +1,221 @@
+//! Last-resort overload mode for relay floods. While the kind 1059 ingest
+//! rate is above `OVERLOAD_EVENTS_PER_SEC`, events are sampled before the
+//! dedup claim, so a misbehaving relay can't saturate the host. Only events
+//! for unregistered recipients are shed: those sampled out are checked
+//! against the store first, and a registered recipient's event always gets
+//! through. The mode ends once the rate falls back below half the ceiling.
+
+use log::warn;
+use std::future::Future;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::metrics::Metrics;
+
+const BUCKET: Duration = Duration::from_secs(1);
+/// Weight of the latest second in the estimate; a burst registers within a
+/// couple of seconds and a single spike decays just as fast.
+const ALPHA: f64 = 0.5;
+
+/// Events per second, as an exponentially weighted average of whole seconds.
+pub struct RateEstimator {
+    bucket_start: Instant,
+    count: u64,
+    rate: f64,
+}
+
+impl RateEstimator {
+    pub fn new(now: Instant) -> Self {
+        Self { bucket_start: now, count: 0, rate: 0.0 }
+    }
+
+    pub fn observe(&mut self, now: Instant) {
+        self.roll(now);
+        self.count += 1;
+    }
+
+    /// The estimate as of the last completed second.
+    pub fn rate(&mut self, now: Instant) -> f64 {
+        self.roll(now);
+        self.rate
+    }
+
+    fn roll(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.bucket_start).as_secs();
+        if elapsed == 0 {
+            return;
+        }
+        self.rate = ALPHA * self.count as f64 + (1.0 - ALPHA) * self.rate;
+        // Seconds without events pull the estimate down too
+        self.rate *= (1.0 - ALPHA).powi((elapsed - 1).min(64) as i32);
+        self.count = 0;
+        self.bucket_start += BUCKET * elapsed as u32;
+    }
+}
+
+struct GuardState {
+    estimator: RateEstimator,
+    overloaded: bool,
+}
+
+pub struct OverloadGuard {
+    /// Events per second above which sampling starts
+    ceiling: u64,
+    state: Mutex<GuardState>,
+    metrics: Arc<Metrics>,
+}
+
+impl OverloadGuard {
+    pub fn new(ceiling: u64, metrics: Arc<Metrics>) -> Self {
+        Self {
+            ceiling,
+            state: Mutex::new(GuardState { estimator: RateEstimator::new(Instant::now()), overloaded: false }),
+            metrics,
+        }
+    }
+
+    /// Count an incoming event and decide whether to handle it. Outside
+    /// overload mode every event is; in it, about `ceiling / rate` of them,
+    /// plus every event `registered` says has a registered recipient. The
+    /// lookup only runs for events sampling would shed.
+    pub async fn admit<F, Fut>(&self, now: Instant, registered: F) -> bool
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        let sampled = self.sample(now, rand::random::<f64>());
+        let registered = !sampled && registered().await;
+        self.decide(sampled, registered)
+    }
+
+    /// Whether sampling keeps an event, given the draw, uniform in [0, 1).
+    fn sample(&self, now: Instant, draw: f64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.estimator.observe(now);
+        let rate = state.estimator.rate(now);
+
+        let ceiling = self.ceiling as f64;
+        if !state.overloaded && rate > ceiling {
+            state.overloaded = true;
+            warn!("Entering overload mode: ~{:.0} events/s exceeds {}; sampling events", rate, self.ceiling);
+            self.metrics.overload_mode.store(1, Ordering::Relaxed);
+            Metrics::inc(&self.metrics.overload_transitions);
+        } else if state.overloaded && rate < ceiling / 2.0 {
+            state.overloaded = false;
+            warn!("Leaving overload mode: ~{:.0} events/s", rate);
+            self.metrics.overload_mode.store(0, Ordering::Relaxed);
+            Metrics::inc(&self.metrics.overload_transitions);
+        }
+
+        !state.overloaded || draw < ceiling / rate
+    }
+
+    fn decide(&self, sampled: bool, registered: bool) -> bool {
+        if sampled || registered {
+            return true;
+        }
+        Metrics::inc(&self.metrics.events_sampled_out);
+        false
+    }
+
+    pub fn is_overloaded(&self) -> bool {
+        self.state.lock().unwrap().overloaded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed `per_sec` evenly spaced events for unregistered recipients for
+    /// `secs` seconds from `start`, returning how many were admitted and the
+    /// time after the last second.
+    fn burst(guard: &OverloadGuard, start: Instant, per_sec: u32, secs: u32, draw: f64) -> (usize, Instant) {
+        let mut admitted = 0;
+        for i in 0..per_sec * secs {
+            let at = start + BUCKET * i / per_sec;
+            admitted += guard.decide(guard.sample(at, draw), false) as usize;
+        }
+        (admitted, start + BUCKET * secs)
+    }
+
+    #[test]
+    fn test_rate_estimator_tracks_synthetic_bursts() {
+        let t0 = Instant::now();
+        let mut estimator = RateEstimator::new(t0);
+        for i in 0..1000 {
+            estimator.observe(t0 + BUCKET * 5 * i / 1000);
+        }
+        // 200/s for five seconds converges on 200
+        let rate = estimator.rate(t0 + BUCKET * 5);
+        assert!((190.0..=200.0).contains(&rate), "{}", rate);
+
+        // A one-second spike of 5000 moves it in one step, then decays
+        for i in 0..5000 {
+            estimator.observe(t0 + BUCKET * 5 + BUCKET * i / 5000);
+        }
+        let spiked = estimator.rate(t0 + BUCKET * 6);
+        assert!(spiked > 2500.0, "{}", spiked);
+        let quiet = estimator.rate(t0 + BUCKET * 16);
+        assert!(quiet < 5.0, "{}", quiet);
+    }
+
+    #[test]
+    fn test_overload_mode_samples_during_flood_and_exits_after() {
+        let metrics = Arc::new(Metrics::new());
+        let guard = OverloadGuard::new(1000, metrics.clone());
+        let t0 = Instant::now();
+        guard.state.lock().unwrap().estimator = RateEstimator::new(t0);
+
+        // Normal traffic is untouched
+        let (admitted, t) = burst(&guard, t0, 500, 5, 0.99);
+        assert_eq!(admitted, 2500);
+        assert!(!guard.is_overloaded());
+
+        // A 10x flood trips the mode; with draws above ceiling/rate, events are shed
+        let (admitted, t) = burst(&guard, t, 10_000, 3, 0.5);
+        assert!(guard.is_overloaded());
+        assert!(admitted < 20_000, "{}", admitted);
+        assert_eq!(Metrics::get(&metrics.events_sampled_out), 30_000 - admitted as u64);
+        assert_eq!(Metrics::get(&metrics.overload_mode), 1);
+
+        // Slightly over the ceiling keeps the mode, shedding less as the estimate settles
+        let (_, t) = burst(&guard, t, 1200, 10, 0.5);
+        let (admitted, t) = burst(&guard, t, 1200, 1, 0.5);
+        assert!(guard.is_overloaded());
+        assert_eq!(admitted, 1200);
+
+        // Falling under half the ceiling ends it
+        burst(&guard, t, 100, 5, 0.99);
+        assert!(!guard.is_overloaded());
+        assert_eq!(Metrics::get(&metrics.overload_mode), 0);
+        assert_eq!(Metrics::get(&metrics.overload_transitions), 2);
+        assert!(metrics.render().contains("mostro_push_overload_mode 0"));
+    }
+
+    #[tokio::test]
+    async fn test_registered_recipients_get_through_a_flood() {
+        let metrics = Arc::new(Metrics::new());
+        let guard = OverloadGuard::new(1000, metrics.clone());
+        let t0 = Instant::now();
+        guard.state.lock().unwrap().estimator = RateEstimator::new(t0);
+        let (_, t) = burst(&guard, t0, 10_000, 3, 0.99);
+        assert!(guard.is_overloaded());
+        let shed = Metrics::get(&metrics.events_sampled_out);
+
+        // Every tenth event of the flood is for a registered recipient
+        let mut admitted = 0;
+        for i in 0..10_000u32 {
+            let at = t + BUCKET * i / 10_000;
+            let is_registered = i % 10 == 0;
+            let admit = guard.admit(at, || async move { is_registered }).await;
+            assert!(admit || !is_registered, "registered recipient's event {} was shed", i);
+            admitted += admit as usize;
+        }
+        assert!(guard.is_overloaded());
+        // Only unregistered recipients' events were shed
+        assert_eq!(Metrics::get(&metrics.events_sampled_out) - shed, 10_000 - admitted as u64);
+        assert!(admitted < 5_000, "{}", admitted);
+    }
+}
@@ -0,0 +1,152 @@
+//! Trust-on-first-use pin of the Mostro pubkey: the first run records which
+//! Mostro instance it serves, and later runs refuse a different key unless
+//! the operator acknowledges the change.
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MostroPin {
+    pub mostro_pubkey: String,
+    /// SHA-256 of the sorted relay list; a change is logged but allowed
+    pub relays_fingerprint: String,
+    pub pinned_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PinCheck {
+    /// No pin yet; the configured values were pinned
+    Pinned,
+    Matched,
+    /// Same Mostro key, different relays; the pin was updated
+    RelaysChanged,
+    /// The Mostro key changed with the operator's acknowledgment; re-pinned
+    KeyChangeAccepted { previous: String },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PinError {
+    /// The configured key differs from the pinned one and the change wasn't acknowledged
+    KeyChanged { pinned: String, configured: String },
+    Io(String),
+}
+
+impl std::fmt::Display for PinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PinError::KeyChanged { pinned, configured } => write!(
+                f,
+                "MOSTRO_PUBKEY changed from the pinned {} to {}; start with \
+                 --accept-mostro-key-change (or ACCEPT_MOSTRO_KEY_CHANGE=true) if this is intended",
+                pinned, configured
+            ),
+            PinError::Io(e) => write!(f, "Mostro pubkey pin: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PinError {}
+
+pub fn relays_fingerprint(relays: &[String]) -> String {
+    let mut sorted: Vec<&str> = relays.iter().map(String::as_str).collect();
+    sorted.sort_unstable();
+    hex::encode(Sha256::digest(sorted.join("\n").as_bytes()))
+}
+
+/// Compare the configured Mostro pubkey and relays against the pin at `path`,
+/// creating or updating the pin when allowed.
+pub fn check_pin(
+    path: &Path,
+    mostro_pubkey: &str,
+    relays: &[String],
+    accept_key_change: bool,
+) -> Result<PinCheck, PinError> {
+    let fingerprint = relays_fingerprint(relays);
+    let pinned = match fs::read_to_string(path) {
+        Ok(content) => Some(
+            serde_json::from_str::<MostroPin>(&content)
+                .map_err(|e| PinError::Io(format!("corrupt pin file {}: {}", path.display(), e)))?,
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(PinError::Io(format!("{}: {}", path.display(), e))),
+    };
+
+    let check = match pinned {
+        None => PinCheck::Pinned,
+        Some(pin) if pin.mostro_pubkey != mostro_pubkey => {
+            if !accept_key_change {
+                return Err(PinError::KeyChanged {
+                    pinned: pin.mostro_pubkey,
+                    configured: mostro_pubkey.to_string(),
+                });
+            }
+            warn!(
+                "Accepting Mostro pubkey change from {} to {}",
+                pin.mostro_pubkey, mostro_pubkey
+            );
+            PinCheck::KeyChangeAccepted { previous: pin.mostro_pubkey }
+        }
+        Some(pin) if pin.relays_fingerprint != fingerprint => {
+            info!("Relay set changed since the Mostro pubkey was pinned");
+            PinCheck::RelaysChanged
+        }
+        Some(_) => return Ok(PinCheck::Matched),
+    };
+
+    let pin = MostroPin {
+        mostro_pubkey: mostro_pubkey.to_string(),
+        relays_fingerprint: fingerprint,
+        pinned_at: Utc::now(),
+    };
+    write_pin(path, &pin).map_err(|e| PinError::Io(format!("{}: {}", path.display(), e)))?;
+    Ok(check)
+}
+
+fn write_pin(path: &Path, pin: &MostroPin) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Write to temporary file first, then rename for atomic write
+    let temp_path = path.with_extension("tmp");
+    fs::write(&temp_path, serde_json::to_string_pretty(pin)?)?;
+    fs::rename(&temp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_A: &str = "dbe0b1be7aafd3cfba92d7463edbd4e33b2969f61bd554d37ac56f032e13355a";
+    const KEY_B: &str = "a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2";
+
+    #[test]
+    fn test_pin_refuses_unacknowledged_key_change() {
+        let path = std::env::temp_dir().join(format!("mostro-pin-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let relays = vec!["wss://a.example".to_string(), "wss://b.example".to_string()];
+
+        assert_eq!(check_pin(&path, KEY_A, &relays, false), Ok(PinCheck::Pinned));
+        assert_eq!(check_pin(&path, KEY_A, &relays, false), Ok(PinCheck::Matched));
+
+        // Order doesn't matter; a different set is allowed and re-pinned
+        let reordered = vec![relays[1].clone(), relays[0].clone()];
+        assert_eq!(check_pin(&path, KEY_A, &reordered, false), Ok(PinCheck::Matched));
+        assert_eq!(check_pin(&path, KEY_A, &relays[..1], false), Ok(PinCheck::RelaysChanged));
+
+        assert_eq!(
+            check_pin(&path, KEY_B, &relays, false),
+            Err(PinError::KeyChanged { pinned: KEY_A.to_string(), configured: KEY_B.to_string() })
+        );
+        // The refused change left the pin alone
+        assert_eq!(
+            check_pin(&path, KEY_B, &relays, true),
+            Ok(PinCheck::KeyChangeAccepted { previous: KEY_A.to_string() })
+        );
+        assert_eq!(check_pin(&path, KEY_B, &relays, false), Ok(PinCheck::Matched));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,44 @@
+use tokio::sync::{watch, Notify};
+
+/// Lets the admin API make the listener drop its relay connections and
+/// reconnect right away, skipping any backoff.
+pub struct ReconnectControl {
+    requested: Notify,
+    /// Bumped each time the listener finishes connecting
+    connections: watch::Sender<u64>,
+}
+
+impl Default for ReconnectControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReconnectControl {
+    pub fn new() -> Self {
+        Self {
+            requested: Notify::new(),
+            connections: watch::channel(0).0,
+        }
+    }
+
+    /// Ask for a reconnect. A request made while the listener isn't waiting
+    /// is kept until it next checks.
+    pub fn request(&self) {
+        self.requested.notify_one();
+    }
+
+    /// Resolves once a reconnect is requested.
+    pub async fn requested(&self) {
+        self.requested.notified().await;
+    }
+
+    pub(crate) fn mark_connected(&self) {
+        self.connections.send_modify(|count| *count += 1);
+    }
+
+    /// Observe completed connections, e.g. to wait for the one a request triggered.
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.connections.subscribe()
+    }
+}
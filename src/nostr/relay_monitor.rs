@@ -0,0 +1,167 @@
+//! Relay hygiene driven by NIP-66 relay monitor events.
+//!
+//! Monitors publish kind 30166 events whose `d` tag is the relay URL. A relay
+//! is reported down by an `["s", "offline"]` status tag; a report with an
+//! `["s", "online"]` tag or round-trip measurements (`rtt-*` tags) marks it
+//! healthy, which makes it a candidate to add when auto-add is enabled.
+
+use log::{info, warn};
+use nostr_sdk::prelude::*;
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+pub const RELAY_DISCOVERY_KIND: u64 = 30166;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RelayAction {
+    Add(String),
+    Remove(String),
+}
+
+/// Trusted monitors and the relay set they are allowed to adjust.
+pub struct RelayMonitor {
+    monitors: HashSet<XOnlyPublicKey>,
+    auto_add: bool,
+    relays: RwLock<Vec<String>>,
+}
+
+impl RelayMonitor {
+    pub fn new(monitor_pubkeys: &[String], auto_add: bool, relays: Vec<String>) -> Result<Self, String> {
+        let monitors = monitor_pubkeys
+            .iter()
+            .map(|pk| XOnlyPublicKey::from_str(pk).map_err(|e| format!("Invalid monitor pubkey {}: {}", pk, e)))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            monitors,
+            auto_add,
+            relays: RwLock::new(relays),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.monitors.is_empty()
+    }
+
+    pub fn monitors(&self) -> Vec<XOnlyPublicKey> {
+        self.monitors.iter().copied().collect()
+    }
+
+    /// Current relay set, used when (re)connecting.
+    pub fn relays(&self) -> Vec<String> {
+        self.relays.read().unwrap().clone()
+    }
+
+    /// Decide what a monitor event means for the relay set, and apply it.
+    /// Returns the action the caller should mirror on the live client.
+    pub fn handle_event(&self, event: &Event) -> Option<RelayAction> {
+        if event.kind != Kind::Custom(RELAY_DISCOVERY_KIND) || !self.monitors.contains(&event.pubkey) {
+            return None;
+        }
+
+        let mut relay_url = None;
+        let mut status = None;
+        let mut has_rtt = false;
+        for tag in event.tags.iter() {
+            let tag = tag.as_vec();
+            match (tag[0].as_str(), tag.get(1)) {
+                ("d", Some(url)) => relay_url = Some(normalize(url)),
+                ("s", Some(s)) => status = Some(s.to_lowercase()),
+                (name, _) if name.starts_with("rtt-") => has_rtt = true,
+                _ => {}
+            }
+        }
+        let relay_url = relay_url?;
+
+        let mut relays = self.relays.write().unwrap();
+        let known = relays.iter().any(|r| normalize(r) == relay_url);
+        match status.as_deref() {
+            Some("offline") if known => {
+                // Never drop the last relay; being connected somewhere beats nothing
+                if relays.len() <= 1 {
+                    warn!("Monitor reports {} down, but it is the only relay; keeping it", relay_url);
+                    return None;
+                }
+                relays.retain(|r| normalize(r) != relay_url);
+                info!("Monitor reports {} down, removing it", relay_url);
+                Some(RelayAction::Remove(relay_url))
+            }
+            Some("online") | None if !known && self.auto_add && (status.is_some() || has_rtt) => {
+                relays.push(relay_url.clone());
+                info!("Monitor recommends {}, adding it", relay_url);
+                Some(RelayAction::Add(relay_url))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn normalize(url: &str) -> String {
+    url.trim().trim_end_matches('/').to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monitor_event(keys: &Keys, relay: &str, tags: Vec<Vec<&str>>) -> Event {
+        let mut all = vec![Tag::parse(vec!["d", relay]).unwrap()];
+        for tag in tags {
+            all.push(Tag::parse(tag).unwrap());
+        }
+        EventBuilder::new(Kind::Custom(RELAY_DISCOVERY_KIND), "", all)
+            .to_event(keys)
+            .unwrap()
+    }
+
+    fn monitor(keys: &Keys, auto_add: bool) -> RelayMonitor {
+        RelayMonitor::new(
+            &[keys.public_key().to_string()],
+            auto_add,
+            vec!["wss://relay.one".to_string(), "wss://relay.two".to_string()],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_relay_down_event_removes_relay() {
+        let keys = Keys::generate();
+        let monitor = monitor(&keys, false);
+
+        let action = monitor.handle_event(&monitor_event(&keys, "wss://relay.one/", vec![vec!["s", "offline"]]));
+        assert_eq!(action, Some(RelayAction::Remove("wss://relay.one".to_string())));
+        assert_eq!(monitor.relays(), vec!["wss://relay.two".to_string()]);
+
+        // The last relay is kept even if reported down
+        let action = monitor.handle_event(&monitor_event(&keys, "wss://relay.two", vec![vec!["s", "offline"]]));
+        assert_eq!(action, None);
+        assert_eq!(monitor.relays(), vec!["wss://relay.two".to_string()]);
+    }
+
+    #[test]
+    fn test_recommended_relay_added_only_with_auto_add() {
+        let keys = Keys::generate();
+        let event = monitor_event(&keys, "wss://relay.three", vec![vec!["rtt-open", "120"]]);
+
+        assert_eq!(monitor(&keys, false).handle_event(&event), None);
+
+        let monitor = monitor(&keys, true);
+        assert_eq!(
+            monitor.handle_event(&event),
+            Some(RelayAction::Add("wss://relay.three".to_string()))
+        );
+        assert_eq!(monitor.relays().len(), 3);
+    }
+
+    #[test]
+    fn test_untrusted_monitor_is_ignored() {
+        let keys = Keys::generate();
+        let monitor = monitor(&keys, true);
+        let stranger = Keys::generate();
+
+        let event = monitor_event(&stranger, "wss://relay.one", vec![vec!["s", "offline"]]);
+        assert_eq!(monitor.handle_event(&event), None);
+        assert_eq!(monitor.relays().len(), 2);
+    }
+}
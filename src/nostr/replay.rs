@@ -0,0 +1,191 @@
+//! Replays a captured event trace through the handling pipeline, to reproduce
+//! production behaviour locally and report where outcomes diverge.
+
+use async_trait::async_trait;
+use log::{debug, info};
+use serde::Serialize;
+
+use crate::crypto::Platform;
+use crate::push::{PushPayload, PushService};
+use crate::store::{RegisteredToken, TokenStore};
+use super::category::NotificationPreferences;
+use super::listener::{InboundEvent, NostrListener};
+use super::trace::{EventOutcome, TraceRecord};
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReplayMismatch {
+    pub event_id: String,
+    pub expected: EventOutcome,
+    pub actual: EventOutcome,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReplayReport {
+    pub total: usize,
+    pub matched: usize,
+    pub mismatches: Vec<ReplayMismatch>,
+}
+
+/// Feed `records` through `listener` in order. Before each event, the store is
+/// set to what the original event met: a synthetic token on the recorded
+/// platform, or no registration. Gaps between events are divided by `speed`;
+/// a speed of 0 replays without waiting.
+pub async fn replay(
+    listener: &NostrListener,
+    store: &dyn TokenStore,
+    records: &[TraceRecord],
+    speed: f64,
+) -> ReplayReport {
+    let mut mismatches = Vec::new();
+    let mut previous_at: Option<chrono::DateTime<chrono::Utc>> = None;
+
+    for record in records {
+        if let (Some(previous), true) = (previous_at, speed > 0.0) {
+            let gap = (record.at - previous).to_std().unwrap_or_default();
+            tokio::time::sleep(gap.div_f64(speed)).await;
+        }
+        previous_at = Some(record.at);
+
+        if let Some(trade_pubkey) = &record.trade_pubkey {
+            match (&record.platform, record.outcome) {
+                (Some(platform), _) => {
+                    let device_token = format!("replay-{}", &trade_pubkey[..16.min(trade_pubkey.len())]);
+                    store.register(trade_pubkey.clone(), device_token, platform.clone()).await;
+                }
+                // The registration opted out of this category
+                (None, EventOutcome::OptedOut) => {
+                    let device_token = format!("replay-{}", &trade_pubkey[..16.min(trade_pubkey.len())]);
+                    let preferences = record
+                        .category
+                        .map_or(NotificationPreferences::ALL, |c| NotificationPreferences::ALL.without(c));
+                    let token = RegisteredToken::new(device_token, Platform::Android).with_preferences(preferences);
+                    store.register_token(trade_pubkey.clone(), token).await;
+                }
+                (None, EventOutcome::NotRegistered) => {
+                    store.unregister(trade_pubkey).await;
+                }
+                _ => {}
+            }
+        }
+
+        let actual = listener.inject(InboundEvent {
+            event_id: record.event_id.clone(),
+            trade_pubkey: record.trade_pubkey.clone(),
+            no_push: record.no_push,
+            category: record.category,
+            grouping_id: None,
+            relay: record.relay.clone(),
+        })
+        .await;
+        debug!("Replayed event {}: {:?} (originally {:?})", record.event_id, actual, record.outcome);
+
+        if actual != record.outcome {
+            mismatches.push(ReplayMismatch {
+                event_id: record.event_id.clone(),
+                expected: record.outcome,
+                actual,
+            });
+        }
+    }
+
+    info!("Replayed {} events, {} diverged", records.len(), mismatches.len());
+    ReplayReport {
+        total: records.len(),
+        matched: records.len() - mismatches.len(),
+        mismatches,
+    }
+}
+
+/// Push service for replays: accepts every push without contacting a provider.
+pub struct ReplayPush;
+
+#[async_trait]
+impl PushService for ReplayPush {
+    async fn send_silent_push(&self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    async fn send_notification(
+        &self,
+        _device_token: &str,
+        platform: &Platform,
+        _app_id: Option<&str>,
+        _payload: &PushPayload,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        debug!("Replay push to {} device", platform);
+        Ok(())
+    }
+
+    fn supports_platform(&self, _platform: &Platform) -> bool {
+        true
+    }
+
+    fn provider(&self) -> &'static str {
+        "replay"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::health::{Readiness, RelayHealth};
+    use crate::metrics::Metrics;
+    use crate::push::testing::MockPush;
+    use crate::push::{BackfillTracker, Dispatcher};
+    use crate::store::MemoryTokenStore;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    const FIXTURE: &str = r#"
+{"at":"2024-05-01T12:00:00Z","event_id":"e1","trade_pubkey":"a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2","outcome":"not_registered"}
+{"at":"2024-05-01T12:00:01Z","event_id":"e2","trade_pubkey":"a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2","platform":"android","outcome":"delivered"}
+{"at":"2024-05-01T12:00:02Z","event_id":"e3","trade_pubkey":"a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2","no_push":true,"outcome":"suppressed"}
+{"at":"2024-05-01T12:00:03Z","event_id":"e4","outcome":"no_recipient"}
+{"at":"2024-05-01T12:00:04Z","event_id":"e5","trade_pubkey":"b1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2","platform":"ios","outcome":"failed"}
+"#;
+
+    fn fixture() -> Vec<TraceRecord> {
+        let path = std::env::temp_dir().join(format!("mostro-push-trace-{}.jsonl", std::process::id()));
+        std::fs::write(&path, FIXTURE).unwrap();
+        let records = super::super::trace::read_trace(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        records
+    }
+
+    #[tokio::test]
+    async fn test_replay_reproduces_recorded_outcomes() {
+        let (android, sent) = MockPush::new();
+        let (ios, _) = MockPush::new();
+        let services: Vec<Box<dyn PushService>> = vec![
+            Box::new(android.serving("fcm", Platform::Android)),
+            Box::new(ios.serving("apns", Platform::Ios).failing()),
+        ];
+        let metrics = Arc::new(Metrics::new());
+        let store = Arc::new(MemoryTokenStore::new(48));
+        let listener = NostrListener::new(
+            Config::for_tests(),
+            Arc::new(Dispatcher::new(Arc::new(RwLock::new(services)), metrics.clone())),
+            Arc::new(BackfillTracker::new(120, true)),
+            store.clone(),
+            metrics,
+            Arc::new(Readiness::new(0)),
+            Arc::new(RelayHealth::new()),
+        )
+        .unwrap();
+
+        let records = fixture();
+        let report = replay(&listener, store.as_ref(), &records, 0.0).await;
+        assert_eq!(report.total, 5);
+        assert_eq!(report.mismatches, vec![]);
+        assert_eq!(MockPush::sent(&sent), 1);
+
+        // With every provider accepting, the recorded failure diverges
+        let mut records = records;
+        records[4].platform = Some(Platform::Android);
+        let report = replay(&listener, store.as_ref(), &records, 0.0).await;
+        assert_eq!(report.matched, 4);
+        assert_eq!(report.mismatches[0].event_id, "e5");
+        assert_eq!(report.mismatches[0].actual, EventOutcome::Delivered);
+    }
+}
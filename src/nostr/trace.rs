@@ -0,0 +1,104 @@
+//! Event traces: one JSON line per handled event, with enough to reconstruct
+//! the event and the registration state it met, for `replay`.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::crypto::Platform;
+use crate::gc::{compact_jsonl, Compaction, Dataset, RetentionPolicy};
+use super::category::EventCategory;
+
+/// What the pipeline did with an event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventOutcome {
+    /// Carried the no-push marker
+    Suppressed,
+    /// No `p` tag
+    NoRecipient,
+    /// Recipient had no registration
+    NotRegistered,
+    /// Recipient opted out of the event's category
+    OptedOut,
+    Delivered,
+    /// No push service accepted the push
+    Failed,
+    /// Another path already pushed this event to the device
+    AlreadyDelivered,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TraceRecord {
+    pub at: DateTime<Utc>,
+    pub event_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trade_pubkey: Option<String>,
+    #[serde(default)]
+    pub no_push: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<EventCategory>,
+    /// Platform of the registration the event was matched to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Platform>,
+    pub outcome: EventOutcome,
+    /// Relay that delivered the event first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relay: Option<String>,
+}
+
+/// Appends trace records to a file, one per line.
+pub struct TraceWriter {
+    path: PathBuf,
+    file: Mutex<LineWriter<File>>,
+}
+
+impl TraceWriter {
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(LineWriter::new(file)),
+        })
+    }
+
+    pub fn record(&self, record: &TraceRecord) {
+        let Ok(line) = serde_json::to_string(record) else {
+            return;
+        };
+        if let Err(e) = writeln!(self.file.lock().unwrap(), "{}", line) {
+            log::warn!("Failed to write event trace: {}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Dataset for TraceWriter {
+    fn name(&self) -> &'static str {
+        "trace"
+    }
+
+    async fn compact(&self, policy: &RetentionPolicy, now: DateTime<Utc>) -> Result<Compaction, String> {
+        compact_jsonl(&self.path, &mut self.file.lock().unwrap(), policy, now).map_err(|e| e.to_string())
+    }
+}
+
+/// Read a trace file, skipping blank lines.
+pub fn read_trace(path: &Path) -> Result<Vec<TraceRecord>, Box<dyn std::error::Error>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str(&line)
+            .map_err(|e| format!("{}:{}: {}", path.display(), number + 1, e))?;
+        records.push(record);
+    }
+    Ok(records)
+}
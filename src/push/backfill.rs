@@ -0,0 +1,144 @@
+use chrono::{DateTime, Duration, Utc};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Upper bound on tracked recipients so a flood of unmatched events can't grow memory
+const MAX_TRACKED_RECIPIENTS: usize = 10_000;
+/// Upper bound on missed events remembered per recipient
+const MAX_EVENTS_PER_RECIPIENT: usize = 16;
+
+/// When a missed event arrived, and its id
+type MissedEvent = (DateTime<Utc>, String);
+
+/// Remembers recent events addressed to pubkeys with no registered token, so a
+/// registration arriving shortly afterwards can get a catch-up push.
+///
+/// Recipients are keyed by a SHA-256 hash; raw pubkeys are never retained.
+pub struct BackfillTracker {
+    window: Duration,
+    /// Send a single catch-up push regardless of how many events were missed
+    coalesce: bool,
+    missed: Mutex<HashMap<[u8; 32], Vec<MissedEvent>>>,
+}
+
+impl BackfillTracker {
+    pub fn new(window_secs: u64, coalesce: bool) -> Self {
+        Self {
+            window: Duration::seconds(window_secs as i64),
+            coalesce,
+            missed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.window > Duration::zero()
+    }
+
+    pub fn record_missed(&self, trade_pubkey: &str, event_id: &str) {
+        self.record_missed_at(trade_pubkey, event_id, Utc::now());
+    }
+
+    pub fn record_missed_at(&self, trade_pubkey: &str, event_id: &str, at: DateTime<Utc>) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut missed = self.missed.lock().unwrap();
+        let cutoff = at - self.window;
+        if missed.len() >= MAX_TRACKED_RECIPIENTS {
+            missed.retain(|_, events| events.iter().any(|(t, _)| *t >= cutoff));
+        }
+        if missed.len() >= MAX_TRACKED_RECIPIENTS {
+            return;
+        }
+
+        let events = missed.entry(hash_pubkey(trade_pubkey)).or_default();
+        events.retain(|(t, _)| *t >= cutoff);
+        if events.len() < MAX_EVENTS_PER_RECIPIENT {
+            events.push((at, event_id.to_string()));
+        }
+    }
+
+    /// Remove and return the ids of events missed for this pubkey within the window.
+    pub fn take_missed(&self, trade_pubkey: &str) -> Vec<String> {
+        self.take_missed_at(trade_pubkey, Utc::now())
+    }
+
+    /// Group missed events into catch-up pushes, each listing the events it covers.
+    pub fn catch_up_pushes(&self, missed: Vec<String>) -> Vec<Vec<String>> {
+        if missed.is_empty() {
+            Vec::new()
+        } else if self.coalesce {
+            vec![missed]
+        } else {
+            missed.into_iter().map(|event_id| vec![event_id]).collect()
+        }
+    }
+
+    pub fn take_missed_at(&self, trade_pubkey: &str, now: DateTime<Utc>) -> Vec<String> {
+        let mut missed = self.missed.lock().unwrap();
+        let cutoff = now - self.window;
+        missed
+            .remove(&hash_pubkey(trade_pubkey))
+            .map(|events| events.into_iter().filter(|(t, _)| *t >= cutoff).map(|(_, id)| id).collect())
+            .unwrap_or_default()
+    }
+}
+
+fn hash_pubkey(trade_pubkey: &str) -> [u8; 32] {
+    Sha256::digest(trade_pubkey.as_bytes()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUBKEY: &str = "a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2";
+
+    #[test]
+    fn test_registration_before_event_has_nothing_to_backfill() {
+        let tracker = BackfillTracker::new(120, true);
+        let t0 = Utc::now();
+
+        assert!(tracker.take_missed_at(PUBKEY, t0).is_empty());
+        tracker.record_missed_at(PUBKEY, "e1", t0 + Duration::seconds(1));
+        // A later registration sees it, but the earlier one did not
+        assert_eq!(tracker.take_missed_at(PUBKEY, t0 + Duration::seconds(2)), vec!["e1"]);
+    }
+
+    #[test]
+    fn test_registration_within_window_is_backfilled_once() {
+        let tracker = BackfillTracker::new(120, true);
+        let t0 = Utc::now();
+
+        tracker.record_missed_at(PUBKEY, "e1", t0);
+        tracker.record_missed_at(PUBKEY, "e2", t0 + Duration::seconds(10));
+
+        let missed = tracker.take_missed_at(PUBKEY, t0 + Duration::seconds(60));
+        assert_eq!(missed, vec!["e1", "e2"]);
+        assert_eq!(tracker.catch_up_pushes(missed.clone()), vec![vec!["e1", "e2"]]);
+        assert!(tracker.take_missed_at(PUBKEY, t0 + Duration::seconds(61)).is_empty());
+
+        let uncoalesced = BackfillTracker::new(120, false);
+        assert_eq!(uncoalesced.catch_up_pushes(missed).len(), 2);
+    }
+
+    #[test]
+    fn test_registration_after_window_is_not_backfilled() {
+        let tracker = BackfillTracker::new(120, true);
+        let t0 = Utc::now();
+
+        tracker.record_missed_at(PUBKEY, "e1", t0);
+        assert!(tracker.take_missed_at(PUBKEY, t0 + Duration::seconds(121)).is_empty());
+    }
+
+    #[test]
+    fn test_disabled_tracker_records_nothing() {
+        let tracker = BackfillTracker::new(0, true);
+        let t0 = Utc::now();
+
+        tracker.record_missed_at(PUBKEY, "e1", t0);
+        assert!(tracker.take_missed_at(PUBKEY, t0).is_empty());
+    }
+}
@@ -0,0 +1,118 @@
+//! Payload size budgets. FCM and APNs both refuse payloads over 4KB, with
+//! errors that don't say which part was too big; checking before the send
+//! turns an oversized template into a clear log line and a metric instead.
+
+use serde_json::json;
+
+use crate::crypto::Platform;
+use super::PushPayload;
+
+/// What FCM and APNs each accept, and roughly what Web Push services do.
+pub const DEFAULT_BUDGET: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BudgetExceeded {
+    pub platform: Platform,
+    pub size: usize,
+    pub limit: usize,
+}
+
+impl std::fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} payload is {} bytes, over the {}-byte limit",
+            self.platform, self.size, self.limit
+        )
+    }
+}
+
+/// Largest serialized payload sent per platform; 0 leaves a platform unchecked.
+#[derive(Debug, Clone, Copy)]
+pub struct PayloadBudget {
+    pub android: usize,
+    pub ios: usize,
+    pub web: usize,
+}
+
+impl Default for PayloadBudget {
+    fn default() -> Self {
+        Self { android: DEFAULT_BUDGET, ios: DEFAULT_BUDGET, web: DEFAULT_BUDGET }
+    }
+}
+
+impl PayloadBudget {
+    pub fn check(&self, platform: &Platform, payload: &PushPayload) -> Result<(), BudgetExceeded> {
+        let limit = match platform {
+            Platform::Android => self.android,
+            Platform::Ios => self.ios,
+            Platform::Web => self.web,
+        };
+        let size = serialized_size(payload);
+        if limit == 0 || size <= limit {
+            return Ok(());
+        }
+        Err(BudgetExceeded { platform: platform.clone(), size, limit })
+    }
+}
+
+/// Bytes of the payload's user-visible content and data as JSON, which is
+/// what the providers count against their limits.
+pub fn serialized_size(payload: &PushPayload) -> usize {
+    let value = json!({
+        "title": payload.title,
+        "body": payload.body,
+        "data": payload.data,
+        "sound": payload.sound,
+        "badge": payload.badge,
+        "thread_id": payload.thread_id,
+    });
+    serde_json::to_vec(&value).map_or(0, |bytes| bytes.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Metrics;
+    use crate::push::testing::MockPush;
+    use crate::push::{Dispatcher, PushService};
+    use crate::store::RegisteredToken;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    #[tokio::test]
+    async fn test_oversized_payloads_are_refused_per_platform() {
+        let (android, android_sent) = MockPush::new();
+        let (ios, ios_sent) = MockPush::new();
+        let services: Vec<Box<dyn PushService>> = vec![
+            Box::new(android.serving("fcm", Platform::Android)),
+            Box::new(ios.serving("apns", Platform::Ios)),
+        ];
+        let metrics = Arc::new(Metrics::new());
+        let dispatcher = Dispatcher::new(Arc::new(RwLock::new(services)), metrics.clone())
+            .with_payload_budget(PayloadBudget { android: DEFAULT_BUDGET, ios: 2048, web: DEFAULT_BUDGET });
+        let oversized = PushPayload::silent_wake().data("detail", "x".repeat(3000));
+
+        // Within Android's budget but over the one configured for iOS
+        let android_token = RegisteredToken::new("fcm-token".to_string(), Platform::Android);
+        let ios_token = RegisteredToken::new("apns-token".to_string(), Platform::Ios);
+        assert!(dispatcher.dispatch(&android_token, &oversized).await);
+        assert!(!dispatcher.dispatch(&ios_token, &oversized).await);
+        assert_eq!(MockPush::sent(&ios_sent), 0);
+
+        let oversized = oversized.data("more", "x".repeat(2000));
+        assert!(!dispatcher.dispatch(&android_token, &oversized).await);
+        assert_eq!(MockPush::sent(&android_sent), 1);
+        assert_eq!(
+            PayloadBudget::default().check(&Platform::Android, &oversized),
+            Err(BudgetExceeded { platform: Platform::Android, size: serialized_size(&oversized), limit: 4096 })
+        );
+
+        assert_eq!(Metrics::get(&metrics.payloads_oversized_android), 1);
+        assert_eq!(Metrics::get(&metrics.payloads_oversized_ios), 1);
+        assert!(metrics.render().contains("mostro_push_payloads_oversized_total{platform=\"ios\"} 1"));
+        // An unchecked platform sends whatever it gets
+        let unchecked = PayloadBudget { android: 0, ios: 0, web: 0 };
+        assert_eq!(unchecked.check(&Platform::Ios, &oversized), Ok(()));
+    }
+}
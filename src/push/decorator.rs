@@ -0,0 +1,246 @@
+//! Payload decorators adjust a push (e.g. add a deep link) just before it is
+//! sent. They run sandboxed, since a broken one must not stall dispatch:
+//! each call gets its own thread, a wall-clock timeout, an output size cap
+//! and panic isolation. A violation sends the undecorated payload, and after
+//! `max_strikes` violations the decorator is disabled until restart.
+//!
+//! A decorator that overruns its timeout keeps running on its detached
+//! thread; the sandbox stops waiting for it but cannot stop it.
+
+use log::{error, warn};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU32};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::metrics::Metrics;
+use super::PushPayload;
+
+pub trait PayloadDecorator: Send + Sync + 'static {
+    fn name(&self) -> &str;
+
+    fn decorate(&self, payload: PushPayload) -> PushPayload;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SandboxLimits {
+    pub timeout: Duration,
+    /// Bytes of title, body, data and the other text fields together
+    pub max_output_bytes: usize,
+    pub max_strikes: u32,
+}
+
+impl Default for SandboxLimits {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_millis(50),
+            max_output_bytes: 4096,
+            max_strikes: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Violation {
+    Timeout,
+    Panicked,
+    Oversized(usize),
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Violation::Timeout => write!(f, "timed out"),
+            Violation::Panicked => write!(f, "panicked"),
+            Violation::Oversized(size) => write!(f, "produced {} bytes", size),
+        }
+    }
+}
+
+/// Enforces `SandboxLimits` around any decorator.
+pub struct Sandboxed<D: PayloadDecorator + ?Sized> {
+    decorator: Arc<D>,
+    limits: SandboxLimits,
+    strikes: AtomicU32,
+    disabled: AtomicBool,
+    metrics: Arc<Metrics>,
+}
+
+impl<D: PayloadDecorator + ?Sized> Sandboxed<D> {
+    pub fn new(decorator: Arc<D>, limits: SandboxLimits, metrics: Arc<Metrics>) -> Self {
+        Self {
+            decorator,
+            limits,
+            strikes: AtomicU32::new(0),
+            disabled: AtomicBool::new(false),
+            metrics,
+        }
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.disabled.load(Ordering::Relaxed)
+    }
+
+    /// The decorated payload, or `payload` unchanged if the decorator is
+    /// disabled or breaks a limit.
+    pub async fn apply(&self, payload: PushPayload) -> PushPayload {
+        if self.is_disabled() {
+            return payload;
+        }
+        match self.run(payload.clone()).await {
+            Ok(decorated) => decorated,
+            Err(violation) => {
+                self.strike(&violation);
+                payload
+            }
+        }
+    }
+
+    async fn run(&self, payload: PushPayload) -> Result<PushPayload, Violation> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let decorator = self.decorator.clone();
+        let spawned = std::thread::Builder::new()
+            .name(format!("decorator-{}", self.decorator.name()))
+            .spawn(move || {
+                let result = catch_unwind(AssertUnwindSafe(|| decorator.decorate(payload)));
+                let _ = tx.send(result);
+            });
+        if let Err(e) = spawned {
+            warn!("Could not start decorator {}: {}", self.decorator.name(), e);
+            return Err(Violation::Panicked);
+        }
+
+        match tokio::time::timeout(self.limits.timeout, rx).await {
+            Err(_) => Err(Violation::Timeout),
+            Ok(Err(_)) | Ok(Ok(Err(_))) => Err(Violation::Panicked),
+            Ok(Ok(Ok(decorated))) => match output_size(&decorated) {
+                size if size > self.limits.max_output_bytes => Err(Violation::Oversized(size)),
+                _ => Ok(decorated),
+            },
+        }
+    }
+
+    fn strike(&self, violation: &Violation) {
+        Metrics::inc(&self.metrics.decorator_violations);
+        let strikes = self.strikes.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!(
+            "Payload decorator {} {} (strike {}/{}), sending the undecorated payload",
+            self.decorator.name(),
+            violation,
+            strikes,
+            self.limits.max_strikes
+        );
+        if strikes >= self.limits.max_strikes && !self.disabled.swap(true, Ordering::Relaxed) {
+            error!("Disabled payload decorator {} after {} violations", self.decorator.name(), strikes);
+        }
+    }
+}
+
+fn output_size(payload: &PushPayload) -> usize {
+    let text = |s: &Option<String>| s.as_ref().map_or(0, String::len);
+    text(&payload.title)
+        + text(&payload.body)
+        + text(&payload.collapse_key)
+        + text(&payload.thread_id)
+        + text(&payload.sound)
+        + payload.data.iter().map(|(k, v)| k.len() + v.len()).sum::<usize>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Platform;
+    use crate::push::testing::MockPush;
+    use crate::push::{Dispatcher, PushService, PushType};
+    use crate::store::RegisteredToken;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Instant;
+    use tokio::sync::RwLock;
+
+    enum Behaviour {
+        /// Spins until the test ends
+        Loop(Arc<AtomicBool>),
+        Panic,
+        Oversized,
+        DeepLink,
+    }
+
+    struct TestDecorator {
+        behaviour: Behaviour,
+        calls: AtomicUsize,
+    }
+
+    impl TestDecorator {
+        fn new(behaviour: Behaviour) -> Arc<Self> {
+            Arc::new(Self { behaviour, calls: AtomicUsize::new(0) })
+        }
+    }
+
+    impl PayloadDecorator for TestDecorator {
+        fn name(&self) -> &str {
+            match self.behaviour {
+                Behaviour::Loop(_) => "loop",
+                Behaviour::Panic => "panic",
+                Behaviour::Oversized => "oversized",
+                Behaviour::DeepLink => "deep_link",
+            }
+        }
+
+        fn decorate(&self, payload: PushPayload) -> PushPayload {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            match &self.behaviour {
+                Behaviour::Loop(stop) => {
+                    while !stop.load(Ordering::Relaxed) {
+                        std::hint::spin_loop();
+                    }
+                    payload
+                }
+                Behaviour::Panic => panic!("decorator bug"),
+                Behaviour::Oversized => payload.data("blob", "x".repeat(1 << 20)),
+                Behaviour::DeepLink => payload.data("link", "mostro://trade"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_misbehaving_decorators_are_contained() {
+        let stop = Arc::new(AtomicBool::new(false));
+        let looping = TestDecorator::new(Behaviour::Loop(stop.clone()));
+        let panicking = TestDecorator::new(Behaviour::Panic);
+        let oversized = TestDecorator::new(Behaviour::Oversized);
+        let deep_link = TestDecorator::new(Behaviour::DeepLink);
+
+        let (mock, sent) = MockPush::new();
+        let last_payload = mock.last_payload.clone();
+        let services: Vec<Box<dyn PushService>> = vec![Box::new(mock)];
+        let metrics = Arc::new(Metrics::new());
+        let limits = SandboxLimits { max_strikes: 2, ..SandboxLimits::default() };
+        let mut dispatcher = Dispatcher::new(Arc::new(RwLock::new(services)), metrics.clone());
+        for decorator in [&looping, &panicking, &oversized, &deep_link] {
+            dispatcher = dispatcher.with_decorator(decorator.clone(), limits);
+        }
+
+        let token = RegisteredToken::new("fcm-token".to_string(), Platform::Android);
+        let payload = PushPayload::new(PushType::Background).data("type", "silent_wake");
+        for _ in 0..3 {
+            let started = Instant::now();
+            assert!(dispatcher.dispatch(&token, &payload).await);
+            // Bounded by the one timeout, never by the looping decorator
+            assert!(started.elapsed() < Duration::from_millis(500), "{:?}", started.elapsed());
+        }
+        stop.store(true, Ordering::Relaxed);
+
+        assert_eq!(MockPush::sent(&sent), 3);
+        let delivered = last_payload.lock().unwrap().clone().unwrap();
+        assert_eq!(delivered.data.len(), 2);
+        assert_eq!(delivered.data["link"], "mostro://trade");
+
+        // Two strikes each, then disabled and never called again
+        assert_eq!(Metrics::get(&metrics.decorator_violations), 6);
+        for decorator in [&looping, &panicking, &oversized] {
+            assert_eq!(decorator.calls.load(Ordering::SeqCst), 2);
+        }
+        assert_eq!(deep_link.calls.load(Ordering::SeqCst), 3);
+    }
+}
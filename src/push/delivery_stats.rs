@@ -0,0 +1,77 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::crypto::Platform;
+use crate::models::{DeliveryCounts, DeliveryStatsResponse};
+use crate::utils::time_buckets::TimeBuckets;
+
+/// Granularity of delivery outcome accounting.
+const BUCKET_WIDTH: Duration = Duration::from_secs(60);
+/// How far back delivery outcomes can be queried.
+pub const RETENTION: Duration = Duration::from_secs(24 * 3600);
+
+#[derive(Default)]
+struct PlatformOutcomes {
+    android: (u64, u64),
+    ios: (u64, u64),
+    web: (u64, u64),
+}
+
+impl PlatformOutcomes {
+    fn entry(&mut self, platform: &Platform) -> &mut (u64, u64) {
+        match platform {
+            Platform::Android => &mut self.android,
+            Platform::Ios => &mut self.ios,
+            Platform::Web => &mut self.web,
+        }
+    }
+}
+
+/// Recent push outcomes per platform, for a windowed delivery success rate.
+pub struct DeliveryStats {
+    buckets: TimeBuckets<PlatformOutcomes>,
+}
+
+impl DeliveryStats {
+    pub fn new(origin: Instant) -> Self {
+        Self {
+            buckets: TimeBuckets::new(BUCKET_WIDTH, RETENTION, origin),
+        }
+    }
+
+    pub fn record(&self, now: Instant, platform: &Platform, delivered: bool) {
+        self.buckets.update(now, |outcomes| {
+            let (succeeded, failed) = outcomes.entry(platform);
+            if delivered {
+                *succeeded += 1;
+            } else {
+                *failed += 1;
+            }
+        });
+    }
+
+    pub fn summary(&self, now: Instant, window: Duration) -> DeliveryStatsResponse {
+        let window = window.min(self.buckets.retention());
+        let (android, ios, web) = self.buckets.fold(now, window, ((0, 0), (0, 0), (0, 0)), |(a, i, w), o| {
+            (
+                (a.0 + o.android.0, a.1 + o.android.1),
+                (i.0 + o.ios.0, i.1 + o.ios.1),
+                (w.0 + o.web.0, w.1 + o.web.1),
+            )
+        });
+
+        let mut platforms = BTreeMap::from([
+            (Platform::Android.to_string(), DeliveryCounts::new(android.0, android.1)),
+            (Platform::Ios.to_string(), DeliveryCounts::new(ios.0, ios.1)),
+        ]);
+        // No provider serves web yet, so it is only listed once it was pushed to
+        if web != (0, 0) {
+            platforms.insert(Platform::Web.to_string(), DeliveryCounts::new(web.0, web.1));
+        }
+        DeliveryStatsResponse {
+            window_secs: window.as_secs(),
+            total: DeliveryCounts::new(android.0 + ios.0 + web.0, android.1 + ios.1 + web.1),
+            platforms,
+        }
+    }
+}
@@ -0,0 +1,689 @@
+use log::{debug, error, info, warn};
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+use crate::crypto::{Platform, TokenRedaction};
+use crate::metrics::Metrics;
+use crate::models::{DeliveryStatsResponse, ProviderQuotaStatus, ProviderRequestPreview};
+use crate::store::RegisteredToken;
+use crate::watch::WatchHandle;
+use super::budget::PayloadBudget;
+use super::decorator::{PayloadDecorator, SandboxLimits, Sandboxed};
+use super::delivery_stats::DeliveryStats;
+use super::ledger::DeliveryLedger;
+use super::platform_limits::PlatformLimits;
+use super::scheduler::SchedulerPermit;
+use super::{Clock, FairScheduler, PushPayload, PushService, ProviderQuota, SystemClock, TokenValidity};
+
+/// Routes a payload to the configured push services for a registered token.
+/// Shared by the Nostr listener and the HTTP API.
+pub struct Dispatcher {
+    /// Only read while dispatching, so sends to different services run concurrently
+    push_services: Arc<RwLock<Vec<Box<dyn PushService>>>>,
+    metrics: Arc<Metrics>,
+    /// Request budgets for providers that have one configured
+    quotas: Vec<ProviderQuota>,
+    clock: Arc<dyn Clock>,
+    /// How device tokens are identified in delivery logs
+    token_redaction: TokenRedaction,
+    /// Per-service send concurrency; unbounded when unset
+    scheduler: Option<Arc<FairScheduler>>,
+    /// Per-platform in-flight dispatch limits; unbounded when unset
+    platform_limits: Option<PlatformLimits>,
+    /// Recent outcomes, for the delivery success rate
+    delivery_stats: DeliveryStats,
+    /// Applied in order to every payload before sealing
+    decorators: Vec<Sandboxed<dyn PayloadDecorator>>,
+    /// Keeps event pushes to at most one delivery per device; unchecked when unset
+    ledger: Option<Arc<DeliveryLedger>>,
+    /// Per-platform payload size limits; unchecked when unset
+    budget: Option<PayloadBudget>,
+}
+
+impl Dispatcher {
+    pub fn new(
+        push_services: Arc<RwLock<Vec<Box<dyn PushService>>>>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self::with_quotas(push_services, metrics, Vec::new(), Arc::new(SystemClock))
+    }
+
+    pub fn with_quotas(
+        push_services: Arc<RwLock<Vec<Box<dyn PushService>>>>,
+        metrics: Arc<Metrics>,
+        quotas: Vec<ProviderQuota>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Self {
+            push_services,
+            metrics,
+            quotas,
+            delivery_stats: DeliveryStats::new(clock.now()),
+            clock,
+            token_redaction: TokenRedaction::Prefix,
+            scheduler: None,
+            platform_limits: None,
+            decorators: Vec::new(),
+            ledger: None,
+            budget: None,
+        }
+    }
+
+    pub fn with_token_redaction(mut self, token_redaction: TokenRedaction) -> Self {
+        self.token_redaction = token_redaction;
+        self
+    }
+
+    pub fn with_scheduler(mut self, scheduler: FairScheduler) -> Self {
+        self.scheduler = Some(Arc::new(scheduler));
+        self
+    }
+
+    pub fn with_platform_limits(mut self, limits: PlatformLimits) -> Self {
+        self.platform_limits = Some(limits);
+        self
+    }
+
+    pub fn with_decorator(mut self, decorator: Arc<dyn PayloadDecorator>, limits: SandboxLimits) -> Self {
+        self.decorators.push(Sandboxed::new(decorator, limits, self.metrics.clone()));
+        self
+    }
+
+    pub fn with_ledger(mut self, ledger: Arc<DeliveryLedger>) -> Self {
+        self.ledger = Some(ledger);
+        self
+    }
+
+    pub fn with_payload_budget(mut self, budget: PayloadBudget) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// `dispatch_watched` for a push on behalf of `event_ids`, claiming each
+    /// in the delivery ledger first. Returns None without sending when every
+    /// event was already delivered to this device or is being sent by
+    /// another path.
+    pub async fn dispatch_for_events(
+        &self,
+        event_ids: &[String],
+        token: &RegisteredToken,
+        payload: &PushPayload,
+        watch: Option<&WatchHandle>,
+    ) -> Option<bool> {
+        let Some(ledger) = &self.ledger else {
+            return Some(self.dispatch_watched(token, payload, watch).await);
+        };
+
+        let claims = Claims {
+            ledger,
+            device_token: &token.device_token,
+            event_ids: event_ids
+                .iter()
+                .filter(|event_id| ledger.claim(event_id, &token.device_token, chrono::Utc::now()))
+                .collect(),
+            outcome: Dispatched::Failed,
+        };
+        if claims.event_ids.is_empty() {
+            debug!(
+                "Push for {} event(s) already delivered to token {}, skipping",
+                event_ids.len(),
+                self.token_redaction.label(&token.device_token)
+            );
+            Metrics::inc(&self.metrics.pushes_deduplicated);
+            if let Some(watch) = watch {
+                watch.record("skipped", "already delivered by another path");
+            }
+            return None;
+        }
+
+        let mut claims = claims;
+        claims.outcome = self.send(token, payload, watch).await;
+        Some(claims.outcome != Dispatched::Failed)
+    }
+
+    /// Try each service supporting the token's platform until one accepts the push.
+    /// Returns true if the push was delivered to a provider, or delayed because
+    /// the provider is over its quota.
+    pub async fn dispatch(&self, token: &RegisteredToken, payload: &PushPayload) -> bool {
+        self.dispatch_watched(token, payload, None).await
+    }
+
+    /// `dispatch`, recording each step for a watched pubkey.
+    pub async fn dispatch_watched(
+        &self,
+        token: &RegisteredToken,
+        payload: &PushPayload,
+        watch: Option<&WatchHandle>,
+    ) -> bool {
+        self.send(token, payload, watch).await != Dispatched::Failed
+    }
+
+    async fn send(&self, token: &RegisteredToken, payload: &PushPayload, watch: Option<&WatchHandle>) -> Dispatched {
+        let payload = match self.finalize(token, payload).await {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!("Failed to seal push payload: {}", e);
+                return Dispatched::Failed;
+            }
+        };
+        let payload = payload.as_ref();
+        // A provider would refuse it anyway, with a less useful error
+        if let Some(Err(e)) = self.budget.map(|budget| budget.check(&token.platform, payload)) {
+            error!(
+                "Not sending push to token {}{}: {}",
+                self.token_redaction.label(&token.device_token),
+                token.annotations_label(),
+                e
+            );
+            Metrics::inc(self.oversized_counter(&token.platform));
+            Metrics::inc(&self.metrics.pushes_failed);
+            if let Some(watch) = watch {
+                watch.record("rejected", e.to_string());
+            }
+            return Dispatched::Failed;
+        }
+        let _permit = match &self.platform_limits {
+            Some(limits) => Some(limits.acquire(&token.platform).await),
+            None => None,
+        };
+        let _in_flight = InFlight::start(self.in_flight_gauge(&token.platform));
+
+        let started = Instant::now();
+        let outcome = self.try_services(token, payload, watch).await;
+        self.metrics.dispatch_latency.observe(started.elapsed().as_secs_f64());
+        outcome
+    }
+
+    /// `payload` as sent to `token`: decorated, then sealed when the
+    /// registration has a push key.
+    async fn finalize<'a>(&self, token: &RegisteredToken, payload: &'a PushPayload) -> Result<Cow<'a, PushPayload>, String> {
+        let mut payload = Cow::Borrowed(payload);
+        for decorator in &self.decorators {
+            payload = Cow::Owned(decorator.apply(payload.into_owned()).await);
+        }
+
+        // Registrations with a push key only ever receive sealed data
+        match token.push_key_bytes() {
+            Some(push_key) => payload.sealed(&push_key).map(Cow::Owned).map_err(|e| e.to_string()),
+            None => Ok(payload),
+        }
+    }
+
+    /// The requests dispatching `payload` to `token` would make, in the order
+    /// the services would be tried, without sending anything or touching
+    /// quotas and delivery stats.
+    pub async fn preview(&self, token: &RegisteredToken, payload: &PushPayload) -> Result<Vec<ProviderRequestPreview>, String> {
+        let payload = self.finalize(token, payload).await?;
+        if let Some(budget) = self.budget {
+            budget.check(&token.platform, &payload).map_err(|e| e.to_string())?;
+        }
+        let services = self.push_services.read().await;
+        let mut previews = Vec::new();
+        for service in services.iter() {
+            if !service.supports_platform(&token.platform) || !service.serves_environment(token.environment) {
+                continue;
+            }
+            let request = service.preview(&token.device_token, &token.platform, token.app_id.as_deref(), &payload);
+            match request {
+                Some(Ok(request)) => previews.push(request_preview(service.provider(), &request)),
+                Some(Err(e)) => return Err(format!("{}: {}", service.provider(), e)),
+                None => {}
+            }
+        }
+        Ok(previews)
+    }
+
+    /// Warm up every push service, reporting the providers that failed.
+    pub async fn warm_up(&self) -> Result<(), String> {
+        let services = self.push_services.read().await;
+        let mut failed = Vec::new();
+        for service in services.iter() {
+            if let Err(e) = service.warm_up().await {
+                failed.push(format!("{}: {}", service.provider(), e));
+            }
+        }
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(failed.join(", "))
+        }
+    }
+
+    /// Send `payload` to a raw device token through the first service for
+    /// `platform`, bypassing quotas and delivery stats. Returns the provider
+    /// and its result, or None if no service supports the platform.
+    pub async fn test_send(
+        &self,
+        platform: &Platform,
+        device_token: &str,
+        app_id: Option<&str>,
+        payload: &PushPayload,
+    ) -> Option<(&'static str, Result<(), String>)> {
+        let services = self.push_services.read().await;
+        let service = services.iter().find(|s| s.supports_platform(platform))?;
+        let token_label = self.token_redaction.label(device_token);
+        let result = service
+            .send_notification(device_token, platform, app_id, payload)
+            .await
+            .map_err(|e| e.to_string());
+        match &result {
+            Ok(()) => info!("Test push accepted by {} (token {})", service.provider(), token_label),
+            Err(e) => warn!("Test push rejected by {} (token {}): {}", service.provider(), token_label, e),
+        }
+        Some((service.provider(), result))
+    }
+
+    /// Ask the services that would deliver to `token` whether it is still
+    /// valid; the first definite answer wins. Each check spends the
+    /// provider's quota, and a provider out of budget isn't asked.
+    pub async fn validate_token(&self, token: &RegisteredToken) -> TokenValidity {
+        let services = self.push_services.read().await;
+        for service in services.iter() {
+            if !service.supports_platform(&token.platform) || !service.serves_environment(token.environment) {
+                continue;
+            }
+            if let Some(quota) = self.quota(service.provider()) {
+                if !quota.try_acquire(self.clock.now()) {
+                    return TokenValidity::Unknown;
+                }
+            }
+            let validity = service
+                .validate_token(&token.device_token, &token.platform, token.app_id.as_deref())
+                .await;
+            if validity != TokenValidity::Unknown {
+                return validity;
+            }
+        }
+        TokenValidity::Unknown
+    }
+
+    async fn try_services(&self, token: &RegisteredToken, payload: &PushPayload, watch: Option<&WatchHandle>) -> Dispatched {
+        let services = self.push_services.read().await;
+        for service in services.iter() {
+            if service.supports_platform(&token.platform) && service.serves_environment(token.environment) {
+                if let Some(quota) = self.quota(service.provider()) {
+                    if !quota.try_acquire(self.clock.now()) {
+                        quota.enqueue(token.clone(), payload.clone());
+                        Metrics::inc(&self.metrics.pushes_delayed);
+                        if let Some(watch) = watch {
+                            watch.record("queued", format!("provider={} reason=quota", service.provider()));
+                        }
+                        return Dispatched::Queued;
+                    }
+                }
+
+                let token_label = self.token_redaction.label(&token.device_token);
+                debug!(
+                    "Sending {} push to token {}{}",
+                    service.provider(),
+                    token_label,
+                    token.annotations_label()
+                );
+                let _permit = self.send_permit(service.provider()).await;
+                match service.send_notification(
+                    &token.device_token,
+                    &token.platform,
+                    token.app_id.as_deref(),
+                    payload,
+                ).await {
+                    Ok(_) => {
+                        info!(
+                            "Push sent to {} device (token {}){}",
+                            token.platform,
+                            token_label,
+                            token.annotations_label()
+                        );
+                        Metrics::inc(&self.metrics.pushes_sent);
+                        self.delivery_stats.record(self.clock.now(), &token.platform, true);
+                        if let Some(watch) = watch {
+                            watch.record("send", format!("provider={} result=ok", service.provider()));
+                        }
+                        return Dispatched::Sent; // Only need one service to succeed
+                    }
+                    Err(e) => {
+                        error!(
+                            "Failed to send push to token {}{}: {}",
+                            token_label,
+                            token.annotations_label(),
+                            e
+                        );
+                        self.metrics.record_push_error(service.provider(), &e.to_string());
+                        if let Some(watch) = watch {
+                            watch.record("send", format!("provider={} result=error error={:?}", service.provider(), e.to_string()));
+                        }
+                    }
+                }
+            }
+        }
+        Metrics::inc(&self.metrics.pushes_failed);
+        self.delivery_stats.record(self.clock.now(), &token.platform, false);
+        Dispatched::Failed
+    }
+
+    async fn send_permit(&self, provider: &'static str) -> Option<SchedulerPermit> {
+        match &self.scheduler {
+            Some(scheduler) => Some(scheduler.acquire(provider).await),
+            None => None,
+        }
+    }
+
+    fn in_flight_gauge(&self, platform: &Platform) -> &AtomicU64 {
+        self.metrics.in_flight(platform)
+    }
+
+    fn oversized_counter(&self, platform: &Platform) -> &AtomicU64 {
+        match platform {
+            Platform::Android => &self.metrics.payloads_oversized_android,
+            Platform::Ios => &self.metrics.payloads_oversized_ios,
+            Platform::Web => &self.metrics.payloads_oversized_web,
+        }
+    }
+
+    fn quota(&self, provider: &str) -> Option<&ProviderQuota> {
+        self.quotas.iter().find(|q| q.provider() == provider)
+    }
+
+    /// Send pushes delayed by provider quotas, as far as budget allows.
+    /// Returns how long until the next delayed push is due, if any remain.
+    pub async fn drain_delayed(&self) -> Option<Duration> {
+        for quota in &self.quotas {
+            let ready = quota.take_ready(self.clock.now());
+            if ready.is_empty() {
+                continue;
+            }
+
+            let services = self.push_services.read().await;
+            for (token, payload) in ready {
+                let Some(service) = services
+                    .iter()
+                    .find(|s| s.provider() == quota.provider() && s.serves_environment(token.environment))
+                else {
+                    continue;
+                };
+                let _permit = self.send_permit(service.provider()).await;
+                let result = service.send_notification(&token.device_token, &token.platform, token.app_id.as_deref(), &payload).await;
+                self.delivery_stats.record(self.clock.now(), &token.platform, result.is_ok());
+                match result {
+                    Ok(_) => Metrics::inc(&self.metrics.pushes_sent),
+                    Err(e) => {
+                        error!(
+                            "Failed to send delayed push to token {}{}: {}",
+                            self.token_redaction.label(&token.device_token),
+                            token.annotations_label(),
+                            e
+                        );
+                        self.metrics.record_push_error(service.provider(), &e.to_string());
+                        Metrics::inc(&self.metrics.pushes_failed);
+                    }
+                }
+            }
+        }
+
+        let now = self.clock.now();
+        self.quotas.iter().filter_map(|q| q.next_drain_in(now)).min()
+    }
+
+    /// How `device_token` is identified in logs.
+    pub fn token_label(&self, device_token: &str) -> String {
+        self.token_redaction.label(device_token)
+    }
+
+    /// Providers currently dispatched to.
+    pub async fn providers(&self) -> Vec<String> {
+        self.push_services.read().await.iter().map(|s| s.provider().to_string()).collect()
+    }
+
+    /// Platforms at least one provider can deliver to.
+    pub async fn supported_platforms(&self) -> Vec<Platform> {
+        let services = self.push_services.read().await;
+        Platform::ALL
+            .into_iter()
+            .filter(|platform| services.iter().any(|s| s.supports_platform(platform)))
+            .collect()
+    }
+
+    /// Delivery outcomes over the last `window`, capped at the retained history.
+    pub fn delivery_stats(&self, window: Duration) -> DeliveryStatsResponse {
+        self.delivery_stats.summary(self.clock.now(), window)
+    }
+
+    pub fn quota_status(&self) -> Vec<ProviderQuotaStatus> {
+        let now = self.clock.now();
+        self.quotas.iter().map(|q| q.status(now)).collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dispatched {
+    Sent,
+    /// Waiting in a provider's quota queue
+    Queued,
+    Failed,
+}
+
+/// Ledger claims held for one send, settled when dropped so a cancelled send
+/// releases them like a failed one. Claims for a queued push stay in flight,
+/// so GC keeps them while the push waits.
+struct Claims<'a> {
+    ledger: &'a DeliveryLedger,
+    device_token: &'a str,
+    event_ids: Vec<&'a String>,
+    outcome: Dispatched,
+}
+
+impl Drop for Claims<'_> {
+    fn drop(&mut self) {
+        if self.outcome == Dispatched::Queued {
+            return;
+        }
+        let now = chrono::Utc::now();
+        for event_id in &self.event_ids {
+            self.ledger.finish(event_id, self.device_token, self.outcome == Dispatched::Sent, now);
+        }
+    }
+}
+
+/// Counts a dispatch as in flight until dropped.
+struct InFlight<'a>(&'a AtomicU64);
+
+impl<'a> InFlight<'a> {
+    fn start(gauge: &'a AtomicU64) -> Self {
+        gauge.fetch_add(1, Ordering::Relaxed);
+        Self(gauge)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The request as shown by `/admin/preview`; bodies that aren't JSON are
+/// shown as text.
+fn request_preview(provider: &str, request: &reqwest::Request) -> ProviderRequestPreview {
+    let headers = request
+        .headers()
+        .iter()
+        .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned()))
+        .collect();
+    let body = request.body().and_then(|body| body.as_bytes()).unwrap_or_default();
+    ProviderRequestPreview {
+        provider: provider.to_string(),
+        method: request.method().to_string(),
+        url: request.url().to_string(),
+        headers,
+        body: serde_json::from_slice(body)
+            .unwrap_or_else(|_| serde_json::Value::String(String::from_utf8_lossy(body).into_owned())),
+    }
+}
+
+/// Periodically drain pushes delayed by provider quotas.
+pub fn start_drain_task(dispatcher: Arc<Dispatcher>) {
+    tokio::spawn(async move {
+        loop {
+            let wait = dispatcher.drain_delayed().await.unwrap_or(Duration::from_secs(1));
+            tokio::time::sleep(wait.max(Duration::from_millis(10))).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Platform;
+    use crate::push::quota::testing::MockClock;
+    use crate::push::testing::MockPush;
+
+    #[tokio::test]
+    async fn test_push_key_registrations_receive_sealed_data() {
+        let (mock, _) = MockPush::new();
+        let last_payload = mock.last_payload.clone();
+        let services: Vec<Box<dyn PushService>> = vec![Box::new(mock)];
+        let dispatcher = Dispatcher::new(Arc::new(RwLock::new(services)), Arc::new(Metrics::new()));
+        let push_key = [0x42; crate::crypto::PUSH_KEY_SIZE];
+        let mut token = RegisteredToken::new("device-token".to_string(), Platform::Android);
+        token.push_key = Some(hex::encode(push_key));
+        let payload = PushPayload::silent_wake().data("event_id", "abcdef");
+
+        assert!(dispatcher.dispatch(&token, &payload).await);
+        let delivered = last_payload.lock().unwrap().take().unwrap();
+        assert_eq!(delivered.data.keys().collect::<Vec<_>>(), vec!["sealed"]);
+
+        // The app opens it with the key it put in its envelope
+        let blob = base64::Engine::decode(&base64::engine::general_purpose::STANDARD, &delivered.data["sealed"]).unwrap();
+        let opened = crate::crypto::open_push_data(&push_key, &blob).unwrap();
+        assert_eq!(serde_json::from_slice::<std::collections::BTreeMap<String, String>>(&opened).unwrap(), payload.data);
+
+        // Registrations without a key are unchanged
+        token.push_key = None;
+        assert!(dispatcher.dispatch(&token, &payload).await);
+        assert_eq!(last_payload.lock().unwrap().take().unwrap(), payload);
+    }
+
+    #[tokio::test]
+    async fn test_over_quota_pushes_are_delayed_then_drained() {
+        let (mock, sent) = MockPush::new();
+        let services: Vec<Box<dyn PushService>> = vec![Box::new(mock)];
+        let clock = Arc::new(MockClock::new());
+        let metrics = Arc::new(Metrics::new());
+        let dispatcher = Dispatcher::with_quotas(
+            Arc::new(RwLock::new(services)),
+            metrics.clone(),
+            vec![ProviderQuota::new("mock", 1)],
+            clock.clone(),
+        );
+        let token = RegisteredToken::new("device-token".to_string(), Platform::Ios);
+
+        for _ in 0..3 {
+            assert!(dispatcher.dispatch(&token, &PushPayload::silent_wake()).await);
+        }
+        assert_eq!(MockPush::sent(&sent), 1);
+        assert_eq!(Metrics::get(&metrics.pushes_delayed), 2);
+        assert_eq!(dispatcher.quota_status()[0].queued, 2);
+
+        assert_eq!(dispatcher.drain_delayed().await, Some(Duration::from_secs(60)));
+        assert_eq!(MockPush::sent(&sent), 1);
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(dispatcher.drain_delayed().await, Some(Duration::from_secs(60)));
+        assert_eq!(MockPush::sent(&sent), 2);
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(dispatcher.drain_delayed().await, None);
+        assert_eq!(MockPush::sent(&sent), 3);
+        assert_eq!(Metrics::get(&metrics.pushes_sent), 3);
+    }
+
+    #[tokio::test]
+    async fn test_stalled_service_does_not_starve_others() {
+        let (stalled, _) = MockPush::with_delay(Duration::from_secs(3600));
+        let stalled = stalled.serving("apns", Platform::Ios);
+        let (healthy, healthy_sent) = MockPush::with_delay(Duration::from_millis(10));
+        let healthy = healthy.serving("fcm", Platform::Android);
+        let services: Vec<Box<dyn PushService>> = vec![Box::new(stalled), Box::new(healthy)];
+        let scheduler = FairScheduler::for_services(4, &services);
+        let dispatcher = Arc::new(
+            Dispatcher::new(Arc::new(RwLock::new(services)), Arc::new(Metrics::new()))
+                .with_scheduler(scheduler),
+        );
+
+        // Flood the stalled service well past the total concurrency
+        let ios = RegisteredToken::new("ios-token".to_string(), Platform::Ios);
+        for _ in 0..20 {
+            let dispatcher = dispatcher.clone();
+            let ios = ios.clone();
+            tokio::spawn(async move {
+                dispatcher.dispatch(&ios, &PushPayload::silent_wake()).await;
+            });
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let android = RegisteredToken::new("android-token".to_string(), Platform::Android);
+        let started = Instant::now();
+        let mut handles = Vec::new();
+        for _ in 0..20 {
+            let dispatcher = dispatcher.clone();
+            let android = android.clone();
+            handles.push(tokio::spawn(async move {
+                dispatcher.dispatch(&android, &PushPayload::silent_wake()).await
+            }));
+        }
+        for handle in handles {
+            assert!(handle.await.unwrap());
+        }
+
+        // 20 sends of 10ms through at least one reserved slot: well under a second
+        assert_eq!(MockPush::sent(&healthy_sent), 20);
+        assert!(started.elapsed() < Duration::from_secs(1), "{:?}", started.elapsed());
+    }
+
+    #[tokio::test]
+    async fn test_platform_limits_isolate_platforms() {
+        let (stalled, _) = MockPush::with_delay(Duration::from_secs(3600));
+        let stalled = stalled.serving("apns", Platform::Ios);
+        let (healthy, healthy_sent) = MockPush::with_delay(Duration::from_millis(5));
+        let healthy = healthy.serving("fcm", Platform::Android);
+        let services: Vec<Box<dyn PushService>> = vec![Box::new(stalled), Box::new(healthy)];
+        let metrics = Arc::new(Metrics::new());
+        let dispatcher = Arc::new(
+            Dispatcher::new(Arc::new(RwLock::new(services)), metrics.clone())
+                .with_platform_limits(PlatformLimits::new(2, 2, 2)),
+        );
+
+        let ios = RegisteredToken::new("ios-token".to_string(), Platform::Ios);
+        for _ in 0..10 {
+            let dispatcher = dispatcher.clone();
+            let ios = ios.clone();
+            tokio::spawn(async move {
+                dispatcher.dispatch(&ios, &PushPayload::silent_wake()).await;
+            });
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        // Only the iOS limit is in flight; the rest wait on the iOS semaphore
+        assert_eq!(Metrics::get(&metrics.in_flight_ios), 2);
+
+        let android = RegisteredToken::new("android-token".to_string(), Platform::Android);
+        let mut handles = Vec::new();
+        for _ in 0..10 {
+            let dispatcher = dispatcher.clone();
+            let android = android.clone();
+            handles.push(tokio::spawn(async move {
+                dispatcher.dispatch(&android, &PushPayload::silent_wake()).await
+            }));
+        }
+        let all_sent = async {
+            for handle in handles {
+                assert!(handle.await.unwrap());
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(1), all_sent).await.unwrap();
+
+        assert_eq!(MockPush::sent(&healthy_sent), 10);
+        assert_eq!(Metrics::get(&metrics.in_flight_android), 0);
+        assert!(metrics.render().contains("mostro_push_dispatch_in_flight{platform=\"ios\"} 2\n"));
+    }
+}
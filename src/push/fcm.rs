@@ -1,23 +1,28 @@
 use async_trait::async_trait;
 use log::{info, error, debug, warn};
-use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::sync::Arc;
+use std::collections::HashMap;
 use tokio::sync::RwLock;
 use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use reqwest::{Request, RequestBuilder};
 use std::fs;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::config::Config;
+use crate::config::{AndroidNotificationStyle, Config};
 use crate::crypto::Platform;
-use super::PushService;
+use crate::store::PushEnvironment;
+use crate::utils::signing::SigningClient;
+use super::{PushPayload, PushPriority, PushService, PushType, TokenValidity};
+
+const FCM_ORIGIN: &str = "https://fcm.googleapis.com";
+/// Stands in for the OAuth access token in previews.
+const PREVIEW_ACCESS_TOKEN: &str = "<access-token>";
 
 #[derive(Debug, Deserialize)]
 struct ServiceAccount {
     client_email: String,
     private_key: String,
-    project_id: String,
 }
 
 #[derive(Debug, Serialize)]
@@ -40,21 +45,17 @@ struct CachedToken {
     expires_at: u64,
 }
 
-pub struct FcmPush {
-    client: Client,
-    service_account: Option<ServiceAccount>,
-    cached_token: Arc<RwLock<Option<CachedToken>>>,
+/// A Firebase project and the credentials to send through it.
+struct FcmSender {
     project_id: String,
+    service_account: Option<ServiceAccount>,
+    cached_token: RwLock<Option<CachedToken>>,
 }
 
-impl FcmPush {
-    pub fn new(config: Config) -> Self {
-        let service_account_path = std::env::var("FIREBASE_SERVICE_ACCOUNT_PATH").ok();
-        let project_id = std::env::var("FIREBASE_PROJECT_ID")
-            .unwrap_or_else(|_| "mostro".to_string());
-        
+impl FcmSender {
+    fn load(project_id: String, service_account_path: Option<&str>) -> Self {
         let service_account = service_account_path.and_then(|path| {
-            match fs::read_to_string(&path) {
+            match fs::read_to_string(path) {
                 Ok(content) => {
                     match serde_json::from_str::<ServiceAccount>(&content) {
                         Ok(sa) => {
@@ -75,27 +76,134 @@ impl FcmPush {
         });
 
         Self {
-            client: Client::new(),
-            service_account,
-            cached_token: Arc::new(RwLock::new(None)),
             project_id,
+            service_account,
+            cached_token: RwLock::new(None),
+        }
+    }
+
+    fn send_url(&self) -> String {
+        format!("{}/v1/projects/{}/messages:send", FCM_ORIGIN, self.project_id)
+    }
+}
+
+/// Where one app's pushes go, from `PUSH_APPS_PATH`.
+struct FcmApp {
+    apns_topic: Option<String>,
+    /// The default sender when unset
+    sender: Option<FcmSender>,
+    /// Already merged with the default style
+    android: AndroidNotificationStyle,
+}
+
+/// Where a registration's push goes and how it renders.
+struct Route<'a> {
+    sender: &'a FcmSender,
+    apns_topic: Option<&'a str>,
+    android: &'a AndroidNotificationStyle,
+}
+
+pub struct FcmPush {
+    client: SigningClient,
+    sender: FcmSender,
+    android: AndroidNotificationStyle,
+    /// Keyed by app id
+    apps: HashMap<String, FcmApp>,
+    /// Only registrations from this environment are sent when set
+    environment: Option<PushEnvironment>,
+}
+
+impl FcmPush {
+    pub fn new(config: Config) -> Self {
+        let project_id = std::env::var("FIREBASE_PROJECT_ID")
+            .unwrap_or_else(|_| "mostro".to_string());
+        let sender = FcmSender::load(project_id, config.push.firebase_service_account_path.as_deref());
+
+        let apps = config
+            .push
+            .apps
+            .iter()
+            .map(|(app_id, app)| {
+                let sender = app
+                    .fcm_project_id
+                    .clone()
+                    .map(|project_id| FcmSender::load(project_id, app.firebase_service_account_path.as_deref()));
+                let android = app.android.or(&config.push.android_notification);
+                (app_id.clone(), FcmApp { apns_topic: app.apns_topic.clone(), sender, android })
+            })
+            .collect();
+
+        Self {
+            client: SigningClient::new(config.push.signing_key.as_deref()),
+            sender,
+            android: config.push.android_notification.clone(),
+            apps,
+            environment: None,
         }
     }
 
+    /// The service for sandbox registrations, if a sandbox project is
+    /// configured. Apps keep their APNs topics but send through it.
+    pub fn sandbox(config: &Config) -> Option<Self> {
+        let project_id = config.push.sandbox_fcm_project_id.clone()?;
+        let apps = config
+            .push
+            .apps
+            .iter()
+            .map(|(app_id, app)| {
+                let android = app.android.or(&config.push.android_notification);
+                (app_id.clone(), FcmApp { apns_topic: app.apns_topic.clone(), sender: None, android })
+            })
+            .collect();
+        Some(Self {
+            client: SigningClient::new(config.push.signing_key.as_deref()),
+            sender: FcmSender::load(project_id, config.push.sandbox_service_account_path.as_deref()),
+            android: config.push.android_notification.clone(),
+            apps,
+            environment: Some(PushEnvironment::Sandbox),
+        })
+    }
+
+    pub fn with_environment(mut self, environment: PushEnvironment) -> Self {
+        self.environment = Some(environment);
+        self
+    }
+
     /// Initialize FCM service - validates that we can get an access token
     pub async fn init(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        if self.service_account.is_none() {
+        if self.sender.service_account.is_none() {
             return Err("No service account configured".into());
         }
         // Try to get an access token to validate credentials
-        self.get_access_token().await?;
+        self.get_access_token(&self.sender).await?;
         Ok(())
     }
 
-    async fn get_access_token(&self) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    /// The sender, APNs topic and Android style for a registration's app.
+    /// Registrations without an app, or for one no longer configured, use
+    /// the defaults.
+    fn route(&self, app_id: Option<&str>) -> Route<'_> {
+        let default = Route { sender: &self.sender, apns_topic: None, android: &self.android };
+        let Some(app_id) = app_id else {
+            return default;
+        };
+        match self.apps.get(app_id) {
+            Some(app) => Route {
+                sender: app.sender.as_ref().unwrap_or(&self.sender),
+                apns_topic: app.apns_topic.as_deref(),
+                android: &app.android,
+            },
+            None => {
+                warn!("No push configuration for app {}, sending with the defaults", app_id);
+                default
+            }
+        }
+    }
+
+    async fn get_access_token(&self, sender: &FcmSender) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
         // Check cached token
         {
-            let cache = self.cached_token.read().await;
+            let cache = sender.cached_token.read().await;
             if let Some(ref cached) = *cache {
                 let now = SystemTime::now()
                     .duration_since(UNIX_EPOCH)?
@@ -108,7 +216,7 @@ impl FcmPush {
         }
 
         // Need to refresh token
-        let sa = self.service_account.as_ref()
+        let sa = sender.service_account.as_ref()
             .ok_or("No service account configured")?;
 
         let now = SystemTime::now()
@@ -146,7 +254,7 @@ impl FcmPush {
         
         // Cache the token
         {
-            let mut cache = self.cached_token.write().await;
+            let mut cache = sender.cached_token.write().await;
             *cache = Some(CachedToken {
                 token: token_response.access_token.clone(),
                 expires_at: now + token_response.expires_in,
@@ -157,44 +265,147 @@ impl FcmPush {
         Ok(token_response.access_token)
     }
 
-    fn build_silent_payload_for_token(device_token: &str) -> serde_json::Value {
-        json!({
-            "message": {
-                "token": device_token,
-                "data": {
-                    "type": "silent_wake",
-                    "source": "mostro-push-server",
-                    "timestamp": chrono::Utc::now().timestamp().to_string()
-                },
-                "android": {
-                    "priority": "high"
-                },
-                "apns": {
-                    "headers": {
-                        "apns-priority": "10",
-                        "apns-push-type": "background"
-                    },
-                    "payload": {
-                        "aps": {
-                            "content-available": 1
-                        }
-                    }
+    /// The `messages:send` request for one device along `route`.
+    fn request(
+        client: &SigningClient,
+        route: &Route<'_>,
+        device_token: &str,
+        payload: &PushPayload,
+        auth_token: &str,
+    ) -> RequestBuilder {
+        let message = Self::build_message(device_token, payload, route.apns_topic, route.android);
+        client.post(&route.sender.send_url()).bearer_auth(auth_token).json(&message)
+    }
+
+    /// Translate a `PushPayload` into an FCM v1 `messages:send` body,
+    /// including the APNs overrides FCM forwards to iOS devices.
+    /// `apns_topic` selects the iOS app; FCM's default applies when unset.
+    /// `android` styles visible notifications only.
+    pub fn build_message(
+        device_token: &str,
+        payload: &PushPayload,
+        apns_topic: Option<&str>,
+        android_style: &AndroidNotificationStyle,
+    ) -> serde_json::Value {
+        let mut message = json!({
+            "token": device_token,
+            "data": payload.data,
+        });
+
+        if payload.title.is_some() || payload.body.is_some() {
+            message["notification"] = json!({
+                "title": payload.title,
+                "body": payload.body,
+            });
+        }
+
+        let mut android = json!({
+            "priority": match payload.priority {
+                PushPriority::High => "high",
+                PushPriority::Normal => "normal",
+            },
+        });
+        if let Some(ttl) = payload.ttl_secs {
+            android["ttl"] = json!(format!("{}s", ttl));
+        }
+        if let Some(collapse_key) = &payload.collapse_key {
+            android["collapse_key"] = json!(collapse_key);
+        }
+        if let Some(sound) = &payload.sound {
+            android["notification"] = json!({ "sound": sound });
+        }
+        if let Some(thread_id) = &payload.thread_id {
+            // A tag only applies to notifications FCM displays; apps showing
+            // data messages themselves read it from the data
+            if message.get("notification").is_some() {
+                android["notification"]["tag"] = json!(thread_id);
+            } else {
+                message["data"]["thread_id"] = json!(thread_id);
+            }
+        }
+        if message.get("notification").is_some() {
+            let style = [
+                ("icon", &android_style.icon),
+                ("color", &android_style.color),
+                ("click_action", &android_style.click_action),
+            ];
+            for (field, value) in style {
+                if let Some(value) = value {
+                    android["notification"][field] = json!(value);
                 }
             }
+        }
+        message["android"] = android;
+
+        message["apns"] = Self::build_apns(payload, apns_topic);
+
+        json!({ "message": message })
+    }
+
+    fn build_apns(payload: &PushPayload, apns_topic: Option<&str>) -> serde_json::Value {
+        let mut headers = json!({
+            "apns-priority": match payload.priority {
+                PushPriority::High => "10",
+                PushPriority::Normal => "5",
+            },
+            "apns-push-type": match payload.push_type {
+                PushType::Background => "background",
+                PushType::Alert => "alert",
+            },
+        });
+        if let Some(ttl) = payload.ttl_secs {
+            let expiration = chrono::Utc::now().timestamp() + ttl as i64;
+            headers["apns-expiration"] = json!(expiration.to_string());
+        }
+        if let Some(collapse_key) = &payload.collapse_key {
+            headers["apns-collapse-id"] = json!(collapse_key);
+        }
+        if let Some(topic) = apns_topic {
+            headers["apns-topic"] = json!(topic);
+        }
+
+        let mut aps = json!({});
+        if payload.push_type == PushType::Background {
+            aps["content-available"] = json!(1);
+        }
+        if payload.title.is_some() || payload.body.is_some() {
+            aps["alert"] = json!({
+                "title": payload.title,
+                "body": payload.body,
+            });
+        }
+        if let Some(sound) = &payload.sound {
+            aps["sound"] = json!(sound);
+        }
+        if let Some(badge) = payload.badge {
+            aps["badge"] = json!(badge);
+        }
+        if let Some(thread_id) = &payload.thread_id {
+            aps["thread-id"] = json!(thread_id);
+        }
+
+        json!({
+            "headers": headers,
+            "payload": { "aps": aps },
         })
     }
 }
 
+/// Whether an FCM error means the token will never be deliverable again:
+/// `UNREGISTERED` (404) or a malformed registration token.
+fn is_unregistered(status: reqwest::StatusCode, body: &str) -> bool {
+    status == reqwest::StatusCode::NOT_FOUND
+        || body.contains("UNREGISTERED")
+        || (status == reqwest::StatusCode::BAD_REQUEST && body.contains("registration token"))
+}
+
 #[async_trait]
 impl PushService for FcmPush {
     async fn send_silent_push(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let token = self.get_access_token().await
+        let token = self.get_access_token(&self.sender).await
             .map_err(|e| -> Box<dyn std::error::Error> { e.to_string().into() })?;
 
-        let fcm_url = format!(
-            "https://fcm.googleapis.com/v1/projects/{}/messages:send",
-            self.project_id
-        );
+        let fcm_url = self.sender.send_url();
 
         let payload = json!({
             "message": {
@@ -220,10 +431,7 @@ impl PushService for FcmPush {
         });
 
         let response = self.client
-            .post(&fcm_url)
-            .bearer_auth(&token)
-            .json(&payload)
-            .send()
+            .send(self.client.post(&fcm_url).bearer_auth(&token).json(&payload))
             .await?;
 
         if response.status().is_success() {
@@ -235,28 +443,21 @@ impl PushService for FcmPush {
         }
     }
 
-    async fn send_to_token(
+    async fn send_notification(
         &self,
         device_token: &str,
         platform: &Platform,
+        app_id: Option<&str>,
+        payload: &PushPayload,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let auth_token = self.get_access_token().await
+        let route = self.route(app_id);
+        let auth_token = self.get_access_token(route.sender).await
             .map_err(|e| -> Box<dyn std::error::Error> { e.to_string().into() })?;
 
-        let fcm_url = format!(
-            "https://fcm.googleapis.com/v1/projects/{}/messages:send",
-            self.project_id
-        );
-
-        let payload = Self::build_silent_payload_for_token(device_token);
-
-        debug!("Sending FCM to token: {}...", &device_token[..20.min(device_token.len())]);
+        debug!("Sending FCM message");
 
         let response = self.client
-            .post(&fcm_url)
-            .bearer_auth(&auth_token)
-            .json(&payload)
-            .send()
+            .send(Self::request(&self.client, &route, device_token, payload, &auth_token))
             .await?;
 
         if response.status().is_success() {
@@ -272,4 +473,208 @@ impl PushService for FcmPush {
     fn supports_platform(&self, platform: &Platform) -> bool {
         matches!(platform, Platform::Android | Platform::Ios)
     }
+
+    fn preview(
+        &self,
+        device_token: &str,
+        _platform: &Platform,
+        app_id: Option<&str>,
+        payload: &PushPayload,
+    ) -> Option<reqwest::Result<Request>> {
+        let route = self.route(app_id);
+        // No credentials are minted for a preview
+        let request = Self::request(&self.client, &route, device_token, payload, PREVIEW_ACCESS_TOKEN);
+        Some(self.client.prepare(request))
+    }
+
+    fn serves_environment(&self, environment: PushEnvironment) -> bool {
+        self.environment.is_none_or(|e| e == environment)
+    }
+
+    fn provider(&self) -> &'static str {
+        "fcm"
+    }
+
+    async fn warm_up(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.get_access_token(&self.sender).await?;
+        for app in self.apps.values() {
+            if let Some(sender) = &app.sender {
+                self.get_access_token(sender).await?;
+            }
+        }
+        // Any response means the TLS connection is pooled for the first send
+        self.client.head(FCM_ORIGIN).send().await?;
+        Ok(())
+    }
+
+    async fn validate_token(&self, device_token: &str, platform: &Platform, app_id: Option<&str>) -> TokenValidity {
+        let route = self.route(app_id);
+        let auth_token = match self.get_access_token(route.sender).await {
+            Ok(token) => token,
+            Err(e) => {
+                warn!("Skipping FCM token validation: {}", e);
+                return TokenValidity::Unknown;
+            }
+        };
+
+        // `validate_only` checks the message and token without delivering it
+        let mut message = Self::build_message(device_token, &PushPayload::silent_wake(), route.apns_topic, route.android);
+        message["validate_only"] = json!(true);
+        let response = match self
+            .client
+            .send(self.client.post(&route.sender.send_url()).bearer_auth(&auth_token).json(&message))
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("FCM token validation failed for {} device: {}", platform, e);
+                return TokenValidity::Unknown;
+            }
+        };
+
+        let status = response.status();
+        if status.is_success() {
+            return TokenValidity::Valid;
+        }
+        let body = response.text().await.unwrap_or_default();
+        if is_unregistered(status, &body) {
+            TokenValidity::Invalid
+        } else {
+            debug!("FCM couldn't validate {} token ({}): {}", platform, status, body);
+            TokenValidity::Unknown
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_payload() -> PushPayload {
+        PushPayload::new(PushType::Alert)
+            .title("Order update")
+            .body("Your trade has a new message")
+            .data("type", "trade_update")
+            .priority(PushPriority::Normal)
+            .ttl_secs(600)
+            .collapse_key("trade-abc")
+            .sound("default")
+            .badge(2)
+            .thread_id("order-1")
+    }
+
+    #[test]
+    fn test_build_message_fcm_fields() {
+        let message = FcmPush::build_message("device-token", &sample_payload(), None, &AndroidNotificationStyle::default());
+        let message = &message["message"];
+
+        assert_eq!(message["token"], "device-token");
+        assert_eq!(message["data"]["type"], "trade_update");
+        assert_eq!(message["notification"]["title"], "Order update");
+        assert_eq!(message["android"]["priority"], "normal");
+        assert_eq!(message["android"]["ttl"], "600s");
+        assert_eq!(message["android"]["collapse_key"], "trade-abc");
+        assert_eq!(message["android"]["notification"]["sound"], "default");
+        assert_eq!(message["android"]["notification"]["tag"], "order-1");
+    }
+
+    #[test]
+    fn test_build_message_apns_fields() {
+        let message = FcmPush::build_message("device-token", &sample_payload(), None, &AndroidNotificationStyle::default());
+        let apns = &message["message"]["apns"];
+
+        assert_eq!(apns["headers"]["apns-priority"], "5");
+        assert_eq!(apns["headers"]["apns-push-type"], "alert");
+        assert_eq!(apns["headers"]["apns-collapse-id"], "trade-abc");
+        assert!(apns["headers"]["apns-expiration"].is_string());
+        assert_eq!(apns["payload"]["aps"]["alert"]["body"], "Your trade has a new message");
+        assert_eq!(apns["payload"]["aps"]["sound"], "default");
+        assert_eq!(apns["payload"]["aps"]["badge"], 2);
+        assert_eq!(apns["payload"]["aps"]["thread-id"], "order-1");
+        assert!(apns["payload"]["aps"].get("content-available").is_none());
+    }
+
+    #[test]
+    fn test_apps_select_topic_and_sender() {
+        let mut config = Config::for_tests();
+        config.push.apps = crate::config::parse_push_apps(
+            r#"{
+                "com.mostro.app": { "apns_topic": "com.mostro.app" },
+                "com.example.whitelabel": {
+                    "apns_topic": "com.example.whitelabel",
+                    "fcm_project_id": "whitelabel",
+                    "firebase_service_account_path": "/nonexistent/whitelabel.json"
+                }
+            }"#,
+        )
+        .unwrap();
+        let fcm = FcmPush::new(config);
+        let default_project = fcm.sender.project_id.clone();
+
+        let route = fcm.route(Some("com.example.whitelabel"));
+        assert_eq!((route.sender.project_id.as_str(), route.apns_topic), ("whitelabel", Some("com.example.whitelabel")));
+        let route = fcm.route(Some("com.mostro.app"));
+        assert_eq!((route.sender.project_id.as_str(), route.apns_topic), (default_project.as_str(), Some("com.mostro.app")));
+        // No app, or one dropped from the config since registering: the defaults
+        for app_id in [None, Some("com.example.removed")] {
+            let route = fcm.route(app_id);
+            assert_eq!((route.sender.project_id.as_str(), route.apns_topic), (default_project.as_str(), None));
+        }
+
+        let message = FcmPush::build_message("device-token", &sample_payload(), Some("com.mostro.app"), &AndroidNotificationStyle::default());
+        assert_eq!(message["message"]["apns"]["headers"]["apns-topic"], "com.mostro.app");
+        let message = FcmPush::build_message("device-token", &sample_payload(), None, &AndroidNotificationStyle::default());
+        assert!(message["message"]["apns"]["headers"].get("apns-topic").is_none());
+    }
+
+    #[test]
+    fn test_silent_wake_is_background() {
+        let message = FcmPush::build_message("device-token", &PushPayload::silent_wake(), None, &AndroidNotificationStyle::default());
+        let message = &message["message"];
+
+        assert!(message.get("notification").is_none());
+        assert_eq!(message["data"]["type"], "silent_wake");
+        assert_eq!(message["android"]["priority"], "high");
+        assert_eq!(message["apns"]["headers"]["apns-push-type"], "background");
+        assert_eq!(message["apns"]["payload"]["aps"]["content-available"], 1);
+    }
+
+    #[test]
+    fn test_android_style_applies_to_visible_notifications() {
+        let mut config = Config::for_tests();
+        config.push.android_notification = AndroidNotificationStyle {
+            icon: Some("ic_stat_mostro".to_string()),
+            color: Some("#7b1fa2".to_string()),
+            click_action: None,
+        };
+        config.push.apps = crate::config::parse_push_apps(
+            r##"{ "com.mostro.app": { "android": { "color": "#00ff00", "click_action": "OPEN_TRADE" } } }"##,
+        )
+        .unwrap();
+        let fcm = FcmPush::new(config);
+        let body = |app_id: Option<&str>, payload: &PushPayload| -> serde_json::Value {
+            let request = fcm.preview("device-token", &Platform::Android, app_id, payload).unwrap().unwrap();
+            serde_json::from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap()
+        };
+
+        // The app's own fields win, the rest come from the defaults
+        let message = body(Some("com.mostro.app"), &sample_payload());
+        let notification = &message["message"]["android"]["notification"];
+        assert_eq!(notification["icon"], "ic_stat_mostro");
+        assert_eq!(notification["color"], "#00ff00");
+        assert_eq!(notification["click_action"], "OPEN_TRADE");
+        let message = body(None, &sample_payload());
+        assert_eq!(message["message"]["android"]["notification"]["color"], "#7b1fa2");
+        assert!(message["message"]["android"]["notification"].get("click_action").is_none());
+
+        // Wake-ups display nothing, so carry no style
+        let message = body(Some("com.mostro.app"), &PushPayload::silent_wake());
+        assert!(message["message"]["android"].get("notification").is_none());
+        // And unconfigured servers send none
+        let message = FcmPush::build_message("device-token", &sample_payload(), None, &AndroidNotificationStyle::default());
+        assert!(message["message"]["android"]["notification"].get("icon").is_none());
+
+        let invalid = crate::config::parse_push_apps(r#"{ "com.mostro.app": { "android": { "color": "purple" } } }"#);
+        assert!(invalid.unwrap_err().contains("#rrggbb"));
+    }
 }
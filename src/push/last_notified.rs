@@ -0,0 +1,66 @@
+//! When each pubkey was last pushed to successfully, so clients can check
+//! whether notifications reach them. Kept in memory only and bounded: past
+//! the capacity, the pubkeys notified longest ago are forgotten first.
+
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// Pubkeys remembered at once.
+pub const DEFAULT_CAPACITY: usize = 100_000;
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<String, DateTime<Utc>>,
+    /// Record order; slots whose time no longer matches the map are stale
+    order: VecDeque<(String, DateTime<Utc>)>,
+}
+
+pub struct LastNotified {
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+impl LastNotified {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// Record a successful push to `trade_pubkey`.
+    pub fn record(&self, trade_pubkey: &str, at: DateTime<Utc>) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.map.insert(trade_pubkey.to_string(), at);
+        entries.order.push_back((trade_pubkey.to_string(), at));
+        while entries.map.len() > self.capacity {
+            let Some((pubkey, recorded)) = entries.order.pop_front() else {
+                break;
+            };
+            if entries.map.get(&pubkey) == Some(&recorded) {
+                entries.map.remove(&pubkey);
+            }
+        }
+        // Re-notified pubkeys leave stale slots behind; drop them in bulk
+        if entries.order.len() > 2 * self.capacity.max(1) {
+            let Entries { map, order } = &mut *entries;
+            order.retain(|(pubkey, at)| map.get(pubkey) == Some(at));
+        }
+    }
+
+    pub fn get(&self, trade_pubkey: &str) -> Option<DateTime<Utc>> {
+        self.entries.lock().unwrap().map.get(trade_pubkey).copied()
+    }
+
+    /// Forget `trade_pubkey`, e.g. once it unregisters.
+    pub fn forget(&self, trade_pubkey: &str) {
+        self.entries.lock().unwrap().map.remove(trade_pubkey);
+    }
+}
+
+impl Default for LastNotified {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
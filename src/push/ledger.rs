@@ -0,0 +1,388 @@
+//! Delivery-intent ledger: a push for an event reaches a device at most once,
+//! whichever path sends it. The listener, relay catch-up and registration
+//! backfill all claim `(event id, device token)` before sending; a claim held
+//! by another path or an already delivered intent makes them skip the send.
+//!
+//! Only deliveries are terminal. A failed send releases its claim so a later
+//! path may try again; a push waiting in a quota queue keeps its claim in
+//! flight. Intents are forgotten once older than the max age, and
+//! delivered ones are persisted so a restart doesn't resend them.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::fs;
+
+use crate::gc::{Compaction, Dataset, RetentionPolicy};
+use crate::scheduler::Task;
+
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(86_400);
+
+/// How often a persisted ledger is written to disk.
+const PERSIST_INTERVAL: Duration = Duration::from_secs(30);
+
+type IntentKey = [u8; 32];
+
+/// Approximate persisted size of one delivered intent: its hex key and
+/// timestamp as JSON.
+const ENTRY_BYTES: u64 = 128;
+
+/// A delivered intent, as persisted. The key is a hash, so neither event ids
+/// nor device tokens reach the disk.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    pub key: String,
+    pub delivered_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum IntentState {
+    InFlight,
+    Delivered,
+}
+
+#[derive(Default)]
+struct Intents {
+    map: HashMap<IntentKey, (IntentState, DateTime<Utc>)>,
+    /// Claim order; slots whose time no longer matches the map are stale
+    order: VecDeque<(IntentKey, DateTime<Utc>)>,
+}
+
+pub struct DeliveryLedger {
+    max_age: chrono::Duration,
+    intents: Mutex<Intents>,
+}
+
+impl DeliveryLedger {
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            max_age: chrono::Duration::from_std(max_age).unwrap_or(chrono::Duration::MAX),
+            intents: Mutex::new(Intents::default()),
+        }
+    }
+
+    /// Claim the intent to push `event_id` to `device_token`. Returns false if
+    /// it was already delivered or another path is sending it.
+    pub fn claim(&self, event_id: &str, device_token: &str, now: DateTime<Utc>) -> bool {
+        let key = intent_key(event_id, device_token);
+        let mut intents = self.intents.lock().unwrap();
+        self.prune(&mut intents, now);
+        if intents.map.contains_key(&key) {
+            return false;
+        }
+        intents.map.insert(key, (IntentState::InFlight, now));
+        intents.order.push_back((key, now));
+        true
+    }
+
+    /// Record the outcome of a claimed intent: delivered intents are kept,
+    /// failed ones released for another path to retry.
+    pub fn finish(&self, event_id: &str, device_token: &str, delivered: bool, now: DateTime<Utc>) {
+        let key = intent_key(event_id, device_token);
+        let mut intents = self.intents.lock().unwrap();
+        if !delivered {
+            intents.map.remove(&key);
+            return;
+        }
+        intents.map.insert(key, (IntentState::Delivered, now));
+        intents.order.push_back((key, now));
+    }
+
+    pub fn is_delivered(&self, event_id: &str, device_token: &str) -> bool {
+        let key = intent_key(event_id, device_token);
+        let intents = self.intents.lock().unwrap();
+        intents.map.get(&key).is_some_and(|(state, _)| *state == IntentState::Delivered)
+    }
+
+    fn prune(&self, intents: &mut Intents, now: DateTime<Utc>) {
+        let cutoff = now - self.max_age;
+        while let Some((key, at)) = intents.order.front().copied() {
+            if at >= cutoff {
+                break;
+            }
+            intents.order.pop_front();
+            if intents.map.get(&key).is_some_and(|(_, recorded)| *recorded == at) {
+                intents.map.remove(&key);
+            }
+        }
+    }
+
+    /// Drop delivered intents outside `policy`: older than its age, or the
+    /// oldest beyond its size as persisted. Claims in flight are kept, since
+    /// their push may still be sending or waiting in a quota queue. Returns
+    /// how many intents went.
+    pub fn compact(&self, policy: &RetentionPolicy, now: DateTime<Utc>) -> u64 {
+        let mut intents = self.intents.lock().unwrap();
+        self.prune(&mut intents, now);
+
+        let mut delivered: Vec<(IntentKey, DateTime<Utc>)> = intents
+            .map
+            .iter()
+            .filter(|(_, (state, _))| *state == IntentState::Delivered)
+            .map(|(key, (_, at))| (*key, *at))
+            .collect();
+        delivered.sort_by_key(|(_, at)| *at);
+
+        let cutoff = policy.cutoff(now);
+        let expired = delivered.iter().take_while(|(_, at)| cutoff.is_some_and(|cutoff| *at < cutoff)).count();
+        let mut remove = expired;
+        if let Some(max_bytes) = policy.max_bytes {
+            let retained = (delivered.len() - expired) as u64;
+            let over = (retained * ENTRY_BYTES).saturating_sub(max_bytes).div_ceil(ENTRY_BYTES);
+            remove += over as usize;
+        }
+        for (key, _) in &delivered[..remove] {
+            intents.map.remove(key);
+        }
+        // Drop the order slots of removed and superseded intents too
+        let Intents { map, order } = &mut *intents;
+        order.retain(|(key, at)| map.get(key).is_some_and(|(_, recorded)| recorded == at));
+        remove as u64
+    }
+
+    pub fn len(&self) -> usize {
+        self.intents.lock().unwrap().map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Delivered intents to persist; in-flight claims die with the process.
+    pub fn entries(&self) -> Vec<LedgerEntry> {
+        let intents = self.intents.lock().unwrap();
+        intents
+            .map
+            .iter()
+            .filter(|(_, (state, _))| *state == IntentState::Delivered)
+            .map(|(key, (_, at))| LedgerEntry { key: hex::encode(key), delivered_at: *at })
+            .collect()
+    }
+
+    /// Add delivered intents persisted by a previous process, dropping any
+    /// past the max age.
+    pub fn restore(&self, entries: &[LedgerEntry], now: DateTime<Utc>) {
+        let mut restored: Vec<_> = entries
+            .iter()
+            .filter(|entry| entry.delivered_at >= now - self.max_age)
+            .filter_map(|entry| {
+                let key: IntentKey = hex::decode(&entry.key).ok()?.try_into().ok()?;
+                Some((key, entry.delivered_at))
+            })
+            .collect();
+        restored.sort_by_key(|(_, at)| *at);
+
+        let mut intents = self.intents.lock().unwrap();
+        for (key, at) in restored {
+            intents.map.insert(key, (IntentState::Delivered, at));
+            intents.order.push_back((key, at));
+        }
+        // Keep the order oldest first for pruning
+        intents.order.make_contiguous().sort_by_key(|(_, at)| *at);
+    }
+}
+
+fn intent_key(event_id: &str, device_token: &str) -> IntentKey {
+    let mut hasher = Sha256::new();
+    hasher.update(event_id.as_bytes());
+    hasher.update([0]);
+    hasher.update(device_token.as_bytes());
+    hasher.finalize().into()
+}
+
+/// Load delivered intents from `path`. A missing or unreadable file starts empty.
+pub async fn load(ledger: &DeliveryLedger, path: &Path) {
+    let content = match fs::read_to_string(path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+        Err(e) => {
+            warn!("Failed to read delivery ledger {}: {}", path.display(), e);
+            return;
+        }
+    };
+    match serde_json::from_str::<Vec<LedgerEntry>>(&content) {
+        Ok(entries) => {
+            ledger.restore(&entries, Utc::now());
+            info!("Restored {} delivered intent(s) from {}", ledger.len(), path.display());
+        }
+        Err(e) => warn!("Ignoring corrupt delivery ledger {}: {}", path.display(), e),
+    }
+}
+
+pub async fn save(ledger: &DeliveryLedger, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    let content = serde_json::to_string(&ledger.entries())?;
+
+    // Write to temporary file first, then rename for atomic write
+    let temp_path = path.with_extension("tmp");
+    fs::write(&temp_path, content).await?;
+    fs::rename(&temp_path, path).await?;
+
+    Ok(())
+}
+
+/// The ledger as a GC dataset, rewriting its file after each compaction.
+pub struct PersistedLedger {
+    pub ledger: Arc<DeliveryLedger>,
+    pub path: PathBuf,
+}
+
+#[async_trait]
+impl Dataset for PersistedLedger {
+    fn name(&self) -> &'static str {
+        "delivery_ledger"
+    }
+
+    async fn compact(&self, policy: &RetentionPolicy, now: DateTime<Utc>) -> Result<Compaction, String> {
+        let before = fs::metadata(&self.path).await.map_or(0, |meta| meta.len());
+        let removed = self.ledger.compact(policy, now);
+        save(&self.ledger, &self.path).await.map_err(|e| e.to_string())?;
+        let after = fs::metadata(&self.path).await.map_or(0, |meta| meta.len());
+        Ok(Compaction { removed, reclaimed_bytes: before.saturating_sub(after) })
+    }
+}
+
+pub fn persist_task(ledger: Arc<DeliveryLedger>, path: PathBuf) -> Task {
+    Task::every("delivery_ledger", PERSIST_INTERVAL, move || {
+        let (ledger, path) = (ledger.clone(), path.clone());
+        async move {
+            if let Err(e) = save(&ledger, &path).await {
+                warn!("Failed to write delivery ledger: {}", e);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Platform;
+    use crate::metrics::Metrics;
+    use crate::push::testing::MockPush;
+    use crate::push::quota::testing::MockClock;
+    use crate::push::{Dispatcher, ProviderQuota, PushPayload, PushService};
+    use crate::store::RegisteredToken;
+    use tokio::sync::RwLock;
+
+    fn ledgered(mock: MockPush) -> (Dispatcher, Arc<DeliveryLedger>, Arc<Metrics>) {
+        let services: Vec<Box<dyn PushService>> = vec![Box::new(mock)];
+        let metrics = Arc::new(Metrics::new());
+        let ledger = Arc::new(DeliveryLedger::new(DEFAULT_MAX_AGE));
+        let dispatcher = Dispatcher::new(Arc::new(RwLock::new(services)), metrics.clone()).with_ledger(ledger.clone());
+        (dispatcher, ledger, metrics)
+    }
+
+    #[tokio::test]
+    async fn test_racing_paths_push_an_event_once() {
+        let (mock, sent) = MockPush::with_delay(Duration::from_millis(50));
+        let (dispatcher, ledger, metrics) = ledgered(mock);
+        let token = RegisteredToken::new("fcm-token".to_string(), Platform::Android);
+        let live = PushPayload::silent_wake();
+        let backfill = PushPayload::silent_wake().data("backfill", "true");
+        let e1 = vec!["e1".to_string()];
+
+        // The live event and a catch-up push for it race; one of them sends
+        let (a, b) = tokio::join!(
+            dispatcher.dispatch_for_events(&e1, &token, &live, None),
+            dispatcher.dispatch_for_events(&e1, &token, &backfill, None),
+        );
+        assert_eq!([a, b].iter().filter(|r| **r == Some(true)).count(), 1);
+        assert_eq!([a, b].iter().filter(|r| r.is_none()).count(), 1);
+        assert_eq!(MockPush::sent(&sent), 1);
+        assert_eq!(Metrics::get(&metrics.pushes_deduplicated), 1);
+
+        // Later paths skip it too, but a push also covering a new event goes out
+        assert_eq!(dispatcher.dispatch_for_events(&e1, &token, &backfill, None).await, None);
+        let both = vec!["e1".to_string(), "e2".to_string()];
+        assert_eq!(dispatcher.dispatch_for_events(&both, &token, &backfill, None).await, Some(true));
+        assert!(ledger.is_delivered("e2", "fcm-token"));
+        assert_eq!(MockPush::sent(&sent), 2);
+
+        // The same event for another device is a separate intent
+        let other = RegisteredToken::new("other-token".to_string(), Platform::Android);
+        assert_eq!(dispatcher.dispatch_for_events(&e1, &other, &live, None).await, Some(true));
+
+        // A send cancelled mid-flight releases its claim, like a failure
+        let e3 = vec!["e3".to_string()];
+        let cancelled = dispatcher.dispatch_for_events(&e3, &token, &live, None);
+        assert!(tokio::time::timeout(Duration::from_millis(10), cancelled).await.is_err());
+        assert_eq!(dispatcher.dispatch_for_events(&e3, &token, &live, None).await, Some(true));
+
+        let (failing, _) = MockPush::new();
+        let (dispatcher, ledger, _) = ledgered(failing.failing());
+        assert_eq!(dispatcher.dispatch_for_events(&e1, &token, &live, None).await, Some(false));
+        assert!(!ledger.is_delivered("e1", "fcm-token"));
+        assert!(ledger.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_delivered_intents_survive_restart_until_max_age() {
+        let path = std::env::temp_dir().join(format!("mostro-push-ledger-{}.json", std::process::id()));
+        let ledger = DeliveryLedger::new(Duration::from_secs(3600));
+        let t0 = Utc::now();
+        assert!(ledger.claim("e1", "token", t0));
+        ledger.finish("e1", "token", true, t0);
+        assert!(ledger.claim("e2", "token", t0));
+        save(&ledger, &path).await.unwrap();
+        assert!(!std::fs::read_to_string(&path).unwrap().contains("token"));
+
+        // Only the delivered intent is persisted
+        let restarted = DeliveryLedger::new(Duration::from_secs(3600));
+        load(&restarted, &path).await;
+        std::fs::remove_file(&path).ok();
+        assert!(restarted.is_delivered("e1", "token"));
+        assert!(!restarted.claim("e1", "token", t0));
+        assert!(restarted.claim("e2", "token", t0));
+
+        // Past the max age both are forgotten and may be sent again
+        let later = t0 + chrono::Duration::seconds(3601);
+        assert!(restarted.claim("e1", "token", later));
+        assert_eq!(restarted.len(), 1);
+        let stale = DeliveryLedger::new(Duration::from_secs(3600));
+        stale.restore(&ledger.entries(), later);
+        assert!(stale.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_gc_keeps_claims_of_queued_pushes() {
+        let (mock, sent) = MockPush::new();
+        let services: Vec<Box<dyn PushService>> = vec![Box::new(mock)];
+        let ledger = Arc::new(DeliveryLedger::new(DEFAULT_MAX_AGE));
+        let dispatcher = Dispatcher::with_quotas(
+            Arc::new(RwLock::new(services)),
+            Arc::new(Metrics::new()),
+            vec![ProviderQuota::new("mock", 1)],
+            Arc::new(MockClock::new()),
+        )
+        .with_ledger(ledger.clone());
+        let token = RegisteredToken::new("device-token".to_string(), Platform::Android);
+        let (e1, e2) = (vec!["e1".to_string()], vec!["e2".to_string()]);
+
+        // e1 uses the quota; e2 waits in the queue with its claim held
+        assert_eq!(dispatcher.dispatch_for_events(&e1, &token, &PushPayload::silent_wake(), None).await, Some(true));
+        assert_eq!(dispatcher.dispatch_for_events(&e2, &token, &PushPayload::silent_wake(), None).await, Some(true));
+        assert_eq!(MockPush::sent(&sent), 1);
+        assert!(ledger.is_delivered("e1", "device-token"));
+        assert!(!ledger.is_delivered("e2", "device-token"));
+
+        // A policy keeping nothing drops the delivered intent but not the claim
+        let path = std::env::temp_dir().join(format!("mostro-push-ledger-gc-{}.json", std::process::id()));
+        let persisted = PersistedLedger { ledger: ledger.clone(), path: path.clone() };
+        let policy = RetentionPolicy { max_age: Some(Duration::ZERO), max_bytes: Some(0) };
+        let later = Utc::now() + chrono::Duration::seconds(1);
+        let compaction = persisted.compact(&policy, later).await.unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(compaction.removed, 1);
+        assert_eq!(ledger.len(), 1);
+        // Another path still can't send e2 while it's queued
+        assert_eq!(dispatcher.dispatch_for_events(&e2, &token, &PushPayload::silent_wake(), None).await, None);
+    }
+}
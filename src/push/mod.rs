@@ -1,25 +1,96 @@
 use async_trait::async_trait;
 use std::sync::Arc;
 
+pub mod backfill;
+pub mod budget;
+pub mod decorator;
+pub mod delivery_stats;
+pub mod dispatcher;
 pub mod fcm;
+pub mod last_notified;
+pub mod ledger;
+pub mod outbox;
+pub mod payload;
+pub mod platform_limits;
+pub mod quota;
+pub mod reaper;
+pub mod scheduler;
 pub mod unifiedpush;
 
+pub use backfill::BackfillTracker;
+pub use budget::PayloadBudget;
+pub use decorator::{PayloadDecorator, SandboxLimits, Sandboxed};
+pub use dispatcher::Dispatcher;
 pub use fcm::FcmPush;
+pub use last_notified::LastNotified;
+pub use ledger::DeliveryLedger;
+pub use outbox::Outbox;
+pub use payload::{PushPayload, PushPriority, PushType};
+pub use platform_limits::PlatformLimits;
+pub use quota::{Clock, ProviderQuota, SystemClock};
+pub use reaper::TokenReaper;
+pub use scheduler::FairScheduler;
 pub use unifiedpush::UnifiedPushService;
 
 use crate::crypto::Platform;
+use crate::store::PushEnvironment;
+
+/// What a provider said about a device token when asked without delivering.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenValidity {
+    Valid,
+    /// The provider no longer knows the token, e.g. the app was uninstalled
+    Invalid,
+    /// The provider can't validate tokens, or the check itself failed
+    Unknown,
+}
 
 #[async_trait]
 pub trait PushService: Send + Sync {
     async fn send_silent_push(&self) -> Result<(), Box<dyn std::error::Error>>;
     
-    async fn send_to_token(
+    /// `app_id` is the registration's app build, for providers that route by it.
+    async fn send_notification(
         &self,
         device_token: &str,
         platform: &Platform,
+        app_id: Option<&str>,
+        payload: &PushPayload,
     ) -> Result<(), Box<dyn std::error::Error>>;
     
     fn supports_platform(&self, platform: &Platform) -> bool;
+
+    /// Whether registrations from `environment` may be sent through this
+    /// service. Services credentialed for one environment only say so here.
+    fn serves_environment(&self, _environment: PushEnvironment) -> bool {
+        true
+    }
+
+    /// The request `send_notification` would make, built without sending it
+    /// or minting credentials. None when the service can't say.
+    fn preview(
+        &self,
+        _device_token: &str,
+        _platform: &Platform,
+        _app_id: Option<&str>,
+        _payload: &PushPayload,
+    ) -> Option<reqwest::Result<reqwest::Request>> {
+        None
+    }
+
+    /// Provider name used for quota accounting and metrics labels.
+    fn provider(&self) -> &'static str;
+
+    /// Open connections and mint credentials ahead of the first push.
+    async fn warm_up(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        Ok(())
+    }
+
+    /// Ask the provider whether `device_token` is still deliverable, without
+    /// showing anything on the device.
+    async fn validate_token(&self, _device_token: &str, _platform: &Platform, _app_id: Option<&str>) -> TokenValidity {
+        TokenValidity::Unknown
+    }
 }
 
 // Implement PushService for Arc<UnifiedPushService> to allow shared ownership
@@ -29,17 +100,45 @@ impl PushService for Arc<UnifiedPushService> {
         (**self).send_silent_push().await
     }
     
-    async fn send_to_token(
+    async fn send_notification(
         &self,
         device_token: &str,
         platform: &Platform,
+        app_id: Option<&str>,
+        payload: &PushPayload,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        (**self).send_to_token(device_token, platform).await
+        (**self).send_notification(device_token, platform, app_id, payload).await
     }
     
     fn supports_platform(&self, platform: &Platform) -> bool {
         (**self).supports_platform(platform)
     }
+
+    fn serves_environment(&self, environment: PushEnvironment) -> bool {
+        (**self).serves_environment(environment)
+    }
+
+    fn preview(
+        &self,
+        device_token: &str,
+        platform: &Platform,
+        app_id: Option<&str>,
+        payload: &PushPayload,
+    ) -> Option<reqwest::Result<reqwest::Request>> {
+        (**self).preview(device_token, platform, app_id, payload)
+    }
+
+    fn provider(&self) -> &'static str {
+        (**self).provider()
+    }
+
+    async fn warm_up(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        (**self).warm_up().await
+    }
+
+    async fn validate_token(&self, device_token: &str, platform: &Platform, app_id: Option<&str>) -> TokenValidity {
+        (**self).validate_token(device_token, platform, app_id).await
+    }
 }
 
 // Implement PushService for Arc<FcmPush> to allow shared ownership
@@ -49,15 +148,164 @@ impl PushService for Arc<FcmPush> {
         (**self).send_silent_push().await
     }
     
-    async fn send_to_token(
+    async fn send_notification(
         &self,
         device_token: &str,
         platform: &Platform,
+        app_id: Option<&str>,
+        payload: &PushPayload,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        (**self).send_to_token(device_token, platform).await
+        (**self).send_notification(device_token, platform, app_id, payload).await
     }
     
     fn supports_platform(&self, platform: &Platform) -> bool {
         (**self).supports_platform(platform)
     }
+
+    fn serves_environment(&self, environment: PushEnvironment) -> bool {
+        (**self).serves_environment(environment)
+    }
+
+    fn preview(
+        &self,
+        device_token: &str,
+        platform: &Platform,
+        app_id: Option<&str>,
+        payload: &PushPayload,
+    ) -> Option<reqwest::Result<reqwest::Request>> {
+        (**self).preview(device_token, platform, app_id, payload)
+    }
+
+    fn provider(&self) -> &'static str {
+        (**self).provider()
+    }
+
+    async fn warm_up(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        (**self).warm_up().await
+    }
+
+    async fn validate_token(&self, device_token: &str, platform: &Platform, app_id: Option<&str>) -> TokenValidity {
+        (**self).validate_token(device_token, platform, app_id).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    /// Push service that records sends instead of talking to a provider.
+    pub(crate) struct MockPush {
+        pub sent: Arc<AtomicUsize>,
+        /// Simulated provider latency before the send is recorded
+        pub delay: Option<Duration>,
+        pub provider: &'static str,
+        /// Only this platform is supported when set
+        pub platform: Option<Platform>,
+        /// Reject every send, as a provider outage would
+        pub fail: bool,
+        /// Payload of the most recent successful send
+        pub last_payload: Arc<Mutex<Option<PushPayload>>>,
+        /// Only this environment is served when set
+        pub environment: Option<PushEnvironment>,
+        /// Device tokens reported invalid when validated
+        pub invalid_tokens: Vec<String>,
+    }
+
+    impl MockPush {
+        pub(crate) fn new() -> (Self, Arc<AtomicUsize>) {
+            let sent = Arc::new(AtomicUsize::new(0));
+            let mock = Self {
+                sent: sent.clone(),
+                delay: None,
+                provider: "mock",
+                platform: None,
+                fail: false,
+                last_payload: Arc::new(Mutex::new(None)),
+                environment: None,
+                invalid_tokens: Vec::new(),
+            };
+            (mock, sent)
+        }
+
+        pub(crate) fn with_delay(delay: Duration) -> (Self, Arc<AtomicUsize>) {
+            let (mut mock, sent) = Self::new();
+            mock.delay = Some(delay);
+            (mock, sent)
+        }
+
+        /// Present as a distinct provider serving a single platform.
+        pub(crate) fn serving(mut self, provider: &'static str, platform: Platform) -> Self {
+            self.provider = provider;
+            self.platform = Some(platform);
+            self
+        }
+
+        pub(crate) fn for_environment(mut self, environment: PushEnvironment) -> Self {
+            self.environment = Some(environment);
+            self
+        }
+
+        pub(crate) fn failing(mut self) -> Self {
+            self.fail = true;
+            self
+        }
+
+        /// Report `device_token` invalid, as for an uninstalled app.
+        pub(crate) fn rejecting_token(mut self, device_token: &str) -> Self {
+            self.invalid_tokens.push(device_token.to_string());
+            self
+        }
+
+        pub(crate) fn sent(counter: &AtomicUsize) -> usize {
+            counter.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait]
+    impl PushService for MockPush {
+        async fn send_silent_push(&self) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+
+        async fn send_notification(
+            &self,
+            _device_token: &str,
+            _platform: &Platform,
+            _app_id: Option<&str>,
+            payload: &PushPayload,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            if let Some(delay) = self.delay {
+                tokio::time::sleep(delay).await;
+            }
+            if self.fail {
+                return Err("mock provider rejected the push".into());
+            }
+            *self.last_payload.lock().unwrap() = Some(payload.clone());
+            self.sent.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn supports_platform(&self, platform: &Platform) -> bool {
+            self.platform.as_ref().is_none_or(|p| p == platform)
+        }
+
+        fn serves_environment(&self, environment: PushEnvironment) -> bool {
+            self.environment.is_none_or(|e| e == environment)
+        }
+
+        fn provider(&self) -> &'static str {
+            self.provider
+        }
+
+        async fn validate_token(&self, device_token: &str, _platform: &Platform, _app_id: Option<&str>) -> TokenValidity {
+            if self.invalid_tokens.iter().any(|t| t == device_token) {
+                TokenValidity::Invalid
+            } else {
+                TokenValidity::Valid
+            }
+        }
+    }
 }
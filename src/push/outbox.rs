@@ -0,0 +1,134 @@
+//! Outbox of event pushes no provider accepted. When every registration of
+//! an event's recipient fails, the event is kept, so that after a restart
+//! during a provider outage it is pushed again to whatever the recipient has
+//! registered by then. Only the event is kept, never device tokens. The
+//! outbox is bounded by count, oldest dropped first, and by age.
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::fs;
+
+use crate::nostr::EventCategory;
+use crate::scheduler::Task;
+
+/// How often a persisted outbox is written to disk.
+const PERSIST_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct UndeliveredEvent {
+    pub event_id: String,
+    pub trade_pubkey: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<EventCategory>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grouping_id: Option<String>,
+    /// When the event reached us, for the age limits
+    pub received_at: DateTime<Utc>,
+}
+
+pub struct Outbox {
+    max_entries: usize,
+    ttl: chrono::Duration,
+    /// Oldest first
+    entries: Mutex<VecDeque<UndeliveredEvent>>,
+}
+
+impl Outbox {
+    pub fn new(max_entries: usize, ttl: Duration) -> Self {
+        Self {
+            max_entries,
+            ttl: chrono::Duration::from_std(ttl).unwrap_or(chrono::Duration::MAX),
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Keep `event` for redelivery, replacing an earlier failure of the same
+    /// event and dropping the oldest entry when full.
+    pub fn record(&self, event: UndeliveredEvent) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|entry| entry.event_id != event.event_id);
+        entries.push_back(event);
+        while entries.len() > self.max_entries {
+            entries.pop_front();
+        }
+    }
+
+    /// Forget `event_id`, once it was delivered or has nowhere to go.
+    pub fn remove(&self, event_id: &str) {
+        self.entries.lock().unwrap().retain(|entry| entry.event_id != event_id);
+    }
+
+    /// Entries still within the TTL, oldest first; expired ones are dropped.
+    pub fn pending(&self, now: DateTime<Utc>) -> Vec<UndeliveredEvent> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|entry| entry.received_at >= now - self.ttl);
+        entries.iter().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Add entries persisted by a previous process, within the bounds.
+    pub fn restore(&self, mut restored: Vec<UndeliveredEvent>, now: DateTime<Utc>) {
+        restored.sort_by_key(|entry| entry.received_at);
+        for entry in restored.into_iter().filter(|entry| entry.received_at >= now - self.ttl) {
+            self.record(entry);
+        }
+    }
+}
+
+/// Load undelivered events from `path`. A missing or unreadable file starts empty.
+pub async fn load(outbox: &Outbox, path: &Path) {
+    let content = match fs::read_to_string(path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+        Err(e) => {
+            warn!("Failed to read outbox {}: {}", path.display(), e);
+            return;
+        }
+    };
+    match serde_json::from_str::<Vec<UndeliveredEvent>>(&content) {
+        Ok(entries) => {
+            outbox.restore(entries, Utc::now());
+            info!("Restored {} undelivered event(s) from {}", outbox.len(), path.display());
+        }
+        Err(e) => warn!("Ignoring corrupt outbox {}: {}", path.display(), e),
+    }
+}
+
+pub async fn save(outbox: &Outbox, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    let entries: Vec<UndeliveredEvent> = outbox.entries.lock().unwrap().iter().cloned().collect();
+    let content = serde_json::to_string(&entries)?;
+
+    // Write to temporary file first, then rename for atomic write
+    let temp_path = path.with_extension("tmp");
+    fs::write(&temp_path, content).await?;
+    fs::rename(&temp_path, path).await?;
+
+    Ok(())
+}
+
+pub fn persist_task(outbox: Arc<Outbox>, path: PathBuf) -> Task {
+    Task::every("outbox", PERSIST_INTERVAL, move || {
+        let (outbox, path) = (outbox.clone(), path.clone());
+        async move {
+            if let Err(e) = save(&outbox, &path).await {
+                warn!("Failed to write outbox: {}", e);
+            }
+        }
+    })
+}
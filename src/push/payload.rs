@@ -0,0 +1,164 @@
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+use crate::crypto::{self, CryptoError, PUSH_KEY_SIZE};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushPriority {
+    High,
+    Normal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushType {
+    /// Data-only wake-up, nothing shown to the user
+    Background,
+    /// Visible notification
+    Alert,
+}
+
+/// Provider-neutral notification. Each `PushService` translates it to its wire format,
+/// so payload policy (priority, TTL, collapsing) is decided in one place.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PushPayload {
+    pub title: Option<String>,
+    pub body: Option<String>,
+    pub data: BTreeMap<String, String>,
+    pub priority: PushPriority,
+    /// Seconds the provider may hold the message for an offline device
+    pub ttl_secs: Option<u32>,
+    pub collapse_key: Option<String>,
+    /// Notifications sharing it are grouped on the device
+    pub thread_id: Option<String>,
+    pub push_type: PushType,
+    pub sound: Option<String>,
+    pub badge: Option<u32>,
+}
+
+/// Grouping id for a pubkey or tag value. Hashed, so the provider sees which
+/// pushes belong together but not the order or pubkey they're about.
+pub fn grouping_id(value: &str) -> String {
+    ::hex::encode(&Sha256::digest(value.as_bytes())[..8])
+}
+
+impl PushPayload {
+    pub fn new(push_type: PushType) -> Self {
+        Self {
+            title: None,
+            body: None,
+            data: BTreeMap::new(),
+            priority: PushPriority::High,
+            ttl_secs: None,
+            collapse_key: None,
+            thread_id: None,
+            push_type,
+            sound: None,
+            badge: None,
+        }
+    }
+
+    /// The silent wake-up sent for every matched Mostro event.
+    pub fn silent_wake() -> Self {
+        Self::new(PushType::Background)
+            .data("type", "silent_wake")
+            .data("source", "mostro-push-server")
+            .data("timestamp", chrono::Utc::now().timestamp().to_string())
+    }
+
+    /// Visible notification sent by `/admin/test-send`.
+    pub fn test_notification() -> Self {
+        Self::new(PushType::Alert)
+            .title("Mostro push test")
+            .body("Push delivery to this device works")
+            .data("type", "test")
+            .data("source", "mostro-push-server")
+    }
+
+    pub fn title(mut self, title: impl Into<String>) -> Self {
+        self.title = Some(title.into());
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<String>) -> Self {
+        self.body = Some(body.into());
+        self
+    }
+
+    pub fn data(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.data.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn priority(mut self, priority: PushPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn ttl_secs(mut self, ttl_secs: u32) -> Self {
+        self.ttl_secs = Some(ttl_secs);
+        self
+    }
+
+    pub fn collapse_key(mut self, collapse_key: impl Into<String>) -> Self {
+        self.collapse_key = Some(collapse_key.into());
+        self
+    }
+
+    pub fn thread_id(mut self, thread_id: impl Into<String>) -> Self {
+        self.thread_id = Some(thread_id.into());
+        self
+    }
+
+    pub fn sound(mut self, sound: impl Into<String>) -> Self {
+        self.sound = Some(sound.into());
+        self
+    }
+
+    pub fn badge(mut self, badge: u32) -> Self {
+        self.badge = Some(badge);
+        self
+    }
+
+    /// This payload with its data map replaced by a single `sealed` entry:
+    /// the map's JSON encrypted to the registration's push key (base64 of
+    /// nonce || ciphertext), so the provider only relays an opaque blob.
+    pub fn sealed(&self, push_key: &[u8; PUSH_KEY_SIZE]) -> Result<Self, CryptoError> {
+        let plaintext = serde_json::to_vec(&self.data).map_err(|_| CryptoError::CipherError)?;
+        let blob = crypto::seal_push_data(push_key, &plaintext)?;
+        let mut sealed = self.clone();
+        sealed.data = BTreeMap::from([(
+            "sealed".to_string(),
+            base64::engine::general_purpose::STANDARD.encode(blob),
+        )]);
+        Ok(sealed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What the app does on receipt of a sealed push.
+    fn client_open(push_key: &[u8; PUSH_KEY_SIZE], payload: &PushPayload) -> BTreeMap<String, String> {
+        let blob = base64::engine::general_purpose::STANDARD.decode(&payload.data["sealed"]).unwrap();
+        serde_json::from_slice(&crypto::open_push_data(push_key, &blob).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_sealed_payload_round_trips() {
+        let push_key = [0x42; PUSH_KEY_SIZE];
+        let payload = PushPayload::silent_wake().data("event_id", "abcdef").title("Trade update");
+
+        let sealed = payload.sealed(&push_key).unwrap();
+        assert_eq!(sealed.data.len(), 1);
+        assert!(!sealed.data["sealed"].contains("abcdef"));
+        assert_eq!(sealed.title, payload.title);
+        assert_eq!(client_open(&push_key, &sealed), payload.data);
+
+        // Sealing is randomized, and only the registration's key opens it
+        assert_ne!(payload.sealed(&push_key).unwrap().data, sealed.data);
+        let blob = base64::engine::general_purpose::STANDARD.decode(&sealed.data["sealed"]).unwrap();
+        assert!(crypto::open_push_data(&[0x43; PUSH_KEY_SIZE], &blob).is_err());
+    }
+}
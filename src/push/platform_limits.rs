@@ -0,0 +1,42 @@
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::crypto::Platform;
+
+/// Separate in-flight limits per platform, so a slow APNs can't hold every
+/// dispatch slot while FCM sends wait, and vice versa.
+pub struct PlatformLimits {
+    android: Semaphore,
+    ios: Semaphore,
+    web: Semaphore,
+}
+
+impl PlatformLimits {
+    pub fn new(android: usize, ios: usize, web: usize) -> Self {
+        Self {
+            android: Semaphore::new(android.max(1)),
+            ios: Semaphore::new(ios.max(1)),
+            web: Semaphore::new(web.max(1)),
+        }
+    }
+
+    /// Limits from per-platform settings, falling back to the global limit,
+    /// which web pushes always use. None when none bounds anything.
+    pub fn from_config(global: usize, android: Option<usize>, ios: Option<usize>) -> Option<Self> {
+        let android = android.unwrap_or(global);
+        let ios = ios.unwrap_or(global);
+        if android == 0 && ios == 0 && global == 0 {
+            return None;
+        }
+        let bound = |limit: usize| if limit == 0 { Semaphore::MAX_PERMITS } else { limit };
+        Some(Self::new(bound(android), bound(ios), bound(global)))
+    }
+
+    pub async fn acquire(&self, platform: &Platform) -> SemaphorePermit<'_> {
+        let semaphore = match platform {
+            Platform::Android => &self.android,
+            Platform::Ios => &self.ios,
+            Platform::Web => &self.web,
+        };
+        semaphore.acquire().await.expect("platform semaphores are never closed")
+    }
+}
@@ -0,0 +1,239 @@
+use log::warn;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::models::ProviderQuotaStatus;
+use crate::store::RegisteredToken;
+use super::PushPayload;
+
+/// Quotas are budgeted per sliding minute.
+const WINDOW: Duration = Duration::from_secs(60);
+/// Share of the budget at which a warning is logged.
+const WARN_RATIO: f64 = 0.8;
+/// Queued pushes kept per provider while over budget; the oldest are dropped beyond this.
+const MAX_QUEUED: usize = 10_000;
+
+/// Source of time for quota accounting, mockable in tests.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Sliding-window request budget for one push provider. While over budget,
+/// pushes are queued and drained as older requests leave the window.
+pub struct ProviderQuota {
+    provider: &'static str,
+    limit_per_minute: u32,
+    state: Mutex<QuotaState>,
+}
+
+struct QuotaState {
+    /// Send times within the current window, oldest first
+    sent: VecDeque<Instant>,
+    queued: VecDeque<(RegisteredToken, PushPayload)>,
+    warned: bool,
+}
+
+impl QuotaState {
+    fn prune(&mut self, now: Instant) {
+        while self.sent.front().is_some_and(|t| now.duration_since(*t) >= WINDOW) {
+            self.sent.pop_front();
+        }
+    }
+}
+
+impl ProviderQuota {
+    pub fn new(provider: &'static str, limit_per_minute: u32) -> Self {
+        Self {
+            provider,
+            limit_per_minute,
+            state: Mutex::new(QuotaState {
+                sent: VecDeque::new(),
+                queued: VecDeque::new(),
+                warned: false,
+            }),
+        }
+    }
+
+    pub fn provider(&self) -> &'static str {
+        self.provider
+    }
+
+    /// Consume one request from the budget if available. Fails while pushes
+    /// are queued so they keep their order.
+    pub fn try_acquire(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.prune(now);
+        if !state.queued.is_empty() {
+            return false;
+        }
+        self.acquire(&mut state, now)
+    }
+
+    fn acquire(&self, state: &mut QuotaState, now: Instant) -> bool {
+        let used = state.sent.len() as u32;
+        if used >= self.limit_per_minute {
+            return false;
+        }
+        state.sent.push_back(now);
+
+        let ratio = (used + 1) as f64 / self.limit_per_minute as f64;
+        if ratio >= WARN_RATIO && !state.warned {
+            warn!(
+                "{} quota at {:.0}% ({}/{} per minute)",
+                self.provider,
+                ratio * 100.0,
+                used + 1,
+                self.limit_per_minute
+            );
+            state.warned = true;
+        } else if ratio < WARN_RATIO {
+            state.warned = false;
+        }
+        true
+    }
+
+    pub fn enqueue(&self, token: RegisteredToken, payload: PushPayload) {
+        let mut state = self.state.lock().unwrap();
+        if state.queued.is_empty() {
+            warn!("{} quota exhausted, delaying pushes", self.provider);
+        }
+        if state.queued.len() >= MAX_QUEUED {
+            warn!("{} delay queue full, dropping oldest push", self.provider);
+            state.queued.pop_front();
+        }
+        state.queued.push_back((token, payload));
+    }
+
+    /// Dequeue as many delayed pushes as the budget allows right now.
+    pub fn take_ready(&self, now: Instant) -> Vec<(RegisteredToken, PushPayload)> {
+        let mut state = self.state.lock().unwrap();
+        state.prune(now);
+        let mut ready = Vec::new();
+        while !state.queued.is_empty() && self.acquire(&mut state, now) {
+            ready.extend(state.queued.pop_front());
+        }
+        ready
+    }
+
+    /// Time until the next queued push can be sent, or None if nothing is queued.
+    pub fn next_drain_in(&self, now: Instant) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        state.prune(now);
+        if state.queued.is_empty() {
+            return None;
+        }
+        if (state.sent.len() as u32) < self.limit_per_minute {
+            return Some(Duration::ZERO);
+        }
+        state.sent.front().map(|oldest| WINDOW.saturating_sub(now.duration_since(*oldest)))
+    }
+
+    pub fn status(&self, now: Instant) -> ProviderQuotaStatus {
+        let mut state = self.state.lock().unwrap();
+        state.prune(now);
+        ProviderQuotaStatus {
+            provider: self.provider.to_string(),
+            limit_per_minute: self.limit_per_minute,
+            used: state.sent.len() as u32,
+            queued: state.queued.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+
+    /// Clock that only moves when told to.
+    pub(crate) struct MockClock {
+        now: Mutex<Instant>,
+    }
+
+    impl MockClock {
+        pub(crate) fn new() -> Self {
+            Self { now: Mutex::new(Instant::now()) }
+        }
+
+        pub(crate) fn advance(&self, by: Duration) {
+            *self.now.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            *self.now.lock().unwrap()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::MockClock;
+    use super::*;
+    use crate::crypto::Platform;
+
+    fn token() -> RegisteredToken {
+        RegisteredToken::new("device-token".to_string(), Platform::Android)
+    }
+
+    #[test]
+    fn test_sliding_window_accounting() {
+        let clock = MockClock::new();
+        let quota = ProviderQuota::new("fcm", 3);
+
+        assert!(quota.try_acquire(clock.now()));
+        clock.advance(Duration::from_secs(20));
+        assert!(quota.try_acquire(clock.now()));
+        assert!(quota.try_acquire(clock.now()));
+        assert!(!quota.try_acquire(clock.now()));
+        assert_eq!(quota.status(clock.now()).used, 3);
+
+        // The first request leaves the window 60s after it was made
+        clock.advance(Duration::from_secs(39));
+        assert!(!quota.try_acquire(clock.now()));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(quota.status(clock.now()).used, 2);
+        assert!(quota.try_acquire(clock.now()));
+    }
+
+    #[test]
+    fn test_queued_pushes_drain_as_budget_frees() {
+        let clock = MockClock::new();
+        let quota = ProviderQuota::new("fcm", 2);
+
+        assert!(quota.try_acquire(clock.now()));
+        clock.advance(Duration::from_secs(10));
+        assert!(quota.try_acquire(clock.now()));
+        assert!(!quota.try_acquire(clock.now()));
+        quota.enqueue(token(), PushPayload::silent_wake());
+        quota.enqueue(token(), PushPayload::silent_wake());
+        quota.enqueue(token(), PushPayload::silent_wake());
+
+        // Nothing can go out until the first send ages out of the window
+        assert!(quota.take_ready(clock.now()).is_empty());
+        assert_eq!(quota.next_drain_in(clock.now()), Some(Duration::from_secs(50)));
+
+        clock.advance(Duration::from_secs(50));
+        assert_eq!(quota.take_ready(clock.now()).len(), 1);
+        assert_eq!(quota.next_drain_in(clock.now()), Some(Duration::from_secs(10)));
+
+        // New pushes wait behind the queue even once budget is available
+        clock.advance(Duration::from_secs(10));
+        assert!(!quota.try_acquire(clock.now()));
+        assert_eq!(quota.take_ready(clock.now()).len(), 1);
+        assert_eq!(quota.status(clock.now()).queued, 1);
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(quota.take_ready(clock.now()).len(), 1);
+        assert_eq!(quota.next_drain_in(clock.now()), None);
+    }
+}
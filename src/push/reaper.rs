@@ -0,0 +1,148 @@
+//! Periodic sweep for registrations whose device token the provider no
+//! longer accepts, e.g. after the app was uninstalled. Each run validates
+//! one batch of stored tokens, picking up where the previous run stopped,
+//! and unregisters the ones reported invalid. Checks are paced and spend
+//! the provider's push quota, so the sweep never crowds out real pushes.
+
+use log::info;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+use crate::metrics::Metrics;
+use crate::replication::Leadership;
+use crate::scheduler::Task;
+use crate::store::TokenStore;
+use super::{Dispatcher, TokenValidity};
+
+pub struct TokenReaper {
+    store: Arc<dyn TokenStore>,
+    dispatcher: Arc<Dispatcher>,
+    metrics: Arc<Metrics>,
+    /// Tokens validated per run
+    batch_size: usize,
+    /// Pause between checks; unpaced when zero
+    pace: Duration,
+    /// Only the leader reaps; always reaps when unset
+    leadership: Option<Arc<Leadership>>,
+    /// Last store key checked, so the next run continues after it
+    cursor: Mutex<Option<String>>,
+}
+
+impl TokenReaper {
+    /// Check up to `batch_size` tokens per run, at most `checks_per_sec`
+    /// of them a second (unpaced when 0).
+    pub fn new(
+        store: Arc<dyn TokenStore>,
+        dispatcher: Arc<Dispatcher>,
+        metrics: Arc<Metrics>,
+        batch_size: usize,
+        checks_per_sec: u32,
+    ) -> Self {
+        Self {
+            store,
+            dispatcher,
+            metrics,
+            batch_size,
+            pace: if checks_per_sec == 0 {
+                Duration::ZERO
+            } else {
+                Duration::from_secs(1) / checks_per_sec
+            },
+            leadership: None,
+            cursor: Mutex::new(None),
+        }
+    }
+
+    pub fn with_leadership(mut self, leadership: Arc<Leadership>) -> Self {
+        self.leadership = Some(leadership);
+        self
+    }
+
+    /// Validate the next batch and unregister the invalid tokens.
+    /// Returns how many registrations were removed.
+    pub async fn reap(&self) -> usize {
+        if self.leadership.as_ref().is_some_and(|l| !l.is_leader()) {
+            return 0;
+        }
+        let mut cursor = self.cursor.lock().await;
+        let mut registrations = self.store.export().await;
+        registrations.sort_by(|a, b| a.0.cmp(&b.0));
+        let start = cursor
+            .as_deref()
+            .map_or(0, |last| registrations.partition_point(|(key, _)| key.as_str() <= last));
+        // Past the end, start over from the first registration
+        let start = if start >= registrations.len() { 0 } else { start };
+
+        let mut reaped = 0;
+        for (i, (key, token)) in registrations.iter().skip(start).take(self.batch_size).enumerate() {
+            if i > 0 && !self.pace.is_zero() {
+                tokio::time::sleep(self.pace).await;
+            }
+            *cursor = Some(key.clone());
+            if self.dispatcher.validate_token(token).await != TokenValidity::Invalid {
+                continue;
+            }
+            // Leave the registration alone if it moved to another device meanwhile
+            match self.store.get(key).await {
+                Some(current) if current.device_token == token.device_token => {}
+                _ => continue,
+            }
+            if self.store.unregister(key).await.is_some() {
+                info!(
+                    "Unregistered {} token {} reported invalid by its provider",
+                    token.platform,
+                    self.dispatcher.token_label(&token.device_token)
+                );
+                Metrics::inc(&self.metrics.tokens_reaped);
+                reaped += 1;
+            }
+        }
+        reaped
+    }
+}
+
+pub fn reaper_task(reaper: Arc<TokenReaper>, interval: Duration) -> Task {
+    Task::every("token-reaper", interval, move || {
+        let reaper = reaper.clone();
+        async move {
+            let reaped = reaper.reap().await;
+            if reaped > 0 {
+                info!("Removed {} registration(s) with dead device tokens", reaped);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Platform;
+    use crate::push::testing::MockPush;
+    use crate::push::PushService;
+    use crate::store::MemoryTokenStore;
+    use tokio::sync::RwLock;
+
+    const LIVE_PUBKEY: &str = "a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2";
+    const DEAD_PUBKEY: &str = "b1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2";
+
+    #[tokio::test]
+    async fn test_reaper_removes_tokens_the_provider_rejects() {
+        let (mock, _) = MockPush::new();
+        let services: Vec<Box<dyn PushService>> = vec![Box::new(mock.rejecting_token("dead-token"))];
+        let metrics = Arc::new(Metrics::new());
+        let dispatcher = Arc::new(Dispatcher::new(Arc::new(RwLock::new(services)), metrics.clone()));
+        let store = Arc::new(MemoryTokenStore::new(48));
+        store.register(LIVE_PUBKEY.to_string(), "live-token".to_string(), Platform::Android).await;
+        store.register(DEAD_PUBKEY.to_string(), "dead-token".to_string(), Platform::Android).await;
+
+        let reaper = TokenReaper::new(store.clone(), dispatcher, metrics.clone(), 10, 0);
+        assert_eq!(reaper.reap().await, 1);
+        assert!(store.get(LIVE_PUBKEY).await.is_some());
+        assert_eq!(store.get(DEAD_PUBKEY).await, None);
+        assert_eq!(Metrics::get(&metrics.tokens_reaped), 1);
+
+        // Nothing left to remove on the next pass
+        assert_eq!(reaper.reap().await, 0);
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+use super::PushService;
+
+/// Shares dispatch concurrency between push services so a slow provider can't
+/// starve the others. Half the slots are reserved, split evenly between the
+/// services; the other half is a pool any service can borrow from while idle.
+pub struct FairScheduler {
+    total: usize,
+    min_share: HashMap<&'static str, usize>,
+    in_flight: Mutex<HashMap<&'static str, usize>>,
+    released: Notify,
+}
+
+impl FairScheduler {
+    /// Reserve at least one of the `total` slots for each provider.
+    pub fn new(total: usize, providers: &[&'static str]) -> Self {
+        let mut unique: Vec<&'static str> = providers.to_vec();
+        unique.sort_unstable();
+        unique.dedup();
+        let share = (total / (2 * unique.len().max(1))).max(1);
+
+        Self {
+            total: total.max(unique.len()),
+            min_share: unique.into_iter().map(|p| (p, share)).collect(),
+            in_flight: Mutex::new(HashMap::new()),
+            released: Notify::new(),
+        }
+    }
+
+    /// Scheduler for the configured push services.
+    pub fn for_services(total: usize, services: &[Box<dyn PushService>]) -> Self {
+        let providers: Vec<&'static str> = services.iter().map(|s| s.provider()).collect();
+        Self::new(total, &providers)
+    }
+
+    /// Wait for a send slot for the provider. The slot is freed when the permit drops.
+    pub async fn acquire(self: &Arc<Self>, provider: &'static str) -> SchedulerPermit {
+        loop {
+            // Register for wakeups before checking, so a release in between isn't missed
+            let released = self.released.notified();
+            if self.try_take(provider) {
+                return SchedulerPermit { scheduler: self.clone(), provider };
+            }
+            released.await;
+        }
+    }
+
+    fn try_take(&self, provider: &'static str) -> bool {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let busy: usize = in_flight.values().sum();
+        if busy >= self.total {
+            return false;
+        }
+
+        let own = in_flight.get(provider).copied().unwrap_or(0);
+        let within_share = own < self.min_share.get(provider).copied().unwrap_or(0);
+        // Slots other services are entitled to but not using stay reserved for them
+        let reserved: usize = self.min_share
+            .iter()
+            .filter(|(p, _)| **p != provider)
+            .map(|(p, share)| share.saturating_sub(in_flight.get(p).copied().unwrap_or(0)))
+            .sum();
+
+        if within_share || self.total - busy > reserved {
+            *in_flight.entry(provider).or_insert(0) += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Sends currently holding a slot, per provider.
+    pub fn in_flight(&self, provider: &str) -> usize {
+        self.in_flight.lock().unwrap().get(provider).copied().unwrap_or(0)
+    }
+}
+
+pub struct SchedulerPermit {
+    scheduler: Arc<FairScheduler>,
+    provider: &'static str,
+}
+
+impl Drop for SchedulerPermit {
+    fn drop(&mut self) {
+        if let Some(count) = self.scheduler.in_flight.lock().unwrap().get_mut(self.provider) {
+            *count = count.saturating_sub(1);
+        }
+        self.scheduler.released.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_idle_capacity_is_borrowed_but_share_is_reserved() {
+        let scheduler = Arc::new(FairScheduler::new(4, &["fcm", "unifiedpush"]));
+
+        // fcm borrows the shared pool, but not the slot reserved for unifiedpush
+        let mut permits = Vec::new();
+        for _ in 0..3 {
+            permits.push(scheduler.acquire("fcm").await);
+        }
+        assert!(!scheduler.try_take("fcm"));
+        assert!(scheduler.try_take("unifiedpush"));
+        assert!(!scheduler.try_take("unifiedpush"));
+
+        // Once fcm is idle, unifiedpush can use everything but fcm's reserve
+        permits.clear();
+        assert_eq!(scheduler.in_flight("fcm"), 0);
+        assert!(scheduler.try_take("unifiedpush"));
+        assert!(scheduler.try_take("unifiedpush"));
+        assert!(!scheduler.try_take("unifiedpush"));
+        assert_eq!(scheduler.in_flight("unifiedpush"), 3);
+    }
+}
@@ -1,6 +1,6 @@
 use async_trait::async_trait;
 use log::{info, error, debug, warn};
-use reqwest::Client;
+use reqwest::{Request, RequestBuilder};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::path::PathBuf;
@@ -9,7 +9,8 @@ use tokio::fs;
 
 use crate::config::Config;
 use crate::crypto::Platform;
-use super::PushService;
+use crate::utils::signing::SigningClient;
+use super::{PushPayload, PushService};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UnifiedPushEndpoint {
@@ -19,8 +20,7 @@ pub struct UnifiedPushEndpoint {
 }
 
 pub struct UnifiedPushService {
-    config: Config,
-    client: Client,
+    client: SigningClient,
     endpoints: RwLock<HashMap<String, UnifiedPushEndpoint>>,
     storage_path: PathBuf,
 }
@@ -30,8 +30,7 @@ impl UnifiedPushService {
         let storage_path = PathBuf::from("data/unifiedpush_endpoints.json");
 
         Self {
-            config,
-            client: Client::new(),
+            client: SigningClient::new(config.push.signing_key.as_deref()),
             endpoints: RwLock::new(HashMap::new()),
             storage_path,
         }
@@ -122,6 +121,28 @@ impl UnifiedPushService {
     }
 }
 
+impl UnifiedPushService {
+    /// UnifiedPush delivers an opaque body to the distributor, so the payload's
+    /// data map is sent as-is alongside any visible title/body.
+    fn build_body(payload: &PushPayload) -> serde_json::Value {
+        let mut body = serde_json::json!(payload.data);
+        if let Some(title) = &payload.title {
+            body["title"] = serde_json::json!(title);
+        }
+        if let Some(text) = &payload.body {
+            body["body"] = serde_json::json!(text);
+        }
+        if let Some(thread_id) = &payload.thread_id {
+            body["thread_id"] = serde_json::json!(thread_id);
+        }
+        body
+    }
+
+    fn request(&self, endpoint_url: &str, payload: &PushPayload) -> RequestBuilder {
+        self.client.post(endpoint_url).json(&Self::build_body(payload))
+    }
+}
+
 #[async_trait]
 impl PushService for UnifiedPushService {
     async fn send_silent_push(&self) -> Result<(), Box<dyn std::error::Error>> {
@@ -139,9 +160,7 @@ impl PushService for UnifiedPushService {
 
         for endpoint in endpoints.values() {
             match self.client
-                .post(&endpoint.endpoint_url)
-                .json(&payload)
-                .send()
+                .send(self.client.post(&endpoint.endpoint_url).json(&payload))
                 .await
             {
                 Ok(response) => {
@@ -162,23 +181,18 @@ impl PushService for UnifiedPushService {
         Ok(())
     }
 
-    async fn send_to_token(
+    async fn send_notification(
         &self,
         device_token: &str,
         _platform: &Platform,
+        _app_id: Option<&str>,
+        payload: &PushPayload,
     ) -> Result<(), Box<dyn std::error::Error>> {
         // For UnifiedPush, the device_token IS the endpoint URL
-        let payload = serde_json::json!({
-            "type": "silent_wake",
-            "timestamp": chrono::Utc::now().timestamp()
-        });
-
-        debug!("Sending UnifiedPush to endpoint: {}...", &device_token[..30.min(device_token.len())]);
+        debug!("Sending UnifiedPush message");
 
         let response = self.client
-            .post(device_token)
-            .json(&payload)
-            .send()
+            .send(self.request(device_token, payload))
             .await?;
 
         if response.status().is_success() {
@@ -196,4 +210,18 @@ impl PushService for UnifiedPushService {
         // UnifiedPush is primarily for Android (GrapheneOS, LineageOS, etc.)
         matches!(platform, Platform::Android)
     }
+
+    fn preview(
+        &self,
+        device_token: &str,
+        _platform: &Platform,
+        _app_id: Option<&str>,
+        payload: &PushPayload,
+    ) -> Option<reqwest::Result<Request>> {
+        Some(self.client.prepare(self.request(device_token, payload)))
+    }
+
+    fn provider(&self) -> &'static str {
+        "unifiedpush"
+    }
 }
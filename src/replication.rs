@@ -0,0 +1,312 @@
+//! Active/standby replication. The leader runs the Nostr listener and streams
+//! store changes to standbys, which serve reads and can be promoted.
+
+use log::{info, warn};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::{broadcast, watch};
+
+use crate::store::{StoreChange, TokenStore};
+
+/// Changes sent to a standby per request.
+const MAX_BATCH: usize = 500;
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Whether this instance is the leader, and where to find it if not.
+pub struct Leadership {
+    leader: watch::Sender<bool>,
+    /// Base URL of the primary, returned to clients that register with a standby
+    primary_url: Option<String>,
+}
+
+impl Leadership {
+    pub fn new(leader: bool, primary_url: Option<String>) -> Self {
+        Self {
+            leader: watch::channel(leader).0,
+            primary_url,
+        }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        *self.leader.borrow()
+    }
+
+    pub fn set_leader(&self, leader: bool) {
+        let previous = self.leader.send_replace(leader);
+        if previous != leader {
+            info!("{} leadership", if leader { "Acquired" } else { "Released" });
+        }
+    }
+
+    pub fn primary_url(&self) -> Option<&str> {
+        self.primary_url.as_deref()
+    }
+
+    pub async fn wait_until_leader(&self) {
+        let mut leader = self.leader.subscribe();
+        let _ = leader.wait_for(|leader| *leader).await;
+    }
+
+    /// Lead while `path` exists, checked every `interval`, so an external
+    /// coordinator can promote or demote the instance by managing one file.
+    pub fn watch_file(self: &Arc<Self>, path: PathBuf, interval: Duration) {
+        let leadership = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                leadership.set_leader(path.exists());
+            }
+        });
+    }
+}
+
+/// Body of `POST /replication/apply`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicationBatch {
+    /// Clear the standby's store before applying: the changes are a full snapshot
+    pub reset: bool,
+    pub changes: Vec<StoreChange>,
+}
+
+/// Stream store changes to each standby, starting with a snapshot. A standby
+/// that was unreachable or fell behind is resynced from a fresh snapshot.
+pub fn start_replication(store: Arc<dyn TokenStore>, standbys: Vec<String>, token: String) {
+    let client = Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("Failed to build HTTP client");
+    for standby in standbys {
+        info!("Replicating registrations to standby {}", standby);
+        tokio::spawn(replicate_to(store.clone(), client.clone(), standby, token.clone()));
+    }
+}
+
+async fn replicate_to(store: Arc<dyn TokenStore>, client: Client, standby: String, token: String) {
+    let url = format!("{}/replication/apply", standby.trim_end_matches('/'));
+    let mut synced: Option<broadcast::Receiver<StoreChange>> = None;
+
+    loop {
+        let mut changes = match synced.take() {
+            Some(changes) => changes,
+            None => {
+                // Subscribe first: changes racing the snapshot are applied twice, never lost
+                let changes = store.subscribe();
+                let batch = ReplicationBatch { reset: true, changes: store.snapshot().await };
+                if let Err(e) = send(&client, &url, &token, &batch).await {
+                    warn!("Failed to sync standby {}: {}", standby, e);
+                    tokio::time::sleep(RETRY_DELAY).await;
+                    continue;
+                }
+                info!("Synced {} entries to standby {}", batch.changes.len(), standby);
+                changes
+            }
+        };
+
+        let mut batch = match changes.recv().await {
+            Ok(change) => vec![change],
+            Err(RecvError::Lagged(missed)) => {
+                warn!("Standby {} fell {} changes behind, resyncing", standby, missed);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let mut lagged = false;
+        while batch.len() < MAX_BATCH {
+            match changes.try_recv() {
+                Ok(change) => batch.push(change),
+                Err(TryRecvError::Lagged(_)) => {
+                    lagged = true;
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+        if lagged {
+            warn!("Standby {} fell behind, resyncing", standby);
+            continue;
+        }
+
+        let batch = ReplicationBatch { reset: false, changes: batch };
+        match send(&client, &url, &token, &batch).await {
+            Ok(()) => synced = Some(changes),
+            Err(e) => {
+                warn!("Failed to replicate to standby {}, resyncing: {}", standby, e);
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+async fn send(
+    client: &Client,
+    url: &str,
+    token: &str,
+    batch: &ReplicationBatch,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let response = client.post(url).bearer_auth(token).json(batch).send().await?;
+    if !response.status().is_success() {
+        return Err(format!("standby returned {}", response.status()).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::routes::tests::test_state;
+    use crate::api::routes::AppState;
+    use crate::config::Config;
+    use crate::crypto::Platform;
+    use crate::health::{Readiness, RelayHealth};
+    use crate::metrics::Metrics;
+    use crate::nostr::NostrListener;
+    use crate::push::testing::MockPush;
+    use crate::push::{BackfillTracker, Dispatcher, PushService};
+    use actix_web::{test, web, App, HttpServer};
+    use nostr_sdk::prelude::*;
+    use std::sync::atomic::AtomicUsize;
+
+    struct Instance {
+        state: AppState,
+        listener: NostrListener,
+        sent: Arc<AtomicUsize>,
+    }
+
+    fn instance(leader: bool, primary_url: Option<String>) -> Instance {
+        let (mock, sent) = MockPush::new();
+        let services: Vec<Box<dyn PushService>> = vec![Box::new(mock)];
+        let metrics = Arc::new(Metrics::new());
+        let dispatcher = Arc::new(Dispatcher::new(
+            Arc::new(tokio::sync::RwLock::new(services)),
+            metrics.clone(),
+        ));
+        let leadership = Arc::new(Leadership::new(leader, primary_url));
+        let state = AppState {
+            dispatcher: dispatcher.clone(),
+            leadership: leadership.clone(),
+            replication_token: Some("replication-secret".to_string()),
+            ..test_state(Readiness::new(0))
+        };
+        state.readiness.mark_store_loaded();
+        let listener = NostrListener::new(
+            Config::for_tests(),
+            dispatcher,
+            Arc::new(BackfillTracker::new(120, true)),
+            state.token_store.clone(),
+            metrics,
+            Arc::new(Readiness::new(0)),
+            Arc::new(RelayHealth::new()),
+        )
+        .unwrap()
+        .with_leadership(leadership);
+        Instance { state, listener, sent }
+    }
+
+    fn serve(state: AppState) -> String {
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(crate::api::routes::configure)
+                .configure(crate::api::replication::configure)
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let addr = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+        format!("http://{}", addr)
+    }
+
+    async fn wait_for<F, Fut>(mut done: F)
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = bool>,
+    {
+        for _ in 0..100 {
+            if done().await {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("timed out waiting for replication");
+    }
+
+    fn gift_wrap_to(recipient: &str) -> Event {
+        EventBuilder::new(Kind::Custom(1059), "", [Tag::parse(vec!["p", recipient]).unwrap()])
+            .to_event(&Keys::generate())
+            .unwrap()
+    }
+
+    /// Primary and standby in one process: registrations replicate, the
+    /// standby redirects writes, and after promotion it doesn't resend pushes
+    /// for events the primary already handled.
+    #[actix_web::test]
+    async fn test_failover_to_standby() {
+        let primary = instance(true, None);
+        let standby = instance(false, Some("https://primary.example".to_string()));
+        let standby_url = serve(standby.state.clone());
+
+        // Registered before replication starts: arrives with the snapshot
+        let trade_pubkey = Keys::generate().public_key().to_string();
+        primary.state.token_store
+            .register(trade_pubkey.clone(), "device-1".to_string(), Platform::Android)
+            .await;
+        start_replication(
+            primary.state.token_store.clone(),
+            vec![standby_url],
+            "replication-secret".to_string(),
+        );
+        let other = Keys::generate().public_key().to_string();
+        primary.state.token_store
+            .register(other.clone(), "device-2".to_string(), Platform::Ios)
+            .await;
+
+        let standby_store = standby.state.token_store.clone();
+        wait_for(|| async { standby_store.generation() >= 3 }).await;
+        assert_eq!(standby_store.get(&other).await.unwrap().device_token, "device-2");
+
+        // The standby serves reads but points writers at the primary
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(standby.state.clone()))
+                .configure(crate::api::routes::configure),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/api/unregister")
+            .set_json(serde_json::json!({ "trade_pubkey": other }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 307);
+        assert_eq!(
+            resp.headers().get("Location").unwrap(),
+            "https://primary.example/api/unregister"
+        );
+        let req = test::TestRequest::get().uri("/api/status").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        // The primary handles an event, then goes away
+        let handled = gift_wrap_to(&trade_pubkey);
+        primary.listener.handle_and_wait(&handled).await;
+        assert_eq!(MockPush::sent(&primary.sent), 1);
+        let handled_id = handled.id.to_hex();
+        wait_for(|| standby_store.is_claimed(&handled_id)).await;
+        primary.state.leadership.set_leader(false);
+
+        // Standby ignores events until promoted
+        let fresh = gift_wrap_to(&other);
+        standby.listener.handle_and_wait(&fresh).await;
+        assert_eq!(MockPush::sent(&standby.sent), 0);
+
+        standby.state.leadership.set_leader(true);
+        // Relays replay recent events to the new leader's subscription
+        standby.listener.handle_and_wait(&handled).await;
+        standby.listener.handle_and_wait(&fresh).await;
+        assert_eq!(MockPush::sent(&standby.sent), 1);
+    }
+}
@@ -0,0 +1,228 @@
+//! Runs the periodic background tasks. Each task has an interval range and
+//! may report a load signal: the busier it is, the closer it runs to its
+//! minimum interval, so small deployments stay mostly idle.
+
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use log::{debug, info};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::models::TaskStatus;
+
+type Job = Box<dyn Fn() -> BoxFuture<'static, ()> + Send + Sync>;
+type LoadSignal = Box<dyn Fn() -> BoxFuture<'static, u64> + Send + Sync>;
+
+/// A periodic task, before it is registered with a `Scheduler`.
+pub struct Task {
+    name: String,
+    min_interval: Duration,
+    max_interval: Duration,
+    /// Load at which the task runs at `min_interval`
+    full_load: u64,
+    load: Option<LoadSignal>,
+    job: Job,
+}
+
+impl Task {
+    /// Run `job` every `interval`.
+    pub fn every<F, Fut>(name: impl Into<String>, interval: Duration, job: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Self {
+            name: name.into(),
+            min_interval: interval,
+            max_interval: interval,
+            full_load: 0,
+            load: None,
+            job: Box::new(move || Box::pin(job())),
+        }
+    }
+
+    /// Shorten the interval towards `min_interval` as `load` approaches
+    /// `full_load`, e.g. the store size or a queue depth.
+    pub fn adapting<F, Fut>(mut self, min_interval: Duration, full_load: u64, load: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = u64> + Send + 'static,
+    {
+        self.min_interval = min_interval.min(self.max_interval);
+        self.full_load = full_load;
+        self.load = Some(Box::new(move || Box::pin(load())));
+        self
+    }
+}
+
+/// Linear between `max` at no load and `min` at `full_load` or more.
+pub fn adapted_interval(min: Duration, max: Duration, load: u64, full_load: u64) -> Duration {
+    if full_load == 0 {
+        return max;
+    }
+    let fraction = load.min(full_load) as f64 / full_load as f64;
+    max - (max - min).mul_f64(fraction)
+}
+
+struct TaskState {
+    name: String,
+    min_interval: Duration,
+    max_interval: Duration,
+    trigger: Notify,
+    runs: Mutex<Runs>,
+}
+
+#[derive(Default)]
+struct Runs {
+    /// Interval chosen for the upcoming run
+    interval: Duration,
+    last: Option<DateTime<Utc>>,
+    next: Option<DateTime<Utc>>,
+    count: u64,
+}
+
+#[derive(Default)]
+pub struct Scheduler {
+    tasks: Mutex<Vec<Arc<TaskState>>>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start running `task`. The first run comes after one interval.
+    pub fn register(&self, task: Task) {
+        let state = Arc::new(TaskState {
+            name: task.name,
+            min_interval: task.min_interval,
+            max_interval: task.max_interval,
+            trigger: Notify::new(),
+            runs: Mutex::new(Runs { interval: task.max_interval, ..Default::default() }),
+        });
+        self.tasks.lock().unwrap().push(state.clone());
+
+        let (job, load, full_load) = (task.job, task.load, task.full_load);
+        tokio::spawn(async move {
+            loop {
+                let interval = match &load {
+                    Some(load) => adapted_interval(state.min_interval, state.max_interval, load().await, full_load),
+                    None => state.max_interval,
+                };
+                {
+                    let mut runs = state.runs.lock().unwrap();
+                    runs.interval = interval;
+                    runs.next = chrono::Duration::from_std(interval).ok().map(|d| Utc::now() + d);
+                }
+
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => {}
+                    _ = state.trigger.notified() => info!("Running task {} on request", state.name),
+                }
+                debug!("Running task {}", state.name);
+                job().await;
+                let mut runs = state.runs.lock().unwrap();
+                runs.last = Some(Utc::now());
+                runs.count += 1;
+            }
+        });
+    }
+
+    /// Run the named task now, without waiting for its next run. Returns
+    /// false if there is no such task.
+    pub fn trigger(&self, name: &str) -> bool {
+        match self.tasks.lock().unwrap().iter().find(|task| task.name == name) {
+            Some(task) => {
+                task.trigger.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<TaskStatus> {
+        self.tasks
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|task| {
+                let runs = task.runs.lock().unwrap();
+                TaskStatus {
+                    name: task.name.clone(),
+                    min_interval_secs: task.min_interval.as_secs(),
+                    max_interval_secs: task.max_interval.as_secs(),
+                    interval_secs: runs.interval.as_secs(),
+                    last_run: runs.last,
+                    next_run: runs.next,
+                    runs: runs.count,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[test]
+    fn test_interval_adapts_to_load() {
+        let (min, max) = (Duration::from_secs(60), Duration::from_secs(3600));
+        assert_eq!(adapted_interval(min, max, 0, 1000), max);
+        assert_eq!(adapted_interval(min, max, 500, 1000), Duration::from_secs(1830));
+        assert_eq!(adapted_interval(min, max, 1000, 1000), min);
+        assert_eq!(adapted_interval(min, max, 50_000, 1000), min);
+        // Without a load signal the interval is fixed
+        assert_eq!(adapted_interval(min, max, 50_000, 0), max);
+    }
+
+    #[tokio::test]
+    async fn test_manual_trigger_runs_task() {
+        let scheduler = Scheduler::new();
+        let runs = Arc::new(AtomicU64::new(0));
+        let load = Arc::new(AtomicU64::new(0));
+        scheduler.register(
+            Task::every("sweeper", Duration::from_secs(3600), {
+                let runs = runs.clone();
+                move || {
+                    let runs = runs.clone();
+                    async move {
+                        runs.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+            })
+            .adapting(Duration::from_secs(600), 100, {
+                let load = load.clone();
+                move || {
+                    let load = load.clone();
+                    async move { load.load(Ordering::SeqCst) }
+                }
+            }),
+        );
+        assert!(!scheduler.trigger("unknown"));
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let status = &scheduler.snapshot()[0];
+        assert_eq!((status.interval_secs, status.runs, status.last_run), (3600, 0, None));
+
+        // The run picks up the new load when scheduling the next one
+        load.store(100, Ordering::SeqCst);
+        assert!(scheduler.trigger("sweeper"));
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while scheduler.snapshot()[0].runs == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let status = &scheduler.snapshot()[0];
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert!(status.last_run.is_some());
+        assert_eq!(status.interval_secs, 600);
+        assert!(status.next_run > status.last_run);
+    }
+}
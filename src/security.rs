@@ -0,0 +1,188 @@
+//! Startup checks of security-relevant settings. Violations are warnings,
+//! unless `STRICT_SECURITY` is set, in which case the server refuses to start.
+
+use crate::config::Config;
+use crate::models::{ConfigReport, SecurityFinding, SecurityReport};
+
+/// Shortest admin token accepted as strong (e.g. `openssl rand -hex 16`).
+const MIN_ADMIN_TOKEN_LEN: usize = 32;
+
+pub struct Rule {
+    pub id: &'static str,
+    /// What a compliant configuration looks like
+    pub description: &'static str,
+    /// Why the configuration falls short, if it does
+    check: fn(&Config) -> Result<(), String>,
+}
+
+/// Every rule, evaluated in order. Add new rules here.
+pub const RULES: &[Rule] = &[
+    Rule {
+        id: "admin_auth",
+        description: "The admin API requires a strong bearer token",
+        check: check_admin_auth,
+    },
+    Rule {
+        id: "rate_limit",
+        description: "API requests are rate limited",
+        check: check_rate_limit,
+    },
+    Rule {
+        id: "replication_auth",
+        description: "Replication to standbys is authenticated",
+        check: check_replication_auth,
+    },
+    Rule {
+        id: "key_file_permissions",
+        description: "Credential files are readable by their owner only",
+        check: check_key_file_permissions,
+    },
+];
+
+fn check_admin_auth(config: &Config) -> Result<(), String> {
+    match &config.server.admin_token {
+        None => Err("ADMIN_TOKEN is unset".to_string()),
+        Some(token) if token.len() < MIN_ADMIN_TOKEN_LEN => Err(format!(
+            "ADMIN_TOKEN is {} characters; use at least {}",
+            token.len(),
+            MIN_ADMIN_TOKEN_LEN
+        )),
+        Some(_) => Ok(()),
+    }
+}
+
+fn check_rate_limit(config: &Config) -> Result<(), String> {
+    if config.rate_limit.max_per_minute == 0 {
+        return Err("RATE_LIMIT_PER_MINUTE is 0".to_string());
+    }
+    Ok(())
+}
+
+fn check_replication_auth(config: &Config) -> Result<(), String> {
+    if !config.replication.standbys.is_empty() && config.replication.token.is_none() {
+        return Err("REPLICATION_STANDBYS is set without REPLICATION_TOKEN".to_string());
+    }
+    Ok(())
+}
+
+fn check_key_file_permissions(config: &Config) -> Result<(), String> {
+    let Some(path) = &config.push.firebase_service_account_path else {
+        return Ok(());
+    };
+    let metadata = std::fs::metadata(path).map_err(|e| format!("{}: {}", path, e))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = metadata.permissions().mode() & 0o777;
+        if mode & 0o077 != 0 {
+            return Err(format!("{} has mode {:o}; run chmod 600 on it", path, mode));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = metadata;
+    Ok(())
+}
+
+/// Run every rule against `config`.
+pub fn evaluate(config: &Config) -> SecurityReport {
+    SecurityReport {
+        strict: config.server.strict_security,
+        findings: RULES
+            .iter()
+            .map(|rule| {
+                let problem = (rule.check)(config).err();
+                SecurityFinding {
+                    rule: rule.id.to_string(),
+                    description: rule.description.to_string(),
+                    passed: problem.is_none(),
+                    problem,
+                }
+            })
+            .collect(),
+    }
+}
+
+/// Effective settings with the security evaluation, for `/admin/config`.
+pub fn config_report(config: &Config) -> ConfigReport {
+    ConfigReport {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        bind: config.server.bind.to_string(),
+        relays: config.nostr.relays.clone(),
+        mostro_pubkey: config.nostr.mostro_pubkey.clone(),
+        fcm_enabled: config.push.fcm_enabled,
+        unifiedpush_enabled: config.push.unifiedpush_enabled,
+        advertised_platforms: config.push.advertised_platforms.clone(),
+        rate_limit_per_minute: config.rate_limit.max_per_minute,
+        standby: config.replication.standby,
+        admin_token_set: config.server.admin_token.is_some(),
+        replication_token_set: config.replication.token.is_some(),
+        security: evaluate(config),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(id: &str, config: &Config) -> Result<(), String> {
+        let rule = RULES.iter().find(|rule| rule.id == id).unwrap();
+        (rule.check)(config)
+    }
+
+    #[test]
+    fn test_admin_auth_rule() {
+        let mut config = Config::for_tests();
+        assert!(check("admin_auth", &config).is_err());
+        config.server.admin_token = Some("short".to_string());
+        assert!(check("admin_auth", &config).unwrap_err().contains("at least 32"));
+        config.server.admin_token = Some("a".repeat(32));
+        assert_eq!(check("admin_auth", &config), Ok(()));
+    }
+
+    #[test]
+    fn test_rate_limit_rule() {
+        let mut config = Config::for_tests();
+        assert_eq!(check("rate_limit", &config), Ok(()));
+        config.rate_limit.max_per_minute = 0;
+        assert!(check("rate_limit", &config).is_err());
+    }
+
+    #[test]
+    fn test_replication_auth_rule() {
+        let mut config = Config::for_tests();
+        assert_eq!(check("replication_auth", &config), Ok(()));
+        config.replication.standbys = vec!["http://standby:8080".to_string()];
+        assert!(check("replication_auth", &config).is_err());
+        config.replication.token = Some("secret".to_string());
+        assert_eq!(check("replication_auth", &config), Ok(()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_key_file_permissions_rule() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("mostro-sa-{}.json", std::process::id()));
+        std::fs::write(&path, "{}").unwrap();
+        let mut config = Config::for_tests();
+        assert_eq!(check("key_file_permissions", &config), Ok(()));
+
+        config.push.firebase_service_account_path = Some(path.display().to_string());
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(check("key_file_permissions", &config).unwrap_err().contains("644"));
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(check("key_file_permissions", &config), Ok(()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_evaluate_lists_every_rule() {
+        let mut config = Config::for_tests();
+        config.server.strict_security = true;
+        let report = evaluate(&config);
+        assert!(report.strict);
+        assert_eq!(report.findings.len(), RULES.len());
+        let failed: Vec<_> = report.violations().map(|f| f.rule.as_str()).collect();
+        assert_eq!(failed, vec!["admin_auth"]);
+    }
+}
@@ -0,0 +1,228 @@
+//! Delivery SLI: the share of matched events whose push a provider accepted
+//! within a threshold of the event reaching us, over rolling windows.
+//!
+//! Each event counts in the minute it was received. It is good once a
+//! provider accepts it in time, and bad once it fails or the threshold passes
+//! without acceptance, whichever comes first. Events still inside the
+//! threshold are in flight and left out of every ratio, so a window edge never
+//! counts an event that could still turn out good.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::models::{SliReport, SliWindow};
+
+/// Granularity of the aggregates, and so of window edges.
+const BUCKET_SECS: i64 = 60;
+
+pub const DEFAULT_THRESHOLD: Duration = Duration::from_secs(30);
+pub const DEFAULT_WINDOWS: &[u64] = &[3600, 86_400, 7 * 86_400];
+
+/// Outcomes of the events received in one bucket, as persisted in the
+/// metrics checkpoint.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct SliBucket {
+    /// Unix seconds at the start of the bucket
+    pub start: i64,
+    pub good: u64,
+    pub bad: u64,
+}
+
+/// An event whose outcome is not known yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SliTicket(u64);
+
+#[derive(Debug, Default)]
+struct SliState {
+    buckets: BTreeMap<i64, SliBucket>,
+    pending: HashMap<SliTicket, DateTime<Utc>>,
+    next_ticket: u64,
+}
+
+#[derive(Debug)]
+pub struct DeliverySli {
+    threshold: chrono::Duration,
+    /// Window lengths in seconds, shortest first
+    windows: Vec<u64>,
+    state: Mutex<SliState>,
+}
+
+impl Default for DeliverySli {
+    fn default() -> Self {
+        Self::new(DEFAULT_THRESHOLD, DEFAULT_WINDOWS.to_vec())
+    }
+}
+
+impl DeliverySli {
+    pub fn new(threshold: Duration, mut windows: Vec<u64>) -> Self {
+        windows.sort_unstable();
+        windows.dedup();
+        Self {
+            threshold: chrono::Duration::from_std(threshold).unwrap_or(chrono::Duration::MAX),
+            windows,
+            state: Mutex::new(SliState::default()),
+        }
+    }
+
+    /// Start tracking a matched event received at `received_at`.
+    pub fn start(&self, received_at: DateTime<Utc>) -> SliTicket {
+        let mut state = self.state.lock().unwrap();
+        let ticket = SliTicket(state.next_ticket);
+        state.next_ticket += 1;
+        state.pending.insert(ticket, received_at);
+        ticket
+    }
+
+    /// Record the event's outcome: `accepted` by a provider at `at`, or not.
+    /// Events already counted as late are left as they are.
+    pub fn finish(&self, ticket: SliTicket, at: DateTime<Utc>, accepted: bool) {
+        let mut state = self.state.lock().unwrap();
+        let Some(received_at) = state.pending.remove(&ticket) else {
+            return;
+        };
+        let good = accepted && at - received_at <= self.threshold;
+        Self::count(&mut state, received_at, good);
+    }
+
+    fn count(state: &mut SliState, received_at: DateTime<Utc>, good: bool) {
+        let start = received_at.timestamp().div_euclid(BUCKET_SECS) * BUCKET_SECS;
+        let bucket = state.buckets.entry(start).or_insert(SliBucket { start, ..Default::default() });
+        if good {
+            bucket.good += 1;
+        } else {
+            bucket.bad += 1;
+        }
+    }
+
+    /// Count events past the threshold as bad and drop buckets older than
+    /// the longest window.
+    fn sweep(&self, state: &mut SliState, now: DateTime<Utc>) {
+        let late: Vec<_> = state
+            .pending
+            .iter()
+            .filter(|(_, received_at)| now - **received_at > self.threshold)
+            .map(|(ticket, received_at)| (*ticket, *received_at))
+            .collect();
+        for (ticket, received_at) in late {
+            state.pending.remove(&ticket);
+            Self::count(state, received_at, false);
+        }
+
+        let longest = self.windows.last().copied().unwrap_or(0) as i64;
+        let oldest = now.timestamp() - longest - BUCKET_SECS;
+        state.buckets = state.buckets.split_off(&oldest);
+    }
+
+    pub fn report(&self, now: DateTime<Utc>) -> SliReport {
+        let mut state = self.state.lock().unwrap();
+        self.sweep(&mut state, now);
+
+        let windows = self
+            .windows
+            .iter()
+            .map(|&window_secs| {
+                let since = now.timestamp() - window_secs as i64;
+                let (good, bad) = state
+                    .buckets
+                    .range(since.div_euclid(BUCKET_SECS) * BUCKET_SECS..)
+                    .fold((0, 0), |(good, bad), (_, b)| (good + b.good, bad + b.bad));
+                SliWindow::new(window_label(window_secs), window_secs, good, bad)
+            })
+            .collect();
+
+        SliReport {
+            threshold_secs: self.threshold.num_seconds() as u64,
+            in_flight: state.pending.len() as u64,
+            windows,
+        }
+    }
+
+    /// Aggregates to persist; in-flight events are not kept across restarts.
+    pub fn buckets(&self) -> Vec<SliBucket> {
+        self.state.lock().unwrap().buckets.values().copied().collect()
+    }
+
+    /// Add aggregates persisted by a previous process.
+    pub fn restore(&self, buckets: &[SliBucket]) {
+        let mut state = self.state.lock().unwrap();
+        for restored in buckets {
+            let bucket = state.buckets.entry(restored.start).or_insert(SliBucket {
+                start: restored.start,
+                ..Default::default()
+            });
+            bucket.good += restored.good;
+            bucket.bad += restored.bad;
+        }
+    }
+}
+
+/// `7d`, `24h`, `30m` or `90s`: the largest unit that divides the window,
+/// with a single day shown in hours.
+fn window_label(secs: u64) -> String {
+    match secs {
+        s if s % 86_400 == 0 && s > 86_400 => format!("{}d", s / 86_400),
+        s if s % 3600 == 0 => format!("{}h", s / 3600),
+        s if s % 60 == 0 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_windows_and_in_flight_events_at_the_edges() {
+        let sli = DeliverySli::new(Duration::from_secs(30), vec![86_400, 3600]);
+        let t0 = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let secs = chrono::Duration::seconds;
+
+        // Two hours ago: one fast, one failed
+        let fast = sli.start(t0 - secs(7200));
+        sli.finish(fast, t0 - secs(7200) + secs(2), true);
+        let failed = sli.start(t0 - secs(7200));
+        sli.finish(failed, t0 - secs(7190), false);
+
+        // Within the hour: accepted in time, accepted late, and never resolved
+        let on_time = sli.start(t0 - secs(600));
+        sli.finish(on_time, t0 - secs(570), true);
+        let late = sli.start(t0 - secs(600));
+        sli.finish(late, t0 - secs(569), true);
+        let lost = sli.start(t0 - secs(300));
+
+        // Still inside the threshold: neither good nor bad yet
+        let fresh = sli.start(t0 - secs(10));
+
+        let report = sli.report(t0);
+        assert_eq!(report.threshold_secs, 30);
+        assert_eq!(report.in_flight, 1);
+        assert_eq!(report.windows[0].window, "1h");
+        assert_eq!((report.windows[0].good, report.windows[0].total), (1, 3));
+        assert_eq!(report.windows[1].window, "24h");
+        assert_eq!((report.windows[1].good, report.windows[1].total), (2, 5));
+        assert_eq!(report.windows[1].ratio, Some(0.4));
+
+        // Acceptance after being counted late doesn't rewrite history
+        sli.finish(lost, t0, true);
+        sli.finish(fresh, t0 + secs(5), true);
+        let report = sli.report(t0 + secs(5));
+        assert_eq!(report.in_flight, 0);
+        assert_eq!((report.windows[0].good, report.windows[0].total), (2, 4));
+
+        // Aggregates survive a restart; the two-hour-old bucket leaves the hour
+        let restarted = DeliverySli::new(Duration::from_secs(30), vec![3600, 86_400]);
+        restarted.restore(&sli.buckets());
+        let report = restarted.report(t0 + secs(3600));
+        assert_eq!((report.windows[0].good, report.windows[0].total), (0, 0));
+        assert_eq!(report.windows[0].ratio, None);
+        assert_eq!((report.windows[1].good, report.windows[1].total), (3, 6));
+
+        // Past the longest window everything is dropped
+        assert_eq!(restarted.report(t0 + secs(2 * 86_400)).windows[1].total, 0);
+        assert!(restarted.buckets().is_empty());
+    }
+}
@@ -0,0 +1,331 @@
+//! Read-through cache in front of a store whose lookups are slow (a database
+//! or a remote service), so the listener's per-event lookup stays in memory.
+//!
+//! Lookups, including "not registered", are cached for the TTL, and the least
+//! recently used entry is evicted once the cache is full. Writes made
+//! through the cache evict the pubkey once the backend has acknowledged them,
+//! and a watcher evicts on changes the backend streams, e.g. from replication.
+//! A lookup that raced a write is not cached: every eviction bumps an epoch,
+//! and a backend read is only kept if no eviction happened while it ran.
+
+use async_trait::async_trait;
+use log::{debug, warn};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+use crate::metrics::Metrics;
+use crate::models::{ConflictPolicy, TokenStoreStats};
+use super::{
+    AnnotationError, ImportOutcome, ReencryptError, RegisteredToken, StoreChange, TokenStore,
+};
+
+struct Entry {
+    token: Option<RegisteredToken>,
+    cached_at: Instant,
+    /// Matches the recency queue slot that owns this entry
+    seq: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<String, Entry>,
+    /// Least recently used first; slots whose `seq` no longer matches are stale
+    order: VecDeque<(String, u64)>,
+    next_seq: u64,
+}
+
+impl Entries {
+    /// Move `key` to the most recently used end.
+    fn touch(&mut self, key: &str) {
+        let seq = self.next_seq;
+        if let Some(entry) = self.map.get_mut(key) {
+            entry.seq = seq;
+            self.next_seq += 1;
+            self.order.push_back((key.to_string(), seq));
+        }
+    }
+
+    /// Drop stale recency slots once they outnumber live entries.
+    fn compact(&mut self, capacity: usize) {
+        if self.order.len() > capacity * 2 {
+            let Entries { map, order, .. } = self;
+            order.retain(|(key, seq)| map.get(key).is_some_and(|entry| entry.seq == *seq));
+        }
+    }
+}
+
+pub struct CachedTokenStore {
+    inner: Arc<dyn TokenStore>,
+    capacity: usize,
+    ttl: Duration,
+    entries: Mutex<Entries>,
+    epoch: AtomicU64,
+    metrics: Arc<Metrics>,
+}
+
+impl CachedTokenStore {
+    pub fn new(inner: Arc<dyn TokenStore>, capacity: usize, ttl: Duration, metrics: Arc<Metrics>) -> Self {
+        Self {
+            inner,
+            capacity: capacity.max(1),
+            ttl,
+            entries: Mutex::new(Entries::default()),
+            epoch: AtomicU64::new(0),
+            metrics,
+        }
+    }
+
+    fn cached(&self, trade_pubkey: &str) -> Option<Option<RegisteredToken>> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.map.get(trade_pubkey)?;
+        if entry.cached_at.elapsed() >= self.ttl {
+            return None;
+        }
+        let token = entry.token.clone();
+        entries.touch(trade_pubkey);
+        entries.compact(self.capacity);
+        Some(token)
+    }
+
+    /// Keep a backend read, unless an eviction happened since `epoch`.
+    fn fill(&self, trade_pubkey: &str, token: Option<RegisteredToken>, epoch: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        if self.epoch.load(Ordering::SeqCst) != epoch {
+            return;
+        }
+
+        let seq = entries.next_seq;
+        entries.next_seq += 1;
+        entries.map.insert(trade_pubkey.to_string(), Entry { token, cached_at: Instant::now(), seq });
+        entries.order.push_back((trade_pubkey.to_string(), seq));
+
+        while entries.map.len() > self.capacity {
+            let Some((oldest, seq)) = entries.order.pop_front() else { break };
+            if entries.map.get(&oldest).is_some_and(|entry| entry.seq == seq) {
+                entries.map.remove(&oldest);
+            }
+        }
+        entries.compact(self.capacity);
+    }
+
+    pub fn invalidate(&self, trade_pubkey: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        entries.map.remove(trade_pubkey);
+    }
+
+    pub fn invalidate_all(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        *entries = Entries::default();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Evict entries as the backend reports changes, including ones applied
+/// behind the cache. A lagging watcher drops the whole cache.
+pub fn spawn_invalidation(cache: Arc<CachedTokenStore>) {
+    let mut changes = cache.inner.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match changes.recv().await {
+                Ok(StoreChange::Upsert { trade_pubkey, .. } | StoreChange::Remove { trade_pubkey }) => {
+                    cache.invalidate(&trade_pubkey);
+                }
+                Ok(StoreChange::EventClaimed { .. }) => {}
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("Store cache missed {} changes, dropping all entries", skipped);
+                    cache.invalidate_all();
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+#[async_trait]
+impl TokenStore for CachedTokenStore {
+    async fn register_token(&self, trade_pubkey: String, token: RegisteredToken) -> bool {
+        let is_new = self.inner.register_token(trade_pubkey.clone(), token).await;
+        self.invalidate(&trade_pubkey);
+        is_new
+    }
+
+    async fn unregister(&self, trade_pubkey: &str) -> Option<RegisteredToken> {
+        let removed = self.inner.unregister(trade_pubkey).await;
+        self.invalidate(trade_pubkey);
+        removed
+    }
+
+    async fn get(&self, trade_pubkey: &str) -> Option<RegisteredToken> {
+        if let Some(token) = self.cached(trade_pubkey) {
+            Metrics::inc(&self.metrics.store_cache_hits);
+            return token;
+        }
+
+        Metrics::inc(&self.metrics.store_cache_misses);
+        let epoch = self.epoch.load(Ordering::SeqCst);
+        let token = self.inner.get(trade_pubkey).await;
+        self.fill(trade_pubkey, token.clone(), epoch);
+        token
+    }
+
+    async fn pubkeys_for_token(&self, device_token: &str) -> std::collections::HashSet<String> {
+        self.inner.pubkeys_for_token(device_token).await
+    }
+
+    async fn set_annotation(&self, trade_pubkey: &str, key: &str, value: &str) -> Result<(), AnnotationError> {
+        let result = self.inner.set_annotation(trade_pubkey, key, value).await;
+        self.invalidate(trade_pubkey);
+        result
+    }
+
+    async fn remove_annotation(&self, trade_pubkey: &str, key: &str) -> Result<bool, AnnotationError> {
+        let result = self.inner.remove_annotation(trade_pubkey, key).await;
+        self.invalidate(trade_pubkey);
+        result
+    }
+
+    async fn reencrypt(&self, trade_pubkey: &str, device_token: &str, envelope_version: u8) -> Result<(), ReencryptError> {
+        let result = self.inner.reencrypt(trade_pubkey, device_token, envelope_version).await;
+        self.invalidate(trade_pubkey);
+        result
+    }
+
+    async fn export(&self) -> Vec<(String, RegisteredToken)> {
+        self.inner.export().await
+    }
+
+    async fn import(
+        &self,
+        trade_pubkey: String,
+        token: RegisteredToken,
+        policy: ConflictPolicy,
+        dry_run: bool,
+    ) -> ImportOutcome {
+        let outcome = self.inner.import(trade_pubkey.clone(), token, policy, dry_run).await;
+        if !dry_run {
+            self.invalidate(&trade_pubkey);
+        }
+        outcome
+    }
+
+    async fn cleanup_expired(&self) -> usize {
+        let removed = self.inner.cleanup_expired().await;
+        if removed > 0 {
+            debug!("Dropping store cache after {} expirations", removed);
+            self.invalidate_all();
+        }
+        removed
+    }
+
+    async fn claim_event(&self, event_id: &str) -> bool {
+        self.inner.claim_event(event_id).await
+    }
+
+    async fn is_claimed(&self, event_id: &str) -> bool {
+        self.inner.is_claimed(event_id).await
+    }
+
+    async fn snapshot(&self) -> Vec<StoreChange> {
+        self.inner.snapshot().await
+    }
+
+    async fn clear(&self) {
+        self.inner.clear().await;
+        self.invalidate_all();
+    }
+
+    fn generation(&self) -> u64 {
+        self.inner.generation()
+    }
+
+    async fn count(&self) -> usize {
+        self.inner.count().await
+    }
+
+    async fn get_stats(&self) -> TokenStoreStats {
+        self.inner.get_stats().await
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<StoreChange> {
+        self.inner.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Platform;
+    use crate::store::testing::MockTokenStore;
+
+    const PUBKEY: &str = "a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2";
+
+    fn cached(capacity: usize) -> (CachedTokenStore, Arc<MockTokenStore>, Arc<Metrics>) {
+        let backend = Arc::new(MockTokenStore::new());
+        let metrics = Arc::new(Metrics::new());
+        let cache = CachedTokenStore::new(backend.clone(), capacity, Duration::from_secs(60), metrics.clone());
+        (cache, backend, metrics)
+    }
+
+    #[tokio::test]
+    async fn test_cache_stays_correct_across_register_and_unregister_races() {
+        let (cache, backend, metrics) = cached(16);
+
+        // A cached "not registered" must not hide a registration made since
+        assert_eq!(cache.get(PUBKEY).await, None);
+        assert_eq!(cache.get(PUBKEY).await, None);
+        cache.register(PUBKEY.to_string(), "token-1".to_string(), Platform::Android).await;
+        assert_eq!(cache.get(PUBKEY).await.unwrap().device_token, "token-1");
+        assert_eq!(cache.get(PUBKEY).await.unwrap().device_token, "token-1");
+        assert_eq!(backend.lookups().len(), 2);
+        assert_eq!(Metrics::get(&metrics.store_cache_hits), 2);
+        assert_eq!(Metrics::get(&metrics.store_cache_misses), 2);
+
+        cache.unregister(PUBKEY).await;
+        assert_eq!(cache.get(PUBKEY).await, None);
+
+        // A backend read that overlapped a write is served but not cached
+        let epoch = cache.epoch.load(Ordering::SeqCst);
+        let stale = backend.get(PUBKEY).await;
+        cache.register(PUBKEY.to_string(), "token-2".to_string(), Platform::Android).await;
+        cache.fill(PUBKEY, stale, epoch);
+        assert_eq!(cache.get(PUBKEY).await.unwrap().device_token, "token-2");
+
+        // Changes applied behind the cache reach it through the watcher
+        let cache = Arc::new(cache);
+        spawn_invalidation(cache.clone());
+        backend.unregister(PUBKEY).await;
+        for _ in 0..50 {
+            if cache.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(cache.get(PUBKEY).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_cache_evicts_least_recently_used() {
+        let (cache, backend, _) = cached(2);
+        for key in ["a", "b", "a", "c"] {
+            cache.get(key).await;
+        }
+        // Reading "a" again kept it over "b"
+        assert_eq!(cache.len(), 2);
+        assert!(cache.cached("b").is_none());
+        assert!(cache.cached("a").is_some());
+        assert!(cache.cached("c").is_some());
+        assert_eq!(backend.lookups().len(), 3);
+    }
+}
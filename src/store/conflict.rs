@@ -0,0 +1,116 @@
+use serde::Deserialize;
+use std::str::FromStr;
+
+use crate::crypto::Platform;
+use super::RegisteredToken;
+
+/// How `/api/register` treats a registration whose platform differs from the
+/// one already stored for the pubkey, a sign the pubkey may have leaked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlatformConflictMode {
+    /// Replace the registration silently
+    Off,
+    /// Replace it, but log and audit the change
+    Warn,
+    /// Refuse unless the request sets `replace`
+    Enforce,
+}
+
+impl FromStr for PlatformConflictMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(Self::Off),
+            "warn" => Ok(Self::Warn),
+            "enforce" => Ok(Self::Enforce),
+            other => Err(format!(
+                "Invalid platform conflict policy '{}' (expected off, warn or enforce)",
+                other
+            )),
+        }
+    }
+}
+
+/// Which of a pubkey's devices a newly registered device takes the place of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DevicePolicy {
+    /// One device per pubkey; each new one replaces the rest
+    ReplaceAll,
+    /// One device per platform; a new one replaces those on its platform
+    PerPlatform,
+    /// Devices accumulate up to `MAX_DEVICES_PER_PUBKEY`
+    MultiDevice,
+}
+
+impl DevicePolicy {
+    /// Whether a new device on `platform` replaces the `existing` one.
+    pub fn replaces(self, existing: &RegisteredToken, platform: &Platform) -> bool {
+        match self {
+            Self::ReplaceAll => true,
+            Self::PerPlatform => existing.platform == *platform,
+            Self::MultiDevice => false,
+        }
+    }
+}
+
+impl FromStr for DevicePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "replace_all" => Ok(Self::ReplaceAll),
+            "per_platform" => Ok(Self::PerPlatform),
+            "multi_device" => Ok(Self::MultiDevice),
+            other => Err(format!(
+                "Invalid device policy '{}' (expected replace_all, per_platform or multi_device)",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictDecision {
+    /// Same platform, no registration yet, or the check is off
+    NoConflict,
+    /// The request asked to replace the registration
+    Replaced,
+    Warned,
+    Rejected,
+}
+
+impl ConflictDecision {
+    /// Audit action and result for the decision, if it is worth recording.
+    pub fn audit(self) -> Option<(&'static str, Result<(), String>)> {
+        match self {
+            Self::NoConflict => None,
+            Self::Replaced => Some(("platform_replace", Ok(()))),
+            Self::Warned => Some(("platform_conflict", Ok(()))),
+            Self::Rejected => Some(("platform_conflict", Err("rejected".to_string()))),
+        }
+    }
+}
+
+pub fn check_platform_conflict(
+    mode: PlatformConflictMode,
+    existing: Option<&RegisteredToken>,
+    platform: &Platform,
+    replace: bool,
+) -> ConflictDecision {
+    match existing {
+        _ if mode == PlatformConflictMode::Off => ConflictDecision::NoConflict,
+        Some(existing) if existing.platform != *platform => {
+            if replace {
+                ConflictDecision::Replaced
+            } else if mode == PlatformConflictMode::Enforce {
+                ConflictDecision::Rejected
+            } else {
+                ConflictDecision::Warned
+            }
+        }
+        _ => ConflictDecision::NoConflict,
+    }
+}
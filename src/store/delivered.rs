@@ -0,0 +1,50 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+
+/// Ids of events already handled, oldest evicted first once `capacity` is
+/// reached. Replicated to standbys so a promoted instance skips events the
+/// previous primary already pushed for.
+pub struct DeliveredEvents {
+    inner: Mutex<(VecDeque<String>, HashSet<String>)>,
+    capacity: usize,
+}
+
+impl DeliveredEvents {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new((VecDeque::new(), HashSet::new())),
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Returns false if the id was already recorded.
+    pub fn insert(&self, event_id: &str) -> bool {
+        let mut guard = self.inner.lock().unwrap();
+        let (order, ids) = &mut *guard;
+        if !ids.insert(event_id.to_string()) {
+            return false;
+        }
+        order.push_back(event_id.to_string());
+        if order.len() > self.capacity {
+            if let Some(oldest) = order.pop_front() {
+                ids.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    pub fn contains(&self, event_id: &str) -> bool {
+        self.inner.lock().unwrap().1.contains(event_id)
+    }
+
+    /// Recorded ids, oldest first.
+    pub fn ids(&self) -> Vec<String> {
+        self.inner.lock().unwrap().0.iter().cloned().collect()
+    }
+
+    pub fn clear(&self) {
+        let mut guard = self.inner.lock().unwrap();
+        guard.0.clear();
+        guard.1.clear();
+    }
+}
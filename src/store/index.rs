@@ -0,0 +1,87 @@
+use std::collections::{hash_map, HashMap, HashSet};
+
+use super::{trade_pubkey_of, RegisteredToken};
+
+/// Registrations by pubkey, with a reverse index from each device token to
+/// the pubkeys registered with it.
+#[derive(Default)]
+pub(super) struct Registrations {
+    by_pubkey: HashMap<String, RegisteredToken>,
+    by_token: HashMap<String, HashSet<String>>,
+}
+
+impl Registrations {
+    pub(super) fn get(&self, trade_pubkey: &str) -> Option<&RegisteredToken> {
+        self.by_pubkey.get(trade_pubkey)
+    }
+
+    /// For edits that keep the device token; changing it would leave the
+    /// reverse index stale.
+    pub(super) fn get_mut(&mut self, trade_pubkey: &str) -> Option<&mut RegisteredToken> {
+        self.by_pubkey.get_mut(trade_pubkey)
+    }
+
+    pub(super) fn insert(&mut self, trade_pubkey: String, token: RegisteredToken) -> Option<RegisteredToken> {
+        let previous = self.remove(&trade_pubkey);
+        self.by_token
+            .entry(token.device_token.clone())
+            .or_default()
+            .insert(trade_pubkey.clone());
+        self.by_pubkey.insert(trade_pubkey, token);
+        previous
+    }
+
+    pub(super) fn remove(&mut self, trade_pubkey: &str) -> Option<RegisteredToken> {
+        let removed = self.by_pubkey.remove(trade_pubkey)?;
+        self.unindex(trade_pubkey, &removed.device_token);
+        Some(removed)
+    }
+
+    fn unindex(&mut self, trade_pubkey: &str, device_token: &str) {
+        if let Some(pubkeys) = self.by_token.get_mut(device_token) {
+            pubkeys.remove(trade_pubkey);
+            if pubkeys.is_empty() {
+                self.by_token.remove(device_token);
+            }
+        }
+    }
+
+    /// Drops the registrations `keep` rejects, returning their pubkeys.
+    pub(super) fn retain(&mut self, mut keep: impl FnMut(&String, &RegisteredToken) -> bool) -> Vec<String> {
+        let dropped: Vec<String> = self
+            .by_pubkey
+            .iter()
+            .filter(|(trade_pubkey, token)| !keep(trade_pubkey, token))
+            .map(|(trade_pubkey, _)| trade_pubkey.clone())
+            .collect();
+        for trade_pubkey in &dropped {
+            self.remove(trade_pubkey);
+        }
+        dropped
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.by_pubkey.clear();
+        self.by_token.clear();
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.by_pubkey.len()
+    }
+
+    pub(super) fn iter(&self) -> hash_map::Iter<'_, String, RegisteredToken> {
+        self.by_pubkey.iter()
+    }
+
+    pub(super) fn values(&self) -> hash_map::Values<'_, String, RegisteredToken> {
+        self.by_pubkey.values()
+    }
+
+    /// Trade pubkeys currently registered with `device_token`.
+    pub(super) fn pubkeys_for_token(&self, device_token: &str) -> HashSet<String> {
+        self.by_token
+            .get(device_token)
+            .map(|keys| keys.iter().map(|key| trade_pubkey_of(key).to_string()).collect())
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,104 @@
+//! Live migration of registrations from a running instance into this one.
+
+use log::{info, warn};
+
+use super::{ImportOutcome, TokenStore};
+use crate::models::{ConflictPolicy, ErrorCode, ExportResponse, ExportedRegistration, MigrationReport};
+
+/// Entries re-read after writing and compared with the source.
+const SPOT_CHECKS: usize = 10;
+
+/// Pull all registrations from another instance's `/admin/export`.
+pub async fn fetch_export(
+    client: &reqwest::Client,
+    source_url: &str,
+    admin_token: &str,
+) -> Result<Vec<ExportedRegistration>, Box<dyn std::error::Error>> {
+    let url = format!("{}/admin/export", source_url.trim_end_matches('/'));
+    let response = client.get(&url).bearer_auth(admin_token).send().await?;
+    if !response.status().is_success() {
+        return Err(format!("Export from {} returned {}", url, response.status()).into());
+    }
+    Ok(response.json::<ExportResponse>().await?.registrations)
+}
+
+/// Write exported registrations into the store, then verify the result.
+pub async fn apply(
+    store: &dyn TokenStore,
+    registrations: Vec<ExportedRegistration>,
+    policy: ConflictPolicy,
+    dry_run: bool,
+) -> MigrationReport {
+    let mut report = MigrationReport {
+        dry_run,
+        source_total: registrations.len(),
+        ..Default::default()
+    };
+
+    let mut written = Vec::new();
+    for registration in &registrations {
+        let outcome = store
+            .import(registration.trade_pubkey.clone(), registration.token.clone(), policy, dry_run)
+            .await;
+        match outcome {
+            ImportOutcome::Inserted => report.inserted += 1,
+            ImportOutcome::Overwritten => report.overwritten += 1,
+            ImportOutcome::Skipped => report.skipped += 1,
+        }
+        if outcome != ImportOutcome::Skipped {
+            written.push(registration);
+        }
+    }
+
+    if dry_run {
+        report.success = true;
+        report.message = "Dry run, nothing written".to_string();
+        return report;
+    }
+
+    if let Err(message) = verify(store, &registrations, &written, &mut report).await {
+        warn!("Migration verification failed: {}", message);
+        report.message = message;
+        report.error_code = Some(ErrorCode::MigrationFailed);
+        return report;
+    }
+
+    info!(
+        "Migrated {} registrations ({} new, {} overwritten, {} skipped)",
+        report.source_total, report.inserted, report.overwritten, report.skipped
+    );
+    report.success = true;
+    report.message = "Migration complete".to_string();
+    report
+}
+
+async fn verify(
+    store: &dyn TokenStore,
+    registrations: &[ExportedRegistration],
+    written: &[&ExportedRegistration],
+    report: &mut MigrationReport,
+) -> Result<(), String> {
+    // Every source pubkey must now have a registration, whatever the conflict policy
+    for registration in registrations {
+        if store.get(&registration.trade_pubkey).await.is_none() {
+            return Err(format!(
+                "Registration for {}... missing after migration",
+                &registration.trade_pubkey[..16.min(registration.trade_pubkey.len())]
+            ));
+        }
+    }
+
+    // Written entries must match the source exactly; sample them evenly
+    let step = written.len().div_ceil(SPOT_CHECKS).max(1);
+    for registration in written.iter().step_by(step) {
+        let stored = store.get(&registration.trade_pubkey).await;
+        if stored.as_ref() != Some(&registration.token) {
+            return Err(format!(
+                "Registration for {}... differs from the source",
+                &registration.trade_pubkey[..16.min(registration.trade_pubkey.len())]
+            ));
+        }
+        report.spot_checked += 1;
+    }
+    Ok(())
+}
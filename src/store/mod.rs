@@ -1,57 +1,488 @@
+use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use log::{debug, info, warn};
-use std::collections::HashMap;
-use tokio::sync::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::{broadcast, RwLock};
 
-use crate::crypto::Platform;
+use crate::config::StoreConfig;
+use crate::crypto::{DecryptedToken, Platform, ENVELOPE_V1, ENVELOPE_V2, PUSH_KEY_SIZE};
+use crate::models::{ConflictPolicy, TokenStoreStats};
+use crate::nostr::NotificationPreferences;
+use crate::scheduler::Task;
 
-#[derive(Debug, Clone)]
+pub mod cache;
+pub mod conflict;
+pub mod delivered;
+mod index;
+pub mod migrate;
+#[cfg(feature = "redis")]
+pub mod redis;
+pub mod sqlite;
+pub mod wal;
+pub mod write_queue;
+
+use delivered::DeliveredEvents;
+use index::Registrations;
+pub use cache::CachedTokenStore;
+pub use conflict::{DevicePolicy, PlatformConflictMode};
+pub use sqlite::SqliteTokenStore;
+pub use write_queue::{WriteMode, WriteQueue};
+
+/// Handled event ids remembered for deduplication.
+pub const DELIVERED_EVENTS_CAPACITY: usize = 10_000;
+/// Changes buffered per subscriber before it lags and must resync.
+const CHANGE_BUFFER: usize = 4096;
+
+/// Limits on operator annotations per registration.
+pub const MAX_ANNOTATIONS: usize = 16;
+pub const MAX_ANNOTATION_KEY_LEN: usize = 64;
+pub const MAX_ANNOTATION_VALUE_LEN: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct RegisteredToken {
     pub device_token: String,
     pub platform: Platform,
     pub registered_at: DateTime<Utc>,
+    /// Operator notes (e.g. "beta tester"), attached to delivery logs.
+    /// Admin-only: never expose on public endpoints.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+    /// Envelope format the device token was last submitted in
+    #[serde(default = "default_envelope_version")]
+    pub envelope_version: u8,
+    /// Upgrades made through `/api/reencrypt`, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub envelope_history: Vec<EnvelopeUpgrade>,
+    /// Hex key from the envelope; push data is sealed to it when set.
+    /// As sensitive as the device token, and stored and exported alongside it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub push_key: Option<String>,
+    #[serde(default, skip_serializing_if = "NotificationPreferences::is_all")]
+    pub preferences: NotificationPreferences,
+    /// App build the device runs, selecting its push topic and credentials;
+    /// the server's defaults when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub app_id: Option<String>,
+    #[serde(default, skip_serializing_if = "PushEnvironment::is_production")]
+    pub environment: PushEnvironment,
 }
 
-pub struct TokenStore {
-    tokens: RwLock<HashMap<String, RegisteredToken>>,
-    ttl_hours: u64,
+/// Whether a registration comes from a development (sandbox) or a release
+/// build. Each has its own registrations and push services.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PushEnvironment {
+    #[default]
+    Production,
+    Sandbox,
 }
 
-impl TokenStore {
-    pub fn new(ttl_hours: u64) -> Self {
-        Self {
-            tokens: RwLock::new(HashMap::new()),
-            ttl_hours,
+impl PushEnvironment {
+    pub const ALL: [PushEnvironment; 2] = [PushEnvironment::Production, PushEnvironment::Sandbox];
+
+    pub fn is_production(&self) -> bool {
+        *self == PushEnvironment::Production
+    }
+}
+
+impl std::fmt::Display for PushEnvironment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PushEnvironment::Production => write!(f, "production"),
+            PushEnvironment::Sandbox => write!(f, "sandbox"),
         }
     }
+}
 
-    pub async fn register(
-        &self,
-        trade_pubkey: String,
-        device_token: String,
-        platform: Platform,
-    ) {
-        let token = RegisteredToken {
+/// Store key of a pubkey's registration in `environment`. Production keys are
+/// the bare pubkey, as they were before environments existed.
+pub fn store_key(trade_pubkey: &str, environment: PushEnvironment) -> String {
+    match environment {
+        PushEnvironment::Production => trade_pubkey.to_string(),
+        PushEnvironment::Sandbox => format!("sandbox:{}", trade_pubkey),
+    }
+}
+
+/// Key of a pubkey's `slot`-th device under `store_key`. The first device
+/// keeps the store key itself, so single-device registrations are unchanged.
+pub fn device_key(store_key: &str, slot: usize) -> String {
+    match slot {
+        0 => store_key.to_string(),
+        _ => format!("{}#{}", store_key, slot),
+    }
+}
+
+/// The trade pubkey a store or device key belongs to, in any environment.
+pub fn trade_pubkey_of(key: &str) -> &str {
+    let key = key.strip_prefix("sandbox:").unwrap_or(key);
+    key.split_once('#').map_or(key, |(trade_pubkey, _)| trade_pubkey)
+}
+
+fn default_envelope_version() -> u8 {
+    ENVELOPE_V1
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EnvelopeUpgrade {
+    pub from: u8,
+    pub to: u8,
+    pub at: DateTime<Utc>,
+}
+
+impl RegisteredToken {
+    pub fn new(device_token: String, platform: Platform) -> Self {
+        Self {
             device_token,
             platform,
             registered_at: Utc::now(),
-        };
+            annotations: BTreeMap::new(),
+            envelope_version: ENVELOPE_V1,
+            envelope_history: Vec::new(),
+            push_key: None,
+            preferences: NotificationPreferences::ALL,
+            app_id: None,
+            environment: PushEnvironment::Production,
+        }
+    }
+
+    pub fn with_environment(mut self, environment: PushEnvironment) -> Self {
+        self.environment = environment;
+        self
+    }
+
+    pub fn with_app_id(mut self, app_id: Option<String>) -> Self {
+        self.app_id = app_id;
+        self
+    }
+
+    pub fn with_preferences(mut self, preferences: NotificationPreferences) -> Self {
+        self.preferences = preferences;
+        self
+    }
+
+    /// A registration for a freshly decrypted envelope.
+    pub fn from_decrypted(decrypted: &DecryptedToken) -> Self {
+        let mut token = Self::new(decrypted.device_token.clone(), decrypted.platform.clone());
+        token.envelope_version = decrypted.envelope_version;
+        token.push_key = decrypted.push_key.map(hex::encode);
+        token
+    }
+
+    pub fn push_key_bytes(&self) -> Option<[u8; PUSH_KEY_SIZE]> {
+        let bytes = hex::decode(self.push_key.as_deref()?).ok()?;
+        bytes.try_into().ok()
+    }
+
+    /// Annotations formatted for log lines; empty when there are none.
+    pub fn annotations_label(&self) -> String {
+        if self.annotations.is_empty() {
+            return String::new();
+        }
+        let pairs: Vec<String> = self.annotations
+            .iter()
+            .map(|(key, value)| format!("{}={:?}", key, value))
+            .collect();
+        format!(" [{}]", pairs.join(", "))
+    }
+}
+
+/// A store mutation, as streamed to standby instances.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum StoreChange {
+    /// The registration for a pubkey as it now stands
+    Upsert { trade_pubkey: String, token: RegisteredToken },
+    Remove { trade_pubkey: String },
+    /// An event was claimed for delivery
+    EventClaimed { event_id: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportOutcome {
+    Inserted,
+    Overwritten,
+    Skipped,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ReencryptError {
+    NotRegistered,
+    /// The submitted device token is not the one on record
+    TokenMismatch,
+}
+
+impl std::fmt::Display for ReencryptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReencryptError::NotRegistered => write!(f, "No registration for this trade_pubkey"),
+            ReencryptError::TokenMismatch => {
+                write!(f, "Device token does not match the registered token")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReencryptError {}
+
+#[derive(Debug, PartialEq)]
+pub enum AnnotationError {
+    NotRegistered,
+    EmptyKey,
+    KeyTooLong,
+    ValueTooLong,
+    TooMany,
+}
+
+impl std::fmt::Display for AnnotationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AnnotationError::NotRegistered => write!(f, "No registration for this trade_pubkey"),
+            AnnotationError::EmptyKey => write!(f, "Annotation key must not be empty"),
+            AnnotationError::KeyTooLong => {
+                write!(f, "Annotation key exceeds {} bytes", MAX_ANNOTATION_KEY_LEN)
+            }
+            AnnotationError::ValueTooLong => {
+                write!(f, "Annotation value exceeds {} bytes", MAX_ANNOTATION_VALUE_LEN)
+            }
+            AnnotationError::TooMany => {
+                write!(f, "Registration already has {} annotations", MAX_ANNOTATIONS)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AnnotationError {}
+
+/// Registration storage behind the API and the listener. `MemoryTokenStore`
+/// is the default; other backends implement the same operations.
+#[async_trait]
+pub trait TokenStore: Send + Sync {
+    /// Store a token for the pubkey, replacing any previous one.
+    /// Returns true if the pubkey had no registration before.
+    async fn register(&self, trade_pubkey: String, device_token: String, platform: Platform) -> bool {
+        self.register_token(trade_pubkey, RegisteredToken::new(device_token, platform)).await
+    }
 
+    /// `register` with a prepared registration (envelope version, push key).
+    /// Annotations and envelope history carry over from a previous registration.
+    async fn register_token(&self, trade_pubkey: String, token: RegisteredToken) -> bool;
+
+    /// Remove the registration, returning it if there was one.
+    async fn unregister(&self, trade_pubkey: &str) -> Option<RegisteredToken>;
+
+    async fn get(&self, trade_pubkey: &str) -> Option<RegisteredToken>;
+
+    /// Devices registered under `store_key`, checking the first `max_devices`
+    /// slots, as (device key, registration) pairs in slot order.
+    async fn devices(&self, store_key: &str, max_devices: usize) -> Vec<(String, RegisteredToken)> {
+        let mut devices = Vec::new();
+        for slot in 0..max_devices.max(1) {
+            let key = device_key(store_key, slot);
+            if let Some(token) = self.get(&key).await {
+                devices.push((key, token));
+            }
+        }
+        devices
+    }
+
+    /// Distinct trade pubkeys registered with `device_token`, counting each
+    /// once across its devices and environments.
+    async fn pubkeys_for_token(&self, device_token: &str) -> HashSet<String>;
+
+    async fn set_annotation(&self, trade_pubkey: &str, key: &str, value: &str) -> Result<(), AnnotationError>;
+
+    /// Returns whether the annotation existed.
+    async fn remove_annotation(&self, trade_pubkey: &str, key: &str) -> Result<bool, AnnotationError>;
+
+    /// Move a registration to a newer envelope version, after the caller proved
+    /// it holds the device token on record. Earlier versions are kept as history.
+    async fn reencrypt(&self, trade_pubkey: &str, device_token: &str, envelope_version: u8) -> Result<(), ReencryptError>;
+
+    /// All registrations sorted by pubkey, for migrating to another instance.
+    async fn export(&self) -> Vec<(String, RegisteredToken)>;
+
+    /// Insert a registration from another instance, keeping its timestamp and
+    /// annotations. With `dry_run` only the outcome is computed.
+    async fn import(
+        &self,
+        trade_pubkey: String,
+        token: RegisteredToken,
+        policy: ConflictPolicy,
+        dry_run: bool,
+    ) -> ImportOutcome;
+
+    /// Drop registrations older than the TTL, returning how many were removed.
+    async fn cleanup_expired(&self) -> usize;
+
+    /// Record that an event is being handled. Returns false if it already was,
+    /// here or on the primary this store replicates from.
+    async fn claim_event(&self, event_id: &str) -> bool;
+
+    async fn is_claimed(&self, event_id: &str) -> bool;
+
+    /// Apply a change streamed from the primary.
+    async fn apply(&self, change: StoreChange) {
+        match change {
+            StoreChange::Upsert { trade_pubkey, token } => {
+                self.import(trade_pubkey, token, ConflictPolicy::Overwrite, false).await;
+            }
+            StoreChange::Remove { trade_pubkey } => {
+                self.unregister(&trade_pubkey).await;
+            }
+            StoreChange::EventClaimed { event_id } => {
+                self.claim_event(&event_id).await;
+            }
+        }
+    }
+
+    /// The whole store as changes that rebuild it from empty.
+    async fn snapshot(&self) -> Vec<StoreChange>;
+
+    /// Drop all registrations and claimed events, before loading a snapshot.
+    async fn clear(&self);
+
+    /// Bumped on every mutation so derived views (e.g. cached stats) can detect changes.
+    fn generation(&self) -> u64;
+
+    async fn count(&self) -> usize;
+
+    async fn get_stats(&self) -> TokenStoreStats;
+
+    /// Stream of mutations made from now on. A receiver that falls more than
+    /// the buffer behind gets `Lagged` and should resync from `snapshot`.
+    fn subscribe(&self) -> broadcast::Receiver<StoreChange>;
+}
+
+/// Where registrations live.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreBackend {
+    /// In this process, optionally persisted to `DATABASE_PATH`
+    #[default]
+    Memory,
+    /// Shared by every instance pointed at the same Redis
+    Redis,
+}
+
+impl std::str::FromStr for StoreBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "memory" => Ok(Self::Memory),
+            "redis" => Ok(Self::Redis),
+            other => Err(format!("Invalid store backend '{}' (expected memory or redis)", other)),
+        }
+    }
+}
+
+/// The token store `config` selects. Redis needs the `redis` feature and
+/// `STORE_REDIS_URL`.
+pub async fn open_store(config: &StoreConfig) -> Result<std::sync::Arc<dyn TokenStore>, String> {
+    match (config.backend, &config.database_path) {
+        (StoreBackend::Memory, None) => Ok(std::sync::Arc::new(MemoryTokenStore::with_ttl_secs(config.token_ttl_secs))),
+        (StoreBackend::Memory, Some(path)) => {
+            let store = SqliteTokenStore::open(std::path::Path::new(path), config.token_ttl_secs)
+                .map_err(|e| format!("Failed to open registration database {}: {}", path, e))?;
+            Ok(std::sync::Arc::new(store))
+        }
+        (StoreBackend::Redis, Some(_)) => Err("DATABASE_PATH can't be combined with STORE_BACKEND=redis".to_string()),
+        #[cfg(feature = "redis")]
+        (StoreBackend::Redis, None) => {
+            let url = config.redis_url.as_deref().ok_or("STORE_BACKEND=redis needs STORE_REDIS_URL")?;
+            let store = redis::RedisTokenStore::connect(url, config.token_ttl_secs)
+                .await
+                .map_err(|e| format!("Failed to connect to Redis: {}", e))?;
+            Ok(std::sync::Arc::new(store))
+        }
+        #[cfg(not(feature = "redis"))]
+        (StoreBackend::Redis, None) => Err("STORE_BACKEND=redis needs a build with the redis feature".to_string()),
+    }
+}
+
+/// Registrations in memory, lost on restart unless replicated or backed by
+/// `SqliteTokenStore`.
+pub struct MemoryTokenStore {
+    tokens: RwLock<Registrations>,
+    /// Registrations older than this are expired: no longer returned, and
+    /// removed at the next cleanup
+    ttl: chrono::Duration,
+    generation: AtomicU64,
+    /// Registrations removed by cleanup since start
+    expired: AtomicU64,
+    delivered: DeliveredEvents,
+    /// Mutations, for replication to standbys
+    changes: broadcast::Sender<StoreChange>,
+}
+
+impl MemoryTokenStore {
+    pub fn new(ttl_hours: u64) -> Self {
+        Self::with_ttl_secs(ttl_hours * 3600)
+    }
+
+    pub fn with_ttl_secs(ttl_secs: u64) -> Self {
+        Self {
+            tokens: RwLock::new(Registrations::default()),
+            ttl: chrono::Duration::seconds(ttl_secs.min(i64::MAX as u64) as i64),
+            generation: AtomicU64::new(0),
+            expired: AtomicU64::new(0),
+            delivered: DeliveredEvents::new(DELIVERED_EVENTS_CAPACITY),
+            changes: broadcast::channel(CHANGE_BUFFER).0,
+        }
+    }
+
+    fn is_expired(&self, token: &RegisteredToken, now: DateTime<Utc>) -> bool {
+        now.signed_duration_since(token.registered_at) >= self.ttl
+    }
+
+    fn publish(&self, change: impl FnOnce() -> StoreChange) {
+        if self.changes.receiver_count() > 0 {
+            let _ = self.changes.send(change());
+        }
+    }
+
+    fn publish_upsert(&self, trade_pubkey: &str, token: &RegisteredToken) {
+        self.publish(|| StoreChange::Upsert {
+            trade_pubkey: trade_pubkey.to_string(),
+            token: token.clone(),
+        });
+    }
+}
+
+#[async_trait]
+impl TokenStore for MemoryTokenStore {
+    async fn register_token(&self, trade_pubkey: String, mut token: RegisteredToken) -> bool {
         let mut tokens = self.tokens.write().await;
+        // Annotations belong to the pubkey, so they survive token refreshes
+        let previous = tokens.remove(&trade_pubkey);
+        let is_new = previous.is_none();
+        if let Some(previous) = previous {
+            token.annotations = previous.annotations;
+            token.envelope_history = previous.envelope_history;
+        }
+        self.publish_upsert(&trade_pubkey, &token);
         tokens.insert(trade_pubkey.clone(), token);
+        self.generation.fetch_add(1, Ordering::Relaxed);
         
         info!(
             "Registered token for trade_pubkey: {}... (total: {})",
             &trade_pubkey[..16.min(trade_pubkey.len())],
             tokens.len()
         );
+
+        is_new
     }
 
-    pub async fn unregister(&self, trade_pubkey: &str) -> bool {
+    async fn unregister(&self, trade_pubkey: &str) -> Option<RegisteredToken> {
         let mut tokens = self.tokens.write().await;
-        let removed = tokens.remove(trade_pubkey).is_some();
+        let removed = tokens.remove(trade_pubkey);
         
-        if removed {
+        if removed.is_some() {
+            self.generation.fetch_add(1, Ordering::Relaxed);
+            self.publish(|| StoreChange::Remove { trade_pubkey: trade_pubkey.to_string() });
             info!(
                 "Unregistered token for trade_pubkey: {}... (total: {})",
                 &trade_pubkey[..16.min(trade_pubkey.len())],
@@ -67,72 +498,461 @@ impl TokenStore {
         removed
     }
 
-    pub async fn get(&self, trade_pubkey: &str) -> Option<RegisteredToken> {
+    async fn get(&self, trade_pubkey: &str) -> Option<RegisteredToken> {
+        let tokens = self.tokens.read().await;
+        // Expired registrations are gone as far as callers can tell, even
+        // before the next cleanup removes them
+        tokens.get(trade_pubkey).filter(|token| !self.is_expired(token, Utc::now())).cloned()
+    }
+
+    async fn devices(&self, store_key: &str, max_devices: usize) -> Vec<(String, RegisteredToken)> {
         let tokens = self.tokens.read().await;
-        tokens.get(trade_pubkey).cloned()
+        let now = Utc::now();
+        (0..max_devices.max(1))
+            .map(|slot| device_key(store_key, slot))
+            .filter_map(|key| {
+                let token = tokens.get(&key).filter(|token| !self.is_expired(token, now)).cloned()?;
+                Some((key, token))
+            })
+            .collect()
+    }
+
+    async fn pubkeys_for_token(&self, device_token: &str) -> HashSet<String> {
+        self.tokens.read().await.pubkeys_for_token(device_token)
+    }
+
+    async fn set_annotation(
+        &self,
+        trade_pubkey: &str,
+        key: &str,
+        value: &str,
+    ) -> Result<(), AnnotationError> {
+        if key.is_empty() {
+            return Err(AnnotationError::EmptyKey);
+        }
+        if key.len() > MAX_ANNOTATION_KEY_LEN {
+            return Err(AnnotationError::KeyTooLong);
+        }
+        if value.len() > MAX_ANNOTATION_VALUE_LEN {
+            return Err(AnnotationError::ValueTooLong);
+        }
+
+        let mut tokens = self.tokens.write().await;
+        let token = tokens.get_mut(trade_pubkey).ok_or(AnnotationError::NotRegistered)?;
+        if token.annotations.len() >= MAX_ANNOTATIONS && !token.annotations.contains_key(key) {
+            return Err(AnnotationError::TooMany);
+        }
+        token.annotations.insert(key.to_string(), value.to_string());
+        self.publish_upsert(trade_pubkey, token);
+        Ok(())
+    }
+
+    async fn remove_annotation(&self, trade_pubkey: &str, key: &str) -> Result<bool, AnnotationError> {
+        let mut tokens = self.tokens.write().await;
+        let token = tokens.get_mut(trade_pubkey).ok_or(AnnotationError::NotRegistered)?;
+        let removed = token.annotations.remove(key).is_some();
+        if removed {
+            self.publish_upsert(trade_pubkey, token);
+        }
+        Ok(removed)
     }
 
-    pub async fn cleanup_expired(&self) -> usize {
+    async fn reencrypt(
+        &self,
+        trade_pubkey: &str,
+        device_token: &str,
+        envelope_version: u8,
+    ) -> Result<(), ReencryptError> {
+        let mut tokens = self.tokens.write().await;
+        let token = tokens.get_mut(trade_pubkey).ok_or(ReencryptError::NotRegistered)?;
+        if token.device_token != device_token {
+            return Err(ReencryptError::TokenMismatch);
+        }
+
+        if token.envelope_version != envelope_version {
+            token.envelope_history.push(EnvelopeUpgrade {
+                from: token.envelope_version,
+                to: envelope_version,
+                at: Utc::now(),
+            });
+            token.envelope_version = envelope_version;
+            self.generation.fetch_add(1, Ordering::Relaxed);
+            self.publish_upsert(trade_pubkey, token);
+        }
+        Ok(())
+    }
+
+    async fn export(&self) -> Vec<(String, RegisteredToken)> {
+        let tokens = self.tokens.read().await;
+        let mut entries: Vec<_> = tokens.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    async fn import(
+        &self,
+        trade_pubkey: String,
+        token: RegisteredToken,
+        policy: ConflictPolicy,
+        dry_run: bool,
+    ) -> ImportOutcome {
+        let mut tokens = self.tokens.write().await;
+        let outcome = match (tokens.get(&trade_pubkey), policy) {
+            (None, _) => ImportOutcome::Inserted,
+            (Some(_), ConflictPolicy::Overwrite) => ImportOutcome::Overwritten,
+            (Some(existing), ConflictPolicy::Newest) if token.registered_at > existing.registered_at => {
+                ImportOutcome::Overwritten
+            }
+            (Some(_), _) => ImportOutcome::Skipped,
+        };
+
+        if !dry_run && outcome != ImportOutcome::Skipped {
+            self.publish_upsert(&trade_pubkey, &token);
+            tokens.insert(trade_pubkey, token);
+            self.generation.fetch_add(1, Ordering::Relaxed);
+        }
+        outcome
+    }
+
+    async fn cleanup_expired(&self) -> usize {
         let mut tokens = self.tokens.write().await;
         let now = Utc::now();
-        let ttl = chrono::Duration::hours(self.ttl_hours as i64);
         
-        let initial_count = tokens.len();
-        tokens.retain(|_, token| {
-            now.signed_duration_since(token.registered_at) < ttl
-        });
+        let expired = tokens.retain(|_, token| !self.is_expired(token, now));
+        // Published like an unregister, so caches and standbys drop them too
+        for trade_pubkey in &expired {
+            self.publish(|| StoreChange::Remove { trade_pubkey: trade_pubkey.clone() });
+        }
         
-        let removed = initial_count - tokens.len();
+        let removed = expired.len();
         if removed > 0 {
+            self.generation.fetch_add(1, Ordering::Relaxed);
+            self.expired.fetch_add(removed as u64, Ordering::Relaxed);
             info!("Cleaned up {} expired tokens (remaining: {})", removed, tokens.len());
         }
         
         removed
     }
 
-    pub async fn count(&self) -> usize {
+    async fn claim_event(&self, event_id: &str) -> bool {
+        let claimed = self.delivered.insert(event_id);
+        if claimed {
+            self.publish(|| StoreChange::EventClaimed { event_id: event_id.to_string() });
+        }
+        claimed
+    }
+
+    async fn is_claimed(&self, event_id: &str) -> bool {
+        self.delivered.contains(event_id)
+    }
+
+    async fn snapshot(&self) -> Vec<StoreChange> {
+        let mut changes: Vec<StoreChange> = self.export().await
+            .into_iter()
+            .map(|(trade_pubkey, token)| StoreChange::Upsert { trade_pubkey, token })
+            .collect();
+        changes.extend(
+            self.delivered.ids().into_iter().map(|event_id| StoreChange::EventClaimed { event_id }),
+        );
+        changes
+    }
+
+    async fn clear(&self) {
+        self.tokens.write().await.clear();
+        self.delivered.clear();
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    async fn count(&self) -> usize {
         self.tokens.read().await.len()
     }
 
-    pub async fn get_stats(&self) -> TokenStoreStats {
+    async fn get_stats(&self) -> TokenStoreStats {
         let tokens = self.tokens.read().await;
+        let now = Utc::now();
         let mut android_count = 0;
         let mut ios_count = 0;
+        let mut web_count = 0;
+        let mut envelope_v2_count = 0;
         
-        for token in tokens.values() {
+        // Expired registrations are already hidden from `get`, swept or not
+        let live = tokens.values().filter(|token| !self.is_expired(token, now));
+        let mut total = 0;
+        for token in live {
+            total += 1;
             match token.platform {
                 Platform::Android => android_count += 1,
                 Platform::Ios => ios_count += 1,
+                Platform::Web => web_count += 1,
+            }
+            if token.envelope_version >= ENVELOPE_V2 {
+                envelope_v2_count += 1;
             }
         }
         
         TokenStoreStats {
-            total: tokens.len(),
+            total,
             android: android_count,
             ios: ios_count,
+            web: web_count,
+            envelope_v2: envelope_v2_count,
+            expired: Some(self.expired.load(Ordering::Relaxed)),
         }
     }
-}
 
-#[derive(Debug, Clone, serde::Serialize)]
-pub struct TokenStoreStats {
-    pub total: usize,
-    pub android: usize,
-    pub ios: usize,
+    fn subscribe(&self) -> broadcast::Receiver<StoreChange> {
+        self.changes.subscribe()
+    }
 }
 
-pub fn start_cleanup_task(store: std::sync::Arc<TokenStore>, interval_hours: u64) {
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(
-            tokio::time::Duration::from_secs(interval_hours * 3600)
-        );
-        
-        loop {
-            interval.tick().await;
+/// Shortest cleanup interval, reached once the store holds `CLEANUP_FULL_LOAD` registrations.
+const CLEANUP_MIN_INTERVAL: Duration = Duration::from_secs(600);
+const CLEANUP_FULL_LOAD: u64 = 100_000;
+
+/// Removes expired tokens every `interval_mins`, more often as the store grows.
+pub fn cleanup_task(store: std::sync::Arc<dyn TokenStore>, interval_mins: u64) -> Task {
+    let load_store = store.clone();
+    Task::every("cleanup", Duration::from_secs(interval_mins * 60), move || {
+        let store = store.clone();
+        async move {
             let removed = store.cleanup_expired().await;
             if removed > 0 {
                 warn!("Periodic cleanup removed {} expired tokens", removed);
             }
         }
-    });
+    })
+    .adapting(CLEANUP_MIN_INTERVAL, CLEANUP_FULL_LOAD, move || {
+        let store = load_store.clone();
+        async move { store.count().await as u64 }
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Store that records which pubkeys were looked up, over an in-memory
+    /// store so registrations behave as usual.
+    pub(crate) struct MockTokenStore {
+        inner: MemoryTokenStore,
+        lookups: Mutex<Vec<String>>,
+        lookup_delay: std::time::Duration,
+    }
+
+    impl MockTokenStore {
+        pub(crate) fn new() -> Self {
+            Self {
+                inner: MemoryTokenStore::new(48),
+                lookups: Mutex::new(Vec::new()),
+                lookup_delay: std::time::Duration::ZERO,
+            }
+        }
+
+        /// Make every lookup take `delay`, like a slow backing store.
+        pub(crate) fn with_lookup_delay(mut self, delay: std::time::Duration) -> Self {
+            self.lookup_delay = delay;
+            self
+        }
+
+        pub(crate) fn lookups(&self) -> Vec<String> {
+            self.lookups.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl TokenStore for MockTokenStore {
+        async fn register_token(&self, trade_pubkey: String, token: RegisteredToken) -> bool {
+            self.inner.register_token(trade_pubkey, token).await
+        }
+
+        async fn unregister(&self, trade_pubkey: &str) -> Option<RegisteredToken> {
+            self.inner.unregister(trade_pubkey).await
+        }
+
+        async fn get(&self, trade_pubkey: &str) -> Option<RegisteredToken> {
+            self.lookups.lock().unwrap().push(trade_pubkey.to_string());
+            tokio::time::sleep(self.lookup_delay).await;
+            self.inner.get(trade_pubkey).await
+        }
+
+        async fn devices(&self, store_key: &str, max_devices: usize) -> Vec<(String, RegisteredToken)> {
+            self.lookups.lock().unwrap().push(store_key.to_string());
+            tokio::time::sleep(self.lookup_delay).await;
+            self.inner.devices(store_key, max_devices).await
+        }
+
+        async fn pubkeys_for_token(&self, device_token: &str) -> HashSet<String> {
+            self.inner.pubkeys_for_token(device_token).await
+        }
+
+        async fn set_annotation(&self, trade_pubkey: &str, key: &str, value: &str) -> Result<(), AnnotationError> {
+            self.inner.set_annotation(trade_pubkey, key, value).await
+        }
+
+        async fn remove_annotation(&self, trade_pubkey: &str, key: &str) -> Result<bool, AnnotationError> {
+            self.inner.remove_annotation(trade_pubkey, key).await
+        }
+
+        async fn reencrypt(&self, trade_pubkey: &str, device_token: &str, envelope_version: u8) -> Result<(), ReencryptError> {
+            self.inner.reencrypt(trade_pubkey, device_token, envelope_version).await
+        }
+
+        async fn export(&self) -> Vec<(String, RegisteredToken)> {
+            self.inner.export().await
+        }
+
+        async fn import(
+            &self,
+            trade_pubkey: String,
+            token: RegisteredToken,
+            policy: ConflictPolicy,
+            dry_run: bool,
+        ) -> ImportOutcome {
+            self.inner.import(trade_pubkey, token, policy, dry_run).await
+        }
+
+        async fn cleanup_expired(&self) -> usize {
+            self.inner.cleanup_expired().await
+        }
+
+        async fn claim_event(&self, event_id: &str) -> bool {
+            self.inner.claim_event(event_id).await
+        }
+
+        async fn is_claimed(&self, event_id: &str) -> bool {
+            self.inner.is_claimed(event_id).await
+        }
+
+        async fn snapshot(&self) -> Vec<StoreChange> {
+            self.inner.snapshot().await
+        }
+
+        async fn clear(&self) {
+            self.inner.clear().await
+        }
+
+        fn generation(&self) -> u64 {
+            self.inner.generation()
+        }
+
+        async fn count(&self) -> usize {
+            self.inner.count().await
+        }
+
+        async fn get_stats(&self) -> TokenStoreStats {
+            self.inner.get_stats().await
+        }
+
+        fn subscribe(&self) -> broadcast::Receiver<StoreChange> {
+            self.inner.subscribe()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUBKEY: &str = "a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2";
+
+    #[tokio::test]
+    async fn test_annotation_limits() {
+        let store = MemoryTokenStore::new(48);
+        assert_eq!(
+            store.set_annotation(PUBKEY, "note", "x").await,
+            Err(AnnotationError::NotRegistered)
+        );
+
+        store.register(PUBKEY.to_string(), "token".to_string(), Platform::Android).await;
+        assert_eq!(store.set_annotation(PUBKEY, "", "x").await, Err(AnnotationError::EmptyKey));
+        assert_eq!(
+            store.set_annotation(PUBKEY, &"k".repeat(MAX_ANNOTATION_KEY_LEN + 1), "x").await,
+            Err(AnnotationError::KeyTooLong)
+        );
+        assert_eq!(
+            store.set_annotation(PUBKEY, "note", &"v".repeat(MAX_ANNOTATION_VALUE_LEN + 1)).await,
+            Err(AnnotationError::ValueTooLong)
+        );
+
+        for i in 0..MAX_ANNOTATIONS {
+            store.set_annotation(PUBKEY, &format!("key{}", i), "value").await.unwrap();
+        }
+        assert_eq!(store.set_annotation(PUBKEY, "one-more", "x").await, Err(AnnotationError::TooMany));
+        // Overwriting an existing key is still allowed at the limit
+        store.set_annotation(PUBKEY, "key0", "updated").await.unwrap();
+
+        assert_eq!(store.remove_annotation(PUBKEY, "key0").await, Ok(true));
+        assert_eq!(store.remove_annotation(PUBKEY, "key0").await, Ok(false));
+        store.set_annotation(PUBKEY, "one-more", "x").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_annotations_persist_across_reregistration() {
+        let store = MemoryTokenStore::new(48);
+        store.register(PUBKEY.to_string(), "token-1".to_string(), Platform::Android).await;
+        store.set_annotation(PUBKEY, "support", "missed pushes 2024-05-01").await.unwrap();
+
+        store.register(PUBKEY.to_string(), "token-2".to_string(), Platform::Android).await;
+        let token = store.get(PUBKEY).await.unwrap();
+        assert_eq!(token.device_token, "token-2");
+        assert_eq!(token.annotations["support"], "missed pushes 2024-05-01");
+        assert_eq!(token.annotations_label(), " [support=\"missed pushes 2024-05-01\"]");
+
+        // A pubkey that unregisters starts over
+        store.unregister(PUBKEY).await;
+        store.register(PUBKEY.to_string(), "token-3".to_string(), Platform::Android).await;
+        assert!(store.get(PUBKEY).await.unwrap().annotations.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_expired_registrations_are_hidden_before_cleanup() {
+        const OTHER_PUBKEY: &str = "b1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2";
+        let store = MemoryTokenStore::with_ttl_secs(3600);
+        let stale = || {
+            let mut token = RegisteredToken::new("token".to_string(), Platform::Android);
+            token.registered_at -= chrono::Duration::hours(2);
+            token
+        };
+        store.register_token(PUBKEY.to_string(), stale()).await;
+        store.register_token(OTHER_PUBKEY.to_string(), stale()).await;
+
+        // Past the TTL but not yet swept: still stored, no longer returned or counted
+        assert_eq!(store.get(PUBKEY).await, None);
+        assert_eq!(store.count().await, 2);
+        assert_eq!(store.get_stats().await.total, 0);
+
+        // Registering again restarts the TTL
+        store.register(PUBKEY.to_string(), "token".to_string(), Platform::Android).await;
+        assert!(store.get(PUBKEY).await.is_some());
+
+        // The sweep is published as a removal, for caches and standbys
+        let mut changes = store.subscribe();
+        assert_eq!(store.cleanup_expired().await, 1);
+        let stats = store.get_stats().await;
+        assert_eq!((stats.total, stats.expired), (1, Some(1)));
+        assert!(matches!(
+            changes.try_recv(),
+            Ok(StoreChange::Remove { trade_pubkey }) if trade_pubkey == OTHER_PUBKEY
+        ));
+    }
+
+    #[test]
+    fn test_registered_token_serialization() {
+        let mut token = RegisteredToken::new("token".to_string(), Platform::Ios);
+        let json = serde_json::to_value(&token).unwrap();
+        assert!(json.get("annotations").is_none());
+
+        token.annotations.insert("beta".to_string(), "tester".to_string());
+        let json = serde_json::to_string(&token).unwrap();
+        assert_eq!(serde_json::from_str::<RegisteredToken>(&json).unwrap(), token);
+
+        // Records written before annotations existed still load
+        let legacy = r#"{"device_token":"t","platform":"android","registered_at":"2024-05-01T00:00:00Z"}"#;
+        assert!(serde_json::from_str::<RegisteredToken>(legacy).unwrap().annotations.is_empty());
+    }
 }
@@ -0,0 +1,523 @@
+//! Registrations kept in Redis, so every instance behind a load balancer sees
+//! the registrations any of them accepted.
+//!
+//! Each registration is a JSON value under `mostro-push:token:<store key>`,
+//! expiring with the token TTL, so Redis does the cleanup. A set per device
+//! token (keyed by its hash) lists the pubkeys registered with it. Claimed
+//! event ids are keys too, so every instance skips an event any of them
+//! handled. The change stream and `generation` stay per instance.
+
+use async_trait::async_trait;
+use chrono::Utc;
+use log::{error, info, warn};
+use redis::aio::ConnectionManager;
+use redis::{Cmd, FromRedisValue, RedisResult};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+use crate::crypto::{Platform, ENVELOPE_V2};
+use crate::models::{ConflictPolicy, TokenStoreStats};
+use super::{
+    AnnotationError, EnvelopeUpgrade, ImportOutcome, ReencryptError, RegisteredToken, StoreChange, TokenStore,
+    CHANGE_BUFFER, MAX_ANNOTATIONS, MAX_ANNOTATION_KEY_LEN, MAX_ANNOTATION_VALUE_LEN,
+};
+
+const TOKEN_PREFIX: &str = "mostro-push:token:";
+const DEVICE_PREFIX: &str = "mostro-push:device:";
+const EVENT_PREFIX: &str = "mostro-push:event:";
+/// How long a claimed event stays claimed; far longer than relays keep
+/// handing out an event, or than `CATCHUP_MAX_AGE_SECS` would push it
+const CLAIM_TTL_SECS: u64 = 86_400;
+/// Retries of a command that failed on the connection, each waiting longer
+const RETRIES: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+/// Keys fetched per SCAN and MGET round trip
+const BATCH_SIZE: usize = 500;
+
+pub struct RedisTokenStore {
+    /// Reconnects on its own after Redis drops the connection
+    connection: ConnectionManager,
+    ttl_secs: u64,
+    generation: AtomicU64,
+    changes: broadcast::Sender<StoreChange>,
+}
+
+fn token_key(trade_pubkey: &str) -> String {
+    format!("{}{}", TOKEN_PREFIX, trade_pubkey)
+}
+
+/// Device tokens are as sensitive as the registration, so only a hash names the set.
+fn device_key(device_token: &str) -> String {
+    format!("{}{}", DEVICE_PREFIX, ::hex::encode(&Sha256::digest(device_token.as_bytes())[..16]))
+}
+
+/// Seconds until a registration made at `token.registered_at` expires; at least 1.
+fn expiry_secs(token: &RegisteredToken, ttl_secs: u64) -> u64 {
+    let age = Utc::now().signed_duration_since(token.registered_at).num_seconds().max(0) as u64;
+    ttl_secs.saturating_sub(age).max(1)
+}
+
+fn parse(trade_pubkey: &str, value: &str) -> Option<RegisteredToken> {
+    serde_json::from_str(value)
+        .inspect_err(|e| warn!("Ignoring unreadable registration {}: {}", token_key(trade_pubkey), e))
+        .ok()
+}
+
+/// Whether a failed command may succeed on a fresh connection.
+fn is_transient(e: &redis::RedisError) -> bool {
+    e.is_io_error() || e.is_connection_dropped() || e.is_connection_refusal() || e.is_timeout()
+}
+
+impl RedisTokenStore {
+    pub async fn connect(url: &str, ttl_secs: u64) -> RedisResult<Self> {
+        let connection = ConnectionManager::new(redis::Client::open(url)?).await?;
+        info!("Connected to Redis token store");
+        Ok(Self {
+            connection,
+            ttl_secs,
+            generation: AtomicU64::new(0),
+            changes: broadcast::channel(CHANGE_BUFFER).0,
+        })
+    }
+
+    /// Run `cmd`, retrying with backoff while Redis is unreachable.
+    async fn query<T: FromRedisValue>(&self, cmd: &Cmd) -> RedisResult<T> {
+        let mut attempt = 0;
+        loop {
+            let mut connection = self.connection.clone();
+            match cmd.query_async(&mut connection).await {
+                Err(e) if attempt < RETRIES && is_transient(&e) => {
+                    attempt += 1;
+                    warn!("Redis command failed, retrying ({}/{}): {}", attempt, RETRIES, e);
+                    tokio::time::sleep(RETRY_DELAY * attempt).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn read(&self, trade_pubkey: &str) -> Option<RegisteredToken> {
+        match self.query::<Option<String>>(Cmd::new().arg("GET").arg(token_key(trade_pubkey))).await {
+            Ok(value) => value.and_then(|value| parse(trade_pubkey, &value)),
+            Err(e) => {
+                error!("Failed to read registration from Redis: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Store `token`, expiring `ttl` seconds from now or keeping the current
+    /// expiry when None, and index it under its device token.
+    async fn write(&self, trade_pubkey: &str, token: &RegisteredToken, ttl: Option<u64>) {
+        let value = match serde_json::to_string(token) {
+            Ok(value) => value,
+            Err(e) => {
+                error!("Failed to serialize registration: {}", e);
+                return;
+            }
+        };
+        let mut set = Cmd::new();
+        set.arg("SET").arg(token_key(trade_pubkey)).arg(value);
+        match ttl {
+            Some(secs) => set.arg("EX").arg(secs),
+            None => set.arg("KEEPTTL"),
+        };
+        let device_key = device_key(&token.device_token);
+        let result = async {
+            self.query::<()>(&set).await?;
+            self.query::<()>(Cmd::new().arg("SADD").arg(&device_key).arg(trade_pubkey)).await?;
+            // The index outlives no registration in it by more than a TTL
+            self.query::<()>(Cmd::new().arg("EXPIRE").arg(&device_key).arg(self.ttl_secs)).await
+        }
+        .await;
+        match result {
+            Ok(()) => {
+                self.generation.fetch_add(1, Ordering::Relaxed);
+                self.publish(|| StoreChange::Upsert {
+                    trade_pubkey: trade_pubkey.to_string(),
+                    token: token.clone(),
+                });
+            }
+            Err(e) => error!("Failed to write registration to Redis: {}", e),
+        }
+    }
+
+    /// Drop `trade_pubkey` from the index of a device token it no longer uses.
+    async fn unindex(&self, trade_pubkey: &str, device_token: &str) {
+        let cmd = Cmd::new().arg("SREM").arg(device_key(device_token)).arg(trade_pubkey).clone();
+        if let Err(e) = self.query::<()>(&cmd).await {
+            warn!("Failed to update Redis device index: {}", e);
+        }
+    }
+
+    /// Every key matching `pattern`, through SCAN so Redis isn't blocked.
+    async fn scan(&self, pattern: &str) -> RedisResult<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut cursor = 0u64;
+        loop {
+            let (next, batch): (u64, Vec<String>) = self
+                .query(Cmd::new().arg("SCAN").arg(cursor).arg("MATCH").arg(pattern).arg("COUNT").arg(BATCH_SIZE))
+                .await?;
+            keys.extend(batch);
+            if next == 0 {
+                return Ok(keys);
+            }
+            cursor = next;
+        }
+    }
+
+    /// Every registration, unsorted.
+    async fn all(&self) -> RedisResult<Vec<(String, RegisteredToken)>> {
+        let keys = self.scan(&format!("{}*", TOKEN_PREFIX)).await?;
+        let mut entries = Vec::with_capacity(keys.len());
+        for chunk in keys.chunks(BATCH_SIZE) {
+            let values: Vec<Option<String>> = self.query(Cmd::new().arg("MGET").arg(chunk)).await?;
+            for (key, value) in chunk.iter().zip(values) {
+                let trade_pubkey = &key[TOKEN_PREFIX.len()..];
+                // Expired between SCAN and MGET
+                if let Some(token) = value.and_then(|value| parse(trade_pubkey, &value)) {
+                    entries.push((trade_pubkey.to_string(), token));
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    fn publish(&self, change: impl FnOnce() -> StoreChange) {
+        if self.changes.receiver_count() > 0 {
+            let _ = self.changes.send(change());
+        }
+    }
+}
+
+#[async_trait]
+impl TokenStore for RedisTokenStore {
+    async fn register_token(&self, trade_pubkey: String, mut token: RegisteredToken) -> bool {
+        // Annotations belong to the pubkey, so they survive token refreshes
+        let previous = self.read(&trade_pubkey).await;
+        let is_new = previous.is_none();
+        if let Some(previous) = previous {
+            if previous.device_token != token.device_token {
+                self.unindex(&trade_pubkey, &previous.device_token).await;
+            }
+            token.annotations = previous.annotations;
+            token.envelope_history = previous.envelope_history;
+        }
+        self.write(&trade_pubkey, &token, Some(self.ttl_secs)).await;
+        is_new
+    }
+
+    async fn unregister(&self, trade_pubkey: &str) -> Option<RegisteredToken> {
+        let removed = match self.query::<Option<String>>(Cmd::new().arg("GETDEL").arg(token_key(trade_pubkey))).await {
+            Ok(value) => value.and_then(|value| parse(trade_pubkey, &value)),
+            Err(e) => {
+                error!("Failed to remove registration from Redis: {}", e);
+                None
+            }
+        };
+        if let Some(removed) = &removed {
+            self.unindex(trade_pubkey, &removed.device_token).await;
+            self.generation.fetch_add(1, Ordering::Relaxed);
+            self.publish(|| StoreChange::Remove { trade_pubkey: trade_pubkey.to_string() });
+        }
+        removed
+    }
+
+    async fn get(&self, trade_pubkey: &str) -> Option<RegisteredToken> {
+        self.read(trade_pubkey).await
+    }
+
+    async fn devices(&self, store_key: &str, max_devices: usize) -> Vec<(String, RegisteredToken)> {
+        // One round trip for every slot
+        let keys: Vec<String> = (0..max_devices.max(1)).map(|slot| super::device_key(store_key, slot)).collect();
+        let redis_keys: Vec<String> = keys.iter().map(|key| token_key(key)).collect();
+        match self.query::<Vec<Option<String>>>(Cmd::new().arg("MGET").arg(redis_keys)).await {
+            Ok(values) => keys
+                .into_iter()
+                .zip(values)
+                .filter_map(|(key, value)| {
+                    let token = value.and_then(|value| parse(&key, &value))?;
+                    Some((key, token))
+                })
+                .collect(),
+            Err(e) => {
+                error!("Failed to read registrations from Redis: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    async fn pubkeys_for_token(&self, device_token: &str) -> HashSet<String> {
+        let keys: Vec<String> = match self.query(Cmd::new().arg("SMEMBERS").arg(device_key(device_token))).await {
+            Ok(keys) => keys,
+            Err(e) => {
+                error!("Failed to read Redis device index: {}", e);
+                return HashSet::new();
+            }
+        };
+        let mut pubkeys = HashSet::new();
+        for key in keys {
+            // Members whose registration expired or moved to another token are stale
+            match self.read(&key).await {
+                Some(token) if token.device_token == device_token => {
+                    pubkeys.insert(super::trade_pubkey_of(&key).to_string());
+                }
+                _ => self.unindex(&key, device_token).await,
+            }
+        }
+        pubkeys
+    }
+
+    async fn set_annotation(&self, trade_pubkey: &str, key: &str, value: &str) -> Result<(), AnnotationError> {
+        if key.is_empty() {
+            return Err(AnnotationError::EmptyKey);
+        }
+        if key.len() > MAX_ANNOTATION_KEY_LEN {
+            return Err(AnnotationError::KeyTooLong);
+        }
+        if value.len() > MAX_ANNOTATION_VALUE_LEN {
+            return Err(AnnotationError::ValueTooLong);
+        }
+
+        let mut token = self.read(trade_pubkey).await.ok_or(AnnotationError::NotRegistered)?;
+        if token.annotations.len() >= MAX_ANNOTATIONS && !token.annotations.contains_key(key) {
+            return Err(AnnotationError::TooMany);
+        }
+        token.annotations.insert(key.to_string(), value.to_string());
+        self.write(trade_pubkey, &token, None).await;
+        Ok(())
+    }
+
+    async fn remove_annotation(&self, trade_pubkey: &str, key: &str) -> Result<bool, AnnotationError> {
+        let mut token = self.read(trade_pubkey).await.ok_or(AnnotationError::NotRegistered)?;
+        let removed = token.annotations.remove(key).is_some();
+        if removed {
+            self.write(trade_pubkey, &token, None).await;
+        }
+        Ok(removed)
+    }
+
+    async fn reencrypt(&self, trade_pubkey: &str, device_token: &str, envelope_version: u8) -> Result<(), ReencryptError> {
+        let mut token = self.read(trade_pubkey).await.ok_or(ReencryptError::NotRegistered)?;
+        if token.device_token != device_token {
+            return Err(ReencryptError::TokenMismatch);
+        }
+
+        if token.envelope_version != envelope_version {
+            token.envelope_history.push(EnvelopeUpgrade {
+                from: token.envelope_version,
+                to: envelope_version,
+                at: Utc::now(),
+            });
+            token.envelope_version = envelope_version;
+            self.write(trade_pubkey, &token, None).await;
+        }
+        Ok(())
+    }
+
+    async fn export(&self) -> Vec<(String, RegisteredToken)> {
+        let mut entries = self.all().await.unwrap_or_else(|e| {
+            error!("Failed to export registrations from Redis: {}", e);
+            Vec::new()
+        });
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    async fn import(
+        &self,
+        trade_pubkey: String,
+        token: RegisteredToken,
+        policy: ConflictPolicy,
+        dry_run: bool,
+    ) -> ImportOutcome {
+        let existing = self.read(&trade_pubkey).await;
+        let outcome = match (&existing, policy) {
+            (None, _) => ImportOutcome::Inserted,
+            (Some(_), ConflictPolicy::Overwrite) => ImportOutcome::Overwritten,
+            (Some(existing), ConflictPolicy::Newest) if token.registered_at > existing.registered_at => {
+                ImportOutcome::Overwritten
+            }
+            (Some(_), _) => ImportOutcome::Skipped,
+        };
+
+        if !dry_run && outcome != ImportOutcome::Skipped {
+            if let Some(existing) = existing.filter(|existing| existing.device_token != token.device_token) {
+                self.unindex(&trade_pubkey, &existing.device_token).await;
+            }
+            // Imported registrations keep their age, and so their expiry
+            let ttl = expiry_secs(&token, self.ttl_secs);
+            self.write(&trade_pubkey, &token, Some(ttl)).await;
+        }
+        outcome
+    }
+
+    /// Redis expires registrations itself.
+    async fn cleanup_expired(&self) -> usize {
+        0
+    }
+
+    /// Atomic across instances: only the first SET NX of an event succeeds.
+    /// If Redis is unreachable the event is handled anyway, as a duplicate
+    /// push beats a missed one.
+    async fn claim_event(&self, event_id: &str) -> bool {
+        let claim = Cmd::new()
+            .arg("SET")
+            .arg(format!("{}{}", EVENT_PREFIX, event_id))
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(CLAIM_TTL_SECS)
+            .clone();
+        let claimed = match self.query::<Option<String>>(&claim).await {
+            Ok(reply) => reply.is_some(),
+            Err(e) => {
+                warn!("Failed to claim event in Redis, handling it anyway: {}", e);
+                true
+            }
+        };
+        if claimed {
+            self.publish(|| StoreChange::EventClaimed { event_id: event_id.to_string() });
+        }
+        claimed
+    }
+
+    async fn is_claimed(&self, event_id: &str) -> bool {
+        let exists = Cmd::new().arg("EXISTS").arg(format!("{}{}", EVENT_PREFIX, event_id)).clone();
+        match self.query::<bool>(&exists).await {
+            Ok(exists) => exists,
+            Err(e) => {
+                error!("Failed to read event claim from Redis: {}", e);
+                false
+            }
+        }
+    }
+
+    async fn snapshot(&self) -> Vec<StoreChange> {
+        // Claims live in Redis, where every instance already sees them
+        self.export().await
+            .into_iter()
+            .map(|(trade_pubkey, token)| StoreChange::Upsert { trade_pubkey, token })
+            .collect()
+    }
+
+    async fn clear(&self) {
+        for prefix in [TOKEN_PREFIX, DEVICE_PREFIX, EVENT_PREFIX] {
+            let result = async {
+                for chunk in self.scan(&format!("{}*", prefix)).await?.chunks(BATCH_SIZE) {
+                    self.query::<()>(Cmd::new().arg("DEL").arg(chunk)).await?;
+                }
+                Ok::<_, redis::RedisError>(())
+            }
+            .await;
+            if let Err(e) = result {
+                error!("Failed to clear Redis keys {}*: {}", prefix, e);
+            }
+        }
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts this instance's writes only, so views keyed by it (the
+    /// `/api/status` cache, `CachedTokenStore`) see other instances' writes
+    /// only once their own TTL runs out.
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    async fn count(&self) -> usize {
+        match self.scan(&format!("{}*", TOKEN_PREFIX)).await {
+            Ok(keys) => keys.len(),
+            Err(e) => {
+                error!("Failed to count registrations in Redis: {}", e);
+                0
+            }
+        }
+    }
+
+    async fn get_stats(&self) -> TokenStoreStats {
+        let entries = self.all().await.unwrap_or_else(|e| {
+            error!("Failed to read registration stats from Redis: {}", e);
+            Vec::new()
+        });
+        let (mut android, mut ios, mut web, mut envelope_v2) = (0, 0, 0, 0);
+        for (_, token) in &entries {
+            match token.platform {
+                Platform::Android => android += 1,
+                Platform::Ios => ios += 1,
+                Platform::Web => web += 1,
+            }
+            if token.envelope_version >= ENVELOPE_V2 {
+                envelope_v2 += 1;
+            }
+        }
+        // Redis expires registrations itself, without telling us
+        TokenStoreStats { total: entries.len(), android, ios, web, envelope_v2, expired: None }
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<StoreChange> {
+        self.changes.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys_are_namespaced_and_imports_keep_their_expiry() {
+        let pubkey = "a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2";
+        assert_eq!(token_key(pubkey), format!("mostro-push:token:{}", pubkey));
+        let device = device_key("fcm-token");
+        assert!(device.starts_with(DEVICE_PREFIX) && !device.contains("fcm-token"));
+        assert_eq!(device, device_key("fcm-token"));
+
+        let mut token = RegisteredToken::new("fcm-token".to_string(), Platform::Android);
+        assert_eq!(expiry_secs(&token, 48 * 3600), 48 * 3600);
+        token.registered_at = Utc::now() - chrono::Duration::hours(47);
+        assert!((3590..=3600).contains(&expiry_secs(&token, 48 * 3600)));
+        token.registered_at = Utc::now() - chrono::Duration::hours(49);
+        assert_eq!(expiry_secs(&token, 48 * 3600), 1);
+    }
+
+    /// Needs a disposable Redis at `TEST_REDIS_URL`; skipped without one.
+    #[tokio::test]
+    async fn test_registrations_round_trip_through_redis() {
+        let Ok(url) = std::env::var("TEST_REDIS_URL") else {
+            eprintln!("TEST_REDIS_URL unset, skipping");
+            return;
+        };
+        let store = RedisTokenStore::connect(&url, 3600).await.unwrap();
+        // Random pubkeys, so runs don't see each other's keys
+        let pubkey = ::hex::encode(rand::random::<[u8; 32]>());
+        let second = super::super::device_key(&pubkey, 1);
+        let device_token = format!("fcm-token-{}", pubkey);
+
+        assert!(store.register(pubkey.clone(), device_token.clone(), Platform::Android).await);
+        assert!(store.register(second.clone(), "apns-token".to_string(), Platform::Ios).await);
+        assert!(!store.register(pubkey.clone(), device_token.clone(), Platform::Android).await);
+        let token = store.get(&pubkey).await.unwrap();
+        assert_eq!((token.device_token.as_str(), token.platform), (device_token.as_str(), Platform::Android));
+        assert_eq!(store.pubkeys_for_token(&device_token).await, HashSet::from([pubkey.clone()]));
+
+        let devices = store.devices(&pubkey, 3).await;
+        let keys: Vec<&str> = devices.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, [pubkey.as_str(), second.as_str()]);
+
+        assert_eq!(store.unregister(&pubkey).await.map(|token| token.device_token), Some(device_token.clone()));
+        assert_eq!(store.unregister(&pubkey).await, None);
+        assert_eq!(store.get(&pubkey).await, None);
+        assert!(store.pubkeys_for_token(&device_token).await.is_empty());
+        store.unregister(&second).await;
+        assert!(store.devices(&pubkey, 3).await.is_empty());
+
+        // A second instance over the same Redis sees the first one's claim
+        let other = RedisTokenStore::connect(&url, 3600).await.unwrap();
+        assert!(!other.is_claimed(&pubkey).await);
+        assert!(store.claim_event(&pubkey).await);
+        assert!(!other.claim_event(&pubkey).await);
+        assert!(other.is_claimed(&pubkey).await);
+    }
+}
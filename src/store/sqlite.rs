@@ -0,0 +1,332 @@
+//! Registrations kept in a SQLite file, so restarts and crashes don't drop
+//! every device. All rows are loaded into memory on open and reads are
+//! served from there; every change is written through to the file, on the
+//! blocking pool, before the call returns. Claimed event ids stay in memory
+//! only.
+
+use async_trait::async_trait;
+use chrono::Utc;
+use log::{info, warn};
+use rusqlite::{params, Connection};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::{broadcast, Mutex, OwnedMutexGuard};
+
+use crate::models::{ConflictPolicy, TokenStoreStats};
+use super::{
+    AnnotationError, ImportOutcome, MemoryTokenStore, ReencryptError, RegisteredToken, StoreChange, TokenStore,
+};
+
+/// Schema changes, applied in order; `PRAGMA user_version` counts those applied.
+const MIGRATIONS: &[&str] = &["CREATE TABLE registrations (
+        trade_pubkey TEXT PRIMARY KEY,
+        device_token TEXT NOT NULL,
+        platform TEXT NOT NULL,
+        -- Unix milliseconds, for expiry
+        created_at INTEGER NOT NULL,
+        -- The full registration as JSON
+        registration TEXT NOT NULL
+    )"];
+
+pub struct SqliteTokenStore {
+    inner: MemoryTokenStore,
+    /// Held across each change and its write, so the file sees changes in
+    /// the order the memory did
+    db: Arc<Mutex<Connection>>,
+}
+
+/// The connection, locked for one change and its write.
+type Db = OwnedMutexGuard<Connection>;
+
+impl SqliteTokenStore {
+    /// Open or create the database at `path`, migrate it and load every row.
+    pub fn open(path: &Path, ttl_secs: u64) -> Result<Self, Box<dyn std::error::Error>> {
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut db = Connection::open(path)?;
+        db.pragma_update(None, "journal_mode", "WAL")?;
+        migrate(&mut db)?;
+
+        let mut inner = MemoryTokenStore::with_ttl_secs(ttl_secs);
+        let rows: Vec<(String, String)> = db
+            .prepare("SELECT trade_pubkey, registration FROM registrations")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        let mut loaded = 0;
+        for (trade_pubkey, registration) in rows {
+            match serde_json::from_str::<RegisteredToken>(&registration) {
+                Ok(token) => {
+                    inner.tokens.get_mut().insert(trade_pubkey, token);
+                    loaded += 1;
+                }
+                Err(e) => warn!("Skipping unreadable registration in {}: {}", path.display(), e),
+            }
+        }
+        info!("Loaded {} registration(s) from {}", loaded, path.display());
+
+        Ok(Self { inner, db: Arc::new(Mutex::new(db)) })
+    }
+
+    async fn lock(&self) -> Db {
+        self.db.clone().lock_owned().await
+    }
+
+    /// Write the pubkey's registration as memory now holds it, releasing
+    /// the lock once written.
+    async fn persist(&self, db: Db, trade_pubkey: &str) {
+        // Not `get`: an expired registration is still held until cleanup,
+        // so its row stays too
+        let token = self.inner.tokens.read().await.get(trade_pubkey).cloned();
+        let failure = format!("Failed to persist registration for {}...", &trade_pubkey[..16.min(trade_pubkey.len())]);
+        let trade_pubkey = trade_pubkey.to_string();
+        write(db, failure, move |db| match token {
+            Some(token) => write_row(db, &trade_pubkey, &token),
+            None => db
+                .execute("DELETE FROM registrations WHERE trade_pubkey = ?1", params![trade_pubkey])
+                .map(|_| ()),
+        })
+        .await;
+    }
+}
+
+/// Run a blocking write off the async runtime, keeping `db` locked until
+/// it is done, and log it as `failure` if it fails.
+async fn write<F>(db: Db, failure: String, write: F)
+where
+    F: FnOnce(&Connection) -> rusqlite::Result<()> + Send + 'static,
+{
+    let result = tokio::task::spawn_blocking(move || write(&db)).await;
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => warn!("{}: {}", failure, e),
+        Err(e) => warn!("{}: {}", failure, e),
+    }
+}
+
+fn migrate(db: &mut Connection) -> rusqlite::Result<()> {
+    let applied: usize = db.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+        let tx = db.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", version + 1)?;
+        tx.commit()?;
+        info!("Applied database migration {}", version + 1);
+    }
+    Ok(())
+}
+
+fn write_row(db: &Connection, trade_pubkey: &str, token: &RegisteredToken) -> rusqlite::Result<()> {
+    let registration = serde_json::to_string(token).map_err(|e| rusqlite::Error::ToSqlConversionFailure(e.into()))?;
+    db.execute(
+        "INSERT INTO registrations (trade_pubkey, device_token, platform, created_at, registration)
+         VALUES (?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT (trade_pubkey) DO UPDATE SET
+             device_token = excluded.device_token,
+             platform = excluded.platform,
+             created_at = excluded.created_at,
+             registration = excluded.registration",
+        params![
+            trade_pubkey,
+            token.device_token,
+            token.platform.to_string(),
+            token.registered_at.timestamp_millis(),
+            registration,
+        ],
+    )?;
+    Ok(())
+}
+
+#[async_trait]
+impl TokenStore for SqliteTokenStore {
+    async fn register_token(&self, trade_pubkey: String, token: RegisteredToken) -> bool {
+        let db = self.lock().await;
+        let is_new = self.inner.register_token(trade_pubkey.clone(), token).await;
+        self.persist(db, &trade_pubkey).await;
+        is_new
+    }
+
+    async fn unregister(&self, trade_pubkey: &str) -> Option<RegisteredToken> {
+        let db = self.lock().await;
+        let removed = self.inner.unregister(trade_pubkey).await;
+        if removed.is_some() {
+            self.persist(db, trade_pubkey).await;
+        }
+        removed
+    }
+
+    async fn get(&self, trade_pubkey: &str) -> Option<RegisteredToken> {
+        self.inner.get(trade_pubkey).await
+    }
+
+    async fn devices(&self, store_key: &str, max_devices: usize) -> Vec<(String, RegisteredToken)> {
+        self.inner.devices(store_key, max_devices).await
+    }
+
+    async fn pubkeys_for_token(&self, device_token: &str) -> std::collections::HashSet<String> {
+        self.inner.pubkeys_for_token(device_token).await
+    }
+
+    async fn set_annotation(&self, trade_pubkey: &str, key: &str, value: &str) -> Result<(), AnnotationError> {
+        let db = self.lock().await;
+        self.inner.set_annotation(trade_pubkey, key, value).await?;
+        self.persist(db, trade_pubkey).await;
+        Ok(())
+    }
+
+    async fn remove_annotation(&self, trade_pubkey: &str, key: &str) -> Result<bool, AnnotationError> {
+        let db = self.lock().await;
+        let removed = self.inner.remove_annotation(trade_pubkey, key).await?;
+        if removed {
+            self.persist(db, trade_pubkey).await;
+        }
+        Ok(removed)
+    }
+
+    async fn reencrypt(&self, trade_pubkey: &str, device_token: &str, envelope_version: u8) -> Result<(), ReencryptError> {
+        let db = self.lock().await;
+        self.inner.reencrypt(trade_pubkey, device_token, envelope_version).await?;
+        self.persist(db, trade_pubkey).await;
+        Ok(())
+    }
+
+    async fn export(&self) -> Vec<(String, RegisteredToken)> {
+        self.inner.export().await
+    }
+
+    async fn import(
+        &self,
+        trade_pubkey: String,
+        token: RegisteredToken,
+        policy: ConflictPolicy,
+        dry_run: bool,
+    ) -> ImportOutcome {
+        let db = self.lock().await;
+        let outcome = self.inner.import(trade_pubkey.clone(), token, policy, dry_run).await;
+        if !dry_run && outcome != ImportOutcome::Skipped {
+            self.persist(db, &trade_pubkey).await;
+        }
+        outcome
+    }
+
+    async fn cleanup_expired(&self) -> usize {
+        let db = self.lock().await;
+        let removed = self.inner.cleanup_expired().await;
+        if removed > 0 {
+            let cutoff = (Utc::now() - self.inner.ttl).timestamp_millis();
+            let failure = "Failed to remove expired registrations from the database".to_string();
+            write(db, failure, move |db| {
+                db.execute("DELETE FROM registrations WHERE created_at <= ?1", params![cutoff]).map(|_| ())
+            })
+            .await;
+        }
+        removed
+    }
+
+    async fn claim_event(&self, event_id: &str) -> bool {
+        self.inner.claim_event(event_id).await
+    }
+
+    async fn is_claimed(&self, event_id: &str) -> bool {
+        self.inner.is_claimed(event_id).await
+    }
+
+    async fn snapshot(&self) -> Vec<StoreChange> {
+        self.inner.snapshot().await
+    }
+
+    async fn clear(&self) {
+        let db = self.lock().await;
+        self.inner.clear().await;
+        write(db, "Failed to clear the database".to_string(), |db| {
+            db.execute("DELETE FROM registrations", []).map(|_| ())
+        })
+        .await;
+    }
+
+    fn generation(&self) -> u64 {
+        self.inner.generation()
+    }
+
+    async fn count(&self) -> usize {
+        self.inner.count().await
+    }
+
+    async fn get_stats(&self) -> TokenStoreStats {
+        self.inner.get_stats().await
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<StoreChange> {
+        self.inner.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Platform;
+    use rusqlite::OptionalExtension;
+
+    const PUBKEY: &str = "a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2";
+    const OTHER_PUBKEY: &str = "b1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2";
+
+    /// Whether the file holds a row for `trade_pubkey`.
+    fn row_exists(path: &Path, trade_pubkey: &str) -> bool {
+        Connection::open(path)
+            .unwrap()
+            .query_row("SELECT 1 FROM registrations WHERE trade_pubkey = ?1", params![trade_pubkey], |_| Ok(()))
+            .optional()
+            .unwrap()
+            .is_some()
+    }
+
+    #[tokio::test]
+    async fn test_registrations_survive_reopening_the_database() {
+        let dir = std::env::temp_dir().join(format!("mostro-push-sqlite-{}", rand::random::<u64>()));
+        let path = dir.join("registrations.db");
+
+        let store = SqliteTokenStore::open(&path, 48 * 3600).unwrap();
+        assert!(store.register(PUBKEY.to_string(), "fcm-token".to_string(), Platform::Android).await);
+        store.register(OTHER_PUBKEY.to_string(), "apns-token".to_string(), Platform::Ios).await;
+        store.set_annotation(PUBKEY, "cohort", "beta").await.unwrap();
+        store.unregister(OTHER_PUBKEY).await;
+        assert!(!row_exists(&path, OTHER_PUBKEY));
+        drop(store);
+
+        // A second store over the same file sees what the first wrote
+        let reopened = SqliteTokenStore::open(&path, 48 * 3600).unwrap();
+        let token = reopened.get(PUBKEY).await.unwrap();
+        assert_eq!((token.device_token.as_str(), token.platform), ("fcm-token", Platform::Android));
+        assert_eq!(token.annotations.get("cohort").map(String::as_str), Some("beta"));
+        assert_eq!(reopened.get(OTHER_PUBKEY).await, None);
+        let stats = reopened.get_stats().await;
+        assert_eq!((stats.total, stats.android, stats.ios), (1, 1, 0));
+
+        // Reopening an up-to-date database doesn't migrate it again
+        assert!(!reopened.register(PUBKEY.to_string(), "fcm-token-2".to_string(), Platform::Android).await);
+        drop(reopened);
+        let reopened = SqliteTokenStore::open(&path, 48 * 3600).unwrap();
+        assert_eq!(reopened.get(PUBKEY).await.unwrap().device_token, "fcm-token-2");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_expired_registrations_keep_their_row_until_cleanup() {
+        let dir = std::env::temp_dir().join(format!("mostro-push-sqlite-{}", rand::random::<u64>()));
+        let path = dir.join("registrations.db");
+        let store = SqliteTokenStore::open(&path, 3600).unwrap();
+
+        // Imported already past its TTL: hidden, but held in memory, so on disk too
+        let mut stale = RegisteredToken::new("fcm-token".to_string(), Platform::Android);
+        stale.registered_at -= chrono::Duration::hours(2);
+        store.import(PUBKEY.to_string(), stale, ConflictPolicy::Skip, false).await;
+        assert_eq!(store.get(PUBKEY).await, None);
+        assert!(row_exists(&path, PUBKEY));
+
+        assert_eq!(store.cleanup_expired().await, 1);
+        assert!(!row_exists(&path, PUBKEY));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
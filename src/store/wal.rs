@@ -0,0 +1,308 @@
+//! Registration log: an append-only record of every store write, hash-chained
+//! so tampering with any record breaks every hash after it.
+//!
+//! Records identify pubkeys by their SHA-256 hash, so a user can find their own
+//! registrations without the log revealing anyone else's. The head hash can be
+//! anchored by publishing it as a Nostr note; `verify-wal` checks the chain and
+//! that every anchored head is still where the log says it was.
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use nostr_sdk::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, LineWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+use crate::crypto::Platform;
+use super::{StoreChange, TokenStore};
+
+/// `prev` of the first record.
+pub const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Hashtag marking anchor notes, so they can be fetched by filter.
+pub const ANCHOR_HASHTAG: &str = "mostro-push-wal";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WalOp {
+    Upsert,
+    Remove,
+    /// Changes the recorder missed; the chain stays intact but incomplete
+    Gap,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WalRecord {
+    pub seq: u64,
+    pub at: DateTime<Utc>,
+    pub op: WalOp,
+    /// SHA-256 of the trade pubkey; empty for gaps
+    pub pubkey_hash: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Platform>,
+    /// Hash of the previous record
+    pub prev: String,
+    pub hash: String,
+}
+
+impl WalRecord {
+    /// Hash over every other field, the previous hash included.
+    pub fn compute_hash(&self) -> String {
+        let platform = self.platform.as_ref().map(ToString::to_string).unwrap_or_default();
+        let op = serde_json::to_string(&self.op).unwrap_or_default();
+        let mut hasher = Sha256::new();
+        for field in [
+            self.seq.to_string(),
+            self.at.to_rfc3339(),
+            op,
+            self.pubkey_hash.clone(),
+            platform,
+            self.prev.clone(),
+        ] {
+            hasher.update(field.as_bytes());
+            hasher.update([0]);
+        }
+        ::hex::encode(hasher.finalize())
+    }
+}
+
+struct Head {
+    file: LineWriter<File>,
+    seq: u64,
+    hash: String,
+}
+
+pub struct Wal {
+    head: Mutex<Head>,
+}
+
+impl Wal {
+    /// Open the log at `path`, continuing the chain from its last record.
+    pub fn open(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let (seq, hash) = match path.exists() {
+            true => read_wal(path)?
+                .last()
+                .map_or((0, GENESIS.to_string()), |last| (last.seq + 1, last.hash.clone())),
+            false => (0, GENESIS.to_string()),
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            head: Mutex::new(Head { file: LineWriter::new(file), seq, hash }),
+        })
+    }
+
+    pub fn append(
+        &self,
+        op: WalOp,
+        trade_pubkey: Option<&str>,
+        platform: Option<Platform>,
+        at: DateTime<Utc>,
+    ) -> std::io::Result<WalRecord> {
+        let mut head = self.head.lock().unwrap();
+        let mut record = WalRecord {
+            seq: head.seq,
+            at,
+            op,
+            pubkey_hash: trade_pubkey.map(hash_pubkey).unwrap_or_default(),
+            platform,
+            prev: head.hash.clone(),
+            hash: String::new(),
+        };
+        record.hash = record.compute_hash();
+        writeln!(head.file, "{}", serde_json::to_string(&record)?)?;
+        head.seq += 1;
+        head.hash = record.hash.clone();
+        Ok(record)
+    }
+
+    /// Sequence number and hash of the last record, if any.
+    pub fn head(&self) -> Option<(u64, String)> {
+        let head = self.head.lock().unwrap();
+        head.seq.checked_sub(1).map(|seq| (seq, head.hash.clone()))
+    }
+}
+
+pub fn hash_pubkey(trade_pubkey: &str) -> String {
+    ::hex::encode(Sha256::digest(trade_pubkey.as_bytes()))
+}
+
+/// Append every registration change the store reports.
+pub fn spawn_recorder(wal: Arc<Wal>, store: Arc<dyn TokenStore>) {
+    let mut changes = store.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let result = match changes.recv().await {
+                Ok(StoreChange::Upsert { trade_pubkey, token }) => {
+                    wal.append(WalOp::Upsert, Some(&trade_pubkey), Some(token.platform), Utc::now())
+                }
+                Ok(StoreChange::Remove { trade_pubkey }) => {
+                    wal.append(WalOp::Remove, Some(&trade_pubkey), None, Utc::now())
+                }
+                Ok(StoreChange::EventClaimed { .. }) => continue,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("Registration log missed {} changes, recording a gap", skipped);
+                    wal.append(WalOp::Gap, None, None, Utc::now())
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            if let Err(e) = result {
+                warn!("Failed to append to the registration log: {}", e);
+            }
+        }
+    });
+}
+
+/// Read a log file, skipping blank lines.
+pub fn read_wal(path: &Path) -> Result<Vec<WalRecord>, Box<dyn std::error::Error>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str(&line)
+            .map_err(|e| format!("{}:{}: {}", path.display(), number + 1, e))?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// A published head: the log had record `seq` with hash `head` by `at`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Anchor {
+    pub seq: u64,
+    pub head: String,
+    pub at: DateTime<Utc>,
+}
+
+/// A signed text note anchoring the log's head.
+pub fn anchor_event(keys: &Keys, seq: u64, head: &str) -> Result<Event, nostr_sdk::event::builder::Error> {
+    let tags = [
+        Tag::Hashtag(ANCHOR_HASHTAG.to_string()),
+        Tag::Generic(TagKind::Custom("wal-seq".to_string()), vec![seq.to_string()]),
+        Tag::Generic(TagKind::Custom("wal-head".to_string()), vec![head.to_string()]),
+    ];
+    let content = format!("Mostro push registration log head: record {} is {}", seq, head);
+    EventBuilder::text_note(content, tags).to_event(keys)
+}
+
+/// The anchor an event carries, if it is a validly signed anchor by `author`.
+pub fn parse_anchor(event: &Event, author: &XOnlyPublicKey) -> Option<Anchor> {
+    if event.pubkey != *author || event.kind != Kind::TextNote || event.verify().is_err() {
+        return None;
+    }
+    let tag_value = |name: &str| {
+        event.tags.iter().find_map(|tag| {
+            let tag = tag.as_vec();
+            (tag[0] == name).then(|| tag.get(1).cloned()).flatten()
+        })
+    };
+    Some(Anchor {
+        seq: tag_value("wal-seq")?.parse().ok()?,
+        head: tag_value("wal-head")?,
+        at: DateTime::from_timestamp(event.created_at.as_i64(), 0)?,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WalVerification {
+    pub records: usize,
+    /// First record whose hash or link doesn't hold; the chain is intact when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub broken_at: Option<u64>,
+    pub gaps: usize,
+    pub anchors_matched: usize,
+    /// Anchored heads the log no longer contains
+    pub anchors_mismatched: Vec<Anchor>,
+}
+
+impl WalVerification {
+    pub fn is_valid(&self) -> bool {
+        self.broken_at.is_none() && self.anchors_mismatched.is_empty()
+    }
+}
+
+/// Check every record's hash and link, then each anchor against the record
+/// it names.
+pub fn verify(records: &[WalRecord], anchors: &[Anchor]) -> WalVerification {
+    let mut prev = GENESIS;
+    let mut broken_at = None;
+    for (index, record) in records.iter().enumerate() {
+        if record.seq != index as u64 || record.prev != prev || record.hash != record.compute_hash() {
+            broken_at = Some(index as u64);
+            break;
+        }
+        prev = &record.hash;
+    }
+
+    let (matched, mismatched): (Vec<_>, Vec<_>) = anchors
+        .iter()
+        .cloned()
+        .partition(|anchor| records.get(anchor.seq as usize).is_some_and(|r| r.hash == anchor.head));
+
+    WalVerification {
+        records: records.len(),
+        broken_at,
+        gaps: records.iter().filter(|r| r.op == WalOp::Gap).count(),
+        anchors_matched: matched.len(),
+        anchors_mismatched: mismatched,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUBKEY: &str = "a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4e5f6a1b2";
+
+    #[test]
+    fn test_chain_detects_modified_record_and_stale_anchor() {
+        let path = std::env::temp_dir().join(format!("mostro-push-wal-{}.jsonl", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let wal = Wal::open(&path).unwrap();
+        let t0 = Utc::now();
+        wal.append(WalOp::Upsert, Some(PUBKEY), Some(Platform::Android), t0).unwrap();
+        wal.append(WalOp::Upsert, Some(PUBKEY), Some(Platform::Ios), t0).unwrap();
+        drop(wal);
+
+        // Reopening continues the chain
+        let wal = Wal::open(&path).unwrap();
+        wal.append(WalOp::Remove, Some(PUBKEY), None, t0).unwrap();
+        let (seq, head) = wal.head().unwrap();
+        assert_eq!(seq, 2);
+        let records = read_wal(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(records[0].prev, GENESIS);
+        assert_eq!(records[1].pubkey_hash, hash_pubkey(PUBKEY));
+
+        let keys = Keys::generate();
+        let anchor = parse_anchor(&anchor_event(&keys, seq, &head).unwrap(), &keys.public_key()).unwrap();
+        let report = verify(&records, std::slice::from_ref(&anchor));
+        assert!(report.is_valid(), "{:?}", report);
+        assert_eq!((report.records, report.anchors_matched), (3, 1));
+
+        // Only the configured identity's anchors count
+        let impostor = Keys::generate();
+        assert!(parse_anchor(&anchor_event(&impostor, seq, &head).unwrap(), &keys.public_key()).is_none());
+
+        // Rewriting the middle record, even with a fresh hash, breaks the link after it
+        let mut tampered = records.clone();
+        tampered[1].platform = Some(Platform::Android);
+        assert_eq!(verify(&tampered, &[]).broken_at, Some(1));
+        tampered[1].hash = tampered[1].compute_hash();
+        assert_eq!(verify(&tampered, &[]).broken_at, Some(2));
+
+        // Recomputing the whole chain is caught by the published anchor
+        tampered[2].prev = tampered[1].hash.clone();
+        tampered[2].hash = tampered[2].compute_hash();
+        let report = verify(&tampered, &[anchor]);
+        assert_eq!(report.broken_at, None);
+        assert_eq!(report.anchors_mismatched.len(), 1);
+        assert!(!report.is_valid());
+    }
+}
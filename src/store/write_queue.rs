@@ -0,0 +1,84 @@
+use log::warn;
+use serde::Deserialize;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+use crate::alerts::RegistrationAlerts;
+use crate::metrics::Metrics;
+use super::{trade_pubkey_of, RegisteredToken, TokenStore};
+
+/// When `/api/register` reports success relative to the store write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WriteMode {
+    /// Respond after the registration is stored
+    Durable,
+    /// Respond 202 once the write is queued; it is applied shortly after
+    Accepted,
+}
+
+impl FromStr for WriteMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "durable" => Ok(WriteMode::Durable),
+            "accepted" => Ok(WriteMode::Accepted),
+            other => Err(format!("Invalid write mode '{}' (expected durable or accepted)", other)),
+        }
+    }
+}
+
+struct PendingWrite {
+    trade_pubkey: String,
+    token: RegisteredToken,
+    /// The pubkey had no registration in any environment when accepted
+    first_registration: bool,
+}
+
+/// Registrations accepted but not yet written, applied in order by a background task.
+pub struct WriteQueue {
+    sender: mpsc::UnboundedSender<PendingWrite>,
+    metrics: Arc<Metrics>,
+}
+
+impl WriteQueue {
+    /// Spawn the writer task. First-registration alerts fire once the write lands.
+    pub fn start(
+        store: Arc<dyn TokenStore>,
+        metrics: Arc<Metrics>,
+        alerts: Arc<RegistrationAlerts>,
+    ) -> Self {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<PendingWrite>();
+        let depth = metrics.clone();
+        tokio::spawn(async move {
+            while let Some(write) = receiver.recv().await {
+                let platform = write.token.platform.clone();
+                let is_new = store.register_token(write.trade_pubkey.clone(), write.token).await;
+                depth.register_write_queue_depth.fetch_sub(1, Ordering::Relaxed);
+                let first_registration = write.first_registration && is_new;
+                alerts.on_registered(trade_pubkey_of(&write.trade_pubkey), &platform, first_registration);
+            }
+        });
+
+        Self { sender, metrics }
+    }
+
+    pub fn enqueue(&self, trade_pubkey: String, token: RegisteredToken, first_registration: bool) -> bool {
+        self.metrics.register_write_queue_depth.fetch_add(1, Ordering::Relaxed);
+        let write = PendingWrite { trade_pubkey, token, first_registration };
+        let queued = self.sender.send(write).is_ok();
+        if !queued {
+            warn!("Registration write queue is closed, dropping write");
+            self.metrics.register_write_queue_depth.fetch_sub(1, Ordering::Relaxed);
+        }
+        queued
+    }
+
+    /// Writes queued but not yet applied.
+    pub fn depth(&self) -> u64 {
+        Metrics::get(&self.metrics.register_write_queue_depth)
+    }
+}
@@ -15,6 +15,10 @@ impl BatchingManager {
         }
     }
 
+    pub fn batch_delay_ms(&self) -> u64 {
+        self.batch_delay_ms
+    }
+
     pub async fn should_send(&mut self) -> bool {
         // Check if there's already a pending send
         if self.pending_send.is_some() {
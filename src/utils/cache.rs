@@ -0,0 +1,73 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Single-value cache for computed API responses.
+///
+/// An entry is served while it is younger than the TTL and was computed at
+/// the same source generation the caller currently observes.
+pub struct TtlCache<T: Clone> {
+    ttl: Duration,
+    entry: Mutex<Option<CacheEntry<T>>>,
+}
+
+struct CacheEntry<T> {
+    value: T,
+    stored_at: Instant,
+    generation: u64,
+}
+
+impl<T: Clone> TtlCache<T> {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entry: Mutex::new(None),
+        }
+    }
+
+    pub fn get(&self, generation: u64) -> Option<T> {
+        let entry = self.entry.lock().unwrap();
+        entry
+            .as_ref()
+            .filter(|e| e.generation == generation && e.stored_at.elapsed() < self.ttl)
+            .map(|e| e.value.clone())
+    }
+
+    pub fn put(&self, value: T, generation: u64) {
+        *self.entry.lock().unwrap() = Some(CacheEntry {
+            value,
+            stored_at: Instant::now(),
+            generation,
+        });
+    }
+
+    pub fn invalidate(&self) {
+        *self.entry.lock().unwrap() = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry_expires_after_ttl() {
+        let cache = TtlCache::new(Duration::from_millis(20));
+        cache.put(1u32, 0);
+        assert_eq!(cache.get(0), Some(1));
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(cache.get(0), None);
+    }
+
+    #[test]
+    fn test_generation_change_invalidates() {
+        let cache = TtlCache::new(Duration::from_secs(60));
+        cache.put("stats", 3);
+        assert_eq!(cache.get(3), Some("stats"));
+        assert_eq!(cache.get(4), None);
+
+        cache.put("stats", 4);
+        cache.invalidate();
+        assert_eq!(cache.get(4), None);
+    }
+}
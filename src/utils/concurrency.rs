@@ -0,0 +1,55 @@
+//! Process-wide cap on requests being processed at once, so a flood of
+//! expensive requests queues briefly and is then shed instead of tying up
+//! every worker and the blocking pool.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+pub struct ConcurrencyLimit {
+    /// Unlimited when unset
+    slots: Option<Arc<Semaphore>>,
+    /// How long a request waits for a slot before it is shed
+    wait: Duration,
+    /// Requests holding a slot, or being processed when unlimited
+    in_flight: Arc<AtomicU64>,
+}
+
+/// A request's slot, released when dropped.
+pub struct Slot {
+    _permit: Option<OwnedSemaphorePermit>,
+    in_flight: Arc<AtomicU64>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ConcurrencyLimit {
+    /// At most `limit` requests at once; unlimited when 0.
+    pub fn new(limit: usize, wait: Duration) -> Self {
+        Self {
+            slots: (limit > 0).then(|| Arc::new(Semaphore::new(limit))),
+            wait,
+            in_flight: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Take a slot, waiting up to the configured time for one to free up.
+    /// Returns None when the wait ran out.
+    pub async fn acquire(&self) -> Option<Slot> {
+        let permit = match &self.slots {
+            Some(slots) => Some(tokio::time::timeout(self.wait, slots.clone().acquire_owned()).await.ok()?.ok()?),
+            None => None,
+        };
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        Some(Slot { _permit: permit, in_flight: self.in_flight.clone() })
+    }
+
+    pub fn in_flight(&self) -> u64 {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+}
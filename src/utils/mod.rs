@@ -1 +1,8 @@
 pub mod batching;
+pub mod cache;
+pub mod concurrency;
+pub mod rate;
+#[cfg(feature = "redis")]
+pub mod redis_rate;
+pub mod signing;
+pub mod time_buckets;
@@ -0,0 +1,176 @@
+//! Rate limiting: a process-wide cap on decrypts, and per-client request
+//! limits behind `RequestLimiter`, kept in memory or, for deployments with
+//! several instances, in Redis so a client can't spread across them.
+
+use async_trait::async_trait;
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::RateLimitConfig;
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Process-wide cap on operations per second, over a sliding one-second
+/// window. Without a limit it only measures the rate.
+pub struct RateLimiter {
+    limit: Option<u32>,
+    /// Operation times within the window, oldest first
+    recent: Mutex<VecDeque<Instant>>,
+}
+
+impl RateLimiter {
+    pub fn per_second(limit: Option<u32>) -> Self {
+        Self {
+            limit: limit.filter(|limit| *limit > 0),
+            recent: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Count `count` operations if the limit allows all of them, or return
+    /// how long until it might.
+    pub fn try_acquire(&self, now: Instant, count: usize) -> Result<(), Duration> {
+        let mut recent = self.recent.lock().unwrap();
+        prune(&mut recent, now);
+        if let Some(limit) = self.limit {
+            if recent.len() + count > limit as usize {
+                let oldest = recent.front().copied().unwrap_or(now);
+                return Err(WINDOW - now.duration_since(oldest));
+            }
+        }
+        recent.extend(std::iter::repeat_n(now, count));
+        Ok(())
+    }
+
+    /// Operations counted in the second before `now`.
+    pub fn rate(&self, now: Instant) -> u64 {
+        let mut recent = self.recent.lock().unwrap();
+        prune(&mut recent, now);
+        recent.len() as u64
+    }
+}
+
+fn prune(recent: &mut VecDeque<Instant>, now: Instant) {
+    while recent.front().is_some_and(|t| now.duration_since(*t) >= WINDOW) {
+        recent.pop_front();
+    }
+}
+
+/// Keys a `MemoryRequestLimiter` tracks before dropping those from past windows.
+const MAX_TRACKED_KEYS: usize = 100_000;
+
+/// Requests per key per fixed window, e.g. per client IP or per pubkey.
+#[async_trait]
+pub trait RequestLimiter: Send + Sync {
+    /// Count a request against `key`, or return how long until it may retry.
+    async fn check(&self, key: &str) -> Result<(), Duration>;
+}
+
+/// Where request limit counters live.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+pub enum RateLimitBackend {
+    /// Per process; each instance enforces the limit on its own
+    #[default]
+    Memory,
+    /// Shared by every instance pointed at the same Redis
+    Redis,
+}
+
+impl FromStr for RateLimitBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "memory" => Ok(Self::Memory),
+            "redis" => Ok(Self::Redis),
+            other => Err(format!("Invalid rate limit backend '{}' (expected memory or redis)", other)),
+        }
+    }
+}
+
+pub struct MemoryRequestLimiter {
+    /// Requests per window per key; unlimited at 0
+    limit: u32,
+    window: Duration,
+    /// Start of each key's current window and its count in it
+    counts: Mutex<HashMap<String, (Instant, u32)>>,
+}
+
+impl MemoryRequestLimiter {
+    pub fn per_minute(limit: u32) -> Self {
+        Self::new(limit, Duration::from_secs(60))
+    }
+
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self { limit, window, counts: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn check_at(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        if self.limit == 0 {
+            return Ok(());
+        }
+        let mut counts = self.counts.lock().unwrap();
+        if counts.len() >= MAX_TRACKED_KEYS {
+            counts.retain(|_, (start, _)| now.duration_since(*start) < self.window);
+        }
+        let (start, count) = counts.entry(key.to_string()).or_insert((now, 0));
+        if now.duration_since(*start) >= self.window {
+            (*start, *count) = (now, 0);
+        }
+        if *count >= self.limit {
+            return Err(self.window - now.duration_since(*start));
+        }
+        *count += 1;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl RequestLimiter for MemoryRequestLimiter {
+    async fn check(&self, key: &str) -> Result<(), Duration> {
+        self.check_at(key, Instant::now())
+    }
+}
+
+/// The request limiter `config` selects. Redis needs the `redis` feature and
+/// `REDIS_URL`.
+pub async fn request_limiter(config: &RateLimitConfig) -> Result<Arc<dyn RequestLimiter>, String> {
+    match config.backend {
+        RateLimitBackend::Memory => Ok(Arc::new(MemoryRequestLimiter::per_minute(config.max_per_minute))),
+        #[cfg(feature = "redis")]
+        RateLimitBackend::Redis => {
+            let url = config.redis_url.as_deref().ok_or("RATE_LIMIT_BACKEND=redis needs REDIS_URL")?;
+            let limiter = super::redis_rate::RedisRequestLimiter::connect(url, config.max_per_minute, Duration::from_secs(60))
+                .await
+                .map_err(|e| format!("Failed to connect to Redis: {}", e))?;
+            Ok(Arc::new(limiter))
+        }
+        #[cfg(not(feature = "redis"))]
+        RateLimitBackend::Redis => Err("RATE_LIMIT_BACKEND=redis needs a build with the redis feature".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_limiter_enforces_per_key_windows() {
+        let limiter = MemoryRequestLimiter::per_minute(3);
+        let t0 = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at("ip:203.0.113.7", t0), Ok(()));
+        }
+        let later = t0 + Duration::from_secs(20);
+        assert_eq!(limiter.check_at("ip:203.0.113.7", later), Err(Duration::from_secs(40)));
+        // Other keys have their own budget
+        assert_eq!(limiter.check_at("ip:203.0.113.8", later), Ok(()));
+
+        // The next window starts afresh
+        assert_eq!(limiter.check_at("ip:203.0.113.7", t0 + Duration::from_secs(60)), Ok(()));
+
+        let unlimited = MemoryRequestLimiter::per_minute(0);
+        assert!((0..1000).all(|_| unlimited.check_at("ip:203.0.113.7", t0).is_ok()));
+    }
+}
@@ -0,0 +1,60 @@
+//! Request limits shared across instances through Redis. Each key counts in
+//! a fixed window with `INCR`, and the counter expires with the window, so
+//! every instance sees the same total.
+
+use async_trait::async_trait;
+use log::warn;
+use redis::aio::ConnectionManager;
+use sha2::{Digest, Sha256};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::rate::RequestLimiter;
+
+const KEY_PREFIX: &str = "mostro-push:rate";
+
+pub struct RedisRequestLimiter {
+    connection: ConnectionManager,
+    /// Requests per window per key; unlimited at 0
+    limit: u32,
+    window: Duration,
+}
+
+impl RedisRequestLimiter {
+    pub async fn connect(url: &str, limit: u32, window: Duration) -> redis::RedisResult<Self> {
+        let connection = ConnectionManager::new(redis::Client::open(url)?).await?;
+        Ok(Self { connection, limit, window })
+    }
+}
+
+#[async_trait]
+impl RequestLimiter for RedisRequestLimiter {
+    /// Allows the request when Redis can't be reached: an outage shouldn't
+    /// lock every client out.
+    async fn check(&self, key: &str) -> Result<(), Duration> {
+        if self.limit == 0 {
+            return Ok(());
+        }
+        let window = self.window.as_secs().max(1);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        // Keys carry IPs and pubkeys, which Redis needn't see
+        let digest = Sha256::digest(key.as_bytes());
+        let counter = format!("{}:{}:{}", KEY_PREFIX, ::hex::encode(&digest[..16]), now / window);
+
+        let mut connection = self.connection.clone();
+        let result: redis::RedisResult<(u32,)> = redis::pipe()
+            .atomic()
+            .incr(&counter, 1)
+            .expire(&counter, window as i64)
+            .ignore()
+            .query_async(&mut connection)
+            .await;
+        match result {
+            Ok((count,)) if count > self.limit => Err(Duration::from_secs(window - now % window)),
+            Ok(_) => Ok(()),
+            Err(e) => {
+                warn!("Redis rate limit check failed, allowing request: {}", e);
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,193 @@
+//! Signatures on outbound HTTP requests, for operators whose push gateway or
+//! webhook receiver wants the server to prove who it is instead of trusting
+//! a static bearer token.
+//!
+//! A signed request carries two headers:
+//! - `X-Mostro-Timestamp`: unix seconds when the request was signed
+//! - `X-Mostro-Signature`: hex HMAC-SHA256, keyed with the shared secret, over
+//!   `"{timestamp}\n{METHOD}\n{path and query}\n{hex SHA-256 of the body}"`
+//!
+//! The gateway recomputes the signature, compares it in constant time and
+//! rejects timestamps further than its skew window (`DEFAULT_MAX_SKEW` here)
+//! from its own clock, in either direction since either clock may run ahead.
+//! A stale timestamp means a replayed or badly delayed request. Replays inside
+//! the window are only caught if the gateway remembers signatures it has seen
+//! for that long.
+
+use hmac::{Hmac, Mac};
+use reqwest::header::{HeaderMap, HeaderValue};
+use reqwest::{Client, Request, RequestBuilder, Response};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+pub const TIMESTAMP_HEADER: &str = "x-mostro-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-mostro-signature";
+/// Seconds a signature stays fresh on either side of the verifier's clock.
+pub const DEFAULT_MAX_SKEW: u64 = 300;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SignatureError {
+    /// A signature header is absent or unreadable
+    Missing,
+    /// The timestamp is outside the skew window
+    Stale,
+    Invalid,
+}
+
+impl std::fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignatureError::Missing => write!(f, "missing signature headers"),
+            SignatureError::Stale => write!(f, "signature timestamp outside the allowed window"),
+            SignatureError::Invalid => write!(f, "signature does not match"),
+        }
+    }
+}
+
+pub struct RequestSigner {
+    key: Vec<u8>,
+}
+
+impl RequestSigner {
+    pub fn new(key: &[u8]) -> Self {
+        Self { key: key.to_vec() }
+    }
+
+    fn mac(&self, timestamp: i64, method: &str, path: &str, body: &[u8]) -> Hmac<Sha256> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.key)
+            .expect("HMAC accepts keys of any length");
+        let body_hash = hex::encode(Sha256::digest(body));
+        mac.update(format!("{}\n{}\n{}\n{}", timestamp, method, path, body_hash).as_bytes());
+        mac
+    }
+
+    pub fn signature(&self, timestamp: i64, method: &str, path: &str, body: &[u8]) -> String {
+        hex::encode(self.mac(timestamp, method, path, body).finalize().into_bytes())
+    }
+
+    /// Add the timestamp and signature headers to `request`.
+    pub fn sign(&self, request: &mut Request, timestamp: i64) {
+        let path = match request.url().query() {
+            Some(query) => format!("{}?{}", request.url().path(), query),
+            None => request.url().path().to_string(),
+        };
+        let body = request.body().and_then(|body| body.as_bytes()).unwrap_or_default();
+        let signature = self.signature(timestamp, request.method().as_str(), &path, body);
+
+        let headers = request.headers_mut();
+        headers.insert(TIMESTAMP_HEADER, HeaderValue::from(timestamp));
+        headers.insert(
+            SIGNATURE_HEADER,
+            HeaderValue::from_str(&signature).expect("hex is a valid header value"),
+        );
+    }
+
+    /// Check a request's signature as a gateway would, with `now` from the
+    /// gateway's clock.
+    pub fn verify(
+        &self,
+        headers: &HeaderMap,
+        method: &str,
+        path: &str,
+        body: &[u8],
+        now: i64,
+        max_skew: u64,
+    ) -> Result<(), SignatureError> {
+        let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+        let timestamp: i64 = header(TIMESTAMP_HEADER)
+            .and_then(|value| value.parse().ok())
+            .ok_or(SignatureError::Missing)?;
+        let signature = header(SIGNATURE_HEADER)
+            .and_then(|value| hex::decode(value).ok())
+            .ok_or(SignatureError::Missing)?;
+
+        if now.abs_diff(timestamp) > max_skew {
+            return Err(SignatureError::Stale);
+        }
+        self.mac(timestamp, method, path, body)
+            .verify_slice(&signature)
+            .map_err(|_| SignatureError::Invalid)
+    }
+}
+
+/// HTTP client for push services and webhooks that signs each request sent
+/// through `send` when a signing key is configured. Requests sent straight
+/// from a builder (e.g. to third-party auth endpoints) stay unsigned.
+#[derive(Clone, Default)]
+pub struct SigningClient {
+    client: Client,
+    signer: Option<Arc<RequestSigner>>,
+}
+
+impl SigningClient {
+    pub fn new(key: Option<&str>) -> Self {
+        Self {
+            client: Client::new(),
+            signer: key.map(|key| Arc::new(RequestSigner::new(key.as_bytes()))),
+        }
+    }
+
+    pub fn post(&self, url: &str) -> RequestBuilder {
+        self.client.post(url)
+    }
+
+    pub fn head(&self, url: &str) -> RequestBuilder {
+        self.client.head(url)
+    }
+
+    pub async fn send(&self, request: RequestBuilder) -> reqwest::Result<Response> {
+        let request = self.prepare(request)?;
+        self.client.execute(request).await
+    }
+
+    /// The request `send` would execute, signed when a key is configured.
+    pub fn prepare(&self, request: RequestBuilder) -> reqwest::Result<Request> {
+        let mut request = request.build()?;
+        if let Some(signer) = &self.signer {
+            signer.sign(&mut request, chrono::Utc::now().timestamp());
+        }
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signed_request_headers_and_stale_timestamps() {
+        let signer = RequestSigner::new(b"gateway-secret");
+        let body = br#"{"message":"hi"}"#;
+        let mut request = Client::new()
+            .post("https://gateway.example/v1/send?dry=1")
+            .body(body.to_vec())
+            .build()
+            .unwrap();
+        signer.sign(&mut request, 1_700_000_000);
+
+        let headers = request.headers();
+        assert_eq!(headers[TIMESTAMP_HEADER], "1700000000");
+        let expected = signer.signature(1_700_000_000, "POST", "/v1/send?dry=1", body);
+        assert_eq!(headers[SIGNATURE_HEADER], expected.as_str());
+
+        let verify = |now, body: &[u8]| {
+            signer.verify(headers, "POST", "/v1/send?dry=1", body, now, DEFAULT_MAX_SKEW)
+        };
+        // Either clock may be a little ahead
+        assert_eq!(verify(1_700_000_000 + 299, body), Ok(()));
+        assert_eq!(verify(1_700_000_000 - 299, body), Ok(()));
+        // A replay after the window is detectably stale
+        assert_eq!(verify(1_700_000_000 + 301, body), Err(SignatureError::Stale));
+        assert_eq!(verify(1_700_000_000, b"{}"), Err(SignatureError::Invalid));
+
+        let other = RequestSigner::new(b"other-secret");
+        assert_eq!(
+            other.verify(headers, "POST", "/v1/send?dry=1", body, 1_700_000_000, DEFAULT_MAX_SKEW),
+            Err(SignatureError::Invalid)
+        );
+        assert_eq!(
+            signer.verify(&HeaderMap::new(), "POST", "/", body, 0, DEFAULT_MAX_SKEW),
+            Err(SignatureError::Missing)
+        );
+    }
+}
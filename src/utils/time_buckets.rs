@@ -0,0 +1,89 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Fixed-width time buckets over a bounded retention, for windowed aggregates.
+///
+/// Buckets are aligned to the creation time; a window query covers every bucket
+/// that overlaps the last `window`, so results are accurate to one bucket width.
+pub struct TimeBuckets<T: Default> {
+    width: Duration,
+    retention: usize,
+    origin: Instant,
+    /// (bucket index since origin, value), oldest first
+    buckets: Mutex<VecDeque<(u64, T)>>,
+}
+
+impl<T: Default> TimeBuckets<T> {
+    pub fn new(width: Duration, retention: Duration, origin: Instant) -> Self {
+        let retention = (retention.as_secs_f64() / width.as_secs_f64()).ceil().max(1.0) as usize;
+        Self {
+            width,
+            retention,
+            origin,
+            buckets: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Longest window a query can cover.
+    pub fn retention(&self) -> Duration {
+        self.width * self.retention as u32
+    }
+
+    fn index(&self, now: Instant) -> u64 {
+        (now.saturating_duration_since(self.origin).as_nanos() / self.width.as_nanos()) as u64
+    }
+
+    /// Update the bucket covering `now`.
+    pub fn update(&self, now: Instant, f: impl FnOnce(&mut T)) {
+        let index = self.index(now);
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.back().is_none_or(|(i, _)| *i < index) {
+            buckets.push_back((index, T::default()));
+        }
+        while buckets.front().is_some_and(|(i, _)| index - i >= self.retention as u64) {
+            buckets.pop_front();
+        }
+        // Late updates land in the newest bucket rather than reopening old ones
+        if let Some((_, value)) = buckets.back_mut() {
+            f(value);
+        }
+    }
+
+    /// Fold over the buckets overlapping the last `window` before `now`.
+    pub fn fold<A>(&self, now: Instant, window: Duration, init: A, mut f: impl FnMut(A, &T) -> A) -> A {
+        let newest = self.index(now);
+        let span = (window.as_nanos().div_ceil(self.width.as_nanos()) as u64).clamp(1, self.retention as u64);
+        let buckets = self.buckets.lock().unwrap();
+        buckets
+            .iter()
+            .filter(|(i, _)| *i <= newest && newest - i < span)
+            .fold(init, |acc, (_, value)| f(acc, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_covers_recent_buckets_only() {
+        let origin = Instant::now();
+        let buckets: TimeBuckets<u64> =
+            TimeBuckets::new(Duration::from_secs(60), Duration::from_secs(600), origin);
+
+        buckets.update(origin, |v| *v += 1);
+        buckets.update(origin + Duration::from_secs(30), |v| *v += 1);
+        buckets.update(origin + Duration::from_secs(120), |v| *v += 5);
+
+        let now = origin + Duration::from_secs(150);
+        let sum = |window| buckets.fold(now, window, 0, |acc, v| acc + v);
+        assert_eq!(sum(Duration::from_secs(60)), 5);
+        assert_eq!(sum(Duration::from_secs(180)), 7);
+
+        // Buckets older than the retention are dropped
+        buckets.update(origin + Duration::from_secs(700), |v| *v += 1);
+        let now = origin + Duration::from_secs(700);
+        assert_eq!(buckets.fold(now, Duration::from_secs(3600), 0, |acc, v| acc + v), 6);
+    }
+}
@@ -0,0 +1,145 @@
+//! Startup work that makes the first pushes after a deploy as fast as later
+//! ones: provider connections, OAuth tokens, store and metrics state.
+//! Readiness stays false until it finishes or the deadline passes.
+
+use futures::future::BoxFuture;
+use log::{info, warn};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::health::Readiness;
+use crate::models::{WarmupState, WarmupStatus};
+
+struct Step {
+    name: String,
+    run: BoxFuture<'static, Result<(), String>>,
+}
+
+pub struct Warmup {
+    deadline: Duration,
+    steps: Vec<Step>,
+}
+
+impl Warmup {
+    /// Readiness is held back at most `deadline`.
+    pub fn new(deadline: Duration) -> Self {
+        Self {
+            deadline,
+            steps: Vec::new(),
+        }
+    }
+
+    pub fn step<Fut>(mut self, name: impl Into<String>, run: Fut) -> Self
+    where
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        self.steps.push(Step {
+            name: name.into(),
+            run: Box::pin(run),
+        });
+        self
+    }
+
+    /// Hold readiness back now and run the steps in the background.
+    pub fn spawn(self, readiness: Arc<Readiness>) {
+        readiness.begin_warmup(self.status());
+        tokio::spawn(async move { self.run(&readiness).await });
+    }
+
+    fn status(&self) -> WarmupStatus {
+        WarmupStatus {
+            state: WarmupState::Running,
+            pending: self.steps.iter().map(|step| step.name.clone()).collect(),
+            completed: Vec::new(),
+            failed: Vec::new(),
+            duration_ms: None,
+        }
+    }
+
+    /// Run the steps in order. Readiness flips when they are done, or when
+    /// the deadline passes, in which case warmup is reported as degraded.
+    async fn run(self, readiness: &Readiness) {
+        let started = Instant::now();
+        info!("Warming up: {} step(s), deadline {:?}", self.steps.len(), self.deadline);
+
+        let steps = async {
+            for step in self.steps {
+                let step_started = Instant::now();
+                let result = step.run.await;
+                readiness.update_warmup(|status| {
+                    status.pending.retain(|name| *name != step.name);
+                    match &result {
+                        Ok(()) => {
+                            info!("Warmup step {} done in {:?}", step.name, step_started.elapsed());
+                            status.completed.push(step.name);
+                        }
+                        Err(e) => {
+                            warn!("Warmup step {} failed: {}", step.name, e);
+                            status.failed.push(step.name);
+                        }
+                    }
+                });
+            }
+        };
+        let finished = tokio::time::timeout(self.deadline, steps).await.is_ok();
+
+        readiness.finish_warmup(started.elapsed(), |status| {
+            if !finished {
+                warn!("Warmup deadline passed with {:?} still pending, ready anyway", status.pending);
+            }
+            if finished && status.failed.is_empty() {
+                WarmupState::Complete
+            } else {
+                WarmupState::Degraded
+            }
+        });
+        info!("Warmup finished in {:?}", started.elapsed());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot;
+
+    fn loaded_readiness() -> Arc<Readiness> {
+        let readiness = Arc::new(Readiness::new(0));
+        readiness.mark_store_loaded();
+        readiness
+    }
+
+    #[tokio::test]
+    async fn test_readiness_waits_for_warmup_or_deadline() {
+        let readiness = loaded_readiness();
+        let (done, wait) = oneshot::channel::<()>();
+        Warmup::new(Duration::from_secs(10))
+            .step("metrics", async { Ok(()) })
+            .step("providers", async move { wait.await.map_err(|e| e.to_string()) })
+            .spawn(readiness.clone());
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!readiness.is_ready());
+        let status = readiness.warmup_status().unwrap();
+        assert_eq!(status.completed, vec!["metrics"]);
+        assert_eq!(status.pending, vec!["providers"]);
+
+        done.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(readiness.is_ready());
+        assert_eq!(readiness.warmup_status().unwrap().state, WarmupState::Complete);
+
+        // A step that never finishes can only hold readiness until the deadline
+        let readiness = loaded_readiness();
+        Warmup::new(Duration::from_millis(50))
+            .step("providers", futures::future::pending())
+            .spawn(readiness.clone());
+        assert!(!readiness.is_ready());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(readiness.is_ready());
+        let status = readiness.warmup_status().unwrap();
+        assert_eq!(status.state, WarmupState::Degraded);
+        assert_eq!(status.pending, vec!["providers"]);
+        assert!(status.duration_ms.is_some());
+    }
+}
@@ -0,0 +1,155 @@
+//! Verbose tracing for a few pubkeys, set at runtime through `/admin/watch`
+//! to debug one user's missed notifications without a global debug flood.
+
+use chrono::{DateTime, Duration, Utc};
+use log::info;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use crate::models::{WatchRecord, WatchStatus};
+
+/// Target of the log lines written for watched pubkeys.
+pub const WATCH_TARGET: &str = "watch";
+/// Records kept per watched pubkey; the oldest are dropped beyond this.
+const MAX_RECORDS: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WatchError {
+    /// Every slot is held by an unexpired watch
+    Full(usize),
+}
+
+impl std::fmt::Display for WatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WatchError::Full(max) => write!(f, "already watching {} pubkeys", max),
+        }
+    }
+}
+
+struct WatchEntry {
+    expires_at: DateTime<Utc>,
+    records: VecDeque<WatchRecord>,
+}
+
+pub struct WatchList {
+    max_keys: usize,
+    entries: Mutex<HashMap<String, WatchEntry>>,
+}
+
+impl WatchList {
+    pub fn new(max_keys: usize) -> Self {
+        Self {
+            max_keys,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Watch `trade_pubkey` for `duration`, extending an existing watch.
+    /// Expired watches give up their slot when the list is full.
+    pub fn watch(&self, trade_pubkey: &str, duration: Duration, now: DateTime<Utc>) -> Result<DateTime<Utc>, WatchError> {
+        let mut entries = self.entries.lock().unwrap();
+        let expires_at = now + duration;
+        if let Some(entry) = entries.get_mut(trade_pubkey) {
+            entry.expires_at = expires_at;
+            return Ok(expires_at);
+        }
+        if entries.len() >= self.max_keys {
+            entries.retain(|_, entry| entry.expires_at > now);
+        }
+        if entries.len() >= self.max_keys {
+            return Err(WatchError::Full(self.max_keys));
+        }
+        entries.insert(
+            trade_pubkey.to_string(),
+            WatchEntry { expires_at, records: VecDeque::new() },
+        );
+        Ok(expires_at)
+    }
+
+    /// Stop watching and drop the records. Returns false if it wasn't watched.
+    pub fn unwatch(&self, trade_pubkey: &str) -> bool {
+        self.entries.lock().unwrap().remove(trade_pubkey).is_some()
+    }
+
+    /// A handle for recording `trade_pubkey`'s progress, if it is watched.
+    pub fn handle(self: &Arc<Self>, trade_pubkey: &str, now: DateTime<Utc>) -> Option<WatchHandle> {
+        let entries = self.entries.lock().unwrap();
+        entries.get(trade_pubkey).filter(|entry| entry.expires_at > now)?;
+        Some(WatchHandle {
+            list: self.clone(),
+            trade_pubkey: trade_pubkey.to_string(),
+        })
+    }
+
+    pub fn records(&self, trade_pubkey: &str) -> Option<Vec<WatchRecord>> {
+        let entries = self.entries.lock().unwrap();
+        entries.get(trade_pubkey).map(|entry| entry.records.iter().cloned().collect())
+    }
+
+    pub fn status(&self, now: DateTime<Utc>) -> Vec<WatchStatus> {
+        let entries = self.entries.lock().unwrap();
+        let mut status: Vec<WatchStatus> = entries
+            .iter()
+            .map(|(trade_pubkey, entry)| WatchStatus {
+                trade_pubkey: trade_pubkey.clone(),
+                expires_at: entry.expires_at,
+                active: entry.expires_at > now,
+                records: entry.records.len(),
+            })
+            .collect();
+        status.sort_by(|a, b| a.trade_pubkey.cmp(&b.trade_pubkey));
+        status
+    }
+
+    fn record(&self, trade_pubkey: &str, stage: &str, detail: String) {
+        let now = Utc::now();
+        let mut entries = self.entries.lock().unwrap();
+        let Some(entry) = entries.get_mut(trade_pubkey).filter(|entry| entry.expires_at > now) else {
+            return;
+        };
+        info!(target: WATCH_TARGET, "trade_pubkey={} stage={} {}", trade_pubkey, stage, detail);
+        if entry.records.len() >= MAX_RECORDS {
+            entry.records.pop_front();
+        }
+        entry.records.push_back(WatchRecord { at: now, stage: stage.to_string(), detail });
+    }
+}
+
+/// Records pipeline steps for one watched pubkey.
+#[derive(Clone)]
+pub struct WatchHandle {
+    list: Arc<WatchList>,
+    trade_pubkey: String,
+}
+
+impl WatchHandle {
+    pub fn record(&self, stage: &str, detail: impl Into<String>) {
+        self.list.record(&self.trade_pubkey, stage, detail.into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watch_list_is_bounded_and_expires() {
+        let list = Arc::new(WatchList::new(2));
+        let now = Utc::now();
+        list.watch("a", Duration::minutes(5), now).unwrap();
+        list.watch("b", Duration::hours(1), now).unwrap();
+        assert_eq!(list.watch("c", Duration::hours(1), now), Err(WatchError::Full(2)));
+
+        list.handle("a", now).unwrap().record("event", "id=1");
+        assert!(list.handle("c", now).is_none());
+
+        // Once "a" expires it records nothing more and gives up its slot
+        let later = now + Duration::minutes(10);
+        assert!(list.handle("a", later).is_none());
+        assert!(!list.status(later)[0].active);
+        list.watch("c", Duration::hours(1), later).unwrap();
+        assert!(list.records("a").is_none());
+        assert_eq!(list.status(later).len(), 2);
+    }
+}